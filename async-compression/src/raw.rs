@@ -17,6 +17,7 @@ use bzip2::write::BzEncoder;
 use flate2::bufread::GzDecoder;
 use flate2::write::GzEncoder;
 use zstd::Encoder as ZstdEncoder;
+use zstd::stream::raw::{Decoder as ZstdRawDecoder, Operation};
 
 pub trait RawDecoder<R: BufRead>: Read {
     fn get_ref(&self) -> &R;
@@ -58,6 +59,81 @@ impl<R: BufRead> RawDecoder<R> for GzDecoder<R> {
     }
 }
 
+/// A zstd decoder that is safe to use on asynchronous, buffered input.
+///
+/// zstd::Decoder reads its input into an internal buffer, so it can consume data that follows
+/// the compressed frame, and it loses data if the underlying reader returns WouldBlock. This
+/// decoder works directly on the buffer of the BufRead, consumes only the bytes that the
+/// decompressor actually used and keeps the decompression state between calls, so it can be
+/// retried after WouldBlock and leaves everything after the frame in the reader.
+pub struct ZstdDecoder<R: BufRead> {
+    inner: R,
+    decoder: ZstdRawDecoder,
+    finished: bool,
+}
+
+impl<R: BufRead> ZstdDecoder<R> {
+    pub fn new(r: R) -> Self {
+        ZstdDecoder {
+            inner: r,
+            // Decoder::new() should only fail on OOM, so just call unwrap here. The encoder does
+            // the same thing.
+            decoder: ZstdRawDecoder::new().unwrap(),
+            finished: false,
+        }
+    }
+}
+
+impl<R: BufRead> Read for ZstdDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let status = {
+                let input = self.inner.fill_buf()?;
+                let eof = input.is_empty();
+                let status = self.decoder.run_on_buffers(input, buf)?;
+
+                if eof && status.bytes_written == 0 && status.remaining != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "zstd stream ended in the middle of a frame",
+                    ));
+                }
+                status
+            };
+
+            self.inner.consume(status.bytes_read);
+            // remaining is 0 once the whole frame is decoded and flushed into buf
+            if status.remaining == 0 {
+                self.finished = true;
+            }
+            if status.bytes_written > 0 || self.finished {
+                return Ok(status.bytes_written);
+            }
+        }
+    }
+}
+
+impl<R: BufRead> RawDecoder<R> for ZstdDecoder<R> {
+    #[inline]
+    fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    #[inline]
+    fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    #[inline]
+    fn into_inner(self: Box<Self>) -> R {
+        self.inner
+    }
+}
+
 pub trait RawEncoder<W>: AsyncWrite
 where
     W: AsyncWrite + Send,
@@ -11,7 +11,7 @@ use flate2;
 use futures::{Async, Poll};
 use quickcheck::{Arbitrary, Gen, TestResult};
 use tokio_core::reactor::Core;
-use tokio_io::AsyncWrite;
+use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::io::read_to_end;
 
 use retry::retry_write;
@@ -30,6 +30,10 @@ quickcheck! {
         roundtrip(CompressorType::Gzip(cmprs.0), &input)
     }
 
+    fn test_zstd_roundtrip(cmprs: ZstdCompression, input: Vec<u8>) -> TestResult {
+        roundtrip(CompressorType::Zstd { level: cmprs.0 }, &input)
+    }
+
     fn test_bzip_overreading(
         cmprs: BzipCompression,
         compressable_input: Vec<u8>,
@@ -53,6 +57,22 @@ quickcheck! {
             extra_input.as_slice(),
         )
     }
+
+    fn test_zstd_overreading(
+        cmprs: ZstdCompression,
+        compressable_input: Vec<u8>,
+        extra_input: Vec<u8>
+    ) -> TestResult {
+        check_overreading(
+            CompressorType::Zstd { level: cmprs.0 },
+            compressable_input.as_slice(),
+            extra_input.as_slice(),
+        )
+    }
+
+    fn test_zstd_partial_input(cmprs: ZstdCompression, input: Vec<u8>) -> TestResult {
+        check_partial_input(CompressorType::Zstd { level: cmprs.0 }, &input)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -85,6 +105,14 @@ impl Arbitrary for GzipCompression {
     }
 }
 
+#[derive(Debug, Clone)]
+struct ZstdCompression(i32);
+impl Arbitrary for ZstdCompression {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        ZstdCompression(*g.choose(&[1, 3, 9, 19]).unwrap())
+    }
+}
+
 fn roundtrip(ct: CompressorType, input: &[u8]) -> TestResult {
     let compressed_buf = MeteredWrite::new(Cursor::new(Vec::with_capacity(32 * 1024)));
     let mut compressor = MeteredWrite::new(Compressor::new(compressed_buf, ct));
@@ -229,3 +257,61 @@ fn check_overreading(c_type: CompressorType, data: &[u8], extra: &[u8]) -> TestR
 
     TestResult::passed()
 }
+
+/// A reader that returns WouldBlock before yielding each byte of its data.
+struct BlockingEveryByte {
+    data: Vec<u8>,
+    pos: usize,
+    blocked: bool,
+}
+
+impl Read for BlockingEveryByte {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.data.len() || buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.blocked {
+            self.blocked = true;
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "blocked"));
+        }
+        self.blocked = false;
+        buf[0] = self.data[self.pos];
+        self.pos += 1;
+        Ok(1)
+    }
+}
+
+impl AsyncRead for BlockingEveryByte {}
+
+fn check_partial_input(c_type: CompressorType, data: &[u8]) -> TestResult {
+    let compressed = {
+        let mut compressor = Compressor::new(Cursor::new(Vec::new()), c_type);
+        compressor.write_all(data).unwrap();
+        compressor.try_finish().unwrap().into_inner()
+    };
+
+    let mut decompressor = Decompressor::new(
+        BufReader::new(BlockingEveryByte {
+            data: compressed,
+            pos: 0,
+            blocked: false,
+        }),
+        c_type.decompressor_type(),
+    );
+
+    let mut result = Vec::new();
+    let mut buf = [0u8; 16];
+    loop {
+        match decompressor.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => result.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return TestResult::error(format!("decoding failed: {}", e)),
+        }
+    }
+
+    if result.as_slice() != data {
+        return TestResult::error(format!("decoded data differs: {:?}", result));
+    }
+    TestResult::passed()
+}
@@ -489,6 +489,16 @@ where
         }
     }
 
+    /// Insert a result that was computed elsewhere, for example by a lookup of many keys at
+    /// once. Keys that already have a result, or whose result is being computed, are left alone.
+    pub fn insert<K: Into<F::Key>>(&self, key: K, value: <F::Value as IntoFuture>::Item) {
+        let mut locked = self.inner.hash.lock().expect("lock poison");
+        let key = key.into();
+        if locked.get(&key).is_none() {
+            let _ = locked.insert(key, Slot::Complete(value));
+        }
+    }
+
     /// Invalidate a specific key
     pub fn invalidate<K: Into<F::Key>>(&self, key: K) {
         let mut locked = self.inner.hash.lock().expect("lock poison");
@@ -55,6 +55,24 @@ fn simple() {
     assert_eq!(c.len(), 2);
 }
 
+#[test]
+fn insert() {
+    let count = AtomicUsize::new(0);
+    let c = Asyncmemo::new_unbounded(Upperer(&count));
+
+    c.insert("foo", "bar".to_string());
+    assert_eq!(c.len(), 1);
+    let v = c.get("foo").wait().unwrap();
+    assert_eq!(v, "bar");
+    assert_eq!(count.load(Ordering::Relaxed), 0);
+
+    // Results that are already known aren't replaced
+    c.insert("foo", "baz".to_string());
+    let v = c.get("foo").wait().unwrap();
+    assert_eq!(v, "bar");
+    assert_eq!(count.load(Ordering::Relaxed), 0);
+}
+
 #[test]
 fn clear() {
     let count = AtomicUsize::new(0);
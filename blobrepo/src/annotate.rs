@@ -0,0 +1,312 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Precomputed file annotations, as shown by `hg annotate`.
+//!
+//! The annotation of a file revision gives, for each of its lines, the changeset that introduced
+//! the line and its line number in that changeset. It is derived from the annotations of the
+//! parent revisions by diffing the contents, so annotating a revision needs the annotations of
+//! all its ancestors. Every annotation is stored as a blob once it is derived, so a walk through
+//! the history stops at the ancestors that were annotated before.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use bincode;
+use bytes::Bytes;
+use futures::future::{self, Future, IntoFuture, Loop};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobstore::Blobstore;
+use mercurial_types::{MPath, NodeHash, RepoPath};
+use mercurial_types::bdiff;
+
+use errors::*;
+use repo::BlobRepo;
+use utils::get_annotate_key;
+
+/// Origin of a line of a file
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct AnnotatedLine {
+    /// The changeset that introduced the line
+    pub changeset: NodeHash,
+    /// Line number in that changeset, starting from 1
+    pub line: usize,
+}
+
+/// A file revision, with the path it's stored under
+type FileRevision = (MPath, NodeHash);
+
+/// A file revision that is not annotated yet. The content is only fetched when the revision is
+/// annotated, so that the contents of a long history are never all in memory.
+struct Revision {
+    linknode: NodeHash,
+    /// The parent revisions, including the revision the file was copied from
+    parents: Vec<FileRevision>,
+}
+
+/// Annotation of the revision `node` of `path`. The annotations of the revision and its
+/// ancestors are derived and stored if they don't exist yet.
+pub fn get_annotation(
+    repo: BlobRepo,
+    blobstore: Arc<Blobstore>,
+    path: MPath,
+    node: NodeHash,
+) -> BoxFuture<Vec<AnnotatedLine>, Error> {
+    let start = (path, node);
+
+    find_missing_revisions(repo.clone(), blobstore.clone(), start.clone())
+        .and_then(move |(annotations, mut missing)| {
+            let revisions: Vec<_> = parents_first(&start, &missing)
+                .into_iter()
+                .filter_map(|key| missing.remove(&key).map(|revision| (key, revision)))
+                .collect();
+            let derivation = Derivation::new(annotations, &revisions);
+
+            stream::iter_ok(revisions)
+                .fold(derivation, move |derivation, (key, revision)| {
+                    derivation.derive(&repo, &blobstore, key, revision)
+                })
+                .map(move |mut derivation| {
+                    derivation
+                        .annotations
+                        .remove(&start)
+                        .expect("start revision was not annotated")
+                })
+        })
+        .boxify()
+}
+
+/// State of the annotation of the missing revisions, in parents first order. The contents and
+/// annotations of the revisions are kept only until all their children are annotated.
+struct Derivation {
+    annotations: HashMap<FileRevision, Vec<AnnotatedLine>>,
+    contents: HashMap<FileRevision, Bytes>,
+    /// Number of revisions left to annotate that have each revision as a parent
+    children: HashMap<FileRevision, usize>,
+}
+
+impl Derivation {
+    fn new(
+        annotations: HashMap<FileRevision, Vec<AnnotatedLine>>,
+        revisions: &[(FileRevision, Revision)],
+    ) -> Self {
+        let mut children = HashMap::new();
+        for &(_, ref revision) in revisions {
+            for parent in revision.parents.iter() {
+                *children.entry(parent.clone()).or_insert(0) += 1;
+            }
+        }
+        Derivation {
+            annotations,
+            contents: HashMap::new(),
+            children,
+        }
+    }
+
+    /// Annotate `revision`, whose parents must be annotated already, and store the result
+    fn derive(
+        mut self,
+        repo: &BlobRepo,
+        blobstore: &Arc<Blobstore>,
+        key: FileRevision,
+        revision: Revision,
+    ) -> BoxFuture<Self, Error> {
+        let parents: Vec<_> = revision
+            .parents
+            .iter()
+            .map(|parent| {
+                let annotation = self.annotations[parent].clone();
+                self.content(repo, parent)
+                    .map(move |content| (content, annotation))
+            })
+            .collect();
+        let content = self.content(repo, &key);
+
+        let blobstore = blobstore.clone();
+        let blobkey = get_annotate_key(&key.0, &key.1);
+        content
+            .join(future::join_all(parents))
+            .and_then(move |(content, parent_lines)| {
+                let annotation = annotate_lines(&content, revision.linknode, &parent_lines);
+                let blob = try_boxfuture!(bincode::serialize(&annotation));
+
+                let parents = revision.parents.into_iter().zip(parent_lines);
+                for (parent, (parent_content, _)) in parents {
+                    self.contents.insert(parent.clone(), parent_content);
+                    self.forget_child(parent);
+                }
+                if self.children.contains_key(&key) {
+                    self.contents.insert(key.clone(), content);
+                }
+                self.annotations.insert(key, annotation);
+
+                blobstore
+                    .put(blobkey, Bytes::from(blob))
+                    .map(move |()| self)
+                    .boxify()
+            })
+            .boxify()
+    }
+
+    fn content(&self, repo: &BlobRepo, key: &FileRevision) -> BoxFuture<Bytes, Error> {
+        match self.contents.get(key) {
+            Some(content) => future::ok(content.clone()).boxify(),
+            None => repo.get_file_content(&key.1),
+        }
+    }
+
+    /// Record that a child of `parent` was annotated, and drop the content and annotation of
+    /// `parent` once nothing needs them anymore
+    fn forget_child(&mut self, parent: FileRevision) {
+        let done = match self.children.get_mut(&parent) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if done {
+            self.children.remove(&parent);
+            self.contents.remove(&parent);
+            self.annotations.remove(&parent);
+        }
+    }
+}
+
+/// Walk back from `start` until the revisions with a stored annotation. Returns the stored
+/// annotations that were found, and the revisions that need to be annotated.
+fn find_missing_revisions(
+    repo: BlobRepo,
+    blobstore: Arc<Blobstore>,
+    start: FileRevision,
+) -> BoxFuture<
+    (
+        HashMap<FileRevision, Vec<AnnotatedLine>>,
+        HashMap<FileRevision, Revision>,
+    ),
+    Error,
+> {
+    future::loop_fn(
+        (vec![start], HashMap::new(), HashMap::new()),
+        move |(mut pending, mut annotations, mut missing)| {
+            let key: FileRevision = match pending.pop() {
+                Some(key) => key,
+                None => return future::ok(Loop::Break((annotations, missing))).boxify(),
+            };
+            if annotations.contains_key(&key) || missing.contains_key(&key) {
+                return future::ok(Loop::Continue((pending, annotations, missing))).boxify();
+            }
+
+            let repo = repo.clone();
+            let annotation = load_annotation(&blobstore, &key);
+            annotation
+                .and_then(move |annotation| match annotation {
+                    Some(annotation) => {
+                        annotations.insert(key, annotation);
+                        future::ok(Loop::Continue((pending, annotations, missing))).boxify()
+                    }
+                    None => get_revision(&repo, key.clone())
+                        .map(move |revision| {
+                            pending.extend(revision.parents.iter().cloned());
+                            missing.insert(key, revision);
+                            Loop::Continue((pending, annotations, missing))
+                        })
+                        .boxify(),
+                })
+                .boxify()
+        },
+    ).boxify()
+}
+
+/// Order the revisions of `missing` that `start` depends on so that each revision comes after
+/// its parents
+fn parents_first(
+    start: &FileRevision,
+    missing: &HashMap<FileRevision, Revision>,
+) -> Vec<FileRevision> {
+    let mut order = Vec::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec![(start.clone(), false)];
+
+    while let Some((key, parents_done)) = stack.pop() {
+        if parents_done {
+            order.push(key);
+            continue;
+        }
+        let revision = match missing.get(&key) {
+            Some(revision) => revision,
+            None => continue,
+        };
+        if !visited.insert(key.clone()) {
+            continue;
+        }
+        stack.push((key, true));
+        stack.extend(revision.parents.iter().map(|parent| (parent.clone(), false)));
+    }
+    order
+}
+
+fn load_annotation(
+    blobstore: &Arc<Blobstore>,
+    key: &FileRevision,
+) -> BoxFuture<Option<Vec<AnnotatedLine>>, Error> {
+    blobstore
+        .get(get_annotate_key(&key.0, &key.1))
+        .and_then(|blob| -> Result<Option<Vec<AnnotatedLine>>> {
+            match blob {
+                Some(blob) => Ok(Some(bincode::deserialize(blob.as_ref())?)),
+                None => Ok(None),
+            }
+        })
+        .boxify()
+}
+
+fn get_revision(repo: &BlobRepo, key: FileRevision) -> BoxFuture<Revision, Error> {
+    let (path, node) = key;
+    let copyfrom = repo.get_file_copy(&node);
+    let parents = repo.get_parents(&node);
+    let linknode = RepoPath::file(path.clone()).into_future().and_then({
+        let repo = repo.clone();
+        move |repopath| repo.get_linknode(repopath, &node)
+    });
+
+    copyfrom
+        .join3(parents, linknode)
+        .map(move |(copyfrom, parents, linknode)| {
+            let mut parents: Vec<_> = parents.into_iter().map(|p| (path.clone(), p)).collect();
+            parents.extend(copyfrom);
+            Revision { linknode, parents }
+        })
+        .boxify()
+}
+
+/// Annotate `content` from the contents and annotations of its parents. The lines that don't
+/// come from a parent were introduced by `linknode`. A line that comes from several parents
+/// keeps the annotation of the last one, like in Mercurial.
+fn annotate_lines(
+    content: &[u8],
+    linknode: NodeHash,
+    parents: &[(Bytes, Vec<AnnotatedLine>)],
+) -> Vec<AnnotatedLine> {
+    let mut annotation: Vec<_> = (0..bdiff::lines(content).len())
+        .map(|idx| AnnotatedLine {
+            changeset: linknode,
+            line: idx + 1,
+        })
+        .collect();
+
+    for &(ref parent_content, ref parent_annotation) in parents {
+        for block in bdiff::blocks(parent_content.as_ref(), content) {
+            for idx in 0..block.len {
+                annotation[block.new_start + idx] = parent_annotation[block.old_start + idx];
+            }
+        }
+    }
+    annotation
+}
@@ -0,0 +1,140 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Coalescing of concurrent changeset lookups into batched queries.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use failure::{Compat, Error};
+use futures::future::{self, Future, Shared};
+use futures_ext::{BoxFuture, FutureExt};
+
+use changesets::{ChangesetEntry, ChangesetInsert, Changesets};
+use mercurial_types::{HgChangesetId, RepositoryId};
+
+// A batch binds its changesets and the repo id as variables of one query, and SQLite allows at
+// most 999 of them by default
+const DEFAULT_MAX_BATCH_SIZE: usize = 900;
+
+type BatchFuture = Shared<BoxFuture<Arc<HashMap<HgChangesetId, ChangesetEntry>>, Compat<Error>>>;
+
+struct Batch {
+    cs_ids: Arc<Mutex<Vec<HgChangesetId>>>,
+    entries: BatchFuture,
+}
+
+/// `Changesets` that collect the single gets for a repo into a batch until one of them is
+/// polled, and then look up the whole batch with one `get_many`. Stream combinators like
+/// `buffered` create all their futures before polling any of them, so their lookups end up in
+/// the same query. If the batched lookup fails, each get of the batch retries on its own, so
+/// that an error only fails the gets it belongs to.
+pub struct BatchingChangesets {
+    inner: Arc<Changesets>,
+    pending: Arc<Mutex<HashMap<RepositoryId, Batch>>>,
+    max_batch_size: usize,
+}
+
+impl BatchingChangesets {
+    pub fn new(inner: Arc<Changesets>) -> Self {
+        Self::with_max_batch_size(inner, DEFAULT_MAX_BATCH_SIZE)
+    }
+
+    pub fn with_max_batch_size(inner: Arc<Changesets>, max_batch_size: usize) -> Self {
+        BatchingChangesets {
+            inner,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            max_batch_size,
+        }
+    }
+
+    fn new_batch(&self, repo_id: RepositoryId) -> Batch {
+        let inner = self.inner.clone();
+        let pending = self.pending.clone();
+        let cs_ids = Arc::new(Mutex::new(Vec::new()));
+
+        let lookup = future::lazy({
+            let cs_ids = cs_ids.clone();
+            move || {
+                let cs_ids = {
+                    // Once the batch has started, later gets have to go into a new one
+                    let mut pending = pending.lock().expect("lock poisoned");
+                    let started = pending
+                        .get(&repo_id)
+                        .map(|batch| Arc::ptr_eq(&batch.cs_ids, &cs_ids))
+                        .unwrap_or(false);
+                    if started {
+                        pending.remove(&repo_id);
+                    }
+                    let mut cs_ids = cs_ids.lock().expect("lock poisoned");
+                    mem::replace(&mut *cs_ids, Vec::new())
+                };
+                inner.get_many(repo_id, &cs_ids)
+            }
+        });
+        let entries = lookup
+            .map(|entries| {
+                let entries = entries.into_iter().map(|entry| (entry.cs_id, entry));
+                Arc::new(entries.collect())
+            })
+            .map_err(Error::compat)
+            .boxify()
+            .shared();
+
+        Batch { cs_ids, entries }
+    }
+}
+
+impl Changesets for BatchingChangesets {
+    fn add(&self, cs: &ChangesetInsert) -> BoxFuture<(), Error> {
+        self.inner.add(cs)
+    }
+
+    fn get(
+        &self,
+        repo_id: RepositoryId,
+        cs_id: HgChangesetId,
+    ) -> BoxFuture<Option<ChangesetEntry>, Error> {
+        let entries = {
+            let mut pending = self.pending.lock().expect("lock poisoned");
+            let (entries, full) = {
+                let batch = pending
+                    .entry(repo_id)
+                    .or_insert_with(|| self.new_batch(repo_id));
+                let mut cs_ids = batch.cs_ids.lock().expect("lock poisoned");
+                cs_ids.push(cs_id);
+                (batch.entries.clone(), cs_ids.len() >= self.max_batch_size)
+            };
+            if full {
+                pending.remove(&repo_id);
+            }
+            entries
+        };
+
+        let inner = self.inner.clone();
+        entries
+            .map(move |entries| entries.get(&cs_id).cloned())
+            .or_else(move |_| inner.get(repo_id, cs_id))
+            .boxify()
+    }
+
+    fn get_many(
+        &self,
+        repo_id: RepositoryId,
+        cs_ids: &[HgChangesetId],
+    ) -> BoxFuture<Vec<ChangesetEntry>, Error> {
+        self.inner.get_many(repo_id, cs_ids)
+    }
+
+    fn get_children(
+        &self,
+        repo_id: RepositoryId,
+        cs_id: HgChangesetId,
+    ) -> BoxFuture<Vec<HgChangesetId>, Error> {
+        self.inner.get_children(repo_id, cs_id)
+    }
+}
@@ -10,15 +10,18 @@ use bincode;
 
 pub use failure::Error;
 
-use mercurial_types::{Blob, HgBlobHash, HgChangesetId, NodeHash, Parents, RepoPath, Type};
+use mercurial_types::{Blob, HgBlobHash, HgChangesetId, MPath, NodeHash, Parents, RepoPath,
+                      Sha256, Type};
 
 #[derive(Debug)]
 pub enum StateOpenError {
     Heads,
     Bookmarks,
+    Scratchbooks,
     Blobstore,
     Changesets,
     Linknodes,
+    Obsmarkers,
 }
 
 impl fmt::Display for StateOpenError {
@@ -28,9 +31,11 @@ impl fmt::Display for StateOpenError {
         match *self {
             Heads => write!(f, "heads"),
             Bookmarks => write!(f, "bookmarks"),
+            Scratchbooks => write!(f, "scratch bookmarks"),
             Blobstore => write!(f, "blob store"),
             Changesets => write!(f, "changesets"),
             Linknodes => write!(f, "linknodes"),
+            Obsmarkers => write!(f, "obsmarkers"),
         }
     }
 }
@@ -60,4 +65,9 @@ pub enum ErrorKind {
     #[fail(display = "Parents failed to complete")] ParentsFailed,
     #[fail(display = "Expected {} to be a manifest, found a {} instead", _0, _1)]
     NotAManifest(NodeHash, Type),
+    #[fail(display = "Content doesn't match lfs oid {}", _0)] LfsContentMismatch(Sha256),
+    #[fail(display = "Scratch bookmark {} was moved concurrently", _0)]
+    ScratchBookmarkMoved(String),
+    #[fail(display = "Path {} is not a file in changeset {}", _0, _1)]
+    NotAFile(MPath, HgChangesetId),
 }
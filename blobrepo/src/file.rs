@@ -33,6 +33,25 @@ pub struct BlobEntry {
     ty: Type,
 }
 
+/// Fetch the content of a filenode as it is stored in the filelog, with the copy metadata
+pub fn fetch_raw_filenode_bytes(
+    blobstore: &Arc<Blobstore>,
+    nodeid: NodeHash,
+) -> BoxFuture<Bytes, Error> {
+    get_node(blobstore, nodeid)
+        .and_then({
+            let blobstore = blobstore.clone();
+            move |node| {
+                let key = format!("sha1-{}", node.blob.sha1());
+
+                blobstore.get(key).and_then(move |blob| {
+                    blob.ok_or(ErrorKind::ContentMissing(nodeid, node.blob).into())
+                })
+            }
+        })
+        .boxify()
+}
+
 pub fn fetch_file_content_and_renames_from_blobstore(
     blobstore: &Arc<Blobstore>,
     nodeid: NodeHash,
@@ -92,21 +111,7 @@ impl BlobEntry {
     }
 
     fn get_raw_content_inner(&self) -> BoxFuture<Bytes, Error> {
-        let nodeid = self.id.into_nodehash();
-        let blobstore = self.blobstore.clone();
-
-        self.get_node()
-            .and_then({
-                let blobstore = blobstore.clone();
-                move |node| {
-                    let key = format!("sha1-{}", node.blob.sha1());
-
-                    blobstore.get(key).and_then(move |blob| {
-                        blob.ok_or(ErrorKind::ContentMissing(nodeid, node.blob).into())
-                    })
-                }
-            })
-            .boxify()
+        fetch_raw_filenode_bytes(&self.blobstore, self.id.into_nodehash())
     }
 }
 
@@ -0,0 +1,241 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Precomputed file history.
+//!
+//! Walking the history of a file revision needs a parents, a copy info and a linknode lookup for
+//! every ancestor. Instead, the history of each filenode is derived once into a fragment - the
+//! entry of the filenode followed by the entries of up to `FRAGMENT_SIZE - 1` of its closest
+//! ancestors - which is stored as a single blob. Serving a history then reads one fragment, and
+//! only reads more for the ancestors that didn't fit into the fragments read so far. Fragments
+//! are derived when file revisions are pushed, and backfilled for the older revisions.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+
+use bincode;
+use bytes::Bytes;
+use futures::future::{self, Future, IntoFuture};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use blobstore::Blobstore;
+use mercurial_types::{MPath, NodeHash, Parents, RepoPath, NULL_HASH};
+
+use errors::*;
+use repo::BlobRepo;
+use utils::get_file_history_key;
+
+/// Maximum number of entries in a fragment
+pub const FRAGMENT_SIZE: usize = 100;
+
+/// History of a single file revision
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct FileHistoryEntry {
+    pub node: NodeHash,
+    pub parents: Parents,
+    pub linknode: NodeHash,
+    pub copyfrom: Option<(MPath, NodeHash)>,
+}
+
+/// History of `path` starting at `startnodes`. Each entry is returned once, even if it's an
+/// ancestor of several start nodes. The entries come one fragment at a time, so they aren't in
+/// breadth-first order, but every entry other than the start nodes comes after one of its
+/// children.
+pub fn get_file_history(
+    repo: BlobRepo,
+    blobstore: Arc<Blobstore>,
+    path: MPath,
+    startnodes: Vec<NodeHash>,
+) -> BoxStream<FileHistoryEntry, Error> {
+    let frontier: VecDeque<_> = startnodes
+        .into_iter()
+        .filter(|node| *node != NULL_HASH)
+        .collect();
+
+    stream::unfold(
+        (frontier, HashSet::new()),
+        move |cur_data: (VecDeque<NodeHash>, HashSet<NodeHash>)| {
+            let (mut frontier, mut seen_nodes) = cur_data;
+            // Nodes in the frontier may have been returned as part of a later fragment
+            let mut node = frontier.pop_front()?;
+            while seen_nodes.contains(&node) {
+                node = frontier.pop_front()?;
+            }
+
+            let fragment =
+                get_history_fragment(repo.clone(), blobstore.clone(), path.clone(), node);
+            Some(fragment.map(move |fragment| {
+                let entries: Vec<_> = fragment
+                    .into_iter()
+                    .filter(|entry| seen_nodes.insert(entry.node))
+                    .collect();
+                for entry in entries.iter() {
+                    frontier.extend(
+                        entry
+                            .parents
+                            .into_iter()
+                            .filter(|p| !seen_nodes.contains(p)),
+                    );
+                }
+                (entries, (frontier, seen_nodes))
+            }))
+        },
+    ).map(stream::iter_ok)
+        .flatten()
+        .boxify()
+}
+
+/// Fragment of the history of `node`. If it isn't stored, it's derived from the fragments of the
+/// parents without storing anything: fragments are only stored by `store_history_fragment`.
+pub fn get_history_fragment(
+    repo: BlobRepo,
+    blobstore: Arc<Blobstore>,
+    path: MPath,
+    node: NodeHash,
+) -> BoxFuture<Vec<FileHistoryEntry>, Error> {
+    load_history_fragment(&blobstore, &path, &node)
+        .and_then(move |fragment| match fragment {
+            Some(fragment) => future::ok(fragment).boxify(),
+            None => derive_history_fragment(repo, blobstore, path, node),
+        })
+        .boxify()
+}
+
+/// Derive and store the fragment of `node`, unless it's stored already
+pub fn store_history_fragment(
+    repo: BlobRepo,
+    blobstore: Arc<Blobstore>,
+    path: MPath,
+    node: NodeHash,
+) -> BoxFuture<(), Error> {
+    let key = get_file_history_key(&path, &node);
+    blobstore
+        .is_present(key.clone())
+        .and_then(move |present| {
+            if present {
+                return future::ok(()).boxify();
+            }
+            derive_history_fragment(repo, blobstore.clone(), path, node)
+                .and_then(move |fragment| {
+                    let blob = try_boxfuture!(bincode::serialize(&fragment));
+                    blobstore.put(key, Bytes::from(blob)).boxify()
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+fn load_history_fragment(
+    blobstore: &Arc<Blobstore>,
+    path: &MPath,
+    node: &NodeHash,
+) -> BoxFuture<Option<Vec<FileHistoryEntry>>, Error> {
+    blobstore
+        .get(get_file_history_key(path, node))
+        .and_then(|blob| -> Result<Option<Vec<FileHistoryEntry>>> {
+            match blob {
+                Some(blob) => Ok(Some(bincode::deserialize(blob.as_ref())?)),
+                None => Ok(None),
+            }
+        })
+        .boxify()
+}
+
+/// Derive the fragment of `node`. If the fragments of its parents are stored already, which is
+/// the case when the history is derived from the oldest revisions onwards, they are reused.
+/// Otherwise the ancestors are walked one at a time.
+fn derive_history_fragment(
+    repo: BlobRepo,
+    blobstore: Arc<Blobstore>,
+    path: MPath,
+    node: NodeHash,
+) -> BoxFuture<Vec<FileHistoryEntry>, Error> {
+    get_file_history_entry(repo.clone(), path.clone(), node)
+        .and_then(move |entry| {
+            let parent_fragments: Vec<_> = entry
+                .parents
+                .into_iter()
+                .map(|p| load_history_fragment(&blobstore, &path, &p))
+                .collect();
+
+            future::join_all(parent_fragments).and_then(move |parent_fragments| {
+                match parent_fragments.into_iter().collect::<Option<Vec<_>>>() {
+                    Some(parent_fragments) => {
+                        let mut seen_nodes = HashSet::new();
+                        seen_nodes.insert(entry.node);
+                        let mut fragment = vec![entry];
+                        fragment.extend(
+                            parent_fragments
+                                .into_iter()
+                                .flat_map(|f| f)
+                                .filter(|e| seen_nodes.insert(e.node))
+                                .take(FRAGMENT_SIZE - 1),
+                        );
+                        future::ok(fragment).boxify()
+                    }
+                    None => walk_file_history(repo, path, vec![node])
+                        .take(FRAGMENT_SIZE as u64)
+                        .collect()
+                        .boxify(),
+                }
+            })
+        })
+        .boxify()
+}
+
+/// Walk the history of `path` starting at `startnodes` without using the stored fragments
+fn walk_file_history(
+    repo: BlobRepo,
+    path: MPath,
+    startnodes: Vec<NodeHash>,
+) -> BoxStream<FileHistoryEntry, Error> {
+    let mut startstate = VecDeque::new();
+    let mut seen_nodes = HashSet::new();
+    for startnode in startnodes {
+        if startnode != NULL_HASH && seen_nodes.insert(startnode) {
+            startstate.push_back(startnode);
+        }
+    }
+
+    stream::unfold(
+        (startstate, seen_nodes),
+        move |cur_data: (VecDeque<NodeHash>, HashSet<NodeHash>)| {
+            let (mut nodes, mut seen_nodes) = cur_data;
+            let node = nodes.pop_front()?;
+
+            Some(
+                get_file_history_entry(repo.clone(), path.clone(), node).map(move |entry| {
+                    nodes.extend(entry.parents.into_iter().filter(|p| seen_nodes.insert(*p)));
+                    (entry, (nodes, seen_nodes))
+                }),
+            )
+        },
+    ).boxify()
+}
+
+fn get_file_history_entry(
+    repo: BlobRepo,
+    path: MPath,
+    node: NodeHash,
+) -> BoxFuture<FileHistoryEntry, Error> {
+    let parents = repo.get_parents(&node);
+    let copy = repo.get_file_copy(&node);
+    let linknode = RepoPath::file(path)
+        .into_future()
+        .and_then(move |path| repo.get_linknode(path, &node));
+
+    parents
+        .join3(linknode, copy)
+        .map(move |(parents, linknode, copyfrom)| FileHistoryEntry {
+            node,
+            parents,
+            linknode,
+            copyfrom,
+        })
+        .boxify()
+}
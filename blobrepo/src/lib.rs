@@ -46,22 +46,31 @@ extern crate memheads;
 extern crate memlinknodes;
 extern crate mercurial;
 extern crate mercurial_types;
+extern crate obsmarkers;
 extern crate rocksblob;
 extern crate rocksdb;
 extern crate storage_types;
 
 mod repo;
+mod annotate;
+mod batch;
 mod changeset;
 mod manifest;
 mod file;
+mod file_history;
 mod errors;
 mod utils;
 mod repo_commit;
 
 pub use errors::*;
 
+pub use annotate::AnnotatedLine;
+
+pub use batch::BatchingChangesets;
+
 pub use changeset::BlobChangeset;
 pub use file::BlobEntry;
+pub use file_history::FileHistoryEntry;
 pub use manifest::BlobManifest;
 pub use repo::BlobRepo;
 pub use repo_commit::ChangesetHandle;
@@ -4,7 +4,7 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::mem;
 use std::path::Path;
 use std::sync::Arc;
@@ -13,7 +13,7 @@ use bincode;
 use bytes::Bytes;
 use failure::{Fail, ResultExt};
 use futures::{Async, Poll};
-use futures::future::Future;
+use futures::future::{self, Future};
 use futures::stream::{self, Stream};
 use futures::sync::oneshot;
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
@@ -22,7 +22,7 @@ use slog::{Discard, Drain, Logger};
 use uuid::Uuid;
 
 use blobstore::Blobstore;
-use bookmarks::Bookmarks;
+use bookmarks::{Bookmarks, BookmarksMut};
 use changesets::{ChangesetInsert, Changesets, SqliteChangesets};
 use fileblob::Fileblob;
 use filebookmarks::FileBookmarks;
@@ -36,28 +36,36 @@ use membookmarks::MemBookmarks;
 use memheads::MemHeads;
 use memlinknodes::MemLinknodes;
 use mercurial_types::{Blob, BlobNode, Changeset, Entry, HgChangesetId, MPath, Manifest, NodeHash,
-                      Parents, RepoPath, RepositoryId, Time};
+                      Obsmarker, Parents, Phase, RepoPath, RepositoryId, Sha256, Time};
 use mercurial_types::manifest;
 use mercurial_types::nodehash::HgManifestId;
+use obsmarkers::{Obsmarkers, SqliteObsmarkers};
 use rocksblob::Rocksblob;
 use rocksdb;
 use storage_types::Version;
 use tokio_core::reactor::Remote;
 
+use BatchingChangesets;
 use BlobChangeset;
 use BlobManifest;
+use annotate::{self, AnnotatedLine};
 use errors::*;
-use file::{fetch_file_content_and_renames_from_blobstore, BlobEntry};
+use file::{fetch_file_content_and_renames_from_blobstore, fetch_raw_filenode_bytes, BlobEntry};
+use file_history::{self, FileHistoryEntry};
 use repo_commit::*;
-use utils::{get_node, get_node_key, RawNodeBlob};
+use utils::{get_lfs_content_key, get_lfs_file_key, get_node, get_node_key, get_phase_key,
+            RawNodeBlob};
 
 pub struct BlobRepo {
     logger: Logger,
     blobstore: Arc<Blobstore>,
     bookmarks: Arc<Bookmarks>,
+    /// Bookmarks of infinitepush pushes, kept apart from the public bookmarks
+    scratch_bookmarks: Arc<BookmarksMut>,
     heads: Arc<Heads>,
     linknodes: Arc<Linknodes>,
     changesets: Arc<Changesets>,
+    obsmarkers: Arc<Obsmarkers>,
     repoid: RepositoryId,
 }
 
@@ -66,18 +74,23 @@ impl BlobRepo {
         logger: Logger,
         heads: Arc<Heads>,
         bookmarks: Arc<Bookmarks>,
+        scratch_bookmarks: Arc<BookmarksMut>,
         blobstore: Arc<Blobstore>,
         linknodes: Arc<Linknodes>,
         changesets: Arc<Changesets>,
+        obsmarkers: Arc<Obsmarkers>,
         repoid: RepositoryId,
     ) -> Self {
         BlobRepo {
             logger,
             heads,
             bookmarks,
+            scratch_bookmarks,
             blobstore,
             linknodes,
-            changesets,
+            // Lookups of single changesets made at the same time share a query
+            changesets: Arc::new(BatchingChangesets::new(changesets)),
+            obsmarkers,
             repoid,
         }
     }
@@ -87,20 +100,27 @@ impl BlobRepo {
             .context(ErrorKind::StateOpen(StateOpenError::Heads))?;
         let bookmarks = FileBookmarks::open(path.join("books"))
             .context(ErrorKind::StateOpen(StateOpenError::Bookmarks))?;
+        let scratch_bookmarks = FileBookmarks::create(path.join("scratchbooks"))
+            .context(ErrorKind::StateOpen(StateOpenError::Scratchbooks))?;
         let blobstore = Fileblob::open(path.join("blobs"))
             .context(ErrorKind::StateOpen(StateOpenError::Blobstore))?;
         let linknodes = FileLinknodes::open(path.join("linknodes"))
             .context(ErrorKind::StateOpen(StateOpenError::Linknodes))?;
         let changesets = SqliteChangesets::open(path.join("changesets").to_string_lossy())
             .context(ErrorKind::StateOpen(StateOpenError::Linknodes))?;
+        let obsmarkers =
+            SqliteObsmarkers::open_or_create(path.join("obsmarkers").to_string_lossy())
+                .context(ErrorKind::StateOpen(StateOpenError::Obsmarkers))?;
 
         Ok(Self::new(
             logger,
             Arc::new(heads),
             Arc::new(bookmarks),
+            Arc::new(scratch_bookmarks),
             Arc::new(blobstore),
             Arc::new(linknodes),
             Arc::new(changesets),
+            Arc::new(obsmarkers),
             repoid,
         ))
     }
@@ -110,6 +130,8 @@ impl BlobRepo {
             .context(ErrorKind::StateOpen(StateOpenError::Heads))?;
         let bookmarks = FileBookmarks::open(path.join("books"))
             .context(ErrorKind::StateOpen(StateOpenError::Bookmarks))?;
+        let scratch_bookmarks = FileBookmarks::create(path.join("scratchbooks"))
+            .context(ErrorKind::StateOpen(StateOpenError::Scratchbooks))?;
 
         let options = rocksdb::Options::new().create_if_missing(true);
         let blobstore = Rocksblob::open_with_options(path.join("blobs"), options)
@@ -118,14 +140,19 @@ impl BlobRepo {
             .context(ErrorKind::StateOpen(StateOpenError::Linknodes))?;
         let changesets = SqliteChangesets::open(path.join("changesets").to_string_lossy())
             .context(ErrorKind::StateOpen(StateOpenError::Linknodes))?;
+        let obsmarkers =
+            SqliteObsmarkers::open_or_create(path.join("obsmarkers").to_string_lossy())
+                .context(ErrorKind::StateOpen(StateOpenError::Obsmarkers))?;
 
         Ok(Self::new(
             logger,
             Arc::new(heads),
             Arc::new(bookmarks),
+            Arc::new(scratch_bookmarks),
             Arc::new(blobstore),
             Arc::new(linknodes),
             Arc::new(changesets),
+            Arc::new(obsmarkers),
             repoid,
         ))
     }
@@ -139,15 +166,18 @@ impl BlobRepo {
         blobstore: EagerMemblob,
         linknodes: MemLinknodes,
         changesets: SqliteChangesets,
+        obsmarkers: SqliteObsmarkers,
         repoid: RepositoryId,
     ) -> Self {
         Self::new(
             logger.unwrap_or(Logger::root(Discard {}.ignore_res(), o!())),
             Arc::new(heads),
             Arc::new(bookmarks),
+            Arc::new(MemBookmarks::new()),
             Arc::new(blobstore),
             Arc::new(linknodes),
             Arc::new(changesets),
+            Arc::new(obsmarkers),
             repoid,
         )
     }
@@ -159,15 +189,18 @@ impl BlobRepo {
         blobstore: LazyMemblob,
         linknodes: MemLinknodes,
         changesets: SqliteChangesets,
+        obsmarkers: SqliteObsmarkers,
         repoid: RepositoryId,
     ) -> Self {
         Self::new(
             logger.unwrap_or(Logger::root(Discard {}.ignore_res(), o!())),
             Arc::new(heads),
             Arc::new(bookmarks),
+            Arc::new(MemBookmarks::new()),
             Arc::new(blobstore),
             Arc::new(linknodes),
             Arc::new(changesets),
+            Arc::new(obsmarkers),
             repoid,
         )
     }
@@ -177,10 +210,13 @@ impl BlobRepo {
             logger.unwrap_or(Logger::root(Discard {}.ignore_res(), o!())),
             Arc::new(MemHeads::new()),
             Arc::new(MemBookmarks::new()),
+            Arc::new(MemBookmarks::new()),
             Arc::new(EagerMemblob::new()),
             Arc::new(MemLinknodes::new()),
             Arc::new(SqliteChangesets::in_memory()
                 .context(ErrorKind::StateOpen(StateOpenError::Changesets))?),
+            Arc::new(SqliteObsmarkers::in_memory()
+                .context(ErrorKind::StateOpen(StateOpenError::Obsmarkers))?),
             RepositoryId::new(0),
         ))
     }
@@ -194,18 +230,23 @@ impl BlobRepo {
     ) -> Result<Self> {
         let heads = MemHeads::new();
         let bookmarks = MemBookmarks::new();
+        let scratch_bookmarks = MemBookmarks::new();
         let blobstore = ManifoldBlob::new_with_prefix(bucket.to_string(), prefix, remote);
         let linknodes = MemLinknodes::new();
         let changesets = SqliteChangesets::in_memory()
             .context(ErrorKind::StateOpen(StateOpenError::Changesets))?;
+        let obsmarkers = SqliteObsmarkers::in_memory()
+            .context(ErrorKind::StateOpen(StateOpenError::Obsmarkers))?;
 
         Ok(Self::new(
             logger,
             Arc::new(heads),
             Arc::new(bookmarks),
+            Arc::new(scratch_bookmarks),
             Arc::new(blobstore),
             Arc::new(linknodes),
             Arc::new(changesets),
+            Arc::new(obsmarkers),
             repoid,
         ))
     }
@@ -216,6 +257,11 @@ impl BlobRepo {
             .boxify()
     }
 
+    /// Content of the file including the copy metadata, as it is stored in the filelog
+    pub fn get_raw_file_content(&self, key: &NodeHash) -> BoxFuture<Bytes, Error> {
+        fetch_raw_filenode_bytes(&self.blobstore, *key)
+    }
+
     pub fn get_parents(&self, key: &NodeHash) -> BoxFuture<Parents, Error> {
         get_node(&self.blobstore, *key)
             .map(|rawnode| rawnode.parents)
@@ -228,6 +274,62 @@ impl BlobRepo {
             .boxify()
     }
 
+    /// History of the file at `path` starting at `startnodes`, served from the precomputed
+    /// history fragments. Only the history that isn't precomputed yet is walked.
+    pub fn get_file_history(
+        &self,
+        path: MPath,
+        startnodes: Vec<NodeHash>,
+    ) -> BoxStream<FileHistoryEntry, Error> {
+        file_history::get_file_history(self.clone(), self.blobstore.clone(), path, startnodes)
+    }
+
+    /// Make sure that the history fragment of a filenode is stored. Deriving fragments from the
+    /// oldest revisions onwards is the cheapest, as each one reuses the fragments of its parents.
+    pub fn derive_file_history(&self, path: MPath, node: NodeHash) -> BoxFuture<(), Error> {
+        file_history::store_history_fragment(self.clone(), self.blobstore.clone(), path, node)
+    }
+
+    /// Annotation of the file at `path` in a changeset: for each line, the changeset that
+    /// introduced it and its line number there
+    pub fn annotate(
+        &self,
+        changesetid: &HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Vec<AnnotatedLine>, Error> {
+        let changesetid = *changesetid;
+        let entry = self.get_changeset_by_changesetid(&changesetid)
+            .and_then({
+                let repo = self.clone();
+                move |cs| repo.get_manifest_by_nodeid(&cs.manifestid().into_nodehash())
+            })
+            .and_then({
+                let path = path.clone();
+                move |manifest| manifest.lookup(&path)
+            });
+
+        let repo = self.clone();
+        entry
+            .and_then(move |entry| match entry {
+                Some(ref entry) if entry.get_type() != manifest::Type::Tree => {
+                    let filenode = entry.get_hash().into_nodehash();
+                    repo.annotate_filenode(path, filenode)
+                }
+                _ => future::err(ErrorKind::NotAFile(path, changesetid).into()).boxify(),
+            })
+            .boxify()
+    }
+
+    /// Annotation of the revision `node` of the file at `path`. Annotations are derived once and
+    /// stored, so annotating a file again only derives the annotations of its new revisions.
+    pub fn annotate_filenode(
+        &self,
+        path: MPath,
+        node: NodeHash,
+    ) -> BoxFuture<Vec<AnnotatedLine>, Error> {
+        annotate::get_annotation(self.clone(), self.blobstore.clone(), path, node)
+    }
+
     pub fn get_changesets(&self) -> BoxStream<NodeHash, Error> {
         BlobChangesetStream {
             repo: self.clone(),
@@ -285,10 +387,160 @@ impl BlobRepo {
         self.bookmarks.get(key).boxify()
     }
 
+    /// All the bookmarks with their values
+    pub fn get_bookmarks(&self) -> BoxStream<(Vec<u8>, HgChangesetId), Error> {
+        with_values(self.bookmarks.clone(), self.bookmarks.keys())
+    }
+
+    /// The bookmarks whose names start with `prefix`, with their values
+    pub fn get_bookmarks_with_prefix(
+        &self,
+        prefix: &AsRef<[u8]>,
+    ) -> BoxStream<(Vec<u8>, HgChangesetId), Error> {
+        with_values(self.bookmarks.clone(), self.bookmarks.keys_with_prefix(prefix))
+    }
+
+    /// The scratch bookmarks whose names start with `prefix`, with their values
+    pub fn get_scratch_bookmarks_with_prefix(
+        &self,
+        prefix: &AsRef<[u8]>,
+    ) -> BoxStream<(Vec<u8>, HgChangesetId), Error> {
+        with_values(
+            self.scratch_bookmarks.clone(),
+            self.scratch_bookmarks.keys_with_prefix(prefix),
+        )
+    }
+
+    pub fn get_scratch_bookmark_keys(&self) -> BoxStream<Vec<u8>, Error> {
+        self.scratch_bookmarks.keys().boxify()
+    }
+
+    pub fn get_scratch_bookmark_value(
+        &self,
+        key: &AsRef<[u8]>,
+    ) -> BoxFuture<Option<(HgChangesetId, Version)>, Error> {
+        self.scratch_bookmarks.get(key).boxify()
+    }
+
+    /// Point a scratch bookmark at `value` whatever it pointed at before, or delete it if `value`
+    /// is None. Fails if the bookmark is moved concurrently.
+    pub fn set_scratch_bookmark(
+        &self,
+        key: Vec<u8>,
+        value: Option<HgChangesetId>,
+    ) -> BoxFuture<(), Error> {
+        let scratch_bookmarks = self.scratch_bookmarks.clone();
+        let current = self.scratch_bookmarks.get(&key);
+
+        current
+            .and_then(move |current| {
+                let version = current.map(|(_, version)| version).unwrap_or(Version::absent());
+                let update = match value {
+                    Some(value) => scratch_bookmarks.set(&key, &value, &version),
+                    None => scratch_bookmarks.delete(&key, &version),
+                };
+                update.and_then(move |new_version| match new_version {
+                    Some(_) => Ok(()),
+                    None => Err(ErrorKind::ScratchBookmarkMoved(
+                        String::from_utf8_lossy(&key).into_owned(),
+                    ).into()),
+                })
+            })
+            .boxify()
+    }
+
     pub fn get_linknode(&self, path: RepoPath, node: &NodeHash) -> BoxFuture<NodeHash, Error> {
         self.linknodes.get(path, node)
     }
 
+    /// Phase recorded for a changeset. Changesets pushed with infinitepush are recorded as draft
+    /// and changesets that were made public are recorded as public; nothing is recorded for the
+    /// other changesets.
+    pub fn get_recorded_phase(&self, cs: &HgChangesetId) -> BoxFuture<Option<Phase>, Error> {
+        self.blobstore
+            .get(get_phase_key(cs))
+            .and_then(|phase| match phase {
+                Some(phase) => {
+                    let phase = String::from_utf8_lossy(phase.as_ref()).parse()?;
+                    Ok(Some(phase))
+                }
+                None => Ok(None),
+            })
+            .boxify()
+    }
+
+    /// The recorded phases of several changesets, looked up concurrently. Changesets with
+    /// nothing recorded are left out.
+    pub fn get_recorded_phases(
+        &self,
+        css: &[HgChangesetId],
+    ) -> BoxFuture<HashMap<HgChangesetId, Phase>, Error> {
+        let phases = css.iter().map(|cs| {
+            let cs = *cs;
+            self.get_recorded_phase(&cs).map(move |phase| (cs, phase))
+        });
+        future::join_all(phases.collect::<Vec<_>>())
+            .map(|phases| {
+                phases
+                    .into_iter()
+                    .filter_map(|(cs, phase)| phase.map(|phase| (cs, phase)))
+                    .collect()
+            })
+            .boxify()
+    }
+
+    pub fn record_phase(&self, cs: &HgChangesetId, phase: Phase) -> BoxFuture<(), Error> {
+        self.blobstore
+            .put(get_phase_key(cs), Bytes::from(phase.to_string()))
+    }
+
+    /// Make changesets public. Their ancestors that were recorded as draft are made public too,
+    /// as a public changeset can't have draft ancestors.
+    pub fn make_public(&self, nodes: Vec<NodeHash>) -> BoxFuture<(), Error> {
+        let repo = self.clone();
+        future::loop_fn(
+            (nodes, HashSet::new()),
+            move |(nodes, mut seen): (Vec<NodeHash>, HashSet<NodeHash>)| {
+                let nodes: Vec<_> = nodes.into_iter().filter(|n| seen.insert(*n)).collect();
+                if nodes.is_empty() {
+                    return future::ok(future::Loop::Break(())).boxify();
+                }
+
+                let repo = repo.clone();
+                let parents = nodes.into_iter().map(move |node| {
+                    let csid = HgChangesetId::new(node);
+                    let repo = repo.clone();
+                    repo.get_recorded_phase(&csid)
+                        .and_then(move |phase| match phase {
+                            Some(Phase::Draft) => repo.record_phase(&csid, Phase::Public)
+                                .and_then(move |()| repo.get_changeset_by_changesetid(&csid))
+                                .map(|cs| cs.parents().into_iter().collect())
+                                .boxify(),
+                            // Changesets with nothing recorded are public already
+                            _ => future::ok(vec![]).boxify(),
+                        })
+                });
+
+                future::join_all(parents)
+                    .map(move |parents| {
+                        let parents = parents.into_iter().flat_map(|p| p).collect();
+                        future::Loop::Continue((parents, seen))
+                    })
+                    .boxify()
+            },
+        ).boxify()
+    }
+
+    /// Store obsolescence markers. Returns how many of them weren't stored yet.
+    pub fn add_obsmarkers(&self, markers: Vec<Obsmarker>) -> BoxFuture<usize, Error> {
+        self.obsmarkers.add(self.repoid, markers)
+    }
+
+    /// Obsolescence markers whose predecessor or one of whose successors is one of `nodes`
+    pub fn get_obsmarkers(&self, nodes: Vec<HgChangesetId>) -> BoxFuture<Vec<Obsmarker>, Error> {
+        self.obsmarkers.get(self.repoid, nodes)
+    }
+
     pub fn get_generation_number(&self, cs: &HgChangesetId) -> BoxFuture<Option<u64>, Error> {
         self.changesets
             .get(self.repoid, *cs)
@@ -296,6 +548,95 @@ impl BlobRepo {
             .boxify()
     }
 
+    /// Parents of a changeset. They're read from the changesets table, where concurrent lookups
+    /// are batched, and only changesets that aren't complete yet are loaded from the blobstore.
+    pub fn get_changeset_parents(
+        &self,
+        cs: &HgChangesetId,
+    ) -> BoxFuture<Vec<HgChangesetId>, Error> {
+        let cs = *cs;
+        let blobstore = self.blobstore.clone();
+        self.changesets
+            .get(self.repoid, cs)
+            .and_then(move |entry| match entry {
+                Some(entry) => future::ok(entry.parents).boxify(),
+                None => BlobChangeset::load(&blobstore, &cs)
+                    .and_then(move |changeset| {
+                        changeset.ok_or(ErrorKind::ChangesetMissing(cs).into())
+                    })
+                    .map(|changeset| {
+                        changeset
+                            .parents()
+                            .into_iter()
+                            .map(HgChangesetId::new)
+                            .collect()
+                    })
+                    .boxify(),
+            })
+            .boxify()
+    }
+
+    /// The changesets among `cs_ids` that are complete in the changesets table, fetched in one
+    /// query
+    pub fn get_stored_changesets(
+        &self,
+        cs_ids: &[HgChangesetId],
+    ) -> BoxFuture<HashSet<HgChangesetId>, Error> {
+        self.changesets
+            .get_many(self.repoid, cs_ids)
+            .map(|entries| entries.into_iter().map(|entry| entry.cs_id).collect())
+            .boxify()
+    }
+
+    /// Generation numbers of the changesets that are stored, fetched in one query
+    pub fn get_generation_numbers(
+        &self,
+        cs_ids: &[HgChangesetId],
+    ) -> BoxFuture<HashMap<HgChangesetId, u64>, Error> {
+        self.changesets
+            .get_many(self.repoid, cs_ids)
+            .map(|entries| {
+                entries
+                    .into_iter()
+                    .map(|entry| (entry.cs_id, entry.gen))
+                    .collect()
+            })
+            .boxify()
+    }
+
+    /// The changesets that have `cs` as a parent
+    pub fn get_children(&self, cs: &HgChangesetId) -> BoxFuture<Vec<HgChangesetId>, Error> {
+        self.changesets.get_children(self.repoid, *cs)
+    }
+
+    /// Content of an lfs file, addressed by the oid from its pointer
+    pub fn get_lfs_content(&self, oid: &Sha256) -> BoxFuture<Option<Bytes>, Error> {
+        self.blobstore.get(get_lfs_content_key(oid))
+    }
+
+    pub fn lfs_content_exists(&self, oid: &Sha256) -> BoxFuture<bool, Error> {
+        self.blobstore.is_present(get_lfs_content_key(oid))
+    }
+
+    /// Store the content of an lfs file. The content is verified against the oid, so lfs pointers
+    /// in the repo can only ever refer to the content they were created for.
+    pub fn upload_lfs_content(&self, oid: Sha256, content: Bytes) -> BoxFuture<(), Error> {
+        if Sha256::from(content.as_ref()) != oid {
+            return future::err(ErrorKind::LfsContentMismatch(oid).into()).boxify();
+        }
+        self.blobstore.put(get_lfs_content_key(&oid), content)
+    }
+
+    /// Record that the file revision `nodeid` has the lfs flag, i.e. that its content is an lfs
+    /// pointer. Only revisions marked this way are served as lfs files.
+    pub fn mark_lfs_file(&self, nodeid: &NodeHash) -> BoxFuture<(), Error> {
+        self.blobstore.put(get_lfs_file_key(nodeid), Bytes::new())
+    }
+
+    pub fn is_lfs_file(&self, nodeid: &NodeHash) -> BoxFuture<bool, Error> {
+        self.blobstore.is_present(get_lfs_file_key(nodeid))
+    }
+
     // Given content, ensure that there is a matching BlobEntry in the repo. This may not upload
     // the entry or the data blob if the repo is aware of that data already existing in the
     // underlying store.
@@ -311,10 +652,29 @@ impl BlobRepo {
         p2: Option<NodeHash>,
         path: RepoPath,
     ) -> Result<(NodeHash, BoxFuture<(BlobEntry, RepoPath), Error>)> {
-        let p1 = p1.as_ref();
-        let p2 = p2.as_ref();
         let raw_content = raw_content.clean();
-        let parents = Parents::new(p1, p2);
+        let nodeid = BlobNode::new(raw_content.clone(), p1.as_ref(), p2.as_ref())
+            .nodeid()
+            .ok_or_else(|| Error::from(ErrorKind::BadUploadBlob(raw_content.clone())))?;
+        let upload =
+            self.upload_entry_with_nodeid(raw_content, content_type, p1, p2, path, nodeid)?;
+        Ok((nodeid, upload))
+    }
+
+    /// Like `upload_entry`, but the entry is stored as `nodeid` rather than as the hash of
+    /// `raw_content`. The node of an lfs revision is the hash of the content its pointer refers
+    /// to, so it can't be computed from the pointer that's stored.
+    pub fn upload_entry_with_nodeid(
+        &self,
+        raw_content: Blob,
+        content_type: manifest::Type,
+        p1: Option<NodeHash>,
+        p2: Option<NodeHash>,
+        path: RepoPath,
+        nodeid: NodeHash,
+    ) -> Result<BoxFuture<(BlobEntry, RepoPath), Error>> {
+        let raw_content = raw_content.clean();
+        let parents = Parents::new(p1.as_ref(), p2.as_ref());
 
         let blob_hash = raw_content
             .hash()
@@ -325,10 +685,6 @@ impl BlobRepo {
             blob: blob_hash,
         };
 
-        let nodeid = BlobNode::new(raw_content.clone(), p1, p2)
-            .nodeid()
-            .ok_or_else(|| Error::from(ErrorKind::BadUploadBlob(raw_content.clone())))?;
-
         let blob_entry = BlobEntry::new(
             self.blobstore.clone(),
             path.mpath()
@@ -386,26 +742,23 @@ impl BlobRepo {
                 .into(),
         );
 
-        Ok((
-            nodeid,
-            content_upload
-                .join(node_upload)
-                .map({
-                    let path = path.clone();
-                    |_| (blob_entry, path)
-                })
-                .timed({
-                    let logger = self.logger.clone();
-                    let path = path.clone();
-                    let nodeid = nodeid.clone();
-                    move |stats, result| {
-                        if result.is_ok() {
-                            log_upload_stats(logger, path, nodeid, "finished", stats)
-                        }
+        Ok(content_upload
+            .join(node_upload)
+            .map({
+                let path = path.clone();
+                |_| (blob_entry, path)
+            })
+            .timed({
+                let logger = self.logger.clone();
+                let path = path.clone();
+                let nodeid = nodeid.clone();
+                move |stats, result| {
+                    if result.is_ok() {
+                        log_upload_stats(logger, path, nodeid, "finished", stats)
                     }
-                })
-                .boxify(),
-        ))
+                }
+            })
+            .boxify())
     }
 
     /// Create a changeset in this repo. This will upload all the blobs to the underlying Blobstore
@@ -421,6 +774,57 @@ impl BlobRepo {
         time: Time,
         extra: BTreeMap<Vec<u8>, Vec<u8>>,
         comments: String,
+    ) -> ChangesetHandle {
+        self.create_changeset_impl(
+            p1,
+            p2,
+            root_manifest,
+            new_child_entries,
+            user,
+            time,
+            extra,
+            comments,
+            true,
+        )
+    }
+
+    /// Like `create_changeset`, but the changeset isn't added to the heads of the repo. Changesets
+    /// pushed with infinitepush are only reachable from scratch bookmarks.
+    pub fn create_scratch_changeset(
+        &self,
+        p1: Option<ChangesetHandle>,
+        p2: Option<ChangesetHandle>,
+        root_manifest: BoxFuture<(BlobEntry, RepoPath), Error>,
+        new_child_entries: BoxStream<(BlobEntry, RepoPath), Error>,
+        user: String,
+        time: Time,
+        extra: BTreeMap<Vec<u8>, Vec<u8>>,
+        comments: String,
+    ) -> ChangesetHandle {
+        self.create_changeset_impl(
+            p1,
+            p2,
+            root_manifest,
+            new_child_entries,
+            user,
+            time,
+            extra,
+            comments,
+            false,
+        )
+    }
+
+    fn create_changeset_impl(
+        &self,
+        p1: Option<ChangesetHandle>,
+        p2: Option<ChangesetHandle>,
+        root_manifest: BoxFuture<(BlobEntry, RepoPath), Error>,
+        new_child_entries: BoxStream<(BlobEntry, RepoPath), Error>,
+        user: String,
+        time: Time,
+        extra: BTreeMap<Vec<u8>, Vec<u8>>,
+        comments: String,
+        add_to_heads: bool,
     ) -> ChangesetHandle {
         let entry_processor = UploadEntries::new(self.blobstore.clone());
         let (signal_parent_ready, can_be_parent) = oneshot::channel();
@@ -468,6 +872,11 @@ impl BlobRepo {
 
                                 let cs_id = blobcs.get_changeset_id().into_nodehash();
                                 let manifest_id = *blobcs.manifestid();
+                                let add_head = if add_to_heads {
+                                    heads.add(&cs_id)
+                                } else {
+                                    future::ok(()).boxify()
+                                };
 
                                 debug!(logger, "Changeset uuid to hash mapping";
                                     "changeset_uuid" => format!("{}", uuid),
@@ -475,15 +884,15 @@ impl BlobRepo {
 
                                 blobcs
                                     .save(blobstore)
-                                    .join(heads.add(&cs_id))
+                                    .join(add_head)
                                     .join(entry_processor.finalize(linknodes, cs_id))
-                                    .map(move |_| {
+                                    .map(move |(_, uploaded_files)| {
                                         // We deliberately eat this error - this is only so that
                                         // another changeset can start uploading to the blob store
                                         // while we complete this one
                                         let _ = signal_parent_ready.send((cs_id, manifest_id));
+                                        (blobcs, uploaded_files)
                                     })
-                                    .map(move |_| blobcs)
                                     .boxify()
                             }
                         })
@@ -507,13 +916,22 @@ impl BlobRepo {
                 }
             });
 
+        let repo = self.clone();
         let complete_changesets = self.changesets.clone();
         let repo_id = self.repoid;
         ChangesetHandle::new_pending(
             can_be_parent.shared(),
             changeset
                 .join(parents_complete)
-                .and_then(move |(cs, _)| {
+                .and_then(move |((cs, uploaded_files), _)| {
+                    // The parents are complete, so the history of the new file revisions can
+                    // reuse theirs
+                    let history = uploaded_files
+                        .into_iter()
+                        .map(move |(path, node)| repo.derive_file_history(path, node));
+                    future::join_all(history.collect::<Vec<_>>()).map(move |_| cs)
+                })
+                .and_then(move |cs| {
                     let completion_record = ChangesetInsert {
                         repo_id: repo_id,
                         cs_id: cs.get_changeset_id(),
@@ -545,14 +963,35 @@ impl Clone for BlobRepo {
             logger: self.logger.clone(),
             heads: self.heads.clone(),
             bookmarks: self.bookmarks.clone(),
+            scratch_bookmarks: self.scratch_bookmarks.clone(),
             blobstore: self.blobstore.clone(),
             linknodes: self.linknodes.clone(),
             changesets: self.changesets.clone(),
+            obsmarkers: self.obsmarkers.clone(),
             repoid: self.repoid.clone(),
         }
     }
 }
 
+/// Values of the bookmarks named `names`. Bookmarks that are deleted while they are listed are
+/// skipped.
+fn with_values<B>(
+    bookmarks: Arc<B>,
+    names: BoxStream<Vec<u8>, Error>,
+) -> BoxStream<(Vec<u8>, HgChangesetId), Error>
+where
+    B: Bookmarks + ?Sized,
+{
+    names
+        .and_then(move |name| {
+            bookmarks
+                .get(&name)
+                .map(move |value| value.map(|(csid, _version)| (name, csid)))
+        })
+        .filter_map(|bookmark| bookmark)
+        .boxify()
+}
+
 pub struct BlobChangesetStream {
     repo: BlobRepo,
     seen: HashSet<NodeHash>,
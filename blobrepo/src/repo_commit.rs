@@ -191,7 +191,14 @@ impl UploadEntries {
         }
     }
 
-    pub fn finalize(self, linknodes: Arc<Linknodes>, cs_id: NodeHash) -> BoxFuture<(), Error> {
+    /// Check that the required entries are stored and record the linknodes of the uploaded
+    /// ones. Returns the uploaded file revisions, whose history can be derived once the parent
+    /// changesets are complete.
+    pub fn finalize(
+        self,
+        linknodes: Arc<Linknodes>,
+        cs_id: NodeHash,
+    ) -> BoxFuture<Vec<(MPath, NodeHash)>, Error> {
         let required_checks = {
             let inner = self.inner.lock().expect("Lock poisoned");
             let checks: Vec<_> = inner
@@ -226,9 +233,15 @@ impl UploadEntries {
             future::join_all(checks).boxify()
         };
 
+        let mut uploaded_files = Vec::new();
         let linknodes = {
             let mut inner = self.inner.lock().expect("Lock poisoned");
             let uploaded_entries = mem::replace(&mut inner.uploaded_entries, HashMap::new());
+            for (path, entryid) in uploaded_entries.iter() {
+                if let &RepoPath::FilePath(ref path) = path {
+                    uploaded_files.push((path.clone(), entryid.into_nodehash()));
+                }
+            }
             let futures = uploaded_entries.into_iter().map(move |(path, entryid)| {
                 linknodes
                     .add(path, &entryid.into_nodehash(), &cs_id)
@@ -242,7 +255,7 @@ impl UploadEntries {
 
         parent_checks
             .join3(required_checks, linknodes)
-            .map(|_| ())
+            .map(move |_| uploaded_files)
             .boxify()
     }
 }
@@ -10,7 +10,8 @@ use futures_ext::{BoxFuture, FutureExt};
 use bincode;
 
 use blobstore::Blobstore;
-use mercurial_types::{HgBlobHash, NodeHash, Parents};
+use mercurial_types::{HgBlobHash, HgChangesetId, MPath, NodeHash, Parents, Sha256};
+use mercurial_types::hash::Sha1;
 
 use errors::*;
 
@@ -25,6 +26,32 @@ pub fn get_node_key(nodeid: NodeHash) -> String {
     format!("node-{}.bincode", nodeid)
 }
 
+pub fn get_lfs_content_key(oid: &Sha256) -> String {
+    format!("lfs-sha256-{}", oid)
+}
+
+pub fn get_lfs_file_key(nodeid: &NodeHash) -> String {
+    format!("lfs-file-{}", nodeid)
+}
+
+pub fn get_phase_key(changesetid: &HgChangesetId) -> String {
+    format!("phase-{}", changesetid)
+}
+
+/// Key of the history fragment of a filenode. The same filenode can appear under several paths
+/// with a different linknode for each, so the path is part of the key.
+pub fn get_file_history_key(path: &MPath, nodeid: &NodeHash) -> String {
+    let path_hash = Sha1::from(path.to_vec().as_slice());
+    format!("filehistory-{}-{}.bincode", path_hash, nodeid)
+}
+
+/// Key of the annotation of a filenode. Like the history, the annotation depends on the linknodes
+/// of the filenode and its ancestors, so the path is part of the key.
+pub fn get_annotate_key(path: &MPath, nodeid: &NodeHash) -> String {
+    let path_hash = Sha1::from(path.to_vec().as_slice());
+    format!("annotate-{}-{}.bincode", path_hash, nodeid)
+}
+
 pub fn get_node(blobstore: &Blobstore, nodeid: NodeHash) -> BoxFuture<RawNodeBlob, Error> {
     let key = get_node_key(nodeid);
 
@@ -24,21 +24,30 @@ extern crate membookmarks;
 extern crate memheads;
 extern crate memlinknodes;
 extern crate mercurial_types;
+extern crate obsmarkers;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use bytes::Bytes;
-use futures::Future;
+use failure::Error;
+use futures::{future, Future, Stream};
+use futures_ext::BoxFuture;
 
-use blobrepo::{compute_changed_files, BlobRepo};
+use blobrepo::{compute_changed_files, AnnotatedLine, BatchingChangesets, BlobRepo,
+               FileHistoryEntry};
+use changesets::{ChangesetEntry, ChangesetInsert, Changesets, SqliteChangesets};
 use mercurial_types::{manifest, Blob, Changeset, Entry, EntryId, HgChangesetId, HgManifestId,
-                      MPath, MPathElement, RepoPath};
+                      MPath, MPathElement, Parents, Phase, RepoPath, RepositoryId};
 
 mod stats_units;
 #[macro_use]
 mod utils;
 
-use utils::{create_changeset_no_parents, create_changeset_one_parent, get_empty_eager_repo,
-            get_empty_lazy_repo, run_future, string_to_nodehash, upload_file_no_parents,
-            upload_file_one_parent, upload_manifest_no_parents, upload_manifest_one_parent};
+use utils::{create_changeset_no_parents, create_changeset_one_parent,
+            create_scratch_changeset_no_parents, get_empty_eager_repo, get_empty_lazy_repo,
+            run_future, string_to_nodehash, upload_file_no_parents, upload_file_one_parent,
+            upload_manifest_no_parents, upload_manifest_one_parent};
 
 fn upload_blob_no_parents(repo: BlobRepo) {
     let expected_hash = string_to_nodehash("c3127cdbf2eae0f09653f9237d85c8436425b246");
@@ -218,6 +227,9 @@ fn create_two_changesets(repo: BlobRepo) {
         linknode,
         commit1.get_changeset_id().into_nodehash()
     );
+
+    let parents = run_future(repo.get_changeset_parents(&commit2.get_changeset_id())).unwrap();
+    assert_eq!(parents, vec![commit1.get_changeset_id()]);
 }
 
 test_both_repotypes!(
@@ -226,6 +238,235 @@ test_both_repotypes!(
     create_two_changesets_eager
 );
 
+fn file_history(repo: BlobRepo) {
+    let fake_file_path = RepoPath::file("file").expect("Can't generate fake RepoPath");
+
+    let (filehash1, file_future) = upload_file_no_parents(&repo, "blob", &fake_file_path);
+    let (roothash, root_manifest_future) = upload_manifest_no_parents(
+        &repo,
+        format!("file\0{}\n", filehash1),
+        &RepoPath::root(),
+    );
+    let commit1 = create_changeset_no_parents(&repo, root_manifest_future, vec![file_future]);
+
+    let (filehash2, file_future) =
+        upload_file_one_parent(&repo, "blob2", &fake_file_path, filehash1);
+    let (_, root_manifest_future) = upload_manifest_one_parent(
+        &repo,
+        format!("file\0{}\n", filehash2),
+        &RepoPath::root(),
+        roothash,
+    );
+    let commit2 = create_changeset_one_parent(
+        &repo,
+        root_manifest_future,
+        vec![file_future],
+        commit1.clone(),
+    );
+
+    let (commit1, commit2) = run_future(
+        commit1
+            .get_completed_changeset()
+            .join(commit2.get_completed_changeset()),
+    ).unwrap();
+
+    let expected = vec![
+        FileHistoryEntry {
+            node: filehash2,
+            parents: Parents::One(filehash1),
+            linknode: commit2.get_changeset_id().into_nodehash(),
+            copyfrom: None,
+        },
+        FileHistoryEntry {
+            node: filehash1,
+            parents: Parents::None,
+            linknode: commit1.get_changeset_id().into_nodehash(),
+            copyfrom: None,
+        },
+    ];
+
+    let path = MPath::new("file").unwrap();
+    // The history is derived when the changesets are created, deriving it again does nothing
+    for derived in vec![false, true] {
+        if derived {
+            for filehash in vec![filehash1, filehash2] {
+                run_future(repo.derive_file_history(path.clone(), filehash)).unwrap();
+            }
+        }
+        let history = run_future(
+            repo.get_file_history(path.clone(), vec![filehash2, filehash1])
+                .collect(),
+        ).unwrap();
+        assert_eq!(history, expected);
+    }
+
+    let history = run_future(repo.get_file_history(path, vec![filehash1]).collect()).unwrap();
+    assert_eq!(history, expected[1..].to_vec());
+}
+
+test_both_repotypes!(file_history, file_history_lazy, file_history_eager);
+
+fn annotate(repo: BlobRepo) {
+    let fake_file_path = RepoPath::file("file").expect("Can't generate fake RepoPath");
+
+    let (filehash1, file_future) = upload_file_no_parents(&repo, "a\nb\n", &fake_file_path);
+    let (roothash, root_manifest_future) = upload_manifest_no_parents(
+        &repo,
+        format!("file\0{}\n", filehash1),
+        &RepoPath::root(),
+    );
+    let commit1 = create_changeset_no_parents(&repo, root_manifest_future, vec![file_future]);
+
+    let (filehash2, file_future) =
+        upload_file_one_parent(&repo, "a\nc\nb\n", &fake_file_path, filehash1);
+    let (_, root_manifest_future) = upload_manifest_one_parent(
+        &repo,
+        format!("file\0{}\n", filehash2),
+        &RepoPath::root(),
+        roothash,
+    );
+    let commit2 = create_changeset_one_parent(
+        &repo,
+        root_manifest_future,
+        vec![file_future],
+        commit1.clone(),
+    );
+
+    let (commit1, commit2) = run_future(
+        commit1
+            .get_completed_changeset()
+            .join(commit2.get_completed_changeset()),
+    ).unwrap();
+    let cs1 = commit1.get_changeset_id().into_nodehash();
+    let cs2 = commit2.get_changeset_id().into_nodehash();
+
+    let line = |changeset, line| AnnotatedLine { changeset, line };
+    let expected = vec![line(cs1, 1), line(cs2, 2), line(cs1, 2)];
+
+    let path = MPath::new("file").unwrap();
+    // The first call derives and stores the annotations, the second one reads them back
+    for _ in 0..2 {
+        let annotation =
+            run_future(repo.annotate(&commit2.get_changeset_id(), path.clone())).unwrap();
+        assert_eq!(annotation, expected);
+    }
+
+    let annotation = run_future(repo.annotate(&commit1.get_changeset_id(), path)).unwrap();
+    assert_eq!(annotation, vec![line(cs1, 1), line(cs1, 2)]);
+
+    let missing = MPath::new("missing").unwrap();
+    assert!(run_future(repo.annotate(&commit2.get_changeset_id(), missing)).is_err());
+}
+
+test_both_repotypes!(annotate, annotate_lazy, annotate_eager);
+
+fn make_public(repo: BlobRepo) {
+    let fake_file_path = RepoPath::file("file").expect("Can't generate fake RepoPath");
+
+    let (filehash, file_future) = upload_file_no_parents(&repo, "blob", &fake_file_path);
+    let (roothash, root_manifest_future) = upload_manifest_no_parents(
+        &repo,
+        format!("file\0{}\n", filehash),
+        &RepoPath::root(),
+    );
+    let commit1 = create_changeset_no_parents(&repo, root_manifest_future, vec![file_future]);
+
+    let (filehash, file_future) = upload_file_one_parent(&repo, "blob2", &fake_file_path, filehash);
+    let (_, root_manifest_future) = upload_manifest_one_parent(
+        &repo,
+        format!("file\0{}\n", filehash),
+        &RepoPath::root(),
+        roothash,
+    );
+    let commit2 = create_changeset_one_parent(
+        &repo,
+        root_manifest_future,
+        vec![file_future],
+        commit1.clone(),
+    );
+
+    let (commit1, commit2) = run_future(
+        commit1
+            .get_completed_changeset()
+            .join(commit2.get_completed_changeset()),
+    ).unwrap();
+    let csid1 = commit1.get_changeset_id();
+    let csid2 = commit2.get_changeset_id();
+
+    assert_eq!(run_future(repo.get_recorded_phase(&csid1)).unwrap(), None);
+
+    run_future(repo.record_phase(&csid1, Phase::Draft)).unwrap();
+    run_future(repo.record_phase(&csid2, Phase::Draft)).unwrap();
+    assert_eq!(
+        run_future(repo.get_recorded_phase(&csid1)).unwrap(),
+        Some(Phase::Draft)
+    );
+
+    // Making the child public makes its draft parent public too
+    run_future(repo.make_public(vec![csid2.into_nodehash()])).unwrap();
+    assert_eq!(
+        run_future(repo.get_recorded_phase(&csid1)).unwrap(),
+        Some(Phase::Public)
+    );
+    assert_eq!(
+        run_future(repo.get_recorded_phase(&csid2)).unwrap(),
+        Some(Phase::Public)
+    );
+}
+
+test_both_repotypes!(make_public, make_public_lazy, make_public_eager);
+
+fn scratch_changeset_and_bookmark(repo: BlobRepo) {
+    let fake_file_path = RepoPath::file("file").expect("Can't generate fake RepoPath");
+
+    let (filehash, file_future) = upload_file_no_parents(&repo, "blob", &fake_file_path);
+    let (_, root_manifest_future) = upload_manifest_no_parents(
+        &repo,
+        format!("file\0{}\n", filehash),
+        &RepoPath::root(),
+    );
+    let commit =
+        create_scratch_changeset_no_parents(&repo, root_manifest_future, vec![file_future]);
+    let csid = run_future(commit.get_completed_changeset())
+        .unwrap()
+        .get_changeset_id();
+
+    // The changeset is stored, but it isn't a head
+    assert!(run_future(repo.changeset_exists(&csid)).unwrap());
+    assert!(run_future(repo.get_heads().collect()).unwrap().is_empty());
+
+    let name = b"scratch/test/foo".to_vec();
+    run_future(repo.set_scratch_bookmark(name.clone(), Some(csid))).unwrap();
+    assert_eq!(
+        run_future(repo.get_scratch_bookmark_keys().collect()).unwrap(),
+        vec![name.clone()]
+    );
+    assert_eq!(
+        run_future(repo.get_scratch_bookmark_value(&name))
+            .unwrap()
+            .map(|(csid, _version)| csid),
+        Some(csid)
+    );
+    // Scratch bookmarks aren't public bookmarks
+    assert!(
+        run_future(repo.get_bookmark_keys().collect())
+            .unwrap()
+            .is_empty()
+    );
+
+    run_future(repo.set_scratch_bookmark(name.clone(), None)).unwrap();
+    assert_eq!(
+        run_future(repo.get_scratch_bookmark_value(&name)).unwrap(),
+        None
+    );
+}
+
+test_both_repotypes!(
+    scratch_changeset_and_bookmark,
+    scratch_changeset_and_bookmark_lazy,
+    scratch_changeset_and_bookmark_eager
+);
+
 fn create_bad_changeset(repo: BlobRepo) {
     let dirhash = string_to_nodehash("c2d60b35a8e7e034042a9467783bbdac88a0d219");
 
@@ -360,6 +601,82 @@ test_both_repotypes!(
     check_linknode_creation_eager
 );
 
+struct CountingChangesets {
+    inner: SqliteChangesets,
+    get_many_calls: AtomicUsize,
+}
+
+impl Changesets for CountingChangesets {
+    fn add(&self, cs: &ChangesetInsert) -> BoxFuture<(), Error> {
+        self.inner.add(cs)
+    }
+
+    fn get(
+        &self,
+        repo_id: RepositoryId,
+        cs_id: HgChangesetId,
+    ) -> BoxFuture<Option<ChangesetEntry>, Error> {
+        self.inner.get(repo_id, cs_id)
+    }
+
+    fn get_many(
+        &self,
+        repo_id: RepositoryId,
+        cs_ids: &[HgChangesetId],
+    ) -> BoxFuture<Vec<ChangesetEntry>, Error> {
+        self.get_many_calls.fetch_add(1, Ordering::SeqCst);
+        self.inner.get_many(repo_id, cs_ids)
+    }
+
+    fn get_children(
+        &self,
+        repo_id: RepositoryId,
+        cs_id: HgChangesetId,
+    ) -> BoxFuture<Vec<HgChangesetId>, Error> {
+        self.inner.get_children(repo_id, cs_id)
+    }
+}
+
+#[test]
+fn batch_changeset_gets() {
+    let repoid = RepositoryId::new(0);
+    let counting = Arc::new(CountingChangesets {
+        inner: SqliteChangesets::in_memory().expect("cannot create in memory changesets"),
+        get_many_calls: AtomicUsize::new(0),
+    });
+    let root = HgChangesetId::new(string_to_nodehash("1111111111111111111111111111111111111111"));
+    let child = HgChangesetId::new(string_to_nodehash("2222222222222222222222222222222222222222"));
+    let other = HgChangesetId::new(string_to_nodehash("3333333333333333333333333333333333333333"));
+    for &(cs_id, ref parents) in [(root, vec![]), (child, vec![root])].iter() {
+        let insert = ChangesetInsert {
+            repo_id: repoid,
+            cs_id,
+            parents: parents.clone(),
+        };
+        run_future(counting.add(&insert)).expect("Adding changeset failed");
+    }
+
+    let batching = BatchingChangesets::new(counting.clone());
+    let gets: Vec<_> = vec![child, other, root]
+        .into_iter()
+        .map(|cs_id| batching.get(repoid, cs_id))
+        .collect();
+    let entries = run_future(future::join_all(gets)).expect("Batched gets failed");
+    assert_eq!(
+        entries
+            .iter()
+            .map(|entry| entry.as_ref().map(|entry| (entry.cs_id, entry.gen)))
+            .collect::<Vec<_>>(),
+        vec![Some((child, 2)), None, Some((root, 1))]
+    );
+    assert_eq!(counting.get_many_calls.load(Ordering::SeqCst), 1);
+
+    // The batch has been looked up, so a new get needs a new query
+    let entry = run_future(batching.get(repoid, root)).expect("Get after batch failed");
+    assert_eq!(entry.map(|entry| entry.parents), Some(vec![]));
+    assert_eq!(counting.get_many_calls.load(Ordering::SeqCst), 2);
+}
+
 #[test]
 fn test_compute_changed_files_no_parents() {
     let repo = many_files_dirs::getrepo(None);
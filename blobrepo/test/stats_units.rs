@@ -18,6 +18,7 @@ use membookmarks::MemBookmarks;
 use memheads::MemHeads;
 use memlinknodes::MemLinknodes;
 use mercurial_types::{RepoPath, RepositoryId};
+use obsmarkers::SqliteObsmarkers;
 
 use utils::{create_changeset_no_parents, run_future, upload_file_no_parents,
             upload_manifest_no_parents};
@@ -28,6 +29,7 @@ fn get_logging_blob_repo(logger: Logger) -> BlobRepo {
     let blobs = LazyMemblob::new();
     let linknodes = MemLinknodes::new();
     let changesets = SqliteChangesets::in_memory().expect("cannot create in memory changesets");
+    let obsmarkers = SqliteObsmarkers::in_memory().expect("cannot create in memory obsmarkers");
     let repoid = RepositoryId::new(0);
 
     BlobRepo::new_lazymemblob(
@@ -37,6 +39,7 @@ fn get_logging_blob_repo(logger: Logger) -> BlobRepo {
         blobs,
         linknodes,
         changesets,
+        obsmarkers,
         repoid,
     )
 }
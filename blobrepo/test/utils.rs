@@ -21,6 +21,7 @@ use membookmarks::MemBookmarks;
 use memheads::MemHeads;
 use memlinknodes::MemLinknodes;
 use mercurial_types::{manifest, Blob, NodeHash, RepoPath, RepositoryId, Time};
+use obsmarkers::SqliteObsmarkers;
 
 pub fn get_empty_eager_repo() -> BlobRepo {
     let bookmarks: MemBookmarks = MemBookmarks::new();
@@ -28,9 +29,19 @@ pub fn get_empty_eager_repo() -> BlobRepo {
     let blobs = EagerMemblob::new();
     let linknodes = MemLinknodes::new();
     let changesets = SqliteChangesets::in_memory().expect("cannot create in memory changesets");
+    let obsmarkers = SqliteObsmarkers::in_memory().expect("cannot create in memory obsmarkers");
     let repoid = RepositoryId::new(0);
 
-    BlobRepo::new_memblob(None, heads, bookmarks, blobs, linknodes, changesets, repoid)
+    BlobRepo::new_memblob(
+        None,
+        heads,
+        bookmarks,
+        blobs,
+        linknodes,
+        changesets,
+        obsmarkers,
+        repoid,
+    )
 }
 
 pub fn get_empty_lazy_repo() -> BlobRepo {
@@ -39,9 +50,19 @@ pub fn get_empty_lazy_repo() -> BlobRepo {
     let blobs = LazyMemblob::new();
     let linknodes = MemLinknodes::new();
     let changesets = SqliteChangesets::in_memory().expect("cannot create in memory changesets");
+    let obsmarkers = SqliteObsmarkers::in_memory().expect("cannot create in memory obsmarkers");
     let repoid = RepositoryId::new(0);
 
-    BlobRepo::new_lazymemblob(None, heads, bookmarks, blobs, linknodes, changesets, repoid)
+    BlobRepo::new_lazymemblob(
+        None,
+        heads,
+        bookmarks,
+        blobs,
+        linknodes,
+        changesets,
+        obsmarkers,
+        repoid,
+    )
 }
 
 macro_rules! test_both_repotypes {
@@ -142,6 +163,23 @@ pub fn create_changeset_no_parents(
     )
 }
 
+pub fn create_scratch_changeset_no_parents(
+    repo: &BlobRepo,
+    root_manifest: BoxFuture<(BlobEntry, RepoPath), Error>,
+    other_nodes: Vec<BoxFuture<(BlobEntry, RepoPath), Error>>,
+) -> ChangesetHandle {
+    repo.create_scratch_changeset(
+        None,
+        None,
+        root_manifest,
+        futures_unordered(other_nodes).boxify(),
+        "author <author@fb.com>".into(),
+        Time { time: 0, tz: 0 },
+        BTreeMap::new(),
+        "Scratch commit".into(),
+    )
+}
+
 pub fn create_changeset_one_parent(
     repo: &BlobRepo,
     root_manifest: BoxFuture<(BlobEntry, RepoPath), Error>,
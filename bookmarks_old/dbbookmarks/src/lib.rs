@@ -71,6 +71,15 @@ impl Bookmarks for DbBookmarks {
             .map_err(|e| e.context("DbBookmarks keys failed").into())
             .boxify()
     }
+
+    fn keys_with_prefix(&self, prefix: &AsRef<[u8]>) -> BoxStream<Vec<u8>, Error> {
+        let prefix = prefix.as_ref().to_vec();
+        self.wrapper
+            .with_inner(move |pool| list_keys_with_prefix(pool, prefix))
+            .flatten_stream()
+            .map_err(|e| e.context("DbBookmarks keys_with_prefix failed").into())
+            .boxify()
+    }
 }
 
 impl BookmarksMut for DbBookmarks {
@@ -108,6 +117,28 @@ fn list_keys(pool: Rc<Pool>) -> BoxFutureNonSend<BoxStream<Vec<u8>, Error>, Erro
         .boxify_nonsend()
 }
 
+fn list_keys_with_prefix(
+    pool: Rc<Pool>,
+    prefix: Vec<u8>,
+) -> BoxFutureNonSend<BoxStream<Vec<u8>, Error>, Error> {
+    // The prefix is matched literally, so the LIKE wildcards in it are escaped
+    let mut pattern = Vec::with_capacity(prefix.len() + 1);
+    for byte in prefix {
+        if byte == b'%' || byte == b'_' || byte == b'\\' {
+            pattern.push(b'\\');
+        }
+        pattern.push(byte);
+    }
+    pattern.push(b'%');
+
+    pool.get_conn()
+        .and_then(|conn| conn.prep_exec("SELECT name FROM bookmarks WHERE name LIKE ?", (pattern,)))
+        .and_then(|res| res.collect::<(Vec<u8>,)>())
+        .map(|(_, rows)| stream::iter_ok(rows.into_iter().map(|row| row.0)).boxify())
+        .map_err(|e| SyncFailure::new(e).into())
+        .boxify_nonsend()
+}
+
 fn get_bookmark(
     pool: Rc<Pool>,
     key: Vec<u8>,
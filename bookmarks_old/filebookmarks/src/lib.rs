@@ -11,6 +11,7 @@ extern crate bookmarks;
 extern crate failure_ext as failure;
 extern crate futures;
 extern crate futures_cpupool;
+#[macro_use]
 extern crate percent_encoding;
 
 extern crate filekv;
@@ -35,6 +36,12 @@ use storage_types::Version;
 
 static PREFIX: &'static str = "bookmark:";
 
+define_encode_set! {
+    /// Names may contain '/' (e.g. scratch bookmarks), which can't be part of a file name. Other
+    /// names are encoded like they always were, so that the existing bookmarks are still found.
+    pub BOOKMARK_ENCODE_SET = [DEFAULT_ENCODE_SET] | {'/'}
+}
+
 /// A basic file-based persistent bookmark store.
 ///
 /// Bookmarks are stored as files in the specified base directory. File operations are dispatched
@@ -76,7 +83,7 @@ impl FileBookmarks {
 
 #[inline]
 fn encode_key(key: &AsRef<[u8]>) -> String {
-    percent_encode(key.as_ref(), DEFAULT_ENCODE_SET).to_string()
+    percent_encode(key.as_ref(), BOOKMARK_ENCODE_SET).to_string()
 }
 
 impl Bookmarks for FileBookmarks {
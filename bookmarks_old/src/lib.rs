@@ -15,7 +15,8 @@ extern crate storage_types;
 
 use std::sync::Arc;
 
-use futures_ext::{BoxFuture, BoxStream};
+use futures::Stream;
+use futures_ext::{BoxFuture, BoxStream, StreamExt};
 
 use mercurial_types::nodehash::HgChangesetId;
 use storage_types::Version;
@@ -28,6 +29,15 @@ pub trait Bookmarks: Sync + Send + 'static {
     // Basic operations.
     fn get(&self, key: &AsRef<[u8]>) -> BoxFuture<Option<(HgChangesetId, Version)>, Error>;
     fn keys(&self) -> BoxStream<Vec<u8>, Error>;
+
+    /// The names that start with `prefix`. Stores that can look them up without going through
+    /// all the names should override this.
+    fn keys_with_prefix(&self, prefix: &AsRef<[u8]>) -> BoxStream<Vec<u8>, Error> {
+        let prefix = prefix.as_ref().to_vec();
+        self.keys()
+            .filter(move |key| key.starts_with(&prefix))
+            .boxify()
+    }
 }
 
 // Implement Bookmarks for boxed Bookmarks trait object
@@ -39,6 +49,10 @@ impl Bookmarks for Box<Bookmarks> {
     fn keys(&self) -> BoxStream<Vec<u8>, Error> {
         (**self).keys()
     }
+
+    fn keys_with_prefix(&self, prefix: &AsRef<[u8]>) -> BoxStream<Vec<u8>, Error> {
+        (**self).keys_with_prefix(prefix)
+    }
 }
 
 // Implement Bookmarks for Arced Bookmarks trait object
@@ -50,6 +64,10 @@ impl Bookmarks for Arc<Bookmarks> {
     fn keys(&self) -> BoxStream<Vec<u8>, Error> {
         (**self).keys()
     }
+
+    fn keys_with_prefix(&self, prefix: &AsRef<[u8]>) -> BoxStream<Vec<u8>, Error> {
+        (**self).keys_with_prefix(prefix)
+    }
 }
 
 // Implement Bookmarks for Arc-wrapped Bookmark type
@@ -64,6 +82,10 @@ where
     fn keys(&self) -> BoxStream<Vec<u8>, Error> {
         (**self).keys()
     }
+
+    fn keys_with_prefix(&self, prefix: &AsRef<[u8]>) -> BoxStream<Vec<u8>, Error> {
+        (**self).keys_with_prefix(prefix)
+    }
 }
 
 /// Trait representing write operations on a bookmark store. Consistency is maintained using
@@ -102,6 +102,25 @@ where
     assert_eq!(result, expected);
 }
 
+fn slash<B>(bookmarks: B, core: &mut Core)
+where
+    B: BookmarksMut,
+{
+    let scratch = b"scratch/test/foo";
+    let hash = HgChangesetId::new(nodehash::ONES_HASH);
+
+    let version = core.run(bookmarks.create(&scratch, &hash))
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        core.run(bookmarks.get(&scratch)).unwrap(),
+        Some((hash, version))
+    );
+
+    let result = core.run(bookmarks.keys().collect()).unwrap();
+    assert_eq!(result, vec![scratch.to_vec()]);
+}
+
 fn persistence<F, B>(mut new_bookmarks: F, core: Rc<RefCell<Core>>)
 where
     F: FnMut() -> B,
@@ -150,6 +169,14 @@ macro_rules! bookmarks_test_impl {
                 list(bookmarks, &mut core);
             }
 
+            #[test]
+            fn test_slash() {
+                let mut core = Core::new().unwrap();
+                let state = $state;
+                let bookmarks = $new_cb(&state, &mut core);
+                slash(bookmarks, &mut core);
+            }
+
             #[test]
             fn test_persistence() {
                 // Not all bookmark implementations support persistence.
@@ -93,6 +93,7 @@ mod tests {
             base,
             linknode,
             delta,
+            flags: 0,
         };
 
         let result = convert_to_revlog_changesets(iter_ok(vec![ChangesetDeltaed { chunk }]))
@@ -18,7 +18,9 @@ use quickcheck::{Arbitrary, Gen};
 
 use blobrepo::{BlobEntry, BlobRepo};
 use mercurial_bundles::changegroup::CgDeltaChunk;
-use mercurial_types::{delta, manifest, Blob, Delta, MPath, NodeHash, RepoPath};
+use mercurial_types::{delta, lfs, manifest, Blob, BlobNode, Delta, LfsPointer, MPath, NodeHash,
+                      RepoPath};
+use mercurial_types::lfs::LFS_FLAG;
 use mercurial_types::nodehash::NULL_HASH;
 
 use errors::*;
@@ -39,6 +41,7 @@ pub struct Filelog {
     pub p2: Option<NodeHash>,
     pub linknode: NodeHash,
     pub blob: Blob,
+    pub flags: u16,
 }
 
 impl UploadableBlob for Filelog {
@@ -46,13 +49,54 @@ impl UploadableBlob for Filelog {
 
     fn upload(self, repo: &BlobRepo) -> Result<((NodeHash, RepoPath), Self::Value)> {
         let path = self.path;
-        repo.upload_entry(
+        if self.flags & LFS_FLAG == 0 {
+            let (node, fut) = repo.upload_entry(
+                self.blob,
+                manifest::Type::File,
+                self.p1,
+                self.p2,
+                path.clone(),
+            )?;
+            return Ok(((node, path), fut.map_err(Error::compat).boxify().shared()));
+        }
+
+        // The content of revisions with the lfs flag is a pointer to the actual content, but
+        // their node is the hash of the actual content, so the pointer is stored under the node
+        // of the chunk. Clients upload the content before pushing the pointers to it, so a
+        // pointer to content that's not there is an error.
+        let node = self.node;
+        let (p1, p2) = (self.p1, self.p2);
+        let raw_pointer = self.blob.as_slice().unwrap_or(&[]).to_vec();
+        let pointer = LfsPointer::from_bytes(&raw_pointer)?;
+        let upload = repo.upload_entry_with_nodeid(
             self.blob,
             manifest::Type::File,
-            self.p1,
-            self.p2,
+            p1,
+            p2,
             path.clone(),
-        ).map(move |(node, fut)| ((node, path), fut.map_err(Error::compat).boxify().shared()))
+            node,
+        )?;
+
+        let mark_lfs = {
+            let repo = repo.clone();
+            move |()| repo.mark_lfs_file(&node)
+        };
+        let fut = repo.get_lfs_content(&pointer.oid)
+            .and_then(move |content| -> Result<()> {
+                let content = match content {
+                    Some(content) => content,
+                    None => bail_err!(ErrorKind::LfsContentMissing(pointer.oid)),
+                };
+                let text = lfs::filelog_text(&raw_pointer, &content);
+                let expected = BlobNode::new(Bytes::from(text), p1.as_ref(), p2.as_ref())
+                    .nodeid()
+                    .expect("blob has data");
+                ensure_err!(expected == node, ErrorKind::LfsNodeMismatch(node, pointer.oid));
+                Ok(())
+            })
+            .and_then(mark_lfs)
+            .and_then(move |()| upload);
+        Ok(((node, path), fut.map_err(Error::compat).boxify().shared()))
     }
 }
 
@@ -70,6 +114,7 @@ where
                 p1,
                 p2,
                 linknode,
+                flags,
             } = chunk;
 
             delta_cache
@@ -82,6 +127,7 @@ where
                         p2: p2.into_option(),
                         linknode,
                         blob,
+                        flags,
                     })
                 })
                 .boxify()
@@ -170,6 +216,7 @@ impl Arbitrary for Filelog {
             p2: NodeHash::arbitrary(g).into_option(),
             linknode: NodeHash::arbitrary(g),
             blob: Blob::from(Bytes::from(Vec::<u8>::arbitrary(g))),
+            flags: u16::arbitrary(g),
         }
     }
 
@@ -217,6 +264,12 @@ impl Arbitrary for Filelog {
             append(&mut result, f);
         }
 
+        if self.flags != 0 {
+            let mut f = self.clone();
+            f.flags = 0;
+            append(&mut result, f);
+        }
+
         Box::new(result.into_iter())
     }
 }
@@ -231,6 +284,7 @@ mod tests {
     use futures::stream::iter_ok;
     use itertools::{assert_equal, EitherOrBoth, Itertools};
 
+    use mercurial_types::Sha256;
     use mercurial_types::delta::Fragment;
 
     struct NodeHashGen {
@@ -283,6 +337,7 @@ mod tests {
                 base: NULL_HASH,
                 linknode: f.linknode.clone(),
                 delta: Delta::new_fulltext(f.blob.as_slice().unwrap()),
+                flags: f.flags,
             },
         }
     }
@@ -345,6 +400,7 @@ mod tests {
             p2: Some(THREES_HASH),
             linknode: FOURS_HASH,
             blob: Blob::from(Bytes::from("test file content")),
+            flags: 0,
         };
 
         let f2 = Filelog {
@@ -354,6 +410,7 @@ mod tests {
             p2: Some(SEVENS_HASH),
             linknode: EIGHTS_HASH,
             blob: Blob::from(Bytes::from("test2 file content")),
+            flags: 0,
         };
 
         check_conversion(
@@ -372,6 +429,7 @@ mod tests {
             p2: Some(THREES_HASH),
             linknode: FOURS_HASH,
             blob: Blob::from(Bytes::from("test file content")),
+            flags: 0,
         };
 
         let f2 = Filelog {
@@ -381,6 +439,7 @@ mod tests {
             p2: Some(SEVENS_HASH),
             linknode: EIGHTS_HASH,
             blob: Blob::from(Bytes::from("test2 file content")),
+            flags: 0,
         };
 
         let f1_deltaed = filelog_to_deltaed(&f1);
@@ -424,6 +483,61 @@ mod tests {
         files_check_order(false);
     }
 
+    /// Upload `content` with lfs to a new repo, and push a revision of `path` that points to
+    /// it with the node `node`
+    fn push_lfs_file(content: &[u8], node: Option<NodeHash>) -> (BlobRepo, NodeHash, Result<()>) {
+        let repo = BlobRepo::new_memblob_empty(None).unwrap();
+        let oid = Sha256::from(content);
+        repo.upload_lfs_content(oid, Bytes::from(content))
+            .wait()
+            .unwrap();
+
+        let pointer = LfsPointer::new(oid, content.len() as u64).to_bytes();
+        let node = node.unwrap_or_else(|| {
+            BlobNode::new(Bytes::from(content), None, None)
+                .nodeid()
+                .unwrap()
+        });
+        let filelog = Filelog {
+            path: RepoPath::file(MPath::new(b"large").unwrap()).unwrap(),
+            node,
+            p1: None,
+            p2: None,
+            linknode: NULL_HASH,
+            blob: Blob::from(Bytes::from(pointer)),
+            flags: LFS_FLAG,
+        };
+        let result = filelog.upload(&repo).and_then(|((uploaded, _), fut)| {
+            assert_eq!(uploaded, node);
+            fut.wait().map(|_| ()).map_err(|err| format_err!("{}", *err))
+        });
+        (repo, node, result)
+    }
+
+    #[test]
+    fn lfs_file_push() {
+        let content = b"large file content";
+        let (repo, node, result) = push_lfs_file(content, None);
+        result.expect("push failed");
+
+        // The file revision is served the way getfiles sends it: the pointer, with the lfs flag
+        let pointer = repo.get_file_content(&node).wait().unwrap();
+        let pointer = LfsPointer::from_bytes(pointer.as_ref()).unwrap();
+        assert_eq!(pointer.oid, Sha256::from(&content[..]));
+        assert!(repo.is_lfs_file(&node).wait().unwrap());
+        let lfs_content = repo.get_lfs_content(&pointer.oid).wait().unwrap();
+        assert_eq!(lfs_content, Some(Bytes::from(&content[..])));
+    }
+
+    #[test]
+    fn lfs_file_push_wrong_node() {
+        use mercurial_types_mocks::nodehash::ONES_HASH;
+
+        let (repo, node, result) = push_lfs_file(b"large file content", Some(ONES_HASH));
+        assert!(result.is_err());
+        assert!(!repo.is_lfs_file(&node).wait().unwrap());
+    }
+
     quickcheck! {
         fn sanitycheck_delta_computation(b1: Vec<u8>, b2: Vec<u8>) -> bool {
             assert_equal(&b2, &delta::apply(&b1, &compute_delta(&b1, &b2)));
@@ -6,7 +6,14 @@
 
 pub use failure::{Error, Result, ResultExt};
 
+use mercurial_types::{NodeHash, Sha256};
+
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "Malformed treemanifest part: {}", _0)] MalformedTreemanifestPart(String),
+    #[fail(display = "Pushed lfs pointer to content {} that was not uploaded", _0)]
+    LfsContentMissing(Sha256),
+    #[fail(display = "Pushed lfs file revision {} doesn't match the content {}", _0, _1)]
+    LfsNodeMismatch(NodeHash, Sha256),
+    #[fail(display = "Pushvar {} is not allowed in this repo", _0)] PushvarNotAllowed(String),
 }
@@ -8,6 +8,7 @@
 #![feature(conservative_impl_trait)]
 
 extern crate ascii;
+extern crate async_compression;
 extern crate bytes;
 #[macro_use]
 extern crate failure_ext as failure;
@@ -35,11 +36,16 @@ extern crate stats as stats_crate;
 extern crate tokio_io;
 
 extern crate blobrepo;
+#[cfg(test)]
+extern crate linear;
 extern crate mercurial;
 extern crate mercurial_bundles;
 extern crate mercurial_types;
 #[cfg(test)]
 extern crate mercurial_types_mocks;
+extern crate phases;
+extern crate repoinfo;
+extern crate revset;
 
 mod changegroup;
 pub mod errors;
@@ -48,4 +54,4 @@ mod stats;
 mod wirepackparser;
 mod upload_blobs;
 
-pub use resolver::resolve;
+pub use resolver::{resolve, Pushvars, UnbundleResponse};
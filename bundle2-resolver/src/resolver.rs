@@ -4,14 +4,16 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Cursor;
+use std::mem;
 use std::sync::Arc;
 
 use ascii::AsciiString;
+use async_compression::CompressorType;
 use bytes::Bytes;
 use futures::{Future, IntoFuture, Stream};
-use futures::future::{err, ok};
+use futures::future::{self, err, ok, Loop};
 use futures::stream;
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use slog::Logger;
@@ -19,8 +21,12 @@ use slog::Logger;
 use blobrepo::{BlobEntry, BlobRepo, ChangesetHandle};
 use mercurial::changeset::RevlogChangeset;
 use mercurial::manifest::revlog::ManifestContent;
-use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item};
-use mercurial_types::{Changeset, HgChangesetId, HgManifestId, MPath, NodeHash, RepoPath};
+use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item, ClientOutput};
+use mercurial_types::{Changeset, HgChangesetId, HgManifestId, MPath, NodeHash, Obsmarker,
+                      Phase, RepoPath};
+use phases;
+use repoinfo::RepoGenCache;
+use revset::SkiplistIndex;
 
 use changegroup::{convert_to_revlog_changesets, convert_to_revlog_filelog, split_changegroup,
                   Filelog};
@@ -33,38 +39,101 @@ type Changesets = Vec<(NodeHash, RevlogChangeset)>;
 type Filelogs = HashMap<(NodeHash, RepoPath), <Filelog as UploadableBlob>::Value>;
 type Manifests = HashMap<(NodeHash, RepoPath), <TreemanifestEntry as UploadableBlob>::Value>;
 type UploadedChangesets = HashMap<NodeHash, ChangesetHandle>;
+type ScratchBookmarks = Vec<(Vec<u8>, Option<HgChangesetId>)>;
+
+/// Pushvars by name
+pub type Pushvars = HashMap<String, String>;
+
+/// A push that was resolved successfully
+pub struct UnbundleResponse {
+    /// The bundle2 to send back to the client
+    pub bytes: Bytes,
+    /// The pushvars of the push, which the hooks get in their `HookContext` and which are logged
+    /// with the push
+    pub pushvars: Pushvars,
+}
 
 /// The resolve function takes a bundle2, interprets it's content as Changesets, Filelogs and
 /// Manifests and uploades all of them to the provided BlobRepo in the correct order.
 /// It returns a Future that contains the response that should be send back to the requester.
+/// The response is compressed with the first of `bundle2_compression` that the requester
+/// advertised in its replycaps, or left uncompressed if there is none.
+/// The push is rejected if it has pushvars that aren't in `allowed_pushvars`, or obsmarkers parts
+/// while `obsmarkers` is false.
+/// Messages for the user are written to `output`, and the ones it buffers are sent as output parts
+/// of the response. The pushvars are returned with the response, for the hooks and the logs.
+/// Phases are looked up the way the server serves them, with `repo_generation` and `skiplist`.
 pub fn resolve(
     repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    skiplist: SkiplistIndex,
     logger: Logger,
     heads: Vec<String>,
     bundle2: BoxStream<Bundle2Item, Error>,
-) -> BoxFuture<Bytes, Error> {
+    bundle2_compression: Vec<CompressorType>,
+    allowed_pushvars: Vec<String>,
+    obsmarkers: bool,
+    output: ClientOutput,
+) -> BoxFuture<UnbundleResponse, Error> {
     info!(logger, "unbundle heads {:?}", heads);
 
-    let resolver = Bundle2Resolver::new(repo, logger);
-
-    let bundle2 = resolver.resolve_start_and_replycaps(bundle2);
+    let resolver = Bundle2Resolver::new(
+        repo,
+        repo_generation,
+        skiplist,
+        logger,
+        bundle2_compression,
+        allowed_pushvars,
+        obsmarkers,
+        output,
+    );
 
     resolver
-        .resolve_changegroup(bundle2)
+        .resolve_start_and_replycaps(bundle2)
         .and_then({
             let resolver = resolver.clone();
-            move |(cg_push, bundle2)| {
+            move |(reply_compression, bundle2)| {
                 resolver
-                    .maybe_resolve_bookmark_pushkey(bundle2)
-                    .map(move |(bookmark_push, bundle2)| (cg_push, bookmark_push, bundle2))
+                    .maybe_resolve_pushvars(bundle2)
+                    .map(move |(pushvars, bundle2)| (reply_compression, pushvars, bundle2))
             }
         })
-        .and_then(move |(cg_push, _bookmark_push, bundle2)| {
+        .and_then({
+            let resolver = resolver.clone();
+            move |(reply_compression, pushvars, bundle2)| {
+                resolver
+                    .resolve_changegroup(bundle2)
+                    .map(move |(cg_push, bundle2)| {
+                        (reply_compression, pushvars, cg_push, bundle2)
+                    })
+            }
+        })
+        .and_then({
+            let resolver = resolver.clone();
+            move |(reply_compression, pushvars, cg_push, bundle2)| {
+                resolver
+                    .resolve_pushkeys(bundle2)
+                    .map(move |(pushkeys, bundle2)| {
+                        (reply_compression, pushvars, cg_push, pushkeys, bundle2)
+                    })
+            }
+        })
+        .and_then(move |(reply_compression, pushvars, cg_push, mut pushkeys, bundle2)| {
             let changegroup_id = cg_push.part_id;
+            let infinitepush = cg_push.infinitepush;
+            let scratch_bookmark = match cg_push.scratch_bookmark {
+                Some((name, Some(node))) => Some((name, node)),
+                Some((name, None)) => {
+                    Some((name, try_boxfuture!(scratch_head(&cg_push.changesets))))
+                }
+                None => None,
+            };
             let changesets = cg_push.changesets;
             let filelogs = cg_push.filelogs;
+            let pushed_nodes: Vec<_> = changesets.iter().map(|&(node, _)| node).collect();
+            let obsmarkers = mem::replace(&mut pushkeys.obsmarkers, Vec::new());
 
-            let bundle2 = resolver
+            resolver
                 .resolve_b2xtreegroup2(bundle2)
                 .and_then({
                     let resolver = resolver.clone();
@@ -72,25 +141,73 @@ pub fn resolve(
                     move |(manifests, bundle2)| {
                         resolver
                             .maybe_resolve_infinitepush_bookmarks(bundle2)
-                            .map(|(_, bundle2)| (manifests, bundle2))
+                            .map(|(scratch_bookmarks, bundle2)| {
+                                (manifests, scratch_bookmarks, bundle2)
+                            })
                     }
                 })
                 .and_then({
                     let resolver = resolver.clone();
 
-                    move |(manifests, bundle2)| {
+                    move |(manifests, scratch_bookmarks, bundle2)| {
                         resolver
-                            .upload_changesets(changesets, filelogs, manifests)
+                            .upload_changesets(changesets, filelogs, manifests, infinitepush)
+                            .map(|()| (scratch_bookmarks, bundle2))
+                    }
+                })
+                .and_then({
+                    let resolver = resolver.clone();
+
+                    move |(mut scratch_bookmarks, bundle2)| {
+                        scratch_bookmarks.extend(
+                            scratch_bookmark
+                                .map(|(name, head)| (name, Some(HgChangesetId::new(head)))),
+                        );
+                        resolver
+                            .update_scratch_bookmarks(scratch_bookmarks)
                             .map(|()| bundle2)
                     }
                 })
-                .flatten_stream()
-                .boxify();
+                .and_then({
+                    let resolver = resolver.clone();
 
-            // TODO(stash): actually push bookmarks
-            resolver
-                .ensure_stream_finished(bundle2)
-                .and_then(move |()| resolver.prepare_response(changegroup_id))
+                    move |bundle2| {
+                        resolver
+                            .update_phases(infinitepush, pushed_nodes, pushkeys)
+                            .map(|phase_replies| (phase_replies, bundle2))
+                    }
+                })
+                .and_then({
+                    let resolver = resolver.clone();
+
+                    move |(phase_replies, bundle2)| {
+                        resolver
+                            .add_obsmarkers(obsmarkers)
+                            .map(|obsmarkers_replies| {
+                                (phase_replies, obsmarkers_replies, bundle2)
+                            })
+                    }
+                })
+                .and_then({
+                    let resolver = resolver.clone();
+
+                    // TODO(stash): actually push bookmarks
+                    move |(phase_replies, obsmarkers_replies, bundle2)| {
+                        resolver
+                            .ensure_stream_finished(bundle2)
+                            .map(|()| (phase_replies, obsmarkers_replies))
+                    }
+                })
+                .and_then(move |(phase_replies, obsmarkers_replies)| {
+                    resolver.prepare_response(
+                        changegroup_id,
+                        phase_replies,
+                        obsmarkers_replies,
+                        reply_compression,
+                    )
+                })
+                .map(move |bytes| UnbundleResponse { bytes, pushvars })
+                .boxify()
         })
         .map_err(|err| err.context("bundle2-resolver error").into())
         .boxify()
@@ -104,6 +221,12 @@ fn next_item(
 
 struct ChangegroupPush {
     part_id: PartId,
+    /// Whether the changesets were pushed with infinitepush, which makes them draft and keeps
+    /// them out of the heads of the repo
+    infinitepush: bool,
+    /// Scratch bookmark that the infinitepush asked to update, with the node it should point at
+    /// if the push says so. Otherwise it points at the head of the pushed changesets.
+    scratch_bookmark: Option<(Vec<u8>, Option<NodeHash>)>,
     changesets: Changesets,
     filelogs: Filelogs,
 }
@@ -115,33 +238,105 @@ struct BookmarkPush {
     _new: Option<HgChangesetId>,
 }
 
+struct PhasePush {
+    part_id: PartId,
+    node: NodeHash,
+    old: Phase,
+    new: Phase,
+}
+
+/// Pushkey, phase-heads and obsmarkers parts that follow the changegroup
+#[derive(Default)]
+struct Pushkeys {
+    bookmarks: Vec<BookmarkPush>,
+    phases: Vec<PhasePush>,
+    public_heads: Vec<NodeHash>,
+    obsmarkers: Vec<(PartId, Vec<Obsmarker>)>,
+}
+
 /// Holds repo and logger for convienience access from it's methods
 #[derive(Clone)]
 struct Bundle2Resolver {
     repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    skiplist: SkiplistIndex,
     logger: Logger,
+    bundle2_compression: Arc<Vec<CompressorType>>,
+    allowed_pushvars: Arc<Vec<String>>,
+    /// Whether obsolescence markers can be pushed
+    obsmarkers: bool,
+    output: ClientOutput,
 }
 
 impl Bundle2Resolver {
-    fn new(repo: Arc<BlobRepo>, logger: Logger) -> Self {
-        Self { repo, logger }
+    fn new(
+        repo: Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        skiplist: SkiplistIndex,
+        logger: Logger,
+        bundle2_compression: Vec<CompressorType>,
+        allowed_pushvars: Vec<String>,
+        obsmarkers: bool,
+        output: ClientOutput,
+    ) -> Self {
+        Self {
+            repo,
+            repo_generation,
+            skiplist,
+            logger,
+            bundle2_compression: Arc::new(bundle2_compression),
+            allowed_pushvars: Arc::new(allowed_pushvars),
+            obsmarkers,
+            output,
+        }
     }
 
-    /// Parse Start and Replycaps and ignore their content
+    /// Parse Start and Replycaps. The content of Start is ignored, Replycaps are used to pick
+    /// the compression of the response
     fn resolve_start_and_replycaps(
         &self,
         bundle2: BoxStream<Bundle2Item, Error>,
-    ) -> BoxStream<Bundle2Item, Error> {
+    ) -> BoxFuture<(Option<CompressorType>, BoxStream<Bundle2Item, Error>), Error> {
+        let bundle2_compression = self.bundle2_compression.clone();
+
         next_item(bundle2)
             .and_then(|(start, bundle2)| match start {
                 Some(Bundle2Item::Start(_)) => next_item(bundle2),
                 _ => err(format_err!("Expected Bundle2 Start")).boxify(),
             })
-            .and_then(|(replycaps, bundle2)| match replycaps {
-                Some(Bundle2Item::Replycaps(_, part)) => part.map(|_| bundle2).boxify(),
+            .and_then(move |(replycaps, bundle2)| match replycaps {
+                Some(Bundle2Item::Replycaps(_, part)) => part
+                    .map(move |caps| (caps.negotiate_compression(&bundle2_compression), bundle2))
+                    .boxify(),
                 _ => err(format_err!("Expected Bundle2 Replycaps")).boxify(),
             })
-            .flatten_stream()
+            .boxify()
+    }
+
+    /// Parse pushvars, if there are any. Mercurial sends them right after the replycaps.
+    /// Errors if one of them isn't allowed in the repo.
+    fn maybe_resolve_pushvars(
+        &self,
+        bundle2: BoxStream<Bundle2Item, Error>,
+    ) -> BoxFuture<(Pushvars, BoxStream<Bundle2Item, Error>), Error> {
+        let allowed_pushvars = self.allowed_pushvars.clone();
+        let logger = self.logger.clone();
+
+        next_item(bundle2)
+            .and_then(move |(pushvars, bundle2)| match pushvars {
+                Some(Bundle2Item::Pushvars(header, emptypart)) => {
+                    let pushvars =
+                        try_boxfuture!(get_pushvars(header.aparams(), &allowed_pushvars));
+                    info!(logger, "pushvars {:?}", pushvars);
+                    emptypart.map(move |()| (pushvars, bundle2)).boxify()
+                }
+                Some(part) => ok((
+                    HashMap::new(),
+                    stream::once(Ok(part)).chain(bundle2).boxify(),
+                )).boxify(),
+                None => ok((HashMap::new(), bundle2)).boxify(),
+            })
+            .map_err(|err| err.context("While resolving Pushvars").into())
             .boxify()
     }
 
@@ -157,79 +352,148 @@ impl Bundle2Resolver {
         let repo = self.repo.clone();
 
         next_item(bundle2)
-            .and_then(move |(changegroup, bundle2)| match changegroup {
-                Some(Bundle2Item::Changegroup(header, parts))
-                | Some(Bundle2Item::B2xInfinitepush(header, parts)) => {
-                    let part_id = header.part_id();
-                    let (c, f) = split_changegroup(parts);
-                    convert_to_revlog_changesets(c)
-                        .collect()
-                        .join(
-                            upload_blobs(
-                                repo.clone(),
-                                convert_to_revlog_filelog(repo, f),
-                                UploadBlobsType::EnsureNoDuplicates,
-                            ).map_err(|err| err.context("While uploading File Blobs").into()),
-                        )
-                        .map(move |(changesets, filelogs)| {
-                            let cg_push = ChangegroupPush {
-                                part_id,
-                                changesets,
-                                filelogs,
+            .and_then(move |(changegroup, bundle2)| {
+                let (header, parts, infinitepush) = match changegroup {
+                    Some(Bundle2Item::Changegroup(header, parts)) => (header, parts, false),
+                    Some(Bundle2Item::B2xInfinitepush(header, parts)) => (header, parts, true),
+                    _ => return err(format_err!("Expected Bundle2 Changegroup")).boxify(),
+                };
+
+                let part_id = header.part_id();
+                let scratch_bookmark = if infinitepush {
+                    // Mercurial sends the bookmark as an advisory parameter
+                    let param = |name: &str| {
+                        header.aparams().get(name).or(header.mparams().get(name))
+                    };
+                    match param("bookmark") {
+                        Some(name) => {
+                            let node = match param("bookmarknode") {
+                                Some(node) => Some(try_boxfuture!(parse_node(node))),
+                                None => None,
                             };
-                            (cg_push, bundle2)
-                        })
-                        .boxify()
-                }
-                _ => err(format_err!("Expected Bundle2 Changegroup")).boxify(),
+                            Some((name.to_vec(), node))
+                        }
+                        None => None,
+                    }
+                } else {
+                    None
+                };
+                let (c, f) = split_changegroup(parts);
+                convert_to_revlog_changesets(c)
+                    .collect()
+                    .join(
+                        upload_blobs(
+                            repo.clone(),
+                            convert_to_revlog_filelog(repo, f),
+                            UploadBlobsType::EnsureNoDuplicates,
+                        ).map_err(|err| err.context("While uploading File Blobs").into()),
+                    )
+                    .map(move |(changesets, filelogs)| {
+                        let cg_push = ChangegroupPush {
+                            part_id,
+                            infinitepush,
+                            scratch_bookmark,
+                            changesets,
+                            filelogs,
+                        };
+                        (cg_push, bundle2)
+                    })
+                    .boxify()
             })
             .map_err(|err| err.context("While resolving Changegroup").into())
             .boxify()
     }
 
-    /// Parses bookmark pushkey part if it exists
-    /// Errors if `namespace` is not "bookmarks"
-    fn maybe_resolve_bookmark_pushkey(
+    /// Parses the pushkey, phase-heads and obsmarkers parts that follow the changegroup, if there
+    /// are any.
+    /// Errors if the `namespace` of a pushkey is neither "bookmarks" nor "phases", or if there
+    /// is an obsmarkers part but the repo doesn't accept obsolescence markers
+    fn resolve_pushkeys(
         &self,
         bundle2: BoxStream<Bundle2Item, Error>,
-    ) -> BoxFuture<(Option<BookmarkPush>, BoxStream<Bundle2Item, Error>), Error> {
-        next_item(bundle2)
-            .and_then(move |(newpart, bundle2)| match newpart {
-                Some(Bundle2Item::Pushkey(header, emptypart)) => {
-                    let part_id = header.part_id();
-                    let mparams = header.mparams();
-                    try_boxfuture!(
-                        mparams
-                            .get("namespace")
-                            .ok_or(format_err!("pushkey: `namespace` parameter is not set"))
-                            .and_then(|namespace| if namespace != "bookmarks".as_bytes() {
-                                Err(format_err!(
-                                    "pushkey: unexpected namespace: {:?}",
-                                    namespace
-                                ))
-                            } else {
-                                Ok(())
-                            })
-                    );
+    ) -> BoxFuture<(Pushkeys, BoxStream<Bundle2Item, Error>), Error> {
+        let accept_obsmarkers = self.obsmarkers;
 
-                    let name = try_boxfuture!(get_ascii_param(mparams, "key"));
-                    let old = try_boxfuture!(get_optional_changeset_param(mparams, "old"));
-                    let new = try_boxfuture!(get_optional_changeset_param(mparams, "new"));
+        future::loop_fn(
+            (Pushkeys::default(), bundle2),
+            move |(mut pushkeys, bundle2)| {
+                next_item(bundle2).and_then(move |(newpart, bundle2)| match newpart {
+                    Some(Bundle2Item::Pushkey(header, emptypart)) => {
+                        let part_id = header.part_id();
+                        let mparams = header.mparams();
+                        let namespace = try_boxfuture!(
+                            mparams
+                                .get("namespace")
+                                .ok_or(format_err!("pushkey: `namespace` parameter is not set"))
+                        );
 
-                    let bookmark_push = BookmarkPush {
-                        _part_id: part_id,
-                        _name: name,
-                        _old: old,
-                        _new: new,
-                    };
-                    emptypart
-                        .map(move |_| (Some(bookmark_push), bundle2.boxify()))
-                        .boxify()
-                }
-                Some(part) => ok((None, stream::once(Ok(part)).chain(bundle2).boxify())).boxify(),
-                None => ok((None, bundle2.boxify())).boxify(),
-            })
-            .map_err(|err| err.context("While resolving Pushkey").into())
+                        if namespace == "bookmarks".as_bytes() {
+                            let name = try_boxfuture!(get_ascii_param(mparams, "key"));
+                            let old = try_boxfuture!(get_optional_changeset_param(mparams, "old"));
+                            let new = try_boxfuture!(get_optional_changeset_param(mparams, "new"));
+                            pushkeys.bookmarks.push(BookmarkPush {
+                                _part_id: part_id,
+                                _name: name,
+                                _old: old,
+                                _new: new,
+                            });
+                        } else if namespace == "phases".as_bytes() {
+                            let node = try_boxfuture!(get_ascii_param(mparams, "key"));
+                            let node = try_boxfuture!(NodeHash::from_ascii_str(&node));
+                            let old = try_boxfuture!(get_phase_param(mparams, "old"));
+                            let new = try_boxfuture!(get_phase_param(mparams, "new"));
+                            pushkeys.phases.push(PhasePush {
+                                part_id,
+                                node,
+                                old,
+                                new,
+                            });
+                        } else {
+                            return err(format_err!(
+                                "pushkey: unexpected namespace: {:?}",
+                                namespace
+                            )).boxify();
+                        }
+
+                        emptypart
+                            .map(move |_| Loop::Continue((pushkeys, bundle2)))
+                            .boxify()
+                    }
+                    Some(Bundle2Item::PhaseHeads(_, heads)) => heads
+                        .collect()
+                        .map(move |heads| {
+                            pushkeys.public_heads.extend(
+                                heads
+                                    .into_iter()
+                                    .filter(|&(phase, _)| phase == Phase::Public)
+                                    .map(|(_, node)| node),
+                            );
+                            Loop::Continue((pushkeys, bundle2))
+                        })
+                        .boxify(),
+                    Some(Bundle2Item::Obsmarkers(header, markers)) => {
+                        if !accept_obsmarkers {
+                            return err(format_err!(
+                                "obsmarkers: obsolescence markers are disabled in this repo"
+                            )).boxify();
+                        }
+                        let part_id = header.part_id();
+                        markers
+                            .collect()
+                            .map(move |markers| {
+                                pushkeys.obsmarkers.push((part_id, markers));
+                                Loop::Continue((pushkeys, bundle2))
+                            })
+                            .boxify()
+                    }
+                    Some(part) => ok(Loop::Break((
+                        pushkeys,
+                        stream::once(Ok(part)).chain(bundle2).boxify(),
+                    ))).boxify(),
+                    None => ok(Loop::Break((pushkeys, bundle2))).boxify(),
+                })
+            },
+        ).map_err(|err| err.context("While resolving Pushkey").into())
             .boxify()
     }
 
@@ -260,18 +524,21 @@ impl Bundle2Resolver {
     }
 
     /// Parse b2xinfinitepushscratchbookmarks.
-    /// This part is ignored, so just parse it and forget it
+    /// Those are the bookmarks that commit cloud backs up, they are stored with the other scratch
+    /// bookmarks
     fn maybe_resolve_infinitepush_bookmarks(
         &self,
         bundle2: BoxStream<Bundle2Item, Error>,
-    ) -> BoxFuture<((), BoxStream<Bundle2Item, Error>), Error> {
+    ) -> BoxFuture<(ScratchBookmarks, BoxStream<Bundle2Item, Error>), Error> {
         next_item(bundle2)
             .and_then(
                 move |(infinitepushbookmarks, bundle2)| match infinitepushbookmarks {
-                    Some(Bundle2Item::B2xInfinitepushBookmarks(_, bookmarks)) => {
-                        bookmarks.collect().map(|_| ((), bundle2)).boxify()
-                    }
-                    None => Ok(((), bundle2)).into_future().boxify(),
+                    Some(Bundle2Item::B2xInfinitepushBookmarks(_, bookmarks)) => bookmarks
+                        .map(|(name, node)| (name.into_bytes(), node))
+                        .collect()
+                        .map(|bookmarks| (bookmarks, bundle2))
+                        .boxify(),
+                    None => Ok((vec![], bundle2)).into_future().boxify(),
                     _ => err(format_err!(
                         "Expected B2xInfinitepushBookmarks or end of the stream"
                     )).boxify(),
@@ -294,6 +561,7 @@ impl Bundle2Resolver {
         changesets: Changesets,
         filelogs: Filelogs,
         manifests: Manifests,
+        scratch: bool,
     ) -> BoxFuture<(), Error> {
         fn upload_changeset(
             repo: Arc<BlobRepo>,
@@ -302,6 +570,7 @@ impl Bundle2Resolver {
             mut uploaded_changesets: UploadedChangesets,
             filelogs: &Filelogs,
             manifests: &Manifests,
+            scratch: bool,
         ) -> BoxFuture<UploadedChangesets, Error> {
             let (p1, p2) = {
                 let (p1, p2) = revlog_cs.parents().get_nodes();
@@ -318,16 +587,33 @@ impl Bundle2Resolver {
 
             p1.join(p2)
                 .and_then(move |(p1, p2)| {
-                    let scheduled_uploading = repo.create_changeset(
-                        p1,
-                        p2,
-                        root_manifest,
-                        entries,
-                        String::from_utf8(revlog_cs.user().into())?,
-                        revlog_cs.time().clone(),
-                        revlog_cs.extra().clone(),
-                        String::from_utf8(revlog_cs.comments().into())?,
-                    );
+                    let user = String::from_utf8(revlog_cs.user().into())?;
+                    let time = revlog_cs.time().clone();
+                    let extra = revlog_cs.extra().clone();
+                    let comments = String::from_utf8(revlog_cs.comments().into())?;
+                    let scheduled_uploading = if scratch {
+                        repo.create_scratch_changeset(
+                            p1,
+                            p2,
+                            root_manifest,
+                            entries,
+                            user,
+                            time,
+                            extra,
+                            comments,
+                        )
+                    } else {
+                        repo.create_changeset(
+                            p1,
+                            p2,
+                            root_manifest,
+                            entries,
+                            user,
+                            time,
+                            extra,
+                            comments,
+                        )
+                    };
 
                     uploaded_changesets.insert(node, scheduled_uploading);
                     Ok(uploaded_changesets)
@@ -336,6 +622,8 @@ impl Bundle2Resolver {
         }
 
         let repo = self.repo.clone();
+        let output = self.output.clone();
+        let count = changesets.len();
 
         debug!(self.logger, "changesets: {:?}", changesets);
         debug!(self.logger, "filelogs: {:?}", filelogs.keys());
@@ -352,6 +640,7 @@ impl Bundle2Resolver {
                         uploaded_changesets,
                         &filelogs,
                         &manifests,
+                        scratch,
                     ).map_err(move |err| {
                         err.context(format!(
                             "While trying to upload Changeset with id {:?}",
@@ -368,10 +657,100 @@ impl Bundle2Resolver {
                 ).map_err(Error::from)
                     .for_each(|_| Ok(()))
             })
+            .map(move |()| {
+                if scratch {
+                    output.write(format!("backed up {} scratch changesets", count));
+                } else {
+                    output.write(format!("added {} changesets", count));
+                }
+            })
             .map_err(|err| err.context("While uploading Changesets to BlobRepo").into())
             .boxify()
     }
 
+    /// Record the changesets of an infinitepush as draft, then make public the changesets that
+    /// the phase-heads part and the pushkeys asked for. The pushkeys are handled like the
+    /// pushkey command handles them. Returns the result of each pushkey, for the replies to them.
+    fn update_phases(
+        &self,
+        infinitepush: bool,
+        pushed_nodes: Vec<NodeHash>,
+        pushkeys: Pushkeys,
+    ) -> BoxFuture<Vec<(PartId, bool)>, Error> {
+        let repo = self.repo.clone();
+        let repo_generation = self.repo_generation.clone();
+        let skiplist = self.skiplist.clone();
+
+        let drafts = if infinitepush {
+            pushed_nodes
+                .into_iter()
+                .map(|node| repo.record_phase(&HgChangesetId::new(node), Phase::Draft))
+                .collect()
+        } else {
+            vec![]
+        };
+        let public_heads = pushkeys.public_heads;
+        let phase_pushes = pushkeys.phases;
+
+        future::join_all(drafts)
+            .and_then({
+                let repo = repo.clone();
+                move |_| repo.make_public(public_heads)
+            })
+            .and_then(move |()| {
+                stream::iter_ok(phase_pushes)
+                    .and_then(move |phase_push| {
+                        let PhasePush {
+                            part_id,
+                            node,
+                            old,
+                            new,
+                        } = phase_push;
+                        phases::pushkey(
+                            repo.clone(),
+                            repo_generation.clone(),
+                            skiplist.clone(),
+                            node,
+                            old,
+                            new,
+                        ).map(move |success| (part_id, success))
+                    })
+                    .collect()
+            })
+            .map_err(|err| err.context("While updating phases").into())
+            .boxify()
+    }
+
+    /// Point the scratch bookmarks at their new values, or delete those whose value is None
+    fn update_scratch_bookmarks(
+        &self,
+        scratch_bookmarks: ScratchBookmarks,
+    ) -> BoxFuture<(), Error> {
+        let repo = self.repo.clone();
+
+        stream::iter_ok(scratch_bookmarks)
+            .for_each(move |(name, value)| repo.set_scratch_bookmark(name, value))
+            .map_err(|err| err.context("While updating scratch bookmarks").into())
+            .boxify()
+    }
+
+    /// Store the pushed obsolescence markers. Returns the number of new markers of each part, for
+    /// the replies to them.
+    fn add_obsmarkers(
+        &self,
+        obsmarkers: Vec<(PartId, Vec<Obsmarker>)>,
+    ) -> BoxFuture<Vec<(PartId, usize)>, Error> {
+        let repo = self.repo.clone();
+
+        stream::iter_ok(obsmarkers)
+            .and_then(move |(part_id, markers)| {
+                repo.add_obsmarkers(markers).map(move |new| (part_id, new))
+            })
+            .collect()
+            .map_err(|err| err.context("While storing obsmarkers").into())
+            .boxify()
+    }
+
     /// Ensures that the next item in stream is None
     fn ensure_stream_finished(
         &self,
@@ -386,18 +765,31 @@ impl Bundle2Resolver {
     }
 
     /// Takes a changegroup id and prepares a Bytes response containing Bundle2 with reply to
-    /// changegroup part saying that the push was successful
-    fn prepare_response(&self, changegroup_id: PartId) -> BoxFuture<Bytes, Error> {
+    /// changegroup part saying that the push was successful, followed by replies to the phases
+    /// pushkeys and to the obsmarkers parts, and by the buffered output for the user
+    fn prepare_response(
+        &self,
+        changegroup_id: PartId,
+        phase_replies: Vec<(PartId, bool)>,
+        obsmarkers_replies: Vec<(PartId, usize)>,
+        compression: Option<CompressorType>,
+    ) -> BoxFuture<Bytes, Error> {
         let writer = Cursor::new(Vec::new());
         let mut bundle = Bundle2EncodeBuilder::new(writer);
-        // Mercurial currently hangs while trying to read compressed bundles over the wire:
-        // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
-        // TODO: possibly enable compression support once this is fixed.
-        bundle.set_compressor_type(None);
+        bundle.set_compressor_type(compression);
         bundle.add_part(try_boxfuture!(parts::replychangegroup_part(
             parts::ChangegroupApplyResult::Success { heads_num_diff: 0 },
             changegroup_id,
         )));
+        for (part_id, success) in phase_replies {
+            bundle.add_part(try_boxfuture!(parts::replypushkey_part(success, part_id)));
+        }
+        for (part_id, new) in obsmarkers_replies {
+            bundle.add_part(try_boxfuture!(parts::replyobsmarkers_part(new, part_id)));
+        }
+        for message in self.output.take_buffered() {
+            bundle.add_part(try_boxfuture!(parts::output_part(message)));
+        }
         bundle
             .build()
             .map(|cursor| Bytes::from(cursor.into_inner()))
@@ -406,6 +798,40 @@ impl Bundle2Resolver {
     }
 }
 
+/// The head of the changesets of an infinitepush, that its scratch bookmark is pointed at.
+/// Errors unless there's exactly one.
+fn scratch_head(changesets: &Changesets) -> Result<NodeHash> {
+    let parents: HashSet<_> = changesets
+        .iter()
+        .flat_map(|&(_, ref revlog_cs)| {
+            let (p1, p2) = revlog_cs.parents().get_nodes();
+            p1.into_iter().chain(p2).cloned()
+        })
+        .collect();
+    let heads: Vec<_> = changesets
+        .iter()
+        .map(|&(node, _)| node)
+        .filter(|node| !parents.contains(node))
+        .collect();
+
+    ensure_msg!(
+        !heads.is_empty(),
+        "no changesets were pushed, so the scratch bookmark needs a `bookmarknode` parameter"
+    );
+    ensure_msg!(
+        heads.len() == 1,
+        "a scratch bookmark can only point at one head, but {} were pushed",
+        heads.len()
+    );
+    Ok(heads[0])
+}
+
+fn parse_node(node: &Bytes) -> Result<NodeHash> {
+    let node = AsciiString::from_ascii(node.to_vec())
+        .map_err(|err| format_err!("`bookmarknode` parameter is not ascii: {}", err))?;
+    NodeHash::from_ascii_str(&node)
+}
+
 /// Retrieves the parent from uploaded changesets, if it is missing then fetches it from BlobRepo
 fn get_parent(
     repo: &BlobRepo,
@@ -520,6 +946,33 @@ fn get_ascii_param(params: &HashMap<String, Bytes>, param: &str) -> Result<Ascii
         .map_err(|err| format_err!("`{}` parameter is not ascii: {}", param, err))
 }
 
+/// The pushvars, which are the params of the pushvars part. Errors if one of them isn't allowed.
+fn get_pushvars(
+    params: &HashMap<String, Bytes>,
+    allowed_pushvars: &[String],
+) -> Result<Pushvars> {
+    params
+        .iter()
+        .map(|(name, value)| {
+            if !allowed_pushvars.contains(name) {
+                bail_err!(ErrorKind::PushvarNotAllowed(name.clone()));
+            }
+            let value = String::from_utf8(value.to_vec())
+                .map_err(|err| format_err!("pushvar {} is not utf-8: {}", name, err))?;
+            Ok((name.clone(), value))
+        })
+        .collect()
+}
+
+fn get_phase_param(params: &HashMap<String, Bytes>, param: &str) -> Result<Phase> {
+    let val = get_ascii_param(params, param)?;
+    let phase = val.as_str()
+        .trim()
+        .parse()
+        .map_err(|err| format_err!("`{}` parameter is not a phase: {}", param, err))?;
+    Ok(Phase::from_hg(phase)?)
+}
+
 fn get_optional_changeset_param(
     params: &HashMap<String, Bytes>,
     param: &str,
@@ -532,3 +985,102 @@ fn get_optional_changeset_param(
         Ok(Some(HgChangesetId::from_ascii_str(&val)?))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use linear;
+    use mercurial_bundles::bundle2::{Bundle2Stream, StreamEvent};
+    use mercurial_bundles::changegroup::{Part, Section};
+    use mercurial_bundles::changegroup::packer::Cg2Packer;
+    use mercurial_bundles::part_encode::PartEncodeBuilder;
+    use mercurial_bundles::PartHeaderType;
+    use mercurial_types::manifest::Entry;
+    use slog::Discard;
+
+    /// An infinitepush that pushes no changesets, and points `bookmark` at `bookmarknode` if
+    /// there is one
+    fn scratch_push(
+        bookmark: &str,
+        bookmarknode: Option<NodeHash>,
+    ) -> BoxStream<Bundle2Item, Error> {
+        let mut bundle = Bundle2EncodeBuilder::new(Cursor::new(Vec::new()));
+
+        bundle.add_part(PartEncodeBuilder::mandatory(PartHeaderType::Replycaps).unwrap());
+
+        let mut infinitepush = PartEncodeBuilder::mandatory(PartHeaderType::B2xInfinitepush)
+            .unwrap();
+        infinitepush.add_aparam("bookmark", bookmark.to_string()).unwrap();
+        if let Some(node) = bookmarknode {
+            infinitepush
+                .add_aparam("bookmarknode", node.to_hex().to_string())
+                .unwrap();
+        }
+        let changegroup = stream::iter_ok(vec![
+            Part::SectionEnd(Section::Changeset),
+            Part::SectionEnd(Section::Filelog(MPath::empty())),
+            Part::End,
+        ]);
+        infinitepush.set_data_generated(Cg2Packer::new(changegroup));
+        bundle.add_part(infinitepush);
+
+        let trees = stream::empty::<(Box<Entry + Sync>, NodeHash, MPath), Error>();
+        bundle.add_part(parts::treepack_part(trees).unwrap());
+
+        let mut encoded = bundle.build().wait().unwrap();
+        encoded.set_position(0);
+
+        Bundle2Stream::new(encoded, Logger::root(Discard, o!()))
+            .filter_map(|event| match event {
+                StreamEvent::Next(item) => Some(item),
+                StreamEvent::Done(_) => None,
+            })
+            .boxify()
+    }
+
+    fn push(
+        repo: &Arc<BlobRepo>,
+        bundle2: BoxStream<Bundle2Item, Error>,
+    ) -> Result<UnbundleResponse> {
+        resolve(
+            repo.clone(),
+            RepoGenCache::new(10),
+            SkiplistIndex::new(),
+            Logger::root(Discard, o!()),
+            vec![],
+            bundle2,
+            vec![],
+            vec![],
+            false,
+            ClientOutput::new(),
+        ).wait()
+    }
+
+    #[test]
+    fn test_scratch_bookmark_without_changesets() {
+        let repo = Arc::new(linear::getrepo(None));
+        let node = NodeHash::from_ascii_str(
+            &AsciiString::from_ascii("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157").unwrap(),
+        ).unwrap();
+
+        push(&repo, scratch_push("scratch/test", Some(node))).expect("push failed");
+
+        let value = repo.get_scratch_bookmark_value(&"scratch/test")
+            .wait()
+            .unwrap()
+            .map(|(csid, _version)| csid);
+        assert_eq!(value, Some(HgChangesetId::new(node)));
+    }
+
+    #[test]
+    fn test_scratch_bookmark_without_node() {
+        let repo = Arc::new(linear::getrepo(None));
+
+        assert!(push(&repo, scratch_push("scratch/test", None)).is_err());
+        let value = repo.get_scratch_bookmark_value(&"scratch/test")
+            .wait()
+            .unwrap();
+        assert_eq!(value, None);
+    }
+}
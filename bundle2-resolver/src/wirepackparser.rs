@@ -323,6 +323,7 @@ mod test {
             node,
             delta_base: NULL_HASH,
             delta: delta::Delta::new_fulltext(data),
+            metadata: None,
         })
     }
 
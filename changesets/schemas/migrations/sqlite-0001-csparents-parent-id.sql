@@ -0,0 +1,3 @@
+-- Index the parents of existing changesets tables by parent, for children lookups. Tables
+-- created from sqlite-changesets.sql already have this index.
+CREATE INDEX IF NOT EXISTS csparents_parent_id ON csparents (parent_id);
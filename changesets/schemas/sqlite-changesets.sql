@@ -12,3 +12,5 @@ CREATE TABLE csparents (
   seq INTEGER NOT NULL,
   PRIMARY KEY (cs_id, parent_id, seq)
 );
+
+CREATE INDEX csparents_parent_id ON csparents (parent_id);
@@ -27,7 +27,8 @@ use diesel::backend::Backend;
 use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
 use diesel::result::{DatabaseErrorKind, Error as DieselError};
-use diesel::sql_types::HasSqlType;
+use diesel::sql_query;
+use diesel::sql_types::{HasSqlType, Integer};
 use failure::ResultExt;
 use futures::future;
 
@@ -42,7 +43,7 @@ mod models;
 mod wrappers;
 
 pub use errors::*;
-use models::{ChangesetInsertRow, ChangesetParentRow, ChangesetRow};
+use models::{ChangesetChildRow, ChangesetInsertRow, ChangesetParentRow, ChangesetRow};
 use schema::{changesets, csparents};
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
@@ -71,6 +72,21 @@ pub trait Changesets: Send + Sync {
         repo_id: RepositoryId,
         cs_id: HgChangesetId,
     ) -> BoxFuture<Option<ChangesetEntry>, Error>;
+
+    /// Retrieve the rows specified by these commits, in the same order. Commits that aren't
+    /// stored are skipped.
+    fn get_many(
+        &self,
+        repo_id: RepositoryId,
+        cs_ids: &[HgChangesetId],
+    ) -> BoxFuture<Vec<ChangesetEntry>, Error>;
+
+    /// Retrieve the children of this commit. Empty if the commit isn't stored.
+    fn get_children(
+        &self,
+        repo_id: RepositoryId,
+        cs_id: HgChangesetId,
+    ) -> BoxFuture<Vec<HgChangesetId>, Error>;
 }
 
 pub struct SqliteChangesets {
@@ -140,6 +156,19 @@ impl MysqlChangesets {
     }
 }
 
+/// Most changesets looked up by one query of `get_many`. Each of them is a bound variable, and
+/// SQLite allows at most 999 of them by default.
+const MAX_CHANGESETS_PER_QUERY: usize = 500;
+
+/// The children of a changeset, in the order they were added. Binds the repo id and the hash of
+/// the parent.
+const CHILDREN_QUERY: &str = "\
+    SELECT children.cs_id FROM changesets parents \
+    INNER JOIN csparents ON csparents.parent_id = parents.id \
+    INNER JOIN changesets children ON children.id = csparents.cs_id \
+    WHERE parents.repo_id = ? AND parents.cs_id = ? \
+    ORDER BY children.id ASC";
+
 /// Using a macro here is unfortunate, but it appears to be the only way to share this code
 /// between SQLite and MySQL.
 macro_rules! impl_changesets {
@@ -189,6 +218,90 @@ macro_rules! impl_changesets {
                 future::result(entry).boxify()
             }
 
+            /// Retrieve the changesets specified by these commits with one query for the
+            /// changesets and one for their parents, for each chunk of
+            /// `MAX_CHANGESETS_PER_QUERY` commits.
+            fn get_many(
+                &self,
+                repo_id: RepositoryId,
+                cs_ids: &[HgChangesetId],
+            ) -> BoxFuture<Vec<ChangesetEntry>, Error> {
+                // TODO: don't block -- send this to another thread
+                let connection = self.connection.lock().expect("lock poisoned");
+                let mut entries = HashMap::new();
+                for chunk in cs_ids.chunks(MAX_CHANGESETS_PER_QUERY) {
+                    let query = changesets::table
+                        .filter(changesets::repo_id.eq(repo_id))
+                        .filter(changesets::cs_id.eq_any(chunk));
+                    let changeset_rows = query.load::<ChangesetRow>(&*connection);
+                    // This code is written in this style to allow easy porting to futures.
+                    let chunk_entries = changeset_rows.map_err(failure::Error::from).and_then(
+                        |rows| {
+                            let ids: Vec<_> = rows.iter().map(|row| row.id).collect();
+                            let parent_rows = csparents::table
+                                .filter(csparents::cs_id.eq_any(ids))
+                                .order((csparents::cs_id.asc(), csparents::seq.asc()))
+                                .inner_join(changesets::table)
+                                .load::<(ChangesetParentRow, ChangesetRow)>(&*connection)?;
+
+                            let mut parents: HashMap<i64, Vec<HgChangesetId>> = HashMap::new();
+                            for (parent_row, parent) in parent_rows {
+                                parents
+                                    .entry(parent_row.cs_id)
+                                    .or_insert_with(Vec::new)
+                                    .push(parent.cs_id);
+                            }
+
+                            let mut chunk_entries = Vec::new();
+                            for row in rows {
+                                // Diesel can't express unsigned ints, so convert manually.
+                                let gen = u64::try_from(row.gen)
+                                    .context(ErrorKind::InvalidStoredData)?;
+                                chunk_entries.push(ChangesetEntry {
+                                    repo_id: row.repo_id,
+                                    cs_id: row.cs_id,
+                                    parents: parents.remove(&row.id).unwrap_or_default(),
+                                    gen,
+                                });
+                            }
+                            Ok(chunk_entries)
+                        },
+                    );
+                    match chunk_entries {
+                        Ok(chunk_entries) => entries.extend(
+                            chunk_entries.into_iter().map(|entry| (entry.cs_id, entry)),
+                        ),
+                        Err(err) => return future::err(err).boxify(),
+                    }
+                }
+
+                let entries = cs_ids
+                    .iter()
+                    .filter_map(|cs_id| entries.get(cs_id).cloned())
+                    .collect();
+                future::ok(entries).boxify()
+            }
+
+            /// Retrieve the children of this commit with one query, using the index of the
+            /// parents table by parent.
+            fn get_children(
+                &self,
+                repo_id: RepositoryId,
+                cs_id: HgChangesetId,
+            ) -> BoxFuture<Vec<HgChangesetId>, Error> {
+                // TODO: don't block -- send this to another thread
+                let query = sql_query(CHILDREN_QUERY)
+                    .bind::<Integer, _>(repo_id)
+                    .bind::<NodeHashSql, _>(cs_id);
+                let connection = self.connection.lock().expect("lock poisoned");
+                let child_rows = query.load::<ChangesetChildRow>(&*connection);
+                // This code is written in this style to allow easy porting to futures.
+                let children = child_rows
+                    .map(|rows| rows.into_iter().map(|row| row.cs_id).collect())
+                    .map_err(failure::Error::from);
+                future::result(children).boxify()
+            }
+
             /// Insert a new changeset into this table. Checks that all parents are already in
             /// storage.
             fn add(&self, cs: &ChangesetInsert) -> BoxFuture<(), Error> {
@@ -5,6 +5,7 @@
 // GNU General Public License version 2 or any later version.
 
 use mercurial_types::{HgChangesetId, RepositoryId};
+use mercurial_types::sql_types::NodeHashSql;
 
 use schema::{changesets, csparents};
 
@@ -36,3 +37,12 @@ pub(crate) struct ChangesetInsertRow {
     pub cs_id: HgChangesetId,
     pub gen: i64,
 }
+
+/// A row of the raw SQL query in `get_children`. Diesel's query builder can't join a table to
+/// itself, which is what looking up the children of a changeset by hash needs.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(QueryableByName)]
+pub(crate) struct ChangesetChildRow {
+    #[sql_type = "NodeHashSql"]
+    pub cs_id: HgChangesetId,
+}
@@ -26,4 +26,20 @@ impl Changesets for Arc<Changesets> {
     ) -> BoxFuture<Option<ChangesetEntry>, Error> {
         (**self).get(repo_id, cs_id)
     }
+
+    fn get_many(
+        &self,
+        repo_id: RepositoryId,
+        cs_ids: &[HgChangesetId],
+    ) -> BoxFuture<Vec<ChangesetEntry>, Error> {
+        (**self).get_many(repo_id, cs_ids)
+    }
+
+    fn get_children(
+        &self,
+        repo_id: RepositoryId,
+        cs_id: HgChangesetId,
+    ) -> BoxFuture<Vec<HgChangesetId>, Error> {
+        (**self).get_children(repo_id, cs_id)
+    }
 }
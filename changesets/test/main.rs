@@ -199,6 +199,98 @@ fn complex<C: Changesets>(changesets: C) {
     );
 }
 
+fn children<C: Changesets>(changesets: C) {
+    let rows = vec![
+        (ONES_CSID, vec![]),
+        (TWOS_CSID, vec![ONES_CSID]),
+        (THREES_CSID, vec![ONES_CSID]),
+        (FOURS_CSID, vec![TWOS_CSID, THREES_CSID]),
+    ];
+    for (cs_id, parents) in rows {
+        let row = ChangesetInsert {
+            repo_id: REPO_ZERO,
+            cs_id,
+            parents,
+        };
+        changesets.add(&row).wait().expect("Adding row failed");
+    }
+
+    let children = |cs_id| {
+        changesets
+            .get_children(REPO_ZERO, cs_id)
+            .wait()
+            .expect("Get children failed")
+    };
+    assert_eq!(children(ONES_CSID), vec![TWOS_CSID, THREES_CSID]);
+    assert_eq!(children(TWOS_CSID), vec![FOURS_CSID]);
+    assert_eq!(children(THREES_CSID), vec![FOURS_CSID]);
+    assert_eq!(children(FOURS_CSID), vec![]);
+    assert_eq!(children(FIVES_CSID), vec![]);
+}
+
+fn get_many<C: Changesets>(changesets: C) {
+    let rows = vec![
+        (ONES_CSID, vec![]),
+        (TWOS_CSID, vec![ONES_CSID]),
+        (THREES_CSID, vec![ONES_CSID, TWOS_CSID]),
+    ];
+    for (cs_id, parents) in rows {
+        let row = ChangesetInsert {
+            repo_id: REPO_ZERO,
+            cs_id,
+            parents,
+        };
+        changesets.add(&row).wait().expect("Adding row failed");
+    }
+
+    let result = changesets
+        .get_many(REPO_ZERO, &[THREES_CSID, FOURS_CSID, ONES_CSID])
+        .wait()
+        .expect("Get many failed");
+    assert_eq!(
+        result,
+        vec![
+            ChangesetEntry {
+                repo_id: REPO_ZERO,
+                cs_id: THREES_CSID,
+                parents: vec![ONES_CSID, TWOS_CSID],
+                gen: 3,
+            },
+            ChangesetEntry {
+                repo_id: REPO_ZERO,
+                cs_id: ONES_CSID,
+                parents: vec![],
+                gen: 1,
+            },
+        ],
+    );
+
+    let result = changesets
+        .get_many(REPO_ZERO, &[])
+        .wait()
+        .expect("Get many of nothing failed");
+    assert_eq!(result, vec![]);
+
+    // More commits than SQLite allows variables in one query
+    let mut cs_ids = vec![FOURS_CSID; 2000];
+    cs_ids.push(TWOS_CSID);
+    let result = changesets
+        .get_many(REPO_ZERO, &cs_ids)
+        .wait()
+        .expect("Get many of many failed");
+    assert_eq!(
+        result,
+        vec![
+            ChangesetEntry {
+                repo_id: REPO_ZERO,
+                cs_id: TWOS_CSID,
+                parents: vec![ONES_CSID],
+                gen: 2,
+            },
+        ],
+    );
+}
+
 macro_rules! changesets_test_impl {
     ($mod_name: ident => {
         new: $new_cb: expr,
@@ -230,6 +322,16 @@ macro_rules! changesets_test_impl {
             fn test_complex() {
                 complex($new_cb());
             }
+
+            #[test]
+            fn test_children() {
+                children($new_cb());
+            }
+
+            #[test]
+            fn test_get_many() {
+                get_many($new_cb());
+            }
         }
     }
 }
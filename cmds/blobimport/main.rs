@@ -36,6 +36,7 @@ extern crate manifoldblob;
 extern crate memheads;
 extern crate mercurial;
 extern crate mercurial_types;
+extern crate obsmarkers;
 extern crate rocksblob;
 extern crate rocksdb;
 extern crate services;
@@ -70,6 +71,7 @@ use linknodes::NoopLinknodes;
 use manifoldblob::ManifoldBlob;
 use mercurial::{RevlogRepo, RevlogRepoOptions};
 use mercurial_types::{Changeset, HgChangesetId, RepositoryId};
+use obsmarkers::SqliteObsmarkers;
 use rocksblob::Rocksblob;
 
 const DEFAULT_MANIFOLD_BUCKET: &str = "mononoke_prod";
@@ -205,6 +207,10 @@ where
     iothread.join().expect("failed to join io thread")?;
     res?;
 
+    // Obsolescence markers are only ever added by pushes, so the store starts empty
+    info!(logger, "Creating obsmarkers store: {:?}", output);
+    create_obsmarkers_store(output.clone().into())?;
+
     if !skip.is_none() && !commits_limit.is_none() {
         warn!(
             logger,
@@ -244,6 +250,12 @@ fn open_changesets_store(mut output: PathBuf) -> Result<Arc<Changesets>> {
     )?))
 }
 
+fn create_obsmarkers_store(mut output: PathBuf) -> Result<()> {
+    output.push("obsmarkers");
+    SqliteObsmarkers::create(output.to_string_lossy())?;
+    Ok(())
+}
+
 fn open_repo<P: Into<PathBuf>>(
     input: P,
     inmemory_logs_capacity: Option<usize>,
@@ -72,6 +72,7 @@ CREATE TABLE `csparents` (
     `parent_id` BIGINT UNSIGNED NOT NULL,
     `seq` INT UNSIGNED NOT NULL, -- if ordered
     PRIMARY KEY (`cs_id`, `parent_id`, `seq`),
+    INDEX (`parent_id`), -- for children lookups
     FOREIGN KEY (`cs_id`) REFERENCES `changeset` (`id`),
     FOREIGN KEY (`parent_id`) REFERENCES `changeset` (`id`)
 );
@@ -0,0 +1,301 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Git-LFS server, used by Mercurial's lfs extension to transfer the content of lfs files.
+//!
+//! Implements the batch API (https://github.com/git-lfs/git-lfs/blob/master/docs/api/batch.md)
+//! with the "basic" transfer adapter:
+//! ```
+//! POST /REPO/objects/batch     - returns where objects can be downloaded from or uploaded to
+//! GET  /REPO/lfs/download/OID  - returns the content of an object
+//! PUT  /REPO/lfs/upload/OID    - stores the content of an object, verifying that it matches OID
+//! ```
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use blobrepo::{self, BlobRepo};
+use bytes::Bytes;
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use hyper::StatusCode;
+use mercurial_types::Sha256;
+use serde_json;
+
+use failure::Error;
+
+const TRANSFER_BASIC: &str = "basic";
+
+/// Failures of lfs requests that are the client's fault
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "bad request: {}", _0)] BadRequest(String),
+    #[fail(display = "{} does not exist", _0)] NotFound(String),
+}
+
+/// The status of the response to a failed lfs request: 4xx if the request was wrong, f.e. if
+/// uploaded content doesn't match its oid, and 500 if storing or fetching the object failed
+pub fn error_status(err: &Error) -> StatusCode {
+    match err.downcast_ref::<ErrorKind>() {
+        Some(&ErrorKind::BadRequest(_)) => return StatusCode::BadRequest,
+        Some(&ErrorKind::NotFound(_)) => return StatusCode::NotFound,
+        None => {}
+    }
+    match err.downcast_ref::<blobrepo::ErrorKind>() {
+        Some(&blobrepo::ErrorKind::LfsContentMismatch(_)) => StatusCode::UnprocessableEntity,
+        _ => StatusCode::InternalServerError,
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Operation {
+    Download,
+    Upload,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    operation: Operation,
+    #[serde(default)]
+    transfers: Vec<String>,
+    objects: Vec<RequestObject>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RequestObject {
+    oid: String,
+    size: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchResponse {
+    transfer: &'static str,
+    objects: Vec<ResponseObject>,
+}
+
+#[derive(Debug, Serialize)]
+struct ResponseObject {
+    oid: String,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authenticated: Option<bool>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    actions: HashMap<&'static str, Action>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ObjectError>,
+}
+
+#[derive(Debug, Serialize)]
+struct Action {
+    href: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ObjectError {
+    code: u16,
+    message: String,
+}
+
+impl ResponseObject {
+    fn new(object: RequestObject) -> Self {
+        ResponseObject {
+            oid: object.oid,
+            size: object.size,
+            authenticated: None,
+            actions: HashMap::new(),
+            error: None,
+        }
+    }
+
+    /// Add an action. `authenticated` tells the client that it doesn't need to ask for
+    /// credentials to follow `href`.
+    fn with_action(mut self, name: &'static str, href: String, authenticated: bool) -> Self {
+        if authenticated {
+            self.authenticated = Some(true);
+        }
+        self.actions.insert(name, Action { href });
+        self
+    }
+
+    fn with_error(mut self, code: u16, message: String) -> Self {
+        self.error = Some(ObjectError { code, message });
+        self
+    }
+}
+
+/// Handle a batch request. `base_url` is the url of the repo on this server, which download and
+/// upload urls are relative to. `authenticated` is whether the client presented a verified
+/// certificate, which it will present again when following the urls.
+pub fn batch(
+    repo: Arc<BlobRepo>,
+    base_url: String,
+    authenticated: bool,
+    body: Bytes,
+) -> BoxFuture<Bytes, Error> {
+    let request: BatchRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(err) => return future::err(ErrorKind::BadRequest(err.to_string()).into()).boxify(),
+    };
+    if !request.transfers.is_empty() && !request.transfers.iter().any(|t| t == TRANSFER_BASIC) {
+        let err = ErrorKind::BadRequest("only the basic transfer adapter is supported".into());
+        return future::err(err.into()).boxify();
+    }
+
+    let operation = request.operation;
+    let objects = request.objects.into_iter().map(move |object| {
+        let response = ResponseObject::new(object);
+        let oid = match response.oid.parse::<Sha256>() {
+            Ok(oid) => oid,
+            Err(err) => return future::ok(response.with_error(422, err.to_string())).boxify(),
+        };
+
+        let base_url = base_url.clone();
+        repo.lfs_content_exists(&oid)
+            .map(move |exists| match (operation, exists) {
+                (Operation::Download, true) => response.with_action(
+                    "download",
+                    format!("{}/lfs/download/{}", base_url, oid),
+                    authenticated,
+                ),
+                (Operation::Download, false) => {
+                    response.with_error(404, format!("object {} does not exist", oid))
+                }
+                // Nothing to do if the server already has the object
+                (Operation::Upload, true) => response,
+                (Operation::Upload, false) => response.with_action(
+                    "upload",
+                    format!("{}/lfs/upload/{}", base_url, oid),
+                    authenticated,
+                ),
+            })
+            .boxify()
+    });
+
+    future::join_all(objects)
+        .and_then(|objects| {
+            let response = BatchResponse {
+                transfer: TRANSFER_BASIC,
+                objects,
+            };
+            Ok(Bytes::from(serde_json::to_vec(&response)?))
+        })
+        .boxify()
+}
+
+pub fn download(repo: Arc<BlobRepo>, oid: Sha256) -> BoxFuture<Bytes, Error> {
+    repo.get_lfs_content(&oid)
+        .and_then(move |content| {
+            content.ok_or_else(|| ErrorKind::NotFound(format!("object {}", oid)).into())
+        })
+        .boxify()
+}
+
+pub fn upload(repo: Arc<BlobRepo>, oid: Sha256, content: Bytes) -> BoxFuture<Bytes, Error> {
+    repo.upload_lfs_content(oid, content)
+        .map(|()| Bytes::new())
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn batch_response(repo: Arc<BlobRepo>, request: &str) -> serde_json::Value {
+        let base_url = "https://lfs/repo".to_string();
+        let response = batch(repo, base_url, true, Bytes::from(request))
+            .wait()
+            .unwrap();
+        serde_json::from_slice(&response).unwrap()
+    }
+
+    #[test]
+    fn test_upload_download() {
+        let repo = Arc::new(BlobRepo::new_memblob_empty(None).unwrap());
+        let content = Bytes::from(&b"large file"[..]);
+        let oid = Sha256::from(content.as_ref());
+
+        let request = format!(
+            r#"{{"operation": "download", "objects": [{{"oid": "{}", "size": 10}}]}}"#,
+            oid
+        );
+        let response = batch_response(repo.clone(), &request);
+        assert_eq!(response["objects"][0]["error"]["code"], 404);
+
+        let request = format!(
+            r#"{{"operation": "upload", "transfers": ["basic"],
+                "objects": [{{"oid": "{}", "size": 10}}]}}"#,
+            oid
+        );
+        let response = batch_response(repo.clone(), &request);
+        assert_eq!(
+            response["objects"][0]["actions"]["upload"]["href"],
+            format!("https://lfs/repo/lfs/upload/{}", oid)
+        );
+        assert_eq!(response["objects"][0]["authenticated"], true);
+
+        let wrong_oid = Sha256::from(&b"other content"[..]);
+        let err = upload(repo.clone(), wrong_oid, content.clone())
+            .wait()
+            .unwrap_err();
+        assert_eq!(error_status(&err), StatusCode::UnprocessableEntity);
+        upload(repo.clone(), oid, content.clone()).wait().unwrap();
+
+        let response = batch_response(repo.clone(), &request);
+        assert!(response["objects"][0].get("actions").is_none());
+
+        let request = format!(
+            r#"{{"operation": "download", "objects": [{{"oid": "{}", "size": 10}}]}}"#,
+            oid
+        );
+        let response = batch_response(repo.clone(), &request);
+        assert_eq!(
+            response["objects"][0]["actions"]["download"]["href"],
+            format!("https://lfs/repo/lfs/download/{}", oid)
+        );
+        assert_eq!(download(repo.clone(), oid).wait().unwrap(), content);
+
+        let err = download(repo, wrong_oid).wait().unwrap_err();
+        assert_eq!(error_status(&err), StatusCode::NotFound);
+    }
+
+    #[test]
+    fn test_unauthenticated() {
+        let repo = Arc::new(BlobRepo::new_memblob_empty(None).unwrap());
+        let oid = Sha256::from(&b"large file"[..]);
+        let request = format!(
+            r#"{{"operation": "upload", "objects": [{{"oid": "{}", "size": 10}}]}}"#,
+            oid
+        );
+        let response = batch(
+            repo,
+            "https://lfs/repo".to_string(),
+            false,
+            Bytes::from(request),
+        ).wait()
+            .unwrap();
+        let response: serde_json::Value = serde_json::from_slice(&response).unwrap();
+        assert!(response["objects"][0].get("authenticated").is_none());
+        assert!(response["objects"][0]["actions"].get("upload").is_some());
+    }
+
+    #[test]
+    fn test_bad_oid() {
+        let repo = Arc::new(BlobRepo::new_memblob_empty(None).unwrap());
+        let request = r#"{"operation": "download", "objects": [{"oid": "abc", "size": 1}]}"#;
+        let response = batch_response(repo, request);
+        assert_eq!(response["objects"][0]["error"]["code"], 422);
+    }
+
+    #[test]
+    fn test_bad_request() {
+        let repo = Arc::new(BlobRepo::new_memblob_empty(None).unwrap());
+        let err = batch(repo, "https://lfs/repo".to_string(), true, Bytes::from("{"))
+            .wait()
+            .unwrap_err();
+        assert_eq!(error_status(&err), StatusCode::BadRequest);
+    }
+}
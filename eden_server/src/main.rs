@@ -13,7 +13,13 @@
 /// # Request examples
 /// ```
 /// /REPO/cs/HASH/roottreemanifestid - returns root tree manifest node for the HASH
+/// /REPO/cs/HASH/log/PATH - returns the ancestors of HASH that modified the file or directory
+/// /REPO/cs/HASH/logfollow/PATH - same, but follows copies and renames of the file
+/// /REPO/cs/HASH/annotate/PATH - returns the changeset and line number each line of the file
+///                               at HASH comes from
 /// ```
+///
+/// Also serves the content of lfs files, see the `lfs` module.
 extern crate ascii;
 extern crate blobrepo;
 extern crate bytes;
@@ -22,6 +28,7 @@ extern crate clap;
 extern crate failure_ext as failure;
 extern crate futures;
 extern crate futures_cpupool;
+#[macro_use]
 extern crate futures_ext;
 extern crate futures_stats;
 extern crate hyper;
@@ -31,6 +38,8 @@ extern crate mercurial_types;
 extern crate native_tls;
 extern crate openssl;
 extern crate regex;
+extern crate repoinfo;
+extern crate revset;
 extern crate scuba;
 extern crate secure_utils;
 extern crate serde;
@@ -41,10 +50,13 @@ extern crate serde_json;
 extern crate slog;
 extern crate slog_glog_fmt;
 extern crate tokio_core;
-extern crate tokio_proto;
 extern crate tokio_tls;
 extern crate toml;
+extern crate url;
+
+mod lfs;
 
+use std::cmp::min;
 use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fs::File;
@@ -54,6 +66,7 @@ use std::path::PathBuf;
 use std::str::FromStr;
 use std::string::ToString;
 use std::sync::Arc;
+use tokio_core::net::TcpListener;
 use tokio_core::reactor::Core;
 
 use blobrepo::BlobRepo;
@@ -64,18 +77,21 @@ use futures::sync::oneshot;
 use futures_cpupool::CpuPool;
 use futures_ext::{BoxFuture, FutureExt};
 use futures_stats::{Stats, Timed};
-use hyper::StatusCode;
+use hyper::{Method, StatusCode};
+use hyper::header::{ContentLength, Host};
 use hyper::server::{Http, Request, Response, Service};
-use mercurial_types::{Changeset, MPathElement, NodeHash, RepositoryId};
+use mercurial_types::{Changeset, MPath, MPathElement, NodeHash, RepositoryId, Sha256};
 use mercurial_types::nodehash::HgChangesetId;
 use native_tls::TlsAcceptor;
 use native_tls::backend::openssl::TlsAcceptorBuilderExt;
 use openssl::ssl::{SSL_VERIFY_FAIL_IF_NO_PEER_CERT, SSL_VERIFY_PEER};
 use regex::{Captures, Regex};
+use repoinfo::RepoGenCache;
+use revset::{FileHistoryNodeStream, Revset};
 use scuba::{ScubaClient, ScubaSample};
 use slog::{Drain, Level, Logger};
-use tokio_proto::TcpServer;
-use tokio_tls::proto;
+use tokio_tls::TlsAcceptorExt;
+use url::percent_encoding::percent_decode;
 
 pub use failure::{DisplayChain, Error, Result, ResultExt};
 
@@ -96,6 +112,24 @@ const SCUBA_OPERATION_GET_TREE_CONTENT: &'static str = "get_tree_content";
 const SCUBA_OPERATION_GET_TREE_CONTENT_LIGHT: &'static str = "get_tree_content_light";
 const SCUBA_OPERATION_GET_MENIFEST: &'static str = "get_root_tree_manifest_id";
 const SCUBA_OPERATION_GET_BLOB_CONTENT: &'static str = "get_blob_content";
+const SCUBA_OPERATION_LFS_BATCH: &'static str = "lfs_batch";
+const SCUBA_OPERATION_LFS_DOWNLOAD: &'static str = "lfs_download";
+const SCUBA_OPERATION_LFS_UPLOAD: &'static str = "lfs_upload";
+const SCUBA_OPERATION_FILE_HISTORY: &'static str = "file_history";
+const SCUBA_OPERATION_ANNOTATE: &'static str = "annotate";
+const SCUBA_OPERATION_REVSET: &'static str = "revset";
+
+/// Size in bytes of the generation number cache shared by all connections
+const GENERATION_CACHE_SIZE: usize = 10 * 1024 * 1024;
+
+/// Largest lfs batch request body that is accepted
+const LFS_MAX_BATCH_SIZE: u64 = 1024 * 1024;
+/// Largest lfs object that can be uploaded. Objects are buffered in memory before being stored.
+const LFS_MAX_OBJECT_SIZE: u64 = 512 * 1024 * 1024;
+/// Most changesets returned by a file history request, and the default for its `limit` parameter
+const FILE_HISTORY_MAX_LIMIT: usize = 10_000;
+/// Most changesets returned by a revset request, and the default for its `limit` parameter
+const REVSET_MAX_LIMIT: usize = 10_000;
 
 fn parse_capture<T>(caps: &Captures, index: usize) -> Result<T>
 where
@@ -133,6 +167,57 @@ fn parse_blob_content_url(caps: Captures) -> Result<ParsedUrl> {
     Ok(ParsedUrl::BlobContent(repo, hash))
 }
 
+fn parse_file_history_url(caps: Captures, follow_copies: bool) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let hash = parse_capture::<NodeHash>(&caps, 2)?;
+    let path = parse_capture::<String>(&caps, 3)?;
+    Ok(ParsedUrl::FileHistory(repo, hash, path, follow_copies))
+}
+
+fn parse_log_url(caps: Captures) -> Result<ParsedUrl> {
+    parse_file_history_url(caps, false)
+}
+
+fn parse_log_follow_url(caps: Captures) -> Result<ParsedUrl> {
+    parse_file_history_url(caps, true)
+}
+
+fn parse_annotate_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let hash = parse_capture::<NodeHash>(&caps, 2)?;
+    let path = parse_capture::<String>(&caps, 3)?;
+    Ok(ParsedUrl::Annotate(repo, hash, path))
+}
+
+fn parse_revset_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let revset = parse_capture::<String>(&caps, 2)?;
+    let revset = percent_decode(revset.as_bytes()).decode_utf8()?;
+    Ok(ParsedUrl::Revset(repo, revset.into_owned()))
+}
+
+fn parse_lfs_batch_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    Ok(ParsedUrl::LfsBatch(repo))
+}
+
+fn parse_lfs_download_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let oid = parse_lfs_oid(&caps, 2)?;
+    Ok(ParsedUrl::LfsDownload(repo, oid))
+}
+
+fn parse_lfs_upload_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    let oid = parse_lfs_oid(&caps, 2)?;
+    Ok(ParsedUrl::LfsUpload(repo, oid))
+}
+
+fn parse_lfs_oid(caps: &Captures, index: usize) -> Result<Sha256> {
+    parse_capture::<Sha256>(caps, index)
+        .map_err(|err| lfs::ErrorKind::BadRequest(format!("invalid oid: {}", err)).into())
+}
+
 /// Generic url-handling function
 /// Accepts vector of tuples (regex, url handling function)
 /// If url matches regex then url handling function is called
@@ -150,6 +235,12 @@ enum ParsedUrl {
     TreeContent(String, NodeHash),
     TreeContentLight(String, NodeHash),
     BlobContent(String, NodeHash),
+    FileHistory(String, NodeHash, String, bool),
+    Annotate(String, NodeHash, String),
+    Revset(String, String),
+    LfsBatch(String),
+    LfsDownload(String, Sha256),
+    LfsUpload(String, Sha256),
 }
 
 lazy_static! {
@@ -161,6 +252,13 @@ lazy_static! {
             (r"^/(\w+)/treenode/(\w+)/?$", parse_tree_content_url as UrlParseFunc),
             (r"^/(\w+)/treenode_simple/(\w+)/?$", parse_tree_content_light_url as UrlParseFunc),
             (r"^/(\w+)/blob/(\w+)/?$", parse_blob_content_url as UrlParseFunc),
+            (r"^/(\w+)/cs/(\w+)/log/(.*)$", parse_log_url as UrlParseFunc),
+            (r"^/(\w+)/cs/(\w+)/logfollow/(.*)$", parse_log_follow_url as UrlParseFunc),
+            (r"^/(\w+)/cs/(\w+)/annotate/(.*)$", parse_annotate_url as UrlParseFunc),
+            (r"^/(\w+)/revset/(.+)$", parse_revset_url as UrlParseFunc),
+            (r"^/(\w+)/objects/batch/?$", parse_lfs_batch_url as UrlParseFunc),
+            (r"^/(\w+)/lfs/download/(\w+)/?$", parse_lfs_download_url as UrlParseFunc),
+            (r"^/(\w+)/lfs/upload/(\w+)/?$", parse_lfs_upload_url as UrlParseFunc),
         ].into_iter().map(|(re, func)| Route(Regex::new(re).expect("bad regex"), func)).collect()
     };
 }
@@ -211,21 +309,32 @@ struct TreeMetadataOptions {
 
 struct EdenServer {
     name_to_repo: NameToRepo,
+    repo_generation: RepoGenCache,
     cpupool: Arc<CpuPool>,
     logger: Logger,
     scuba: Arc<ScubaClient>,
+    // Whether the client of this connection presented a verified certificate
+    client_authenticated: bool,
 }
 
 impl EdenServer
 where
     EdenServer: Service,
 {
-    fn new(name_to_repo: NameToRepo, cpupool: Arc<CpuPool>, logger: Logger) -> EdenServer {
+    fn new(
+        name_to_repo: NameToRepo,
+        repo_generation: RepoGenCache,
+        cpupool: Arc<CpuPool>,
+        logger: Logger,
+        client_authenticated: bool,
+    ) -> EdenServer {
         EdenServer {
             name_to_repo,
+            repo_generation,
             cpupool,
             logger,
             scuba: Arc::new(ScubaClient::new(SCUBA_TABLE)),
+            client_authenticated,
         }
     }
 
@@ -306,6 +415,189 @@ where
             .and_then(|content| futures::future::ok(content))
             .boxify()
     }
+
+    /// The first `limit` changesets that modified `path`, starting from `hash`, as a json list of
+    /// hashes
+    fn get_file_history(
+        &self,
+        reponame: String,
+        hash: NodeHash,
+        path: String,
+        follow_copies: bool,
+        limit: usize,
+    ) -> BoxFuture<Bytes, Error> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+        let path = try_boxfuture!(MPath::new(path));
+        let repo_generation = self.repo_generation.clone();
+
+        let history = if follow_copies {
+            FileHistoryNodeStream::following_copies(&repo, repo_generation, hash, path)
+        } else {
+            FileHistoryNodeStream::new(&repo, repo_generation, hash, path)
+        };
+        history
+            .take(limit as u64)
+            .map(|node| serde_json::Value::from(node.to_string()))
+            .collect()
+            .map(|nodes| {
+                let x: serde_json::Value = nodes.into();
+                Bytes::from(x.to_string().into_bytes())
+            })
+            .boxify()
+    }
+
+    /// The annotation of the file at `path` in `hash`, as a json list with the origin of each
+    /// line
+    fn annotate(
+        &self,
+        reponame: String,
+        hash: NodeHash,
+        path: String,
+    ) -> BoxFuture<Bytes, Error> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+        let path = try_boxfuture!(MPath::new(path));
+
+        repo.annotate(&HgChangesetId::new(hash), path)
+            .and_then(|annotation| Ok(Bytes::from(serde_json::to_vec(&annotation)?)))
+            .boxify()
+    }
+
+    /// The first `limit` changesets of `revset`, from the newest to the oldest, as a json list of
+    /// hashes
+    fn evaluate_revset(
+        &self,
+        reponame: String,
+        revset: Revset,
+        limit: usize,
+    ) -> BoxFuture<Bytes, Error> {
+        let repo = try_boxfuture!(self.get_repo(&reponame));
+
+        revset
+            .evaluate(&repo, self.repo_generation.clone())
+            .take(limit as u64)
+            .map(|node| serde_json::Value::from(node.to_string()))
+            .collect()
+            .map(|nodes| {
+                let x: serde_json::Value = nodes.into();
+                Bytes::from(x.to_string().into_bytes())
+            })
+            .boxify()
+    }
+
+    fn get_repo(&self, reponame: &str) -> Result<Arc<BlobRepo>> {
+        self.name_to_repo
+            .get(reponame)
+            .cloned()
+            .ok_or_else(|| failure::err_msg("unknown repo"))
+    }
+
+    /// Like `get_repo`, but an unknown repo is reported as not found to lfs clients
+    fn get_lfs_repo(&self, reponame: &str) -> Result<Arc<BlobRepo>> {
+        self.name_to_repo
+            .get(reponame)
+            .cloned()
+            .ok_or_else(|| lfs::ErrorKind::NotFound(format!("repo {}", reponame)).into())
+    }
+
+    fn lfs_batch(
+        &self,
+        reponame: String,
+        host: Option<String>,
+        content_length: Option<u64>,
+        body: hyper::Body,
+    ) -> BoxFuture<Bytes, Error> {
+        let repo = try_boxfuture!(self.get_lfs_repo(&reponame));
+        let host = try_boxfuture!(host.ok_or_else(|| bad_lfs_request("no Host header".into())));
+        let base_url = format!("https://{}/{}", host, reponame);
+        let authenticated = self.client_authenticated;
+
+        let limit = content_length.unwrap_or(LFS_MAX_BATCH_SIZE);
+        if limit > LFS_MAX_BATCH_SIZE {
+            return futures::future::err(bad_lfs_request(format!(
+                "batch request of {} bytes is too large, the limit is {}",
+                limit,
+                LFS_MAX_BATCH_SIZE
+            ))).boxify();
+        }
+        read_body(body, limit)
+            .and_then(move |body| lfs::batch(repo, base_url, authenticated, body))
+            .boxify()
+    }
+
+    fn lfs_download(&self, reponame: String, oid: Sha256) -> BoxFuture<Bytes, Error> {
+        let repo = try_boxfuture!(self.get_lfs_repo(&reponame));
+        lfs::download(repo, oid)
+    }
+
+    fn lfs_upload(
+        &self,
+        reponame: String,
+        oid: Sha256,
+        content_length: Option<u64>,
+        body: hyper::Body,
+    ) -> BoxFuture<Bytes, Error> {
+        let repo = try_boxfuture!(self.get_lfs_repo(&reponame));
+
+        let size = try_boxfuture!(
+            content_length.ok_or_else(|| bad_lfs_request("no Content-Length header".into()))
+        );
+        if size > LFS_MAX_OBJECT_SIZE {
+            return futures::future::err(bad_lfs_request(format!(
+                "object of {} bytes is too large, the limit is {}",
+                size,
+                LFS_MAX_OBJECT_SIZE
+            ))).boxify();
+        }
+        read_body(body, size)
+            .and_then(move |body| lfs::upload(repo, oid, body))
+            .boxify()
+    }
+}
+
+fn bad_lfs_request(message: String) -> Error {
+    lfs::ErrorKind::BadRequest(message).into()
+}
+
+/// Read the body of an lfs request, which is at most `limit` bytes. The body is rejected as soon
+/// as it goes over the limit, so it's never buffered entirely.
+fn read_body(body: hyper::Body, limit: u64) -> BoxFuture<Bytes, Error> {
+    body.from_err::<Error>()
+        .fold(Vec::new(), move |mut buf, chunk| -> Result<Vec<u8>> {
+            if (buf.len() + chunk.len()) as u64 > limit {
+                return Err(bad_lfs_request(format!(
+                    "request body is larger than {} bytes",
+                    limit
+                )));
+            }
+            buf.extend_from_slice(&chunk);
+            Ok(buf)
+        })
+        .map(Bytes::from)
+        .boxify()
+}
+
+/// The `limit` parameter of a `query` string, which is `max` when it's missing or larger
+fn parse_limit(query: Option<&str>, max: usize) -> Result<usize> {
+    let value = query
+        .unwrap_or("")
+        .split('&')
+        .filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("limit"), Some(value)) => Some(value),
+                _ => None,
+            }
+        })
+        .last();
+    match value {
+        Some(value) => {
+            let limit = value
+                .parse::<usize>()
+                .map_err(|_| format_err!("invalid limit {}", value))?;
+            Ok(min(limit, max))
+        }
+        None => Ok(max),
+    }
 }
 
 /// Add values from the given Stats struct to the given Scuba sample.
@@ -335,13 +627,32 @@ impl Service for EdenServer {
 
         let mut resp = Response::new();
         let parsed_req = match parse_url(req.uri().path(), &ROUTES) {
+            Ok(ParsedUrl::LfsBatch(_)) if req.method() != &Method::Post => {
+                resp.set_status(StatusCode::MethodNotAllowed);
+                return futures::future::ok(resp).boxify();
+            }
+            Ok(ParsedUrl::LfsUpload(..)) if req.method() != &Method::Put => {
+                resp.set_status(StatusCode::MethodNotAllowed);
+                return futures::future::ok(resp).boxify();
+            }
             Ok(req) => req,
             Err(err) => {
+                // Only lfs urls fail to parse with lfs errors, f.e. when the oid is invalid
+                let status = match err.downcast_ref::<lfs::ErrorKind>() {
+                    Some(_) => lfs::error_status(&err),
+                    None => StatusCode::NotFound,
+                };
                 resp.set_body(err.to_string());
-                resp.set_status(StatusCode::NotFound);
+                resp.set_status(status);
                 return futures::future::ok(resp).boxify();
             }
         };
+        let lfs_request = match parsed_req {
+            ParsedUrl::LfsBatch(..) | ParsedUrl::LfsDownload(..) | ParsedUrl::LfsUpload(..) => {
+                true
+            }
+            _ => false,
+        };
 
         let result_future = match parsed_req {
             ParsedUrl::RootTreeHgManifestId(reponame, hash) => {
@@ -372,6 +683,64 @@ impl Service for EdenServer {
                 sample.add(SCUBA_COL_REPO, reponame.clone());
                 self.get_blob_content(reponame, &hash)
             }
+            ParsedUrl::FileHistory(reponame, hash, path, follow_copies) => {
+                sample.add(SCUBA_COL_HASH, hash.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_FILE_HISTORY);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                let limit = match parse_limit(req.uri().query(), FILE_HISTORY_MAX_LIMIT) {
+                    Ok(limit) => limit,
+                    Err(err) => {
+                        resp.set_body(err.to_string());
+                        resp.set_status(StatusCode::BadRequest);
+                        return futures::future::ok(resp).boxify();
+                    }
+                };
+                self.get_file_history(reponame, hash, path, follow_copies, limit)
+            }
+            ParsedUrl::Annotate(reponame, hash, path) => {
+                sample.add(SCUBA_COL_HASH, hash.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_ANNOTATE);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.annotate(reponame, hash, path)
+            }
+            ParsedUrl::Revset(reponame, revset) => {
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_REVSET);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                let parsed = parse_limit(req.uri().query(), REVSET_MAX_LIMIT)
+                    .and_then(|limit| -> Result<_> { Ok((revset.parse::<Revset>()?, limit)) });
+                let (revset, limit) = match parsed {
+                    Ok(parsed) => parsed,
+                    Err(err) => {
+                        resp.set_body(err.to_string());
+                        resp.set_status(StatusCode::BadRequest);
+                        return futures::future::ok(resp).boxify();
+                    }
+                };
+                self.evaluate_revset(reponame, revset, limit)
+            }
+            ParsedUrl::LfsBatch(reponame) => {
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_LFS_BATCH);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                let host = req.headers().get::<Host>().map(|host| match host.port() {
+                    Some(port) => format!("{}:{}", host.hostname(), port),
+                    None => host.hostname().to_string(),
+                });
+                let content_length = req.headers().get::<ContentLength>().map(|len| len.0);
+                self.lfs_batch(reponame, host, content_length, req.body())
+            }
+            ParsedUrl::LfsDownload(reponame, oid) => {
+                sample.add(SCUBA_COL_HASH, oid.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_LFS_DOWNLOAD);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                self.lfs_download(reponame, oid)
+            }
+            ParsedUrl::LfsUpload(reponame, oid) => {
+                sample.add(SCUBA_COL_HASH, oid.to_string());
+                sample.add(SCUBA_COL_OPERATION, SCUBA_OPERATION_LFS_UPLOAD);
+                sample.add(SCUBA_COL_REPO, reponame.clone());
+                let content_length = req.headers().get::<ContentLength>().map(|len| len.0);
+                self.lfs_upload(reponame, oid, content_length, req.body())
+            }
         };
 
         result_future
@@ -383,7 +752,12 @@ impl Service for EdenServer {
                     Err(e) => {
                         let error_msg = format!("{}", DisplayChain::from(&e));
                         resp.set_body(error_msg);
-                        resp.set_status(StatusCode::NotFound);
+                        let status = if lfs_request {
+                            lfs::error_status(&e)
+                        } else {
+                            StatusCode::NotFound
+                        };
+                        resp.set_status(status);
                     }
                 };
                 futures::future::ok(resp)
@@ -435,18 +809,55 @@ fn start_server(addr: &str, reponame: String, repo: BlobRepo, logger: Logger, ss
         }
     };
 
+    let repo_generation = RepoGenCache::new(GENERATION_CACHE_SIZE);
     let cpupool = Arc::new(CpuPool::new_num_cpus());
-    let protoserver = proto::Server::new(Http::new(), tlsacceptor);
-    let tcpserver = TcpServer::new(protoserver, addr);
+    let http = Http::new();
+
+    let mut core = Core::new().expect("failed to create tokio core");
+    let handle = core.handle();
+    let listener = TcpListener::bind(&addr, &handle).expect("failed to bind");
 
     info!(logger, "started eden server");
-    tcpserver.serve(move || {
-        Ok(EdenServer::new(
-            map.clone(),
-            cpupool.clone(),
-            logger.clone(),
-        ))
+    let server_logger = logger.clone();
+    // Connections are accepted here rather than with a TcpServer, so that each service knows
+    // about the client certificate of its connection
+    let server = listener.incoming().for_each(move |(socket, remote_addr)| {
+        let handle = handle.clone();
+        let http = http.clone();
+        let map = map.clone();
+        let repo_generation = repo_generation.clone();
+        let cpupool = cpupool.clone();
+        let logger = logger.clone();
+
+        let connection = tlsacceptor.accept_async(socket).then(move |stream| {
+            match stream {
+                Ok(stream) => {
+                    let client_authenticated = match stream.get_ref().peer_certificate() {
+                        Ok(cert) => cert.is_some(),
+                        Err(_) => false,
+                    };
+                    let service = EdenServer::new(
+                        map,
+                        repo_generation,
+                        cpupool,
+                        logger,
+                        client_authenticated,
+                    );
+                    http.bind_connection(&handle, stream, remote_addr, service);
+                }
+                Err(err) => {
+                    debug!(logger, "tls handshake with {} failed: {}", remote_addr, err);
+                }
+            }
+            Ok::<(), ()>(())
+        });
+        handle.spawn(connection);
+        Ok(())
     });
+
+    if let Err(err) = core.run(server) {
+        error!(server_logger, "{}", err);
+    }
 }
 
 /// Types of repositories supported
@@ -587,5 +998,36 @@ mod test {
         let badhash = std::iter::repeat("x").take(40).collect::<String>();
         let incorrect_url = format!("/repo/cs/{}/roottreemanifestid", badhash);
         assert!(parse_url(&incorrect_url, &routes).is_err());
+
+        let oid = std::iter::repeat("a").take(64).collect::<String>();
+        assert!(parse_url("/repo/objects/batch", &routes).is_ok());
+        assert!(parse_url(&format!("/repo/lfs/download/{}", oid), &routes).is_ok());
+        assert!(parse_url(&format!("/repo/lfs/upload/{}", oid), &routes).is_ok());
+        assert!(parse_url(&format!("/repo/lfs/upload/{}", hash), &routes).is_err());
+
+        match parse_url(&format!("/repo/cs/{}/logfollow/dir/file", hash), &routes) {
+            Ok(ParsedUrl::FileHistory(repo, _, path, follow_copies)) => {
+                assert_eq!(repo, "repo");
+                assert_eq!(path, "dir/file");
+                assert!(follow_copies);
+            }
+            _ => panic!("file history url not parsed"),
+        }
+
+        match parse_url("/repo/revset/%3A%3Amaster%20-%20%3A%3Astable", &routes) {
+            Ok(ParsedUrl::Revset(repo, revset)) => {
+                assert_eq!(repo, "repo");
+                assert_eq!(revset, "::master - ::stable");
+            }
+            _ => panic!("revset url not parsed"),
+        }
+
+        match parse_url(&format!("/repo/cs/{}/annotate/dir/file", hash), &routes) {
+            Ok(ParsedUrl::Annotate(repo, _, path)) => {
+                assert_eq!(repo, "repo");
+                assert_eq!(path, "dir/file");
+            }
+            _ => panic!("annotate url not parsed"),
+        }
     }
 }
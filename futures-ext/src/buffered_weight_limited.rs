@@ -0,0 +1,211 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Definition of the BufferedWeightLimited combinator, executing futures from a stream
+//! concurrently and streaming their results in order, while limiting how much of the results is
+//! buffered.
+
+use std::collections::VecDeque;
+
+use futures::{Async, Future, IntoFuture, Poll, Stream};
+
+/// Stream for the `buffered_weight_limited` method.
+#[must_use = "streams do nothing unless polled"]
+pub struct BufferedWeightLimited<S, F>
+where
+    S: Stream,
+    S::Item: IntoFuture,
+{
+    stream: S,
+    stream_done: bool,
+    // The futures that were started, in the order of the stream. Like in `futures_ordered`, the
+    // results are returned in that order, but the futures run concurrently.
+    queue: VecDeque<Slot<<S::Item as IntoFuture>::Future>>,
+    weight: F,
+    max_in_flight: usize,
+    weight_limit: usize,
+    // Total weight of the results that are done, but not returned yet
+    done_weight: usize,
+}
+
+enum Slot<Fut: Future> {
+    Running(Fut),
+    Done(Fut::Item, usize),
+    Failed(Fut::Error),
+}
+
+pub fn new<S, F>(
+    stream: S,
+    max_in_flight: usize,
+    weight_limit: usize,
+    weight: F,
+) -> BufferedWeightLimited<S, F>
+where
+    S: Stream,
+    S::Item: IntoFuture,
+{
+    assert!(max_in_flight > 0, "max_in_flight must be positive");
+    // With no room for results, no future would ever be started
+    assert!(weight_limit > 0, "weight_limit must be positive");
+    BufferedWeightLimited {
+        stream,
+        stream_done: false,
+        queue: VecDeque::with_capacity(max_in_flight),
+        weight,
+        max_in_flight,
+        weight_limit,
+        done_weight: 0,
+    }
+}
+
+impl<S, F> Stream for BufferedWeightLimited<S, F>
+where
+    S: Stream,
+    S::Item: IntoFuture<Error = S::Error>,
+    F: Fn(&<S::Item as IntoFuture>::Item) -> usize,
+{
+    type Item = <S::Item as IntoFuture>::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // Start new futures while there's room for them. Results that are done are waiting for
+        // the consumer, so if they add up to too much then stop until the consumer catches up.
+        while !self.stream_done && self.queue.len() < self.max_in_flight
+            && self.done_weight < self.weight_limit
+        {
+            match self.stream.poll()? {
+                Async::Ready(Some(fut)) => self.queue.push_back(Slot::Running(fut.into_future())),
+                Async::Ready(None) => self.stream_done = true,
+                Async::NotReady => break,
+            }
+        }
+
+        // Every running future is polled, so that all of them notify the task when they make
+        // progress. There are at most `max_in_flight` of them.
+        for slot in self.queue.iter_mut() {
+            let done = match *slot {
+                Slot::Running(ref mut fut) => match fut.poll() {
+                    Ok(Async::Ready(item)) => {
+                        let weight = (self.weight)(&item);
+                        self.done_weight += weight;
+                        Slot::Done(item, weight)
+                    }
+                    Ok(Async::NotReady) => continue,
+                    // Errors are returned in order too
+                    Err(err) => Slot::Failed(err),
+                },
+                _ => continue,
+            };
+            *slot = done;
+        }
+
+        match self.queue.pop_front() {
+            Some(Slot::Done(item, weight)) => {
+                self.done_weight -= weight;
+                return Ok(Async::Ready(Some(item)));
+            }
+            Some(Slot::Failed(err)) => return Err(err),
+            Some(running) => self.queue.push_front(running),
+            None => {}
+        }
+
+        if self.queue.is_empty() && self.stream_done {
+            Ok(Async::Ready(None))
+        } else {
+            // Either the first future isn't done and was polled above, or the queue is empty and
+            // the stream was polled above, since nothing is waiting for the consumer. Either way
+            // the task will be notified.
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::{future, stream};
+    use futures::sync::oneshot;
+
+    use super::*;
+    use StreamExt;
+
+    #[test]
+    fn test_order() {
+        let futs = vec![future::ok::<_, ()>(1), future::ok(2), future::ok(3)];
+        let res = stream::iter_ok(futs)
+            .buffered_weight_limited(2, 100, |_| 1)
+            .collect()
+            .wait();
+        assert_eq!(res, Ok(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_error_order() {
+        let futs = vec![future::ok(1), future::err(()), future::ok(3)];
+        let mut stream = stream::iter_ok(futs)
+            .buffered_weight_limited(3, 100, |_| 1)
+            .wait();
+        assert_eq!(stream.next(), Some(Ok(1)));
+        assert_eq!(stream.next(), Some(Err(())));
+        assert_eq!(stream.next(), Some(Ok(3)));
+        assert_eq!(stream.next(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_weight_limit() {
+        let _ = stream::iter_ok::<_, ()>(vec![future::ok::<u32, ()>(1)])
+            .buffered_weight_limited(1, 0, |_| 1);
+    }
+
+    #[test]
+    fn test_concurrent() {
+        // The second future completes the first one, so this only finishes if both are polled
+        // at the same time.
+        let (send, recv) = oneshot::channel();
+        let futs: Vec<Box<Future<Item = u32, Error = ()> + Send>> = vec![
+            Box::new(recv.map_err(|_| ())),
+            Box::new(future::lazy(move || {
+                send.send(1).unwrap();
+                Ok(2)
+            })),
+        ];
+        let res = stream::iter_ok(futs)
+            .buffered_weight_limited(2, 100, |_| 1)
+            .collect()
+            .wait();
+        assert_eq!(res, Ok(vec![1, 2]));
+    }
+
+    #[test]
+    fn test_weight_limit() {
+        let started = Arc::new(AtomicUsize::new(0));
+        let futs = (0..10).map({
+            let started = started.clone();
+            move |i| {
+                let started = started.clone();
+                future::lazy(move || {
+                    started.fetch_add(1, Ordering::SeqCst);
+                    Ok::<_, ()>(i)
+                })
+            }
+        });
+
+        // Every result weighs as much as the limit, so no new future is started while a result
+        // is waiting for the consumer.
+        let mut stream = stream::iter_ok(futs)
+            .buffered_weight_limited(5, 10, |_| 10)
+            .wait();
+        assert_eq!(stream.next(), Some(Ok(0)));
+        assert_eq!(started.load(Ordering::SeqCst), 5);
+        assert_eq!(stream.next(), Some(Ok(1)));
+        assert_eq!(started.load(Ordering::SeqCst), 5);
+        assert_eq!(stream.collect::<Vec<_>>().len(), 8);
+        assert_eq!(started.load(Ordering::SeqCst), 10);
+    }
+}
@@ -24,6 +24,7 @@ use futures::{Async, Future, IntoFuture, Poll, Sink, Stream};
 use futures::sync::oneshot;
 use tokio_io::codec::{Decoder, Encoder};
 
+mod buffered_weight_limited;
 mod bytes_stream;
 mod futures_ordered;
 mod streamfork;
@@ -34,6 +35,7 @@ pub mod encode;
 
 pub mod io;
 
+pub use buffered_weight_limited::BufferedWeightLimited;
 pub use bytes_stream::{BytesStream, BytesStreamFuture};
 pub use futures_ordered::{futures_ordered, FuturesOrdered};
 pub use stream_wrappers::{BoxStreamWrapper, CollectNoConsume, StreamWrapper, TakeWhile};
@@ -157,6 +159,24 @@ pub trait StreamExt: Stream {
         encode::encode(self, encoder)
     }
 
+    /// Like `buffered`, runs up to `max_in_flight` futures from this stream at the same time and
+    /// returns their results in order. In addition, no new futures are started while the results
+    /// that are done but not consumed yet weigh `weight_limit` or more, so that a slow consumer
+    /// doesn't make the results pile up. `weight` gives the weight of a result, f.e. its size.
+    fn buffered_weight_limited<F>(
+        self,
+        max_in_flight: usize,
+        weight_limit: usize,
+        weight: F,
+    ) -> BufferedWeightLimited<Self, F>
+    where
+        Self: Sized,
+        Self::Item: IntoFuture<Error = Self::Error>,
+        F: Fn(&<Self::Item as IntoFuture>::Item) -> usize,
+    {
+        buffered_weight_limited::new(self, max_in_flight, weight_limit, weight)
+    }
+
     fn enumerate(self) -> Enumerate<Self>
     where
         Self: Sized,
@@ -15,7 +15,7 @@ use std::str::FromStr;
 
 use slog::Logger;
 
-use bytes::{Bytes, BytesMut};
+use bytes::{BigEndian, Buf, Bytes, BytesMut, IntoBuf};
 use failure::err_msg;
 use futures::IntoFuture;
 use futures::future::{self, err, ok, Either, Future};
@@ -35,6 +35,10 @@ use {BranchRes, GetbundleArgs, GettreepackArgs, SingleRequest, SingleResponse};
 use errors::*;
 
 const HASH_SIZE: usize = 40;
+const NODE_SIZE: usize = 20;
+/// Maximum number of nodes of a file in a getpackv1 request. The nodes of a file are buffered
+/// until all of them have been received, so the count sent by the client must be bounded.
+const GETPACK_MAX_NODES: usize = 10_000;
 
 pub struct HgCommandHandler<H> {
     commands: H,
@@ -112,7 +116,7 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
             SingleRequest::Changegroup { roots } => (
                 hgcmds
                     .changegroup(roots)
-                    .map(|_| SingleResponse::Changegroup)
+                    .map(SingleResponse::Changegroup)
                     .map_err(self::Error::into)
                     .into_stream()
                     .boxify(),
@@ -121,7 +125,7 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
             SingleRequest::Changegroupsubset { bases, heads } => (
                 hgcmds
                     .changegroupsubset(bases, heads)
-                    .map(|_| SingleResponse::Changegroupsubset)
+                    .map(SingleResponse::Changegroupsubset)
                     .map_err(self::Error::into)
                     .into_stream()
                     .boxify(),
@@ -171,6 +175,18 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
                     .boxify(),
                 ok(instream).boxify(),
             ),
+            SingleRequest::Listkeyspatterns {
+                namespace,
+                patterns,
+            } => (
+                hgcmds
+                    .listkeyspatterns(namespace, patterns)
+                    .map(SingleResponse::Listkeyspatterns)
+                    .map_err(self::Error::into)
+                    .into_stream()
+                    .boxify(),
+                ok(instream).boxify(),
+            ),
             SingleRequest::Lookup { key } => (
                 hgcmds
                     .lookup(key)
@@ -197,7 +213,7 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
             } => (
                 hgcmds
                     .pushkey(namespace, key, old, new)
-                    .map(|_| SingleResponse::Pushkey)
+                    .map(SingleResponse::Pushkey)
                     .map_err(self::Error::into)
                     .into_stream()
                     .boxify(),
@@ -206,9 +222,8 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
             SingleRequest::Streamout => (
                 hgcmds
                     .stream_out()
-                    .map(|_| SingleResponse::Streamout)
+                    .map(SingleResponse::Streamout)
                     .map_err(self::Error::into)
-                    .into_stream()
                     .boxify(),
                 ok(instream).boxify(),
             ),
@@ -275,6 +290,17 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
                     instream,
                 )
             }
+            SingleRequest::Getpackv1 => {
+                let (reqs, instream) = decode_getpack_arg_stream(instream);
+                (
+                    hgcmds
+                        .getpackv1(reqs)
+                        .map(SingleResponse::Getpackv1)
+                        .map_err(self::Error::into)
+                        .boxify(),
+                    instream,
+                )
+            }
         }
     }
 
@@ -359,6 +385,53 @@ impl Decoder for GetfilesArgDecoder {
     }
 }
 
+struct GetpackArgDecoder {}
+
+// Parses one (path, nodes) request
+impl Decoder for GetpackArgDecoder {
+    // If None has been decoded, then that means that client has sent all the data
+    type Item = Option<(MPath, Vec<NodeHash>)>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+        let path_len = (&src[..2]).into_buf().get_u16::<BigEndian>() as usize;
+        if path_len == 0 {
+            // Finished parsing the stream
+            src.split_to(2);
+            return Ok(Some(None));
+        }
+
+        let count_offset = 2 + path_len;
+        let nodes_offset = count_offset + 4;
+        if src.len() < nodes_offset {
+            return Ok(None);
+        }
+        let count = (&src[count_offset..nodes_offset])
+            .into_buf()
+            .get_u32::<BigEndian>() as usize;
+        if count > GETPACK_MAX_NODES {
+            return Err(err_msg(format!(
+                "getpackv1 request has {} nodes for a file, the maximum is {}",
+                count, GETPACK_MAX_NODES
+            )));
+        }
+        if src.len() < nodes_offset + count * NODE_SIZE {
+            return Ok(None);
+        }
+
+        let buf = src.split_to(nodes_offset + count * NODE_SIZE);
+        let path = MPath::new(&buf[2..count_offset])?;
+        let nodes = buf[nodes_offset..]
+            .chunks(NODE_SIZE)
+            .map(NodeHash::from_bytes)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(Some((path, nodes))))
+    }
+}
+
 // getfiles args format:
 // (nodepath\n)*\n
 // nodepath := node path
@@ -373,6 +446,38 @@ fn decode_getfiles_arg_stream<S>(
 )
 where
     S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+{
+    decode_arg_stream(input, || GetfilesArgDecoder {})
+}
+
+// getpackv1 args format:
+// (pathlen path nodecount node*)* 0
+// pathlen := u16 (big endian)
+// nodecount := u32 (big endian)
+// node = binary hash (20 bytes)
+fn decode_getpack_arg_stream<S>(
+    input: BytesStream<S>,
+) -> (
+    BoxStream<(MPath, Vec<NodeHash>), Error>,
+    BoxFuture<BytesStream<S>, Error>,
+)
+where
+    S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+{
+    decode_arg_stream(input, || GetpackArgDecoder {})
+}
+
+// Decodes a stream of arguments sent after a command, using a fresh decoder from `create_decoder`
+// for each argument. The decoder returns `Some(None)` once all the arguments have been read.
+fn decode_arg_stream<S, D, F, T>(
+    input: BytesStream<S>,
+    create_decoder: F,
+) -> (BoxStream<T, Error>, BoxFuture<BytesStream<S>, Error>)
+where
+    S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+    D: Decoder<Item = Option<T>, Error = Error> + Send + 'static,
+    F: Fn() -> D + Send + 'static,
+    T: Send + 'static,
 {
     let (send, recv) = oneshot::channel();
 
@@ -384,7 +489,7 @@ where
     // waits for it.
     let entry_stream: BoxStream<_, ::std::result::Result<BytesStream<S>, (_, BytesStream<S>)>> =
         stream::unfold(input, move |input| {
-            let fut_decode = input.into_future_decode(GetfilesArgDecoder {});
+            let fut_decode = input.into_future_decode(create_decoder());
             let fut = fut_decode
                 .map_err(|err| Err(err)) // Real error happened, wrap it in result
                 .and_then(|(maybe_item, instream)| match maybe_item {
@@ -394,17 +499,17 @@ where
                             .into_future()
                             .boxify()
                     }
-                    Some(maybe_nodehash) => {
-                        match maybe_nodehash {
+                    Some(maybe_arg) => {
+                        match maybe_arg {
                             None => {
-                                // None here means that we've read all the node-file pairs
+                                // None here means that we've read all the arguments
                                 // that client has sent us. Return fake error that means that
                                 // we've successfully parsed the stream.
                                 Err(Ok(instream)).into_future().boxify()
                             }
-                            Some(nodehash) => {
+                            Some(arg) => {
                                 // Parsed one more entry - continue
-                                Ok((nodehash, instream)).into_future().boxify()
+                                Ok((arg, instream)).into_future().boxify()
                             }
                         }
                     }
@@ -429,7 +534,7 @@ where
         let mut wrapped_send = Some(send);
         move |val| {
             match val {
-                Ok(nodefile) => Ok(Some(nodefile)),
+                Ok(arg) => Ok(Some(arg)),
                 Err(Ok(instream)) => try_send_instream(&mut wrapped_send, instream).map(|_| None),
                 Err(Err((err, instream))) => {
                     match try_send_instream(&mut wrapped_send, instream) {
@@ -536,13 +641,16 @@ pub trait HgCommands {
     }
 
     // @wireprotocommand('changegroup', 'roots')
-    fn changegroup(&self, _roots: Vec<NodeHash>) -> HgCommandRes<()> {
-        // TODO: streaming something
+    fn changegroup(&self, _roots: Vec<NodeHash>) -> HgCommandRes<Bytes> {
         unimplemented("changegroup")
     }
 
     // @wireprotocommand('changegroupsubset', 'bases heads')
-    fn changegroupsubset(&self, _bases: Vec<NodeHash>, _heads: Vec<NodeHash>) -> HgCommandRes<()> {
+    fn changegroupsubset(
+        &self,
+        _bases: Vec<NodeHash>,
+        _heads: Vec<NodeHash>,
+    ) -> HgCommandRes<Bytes> {
         unimplemented("changegroupsubset")
     }
 
@@ -567,6 +675,15 @@ pub trait HgCommands {
         unimplemented("listkeys")
     }
 
+    // @wireprotocommand('listkeyspatterns', 'namespace patterns')
+    fn listkeyspatterns(
+        &self,
+        _namespace: String,
+        _patterns: Vec<String>,
+    ) -> HgCommandRes<HashMap<Vec<u8>, Vec<u8>>> {
+        unimplemented("listkeyspatterns")
+    }
+
     // @wireprotocommand('lookup', 'key')
     fn lookup(&self, _key: String) -> HgCommandRes<Bytes> {
         unimplemented("lookup")
@@ -582,16 +699,15 @@ pub trait HgCommands {
         &self,
         _namespace: String,
         _key: String,
-        _old: NodeHash,
-        _new: NodeHash,
-    ) -> HgCommandRes<()> {
+        _old: Bytes,
+        _new: Bytes,
+    ) -> HgCommandRes<bool> {
         unimplemented("pushkey")
     }
 
     // @wireprotocommand('stream_out')
-    fn stream_out(&self) -> HgCommandRes<BoxStream<Vec<u8>, Error>> {
-        // XXX raw streaming?
-        unimplemented("stream_out")
+    fn stream_out(&self) -> BoxStream<Bytes, Error> {
+        once(Err(ErrorKind::Unimplemented("stream_out".into()).into())).boxify()
     }
 
     // @wireprotocommand('unbundle', 'heads')
@@ -612,6 +728,14 @@ pub trait HgCommands {
     fn getfiles(&self, _params: BoxStream<(NodeHash, MPath), Error>) -> BoxStream<Bytes, Error> {
         once(Err(ErrorKind::Unimplemented("getfiles".into()).into())).boxify()
     }
+
+    // @wireprotocommand('getpackv1', '*')
+    fn getpackv1(
+        &self,
+        _params: BoxStream<(MPath, Vec<NodeHash>), Error>,
+    ) -> BoxStream<Bytes, Error> {
+        once(Err(ErrorKind::Unimplemented("getpackv1".into()).into())).boxify()
+    }
 }
 
 #[cfg(test)]
@@ -744,4 +868,41 @@ mod test {
         let (paramstream, _input) = decode_getfiles_arg_stream(BytesStream::new(stream::empty()));
         assert!(paramstream.collect().wait().is_err());
     }
+
+    #[test]
+    fn getpackargs() {
+        let mut input = vec![];
+        input.extend_from_slice(b"\x00\x04path\x00\x00\x00\x02");
+        input.extend_from_slice(hash_ones().as_ref());
+        input.extend_from_slice(hash_twos().as_ref());
+        input.extend_from_slice(b"\x00\x05path2\x00\x00\x00\x01");
+        input.extend_from_slice(hash_twos().as_ref());
+        input.extend_from_slice(b"\x00\x00");
+
+        // Split the input to check that partially received arguments are handled
+        let (first, second) = input.split_at(10);
+        let chunks = vec![Ok(Bytes::from(first)), Ok(Bytes::from(second))];
+        let (paramstream, _input) =
+            decode_getpack_arg_stream(BytesStream::new(stream::iter_result(chunks)));
+
+        let res = paramstream.collect().wait().unwrap();
+        assert_eq!(
+            res,
+            vec![
+                (MPath::new("path").unwrap(), vec![hash_ones(), hash_twos()]),
+                (MPath::new("path2").unwrap(), vec![hash_twos()]),
+            ]
+        );
+
+        // Unexpected end of file
+        let (paramstream, _input) =
+            decode_getpack_arg_stream(BytesStream::new(stream::once(Ok(Bytes::from(first)))));
+        assert!(paramstream.collect().wait().is_err());
+
+        // Too many nodes, which fails before they are received
+        let input = Bytes::from(&b"\x00\x04path\xff\xff\xff\xff"[..]);
+        let (paramstream, _input) =
+            decode_getpack_arg_stream(BytesStream::new(stream::once(Ok(input))));
+        assert!(paramstream.collect().wait().is_err());
+    }
 }
@@ -60,10 +60,12 @@ pub mod sshproto;
 // result from `branches()`
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct BranchRes {
-    top: NodeHash,
-    node: NodeHash,
-    p0: Option<NodeHash>,
-    p1: Option<NodeHash>,
+    /// The node the client asked about
+    pub top: NodeHash,
+    /// The first ancestor of `top` (following first parents) that is a merge or a root
+    pub node: NodeHash,
+    pub p0: Option<NodeHash>,
+    pub p1: Option<NodeHash>,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -101,6 +103,12 @@ pub enum SingleRequest {
     Listkeys {
         namespace: String,
     },
+    /// Like `Listkeys`, but only the keys that match one of the patterns. A pattern that ends
+    /// with '*' matches the keys that start with the rest of it.
+    Listkeyspatterns {
+        namespace: String,
+        patterns: Vec<String>,
+    },
     Lookup {
         key: String,
     },
@@ -110,8 +118,8 @@ pub enum SingleRequest {
     Pushkey {
         namespace: String,
         key: String,
-        old: NodeHash,
-        new: NodeHash,
+        old: Bytes,
+        new: Bytes,
     },
     Streamout,
     Unbundle {
@@ -119,6 +127,7 @@ pub enum SingleRequest {
     },
     Gettreepack(GettreepackArgs),
     Getfiles,
+    Getpackv1,
 }
 
 /// The arguments that `getbundle` accepts, in a separate struct for
@@ -129,6 +138,10 @@ pub struct GetbundleArgs {
     pub common: Vec<NodeHash>,
     pub bundlecaps: Vec<Vec<u8>>,
     pub listkeys: Vec<Vec<u8>>,
+    /// Whether the phases of the changesets should be sent in a phase-heads part
+    pub phases: bool,
+    /// Whether the obsolescence markers relevant to the changesets should be sent
+    pub obsmarkers: bool,
 }
 
 impl Debug for GetbundleArgs {
@@ -146,6 +159,8 @@ impl Debug for GetbundleArgs {
             .field("common", &self.common)
             .field("bundlecaps", &bcaps)
             .field("listkeys", &listkeys)
+            .field("phases", &self.phases)
+            .field("obsmarkers", &self.obsmarkers)
             .finish()
     }
 }
@@ -164,6 +179,9 @@ pub struct GettreepackArgs {
     ///  The fullpath (not relative path) of directories underneath
     /// the rootdir that should be sent.
     pub directories: Vec<Bytes>,
+    /// How many levels of directories to send, counting the rootdir as the first level. `None`
+    /// means no limit.
+    pub depth: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -179,21 +197,23 @@ pub enum SingleResponse {
     Branches(Vec<BranchRes>),
     Clonebundles(String),
     Capabilities(Vec<String>),
-    Changegroup,
-    Changegroupsubset,
+    Changegroup(Bytes),
+    Changegroupsubset(Bytes),
     Debugwireargs(Bytes),
     Getbundle(Bytes),
     Heads(HashSet<NodeHash>),
     Hello(HashMap<String, Vec<String>>),
     Listkeys(HashMap<Vec<u8>, Vec<u8>>),
+    Listkeyspatterns(HashMap<Vec<u8>, Vec<u8>>),
     Lookup(Bytes),
     Known(Vec<bool>),
-    Pushkey,
-    Streamout, /* (BoxStream<Vec<u8>, Error>) */
+    Pushkey(bool),
+    Streamout(Bytes),
     ReadyForStream,
     Unbundle(Bytes),
     Gettreepack(Bytes),
     Getfiles(Bytes),
+    Getpackv1(Bytes),
 }
 
 impl SingleResponse {
@@ -202,10 +222,14 @@ impl SingleResponse {
         use SingleResponse::*;
 
         match self {
+            &Changegroup(_) => true,
+            &Changegroupsubset(_) => true,
             &Getbundle(_) => true,
             &ReadyForStream => true,
             &Unbundle(_) => true,
             &Gettreepack(_) => true,
+            &Streamout(_) => true,
+            &Getpackv1(_) => true,
             _ => false,
         }
     }
@@ -19,6 +19,9 @@ use errors;
 use errors::*;
 
 const BAD_UTF8_ERR_CODE: u32 = 111;
+const BAD_INTEGER_ERR_CODE: u32 = 112;
+const BAD_BOOLEAN_ERR_CODE: u32 = 113;
+const BAD_HEX_ERR_CODE: u32 = 114;
 
 /// Parse an unsigned decimal integer. If it reaches the end of input, it returns Incomplete,
 /// as there may be more digits following
@@ -335,6 +338,51 @@ fn utf8_string_complete(inp: &[u8]) -> IResult<&[u8], String> {
     }
 }
 
+/// Parse an unsigned decimal integer, assumes that input is complete. The value is wrapped in
+/// `Some` to be used with `parseval_default` for optional integer params.
+fn integer_option_complete(inp: &[u8]) -> IResult<&[u8], Option<usize>> {
+    match str::from_utf8(inp).ok().and_then(|s| s.parse().ok()) {
+        Some(v) => IResult::Done(b"", Some(v)),
+        None => IResult::Error(ErrorKind::Custom(BAD_INTEGER_ERR_CODE)),
+    }
+}
+
+/// Parse a boolean, assumes that input is complete
+fn boolean_complete(inp: &[u8]) -> IResult<&[u8], bool> {
+    match inp {
+        b"1" | b"True" => IResult::Done(b"", true),
+        b"0" | b"False" => IResult::Done(b"", false),
+        _ => IResult::Error(ErrorKind::Custom(BAD_BOOLEAN_ERR_CODE)),
+    }
+}
+
+/// Parse a space-separated list of hex-encoded utf8 strings (the encoding of Mercurial's
+/// `wireproto.encodelist`), assumes that input is complete
+fn hexstringlist_complete(inp: &[u8]) -> IResult<&[u8], Vec<String>> {
+    fn unhex(hex: &[u8]) -> Option<String> {
+        if hex.len() % 2 != 0 {
+            return None;
+        }
+        let bytes: Option<Vec<u8>> = hex.chunks(2)
+            .map(|pair| {
+                str::from_utf8(pair)
+                    .ok()
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            })
+            .collect();
+        bytes.and_then(|bytes| String::from_utf8(bytes).ok())
+    }
+
+    if inp.is_empty() {
+        return IResult::Done(b"", vec![]);
+    }
+    let res: Option<Vec<_>> = inp.split(|c| *c == b' ').map(unhex).collect();
+    match res {
+        Some(res) => IResult::Done(b"", res),
+        None => IResult::Error(ErrorKind::Custom(BAD_HEX_ERR_CODE)),
+    }
+}
+
 fn bytes_complete(inp: &[u8]) -> IResult<&[u8], Bytes> {
     let res = Bytes::from(inp);
     IResult::Done(b"", res)
@@ -475,7 +523,6 @@ fn parse_with_params(
         | call!(parse_command, "getbundle", parse_params, 0+1,
             |kv| Ok(Getbundle(GetbundleArgs {
                 // Some params are currently ignored, like:
-                // - obsmarkers
                 // - cg
                 // - cbattempted
                 // If those params are needed, they should be parsed here.
@@ -483,12 +530,18 @@ fn parse_with_params(
                 common: parseval_default(&kv, "common", hashlist)?,
                 bundlecaps: parseval_default(&kv, "bundlecaps", commavalues)?,
                 listkeys: parseval_default(&kv, "listkeys", commavalues)?,
+                phases: parseval_default(&kv, "phases", boolean_complete)?,
+                obsmarkers: parseval_default(&kv, "obsmarkers", boolean_complete)?,
             })))
         | command!("heads", Heads, parse_params, {})
         | command!("hello", Hello, parse_params, {})
         | command!("listkeys", Listkeys, parse_params, {
               namespace => ident_string,
           })
+        | command!("listkeyspatterns", Listkeyspatterns, parse_params, {
+              namespace => ident_string,
+              patterns => hexstringlist_complete,
+          })
         | command!("lookup", Lookup, parse_params, {
               key => utf8_string_complete,
           })
@@ -498,9 +551,10 @@ fn parse_with_params(
         | command!("pushkey", Pushkey, parse_params, {
               namespace => ident_string,
               key => ident_string,
-              old => nodehash,
-              new => nodehash,
+              old => bytes_complete,
+              new => bytes_complete,
           })
+        | command!("stream_out", Streamout, parse_params, {})
         | command!("streamout", Streamout, parse_params, {})
         | command!("unbundle", Unbundle, parse_params, {
               heads => stringlist,
@@ -511,8 +565,10 @@ fn parse_with_params(
                 mfnodes: parseval(&kv, "mfnodes", hashlist)?,
                 basemfnodes: parseval(&kv, "basemfnodes", hashlist)?,
                 directories: parseval(&kv, "directories", gettreepack_directories)?,
+                depth: parseval_default(&kv, "depth", integer_option_complete)?,
             })))
         | command!("getfiles", Getfiles, parse_params, {})
+        | command!("getpackv1", Getpackv1, parse_params, {})
     )
 }
 
@@ -1245,13 +1301,15 @@ mod test_parse {
                 common: vec![],
                 bundlecaps: vec![],
                 listkeys: vec![],
+                phases: false,
+                obsmarkers: false,
             })),
         );
 
         // with arguments
         let inp =
             "getbundle\n\
-             * 5\n\
+             * 7\n\
              heads 40\n\
              1111111111111111111111111111111111111111\
              common 81\n\
@@ -1260,6 +1318,10 @@ mod test_parse {
              cap1,CAP2,cap3\
              listkeys 9\n\
              key1,key2\
+             phases 1\n\
+             1\
+             obsmarkers 4\n\
+             True\
              extra 5\n\
              extra";
         test_parse(
@@ -1269,6 +1331,8 @@ mod test_parse {
                 common: vec![hash_twos(), hash_threes()],
                 bundlecaps: vec![b"cap1".to_vec(), b"CAP2".to_vec(), b"cap3".to_vec()],
                 listkeys: vec![b"key1".to_vec(), b"key2".to_vec()],
+                phases: true,
+                obsmarkers: true,
             })),
         );
     }
@@ -1301,6 +1365,24 @@ mod test_parse {
         );
     }
 
+    #[test]
+    fn test_parse_listkeyspatterns() {
+        // "scratch/*" and "master", hex-encoded
+        let inp = "listkeyspatterns\n\
+                   namespace 9\n\
+                   bookmarks\
+                   patterns 31\n\
+                   736372617463682f2a 6d6173746572";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Listkeyspatterns {
+                namespace: "bookmarks".to_string(),
+                patterns: vec!["scratch/*".to_string(), "master".to_string()],
+            }),
+        );
+    }
+
     #[test]
     fn test_parse_lookup() {
         let inp = "lookup\n\
@@ -1347,6 +1429,7 @@ mod test_parse {
                 mfnodes: vec![hash_ones()],
                 basemfnodes: vec![hash_ones()],
                 directories: vec![],
+                depth: None,
             })),
         );
 
@@ -1369,6 +1452,28 @@ mod test_parse {
                 mfnodes: vec![hash_ones(), hash_twos()],
                 basemfnodes: vec![hash_twos(), hash_ones()],
                 directories: vec![Bytes::from(",".as_bytes()), Bytes::from(";".as_bytes())],
+                depth: None,
+            })),
+        );
+
+        let inp = "gettreepack\n\
+                   * 5\n\
+                   rootdir 0\n\
+                   mfnodes 40\n\
+                   1111111111111111111111111111111111111111\
+                   basemfnodes 0\n\
+                   directories 0\n\
+                   depth 2\n\
+                   12";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Gettreepack(GettreepackArgs {
+                rootdir: Bytes::new(),
+                mfnodes: vec![hash_ones()],
+                basemfnodes: vec![],
+                directories: vec![],
+                depth: Some(12),
             })),
         );
     }
@@ -1414,8 +1519,29 @@ mod test_parse {
             Request::Single(SingleRequest::Pushkey {
                 namespace: "bookmarks".to_string(),
                 key: "foobar".to_string(),
-                old: hash_ones(),
-                new: hash_twos(),
+                old: Bytes::from(&b"1111111111111111111111111111111111111111"[..]),
+                new: Bytes::from(&b"2222222222222222222222222222222222222222"[..]),
+            }),
+        );
+
+        // Phases are moved by number, and bookmarks are created from an empty value
+        let inp = "pushkey\n\
+                   namespace 6\n\
+                   phases\
+                   key 40\n\
+                   1111111111111111111111111111111111111111\
+                   old 1\n\
+                   1\
+                   new 1\n\
+                   0";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Pushkey {
+                namespace: "phases".to_string(),
+                key: "1111111111111111111111111111111111111111".to_string(),
+                old: Bytes::from(&b"1"[..]),
+                new: Bytes::from(&b"0"[..]),
             }),
         );
     }
@@ -1425,6 +1551,10 @@ mod test_parse {
         let inp = "streamout\n";
 
         test_parse(inp, Request::Single(SingleRequest::Streamout {}));
+
+        let inp = "stream_out\n";
+
+        test_parse(inp, Request::Single(SingleRequest::Streamout {}));
     }
 
     fn test_parse_unbundle_with(bundle: &[u8]) {
@@ -11,6 +11,8 @@ use bytes::{BufMut, Bytes, BytesMut};
 use futures::stream;
 use futures_ext::StreamExt;
 
+use mercurial_types::{percent_encode, NULL_HASH};
+
 use {batch, Response, SingleResponse};
 use handler::OutputStream;
 
@@ -88,6 +90,22 @@ fn encode_cmd(response: &SingleResponse) -> Bytes {
             Bytes::from(out)
         }
 
+        &Branches(ref branches) => {
+            let mut out = Vec::new();
+
+            for branch in branches {
+                let nodes = [
+                    branch.top,
+                    branch.node,
+                    branch.p0.unwrap_or(NULL_HASH),
+                    branch.p1.unwrap_or(NULL_HASH),
+                ];
+                separated(&mut out, &nodes, " ").expect("write to vec failed");
+            }
+
+            Bytes::from(out)
+        }
+
         &Debugwireargs(ref res) => res.clone(),
 
         &Heads(ref set) => {
@@ -109,22 +127,64 @@ fn encode_cmd(response: &SingleResponse) -> Bytes {
 
         &ReadyForStream => Bytes::from(b"0\n".as_ref()),
 
+        &Pushkey(success) => Bytes::from(if success { &b"1\n"[..] } else { &b"0\n"[..] }),
+
+        &Listkeys(ref keys) | &Listkeyspatterns(ref keys) => {
+            // Keys are sorted to make the output deterministic
+            let mut keys: Vec<_> = keys.iter().collect();
+            keys.sort();
+
+            let mut out = Vec::new();
+            for (idx, &(key, value)) in keys.iter().enumerate() {
+                if idx > 0 {
+                    out.push(b'\n');
+                }
+                out.extend_from_slice(key);
+                out.push(b'\t');
+                out.extend_from_slice(value);
+            }
+
+            Bytes::from(out)
+        }
+
         // TODO(luk, T25574469) The response for Unbundle should be chunked stream of bundle2
         &Unbundle(ref res) => res.clone(),
 
         &Getbundle(ref res) => res.clone(),
 
+        &Changegroup(ref res) => res.clone(),
+
+        &Changegroupsubset(ref res) => res.clone(),
+
         &Gettreepack(ref res) => res.clone(),
 
         &Getfiles(ref res) => res.clone(),
 
+        &Getpackv1(ref res) => res.clone(),
+
+        &Streamout(ref res) => res.clone(),
+
         &Lookup(ref res) => res.clone(),
 
-        &Branchmap(ref _res) => {
-            // We have no plans to support mercurial branches and hence no plans for branchmap,
-            // so just return fake response.
-            Bytes::new()
-        },
+        &Clonebundles(ref res) => Bytes::from(res.as_bytes()),
+
+        &Branchmap(ref branchmap) => {
+            // Branches are sorted to make the output deterministic. Unlike other list responses
+            // there is no trailing newline after the last branch.
+            let mut branches: Vec<_> = branchmap.iter().collect();
+            branches.sort_by_key(|&(name, _)| name);
+
+            let lines: Vec<_> = branches
+                .into_iter()
+                .map(|(name, heads)| {
+                    let mut heads: Vec<_> = heads.iter().map(|head| head.to_string()).collect();
+                    heads.sort();
+                    format!("{} {}", percent_encode(name), heads.join(" "))
+                })
+                .collect();
+
+            Bytes::from(lines.join("\n"))
+        }
 
         r => panic!("Response for {:?} unimplemented", r),
     }
@@ -20,6 +20,7 @@ extern crate tempdir;
 extern crate blobrepo;
 extern crate hlua_futures;
 extern crate mercurial;
+extern crate mercurial_bundles;
 extern crate mercurial_types;
 
 #[cfg(test)]
@@ -37,6 +38,7 @@ use hlua::{AnyLuaValue, Lua, LuaError, PushGuard};
 
 use blobrepo::BlobRepo;
 use hlua_futures::{AnyFuture, LuaCoroutine, LuaCoroutineBuilder};
+use mercurial_bundles::ClientOutput;
 use mercurial_types::{Changeset, NodeHash};
 use mercurial_types::nodehash::HgChangesetId;
 
@@ -59,10 +61,34 @@ pub struct HookContext<'hook> {
     name: &'hook str,
     repo: Arc<BlobRepo>,
     info: HashMap<&'static str, String>,
+    /// Pushvars sent with the push. The hook sees them as `info.pushvars`.
+    pushvars: HashMap<String, String>,
+    /// Where the hook's `output` function sends messages for the user.
+    output: ClientOutput,
     code: &'hook str,
 }
 
 impl<'hook> HookContext<'hook> {
+    /// Context for running the hook `name`, whose Lua source is `code`, on a push. `pushvars`
+    /// are the pushvars of the push, as returned by `bundle2_resolver::resolve`.
+    pub fn new(
+        name: &'hook str,
+        repo: Arc<BlobRepo>,
+        info: HashMap<&'static str, String>,
+        pushvars: HashMap<String, String>,
+        output: ClientOutput,
+        code: &'hook str,
+    ) -> Self {
+        HookContext {
+            name,
+            repo,
+            info,
+            pushvars,
+            output,
+            code,
+        }
+    }
+
     fn run<'a, 'lua>(
         &self,
         lua: &'a mut Lua<'lua>,
@@ -83,6 +109,9 @@ impl<'hook> HookContext<'hook> {
         };
         lua.set("get_author", hlua::function1(get_author));
 
+        let output = self.output.clone();
+        lua.set("output", hlua::function1(move |message: String| output.write(message)));
+
         lua.execute::<()>(self.code)?;
 
         let builder: LuaCoroutineBuilder<_> = match lua.get("hook") {
@@ -91,9 +120,23 @@ impl<'hook> HookContext<'hook> {
                 "function 'hook' not found".into(),
             )),
         };
-        // TODO: do we really need the clone?
+        let mut info: HashMap<&str, AnyLuaValue> = self.info
+            .iter()
+            .map(|(key, value)| (*key, AnyLuaValue::LuaString(value.clone())))
+            .collect();
+        let pushvars = self.pushvars
+            .iter()
+            .map(|(name, value)| {
+                (
+                    AnyLuaValue::LuaString(name.clone()),
+                    AnyLuaValue::LuaString(value.clone()),
+                )
+            })
+            .collect();
+        info.insert("pushvars", AnyLuaValue::LuaArray(pushvars));
+
         // TODO: use chain_err once LuaFunctionCallError implements std::error::Error
-        let coroutine_fut = builder.create(self.info.clone()).map_err(|err| {
+        let coroutine_fut = builder.create(info).map_err(|err| {
             ErrorKind::HookRuntimeError(self.name.into(), format!("{:?}", err)).into()
         });
         coroutine_fut
@@ -133,23 +176,32 @@ mod test {
         };
         let mut hook_manager = HookManager::new();
         let repo = linear::getrepo(None);
-        let hook = HookContext {
-            name: "test",
-            repo: Arc::new(repo),
-            info: hook_info,
-            code: "
+        let output = ClientOutput::new();
+        let hook = HookContext::new(
+            "test",
+            Arc::new(repo),
+            hook_info,
+            hashmap! {
+                "BYPASS_REVIEW".into() => "true".into(),
+            },
+            output.clone(),
+            "
                     function hook(info)
                         if info.repo ~= \"fbsource\" then
                             return false
+                        elseif info.pushvars.BYPASS_REVIEW ~= \"true\" then
+                            return false
                         else
+                            output(\"review bypassed\")
                             author = coroutine.yield(get_author(info.new_hash))
                             return author == \"Jeremy Fitzhardinge <jsgf@fb.com>\"
                         end
                     end",
-        };
+        );
 
         let coroutine_fut = hook_manager.run_hook(hook).unwrap();
         let result = coroutine_fut.wait();
         assert!(result.unwrap());
+        assert_eq!(output.take_buffered(), vec!["review bypassed\n"]);
     }
 }
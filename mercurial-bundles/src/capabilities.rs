@@ -9,17 +9,64 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 
+use async_compression::CompressorType;
 use bytes::BytesMut;
 use tokio_io::codec::Decoder;
 use url::percent_encoding::percent_decode;
 
 use errors::*;
+use utils::get_compression_param;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Capabilities {
     caps: HashMap<String, Vec<String>>,
 }
 
+impl Capabilities {
+    pub fn new(caps: HashMap<String, Vec<String>>) -> Self {
+        Capabilities { caps }
+    }
+
+    /// Returns the values advertised for the given capability, or None if it wasn't advertised
+    /// at all.
+    pub fn get(&self, key: &str) -> Option<&Vec<String>> {
+        self.caps.get(key)
+    }
+
+    /// Extract the bundle2 capabilities from the `bundlecaps` argument of getbundle. Clients
+    /// send them as a single `bundle2=<url encoded capabilities>` entry. Returns None if the
+    /// client didn't advertise any.
+    pub fn from_bundlecaps(bundlecaps: &[Vec<u8>]) -> Result<Option<Self>> {
+        const PREFIX: &[u8] = b"bundle2=";
+
+        let blob = match bundlecaps.iter().find(|cap| cap.starts_with(PREFIX)) {
+            Some(cap) => &cap[PREFIX.len()..],
+            None => return Ok(None),
+        };
+        let mut buf = BytesMut::from(percent_decode(blob).collect::<Vec<u8>>());
+        CapabilitiesUnpacker.decode_eof(&mut buf)
+    }
+
+    /// Pick the compression for a bundle2 sent to the owner of these capabilities. `preferred`
+    /// lists the compression types acceptable to the sender in order of preference, the first one
+    /// that is also present in the `compression` capability is chosen. If there is no match the
+    /// bundle2 should be sent uncompressed.
+    pub fn negotiate_compression(&self, preferred: &[CompressorType]) -> Option<CompressorType> {
+        let supported = match self.get("compression") {
+            Some(supported) => supported,
+            None => return None,
+        };
+
+        preferred
+            .iter()
+            .find(|ct| {
+                let param = get_compression_param(&Some(**ct));
+                supported.iter().any(|s| s == param)
+            })
+            .cloned()
+    }
+}
+
 /// This is a tokio_io Decoder for capabilities used f.e. in "replycaps" part of bundle2
 ///
 /// The format is as follows:
@@ -68,3 +115,66 @@ impl Decoder for CapabilitiesUnpacker {
         Ok(Some(Capabilities { caps }))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_compression::{Bzip2Compression, FlateCompression};
+
+    fn decode(input: &[u8]) -> Capabilities {
+        CapabilitiesUnpacker
+            .decode_eof(&mut BytesMut::from(input))
+            .expect("failed to decode capabilities")
+            .expect("no capabilities decoded")
+    }
+
+    #[test]
+    fn test_from_bundlecaps() {
+        let bundlecaps = vec![
+            b"HG20".to_vec(),
+            b"bundle2=HG20%0Achangegroup%3D01%2C02%0Acompression%3DGZ%2CZS".to_vec(),
+        ];
+        let caps = Capabilities::from_bundlecaps(&bundlecaps)
+            .expect("failed to parse bundlecaps")
+            .expect("bundle2 caps are missing");
+
+        assert_eq!(caps.get("HG20"), Some(&vec![]));
+        assert_eq!(
+            caps.get("changegroup"),
+            Some(&vec!["01".to_string(), "02".to_string()])
+        );
+        assert_eq!(
+            caps.get("compression"),
+            Some(&vec!["GZ".to_string(), "ZS".to_string()])
+        );
+
+        assert_eq!(
+            Capabilities::from_bundlecaps(&[b"HG20".to_vec()]).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_negotiate_compression() {
+        let zstd = CompressorType::Zstd { level: 1 };
+        let gzip = CompressorType::Gzip(FlateCompression::fast());
+        let bzip2 = CompressorType::Bzip2(Bzip2Compression::Default);
+
+        let caps = decode(b"HG20\ncompression=GZ,ZS");
+        assert_matches!(
+            caps.negotiate_compression(&[zstd, gzip]),
+            Some(CompressorType::Zstd { level: 1 })
+        );
+        assert_matches!(
+            caps.negotiate_compression(&[bzip2, gzip]),
+            Some(CompressorType::Gzip(_))
+        );
+        assert_matches!(caps.negotiate_compression(&[bzip2]), None);
+        assert_matches!(caps.negotiate_compression(&[]), None);
+
+        // Old clients don't advertise compression at all
+        let caps = decode(b"HG20\nchangegroup=02");
+        assert_matches!(caps.negotiate_compression(&[zstd, gzip, bzip2]), None);
+    }
+}
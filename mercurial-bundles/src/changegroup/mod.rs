@@ -9,6 +9,13 @@ use mercurial_types::{Delta, MPath, NodeHash};
 pub mod packer;
 pub mod unpacker;
 
+/// Version of a changegroup. Version 3 adds the revlog flags of each revision to version 2.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CgVersion {
+    Cg2Version,
+    Cg3Version,
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Section {
     Changeset,
@@ -40,21 +47,26 @@ pub struct CgDeltaChunk {
     pub base: NodeHash,
     pub linknode: NodeHash,
     pub delta: Delta,
+    /// Revlog flags of the revision. Always 0 for changegroups older than version 3.
+    pub flags: u16,
 }
 
 #[cfg(test)]
 mod test {
     use std::io::{self, Cursor};
 
+    use bytes::{BufMut, BytesMut};
     use futures::Stream;
     use quickcheck::{QuickCheck, StdGen, TestResult};
     use rand;
     use slog::{Drain, Logger};
     use slog_term;
     use tokio_core::reactor::Core;
-    use tokio_io::codec::{FramedRead, FramedWrite};
+    use tokio_io::codec::{Decoder, FramedRead, FramedWrite};
 
     use futures_ext::StreamLayeredExt;
+    use mercurial_types::NULL_HASH;
+    use mercurial_types_mocks::nodehash::{ONES_HASH, THREES_HASH, TWOS_HASH};
     use partial_io::{GenWouldBlock, PartialAsyncRead, PartialAsyncWrite, PartialWithErrors};
 
     use chunk::{ChunkDecoder, ChunkEncoder};
@@ -139,6 +151,88 @@ mod test {
         TestResult::passed()
     }
 
+    /// Version 3 chunk with a fulltext delta
+    fn put_cg3_chunk(
+        buf: &mut BytesMut,
+        node: NodeHash,
+        linknode: NodeHash,
+        flags: u16,
+        text: &[u8],
+    ) {
+        buf.put_u32_be((4 + 5 * 20 + 2 + 12 + text.len()) as u32);
+        for hash in &[node, NULL_HASH, NULL_HASH, NULL_HASH, linknode] {
+            buf.put_slice(hash.as_ref());
+        }
+        buf.put_u16_be(flags);
+        buf.put_u32_be(0);
+        buf.put_u32_be(0);
+        buf.put_u32_be(text.len() as u32);
+        buf.put_slice(text);
+    }
+
+    #[test]
+    fn test_cg3_flags() {
+        let path = MPath::new("lfsfile").unwrap();
+        let mut buf = BytesMut::with_capacity(1024);
+        put_cg3_chunk(&mut buf, ONES_HASH, ONES_HASH, 0, b"changeset");
+        buf.put_u32_be(0);
+        put_cg3_chunk(&mut buf, TWOS_HASH, ONES_HASH, 0, b"manifest");
+        buf.put_u32_be(0);
+        // No tree manifests
+        buf.put_u32_be(0);
+        buf.put_u32_be(4 + 7);
+        buf.put_slice(b"lfsfile");
+        put_cg3_chunk(&mut buf, THREES_HASH, ONES_HASH, 1 << 13, b"pointer");
+        buf.put_u32_be(0);
+        buf.put_u32_be(0);
+
+        let mut unpacker =
+            unpacker::Cg2Unpacker::new_with_version(make_root_logger(), CgVersion::Cg3Version);
+        let mut parts = Vec::new();
+        while let Some(part) = unpacker.decode_eof(&mut buf).unwrap() {
+            parts.push(part);
+        }
+
+        let sections: Vec<_> = parts
+            .iter()
+            .filter(|part| !part.is_section_end() && **part != Part::End)
+            .map(|part| (part.section().clone(), part.chunk().node, part.chunk().flags))
+            .collect();
+        assert_eq!(
+            sections,
+            vec![
+                (Section::Changeset, ONES_HASH, 0),
+                (Section::Manifest, TWOS_HASH, 0),
+                (Section::Filelog(path.clone()), THREES_HASH, 1 << 13),
+            ]
+        );
+        assert_eq!(
+            parts[parts.len() - 2..].to_vec(),
+            vec![Part::SectionEnd(Section::Filelog(path)), Part::End]
+        );
+    }
+
+    #[test]
+    fn test_cg3_tree_manifests() {
+        let mut buf = BytesMut::with_capacity(1024);
+        buf.put_u32_be(0);
+        buf.put_u32_be(0);
+        buf.put_u32_be(4 + 4);
+        buf.put_slice(b"dir/");
+
+        let mut unpacker =
+            unpacker::Cg2Unpacker::new_with_version(make_root_logger(), CgVersion::Cg3Version);
+        assert_eq!(
+            unpacker.decode(&mut buf).unwrap(),
+            Some(Part::SectionEnd(Section::Changeset))
+        );
+        assert_eq!(
+            unpacker.decode(&mut buf).unwrap(),
+            Some(Part::SectionEnd(Section::Manifest))
+        );
+        assert!(unpacker.decode(&mut buf).is_err());
+    }
+
     fn make_root_logger() -> Logger {
         let plain = slog_term::PlainSyncDecorator::new(io::stdout());
         Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
@@ -9,7 +9,7 @@ use std::convert::From;
 use futures::{Async, Poll, Stream};
 
 use byteorder::ByteOrder;
-use bytes::{BigEndian, BufMut};
+use bytes::{BigEndian, BufMut, Bytes};
 
 use chunk::Chunk;
 use delta;
@@ -59,6 +59,57 @@ where
     }
 }
 
+/// Packer for version 1 changegroups, as sent by the legacy `changegroup` and
+/// `changegroupsubset` wire commands. Unlike version 2, chunks don't carry a delta base: every
+/// delta is against the previous node of the section, or against p1 for the first node. The
+/// `base` field of the incoming delta chunks is ignored, so the deltas must already be computed
+/// against those implicit bases.
+///
+/// Version 1 changegroups are sent raw rather than inside a bundle2 part, so this produces bytes
+/// and not bundle2 payload chunks.
+pub struct Cg1Packer<S> {
+    delta_stream: S,
+    last_seen: Section,
+}
+
+impl<S> Cg1Packer<S> {
+    pub fn new(delta_stream: S) -> Self {
+        Cg1Packer {
+            delta_stream: delta_stream,
+            last_seen: Section::Changeset,
+        }
+    }
+}
+
+impl<S> Stream for Cg1Packer<S>
+where
+    S: Stream<Item = Part>,
+    Error: From<S::Error>,
+{
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        use self::Part::*;
+
+        match try_ready!(self.delta_stream.poll()) {
+            None => Ok(Async::Ready(None)),
+            Some(CgChunk(section, delta_chunk)) => {
+                let mut builder = ChunkBuilder::new();
+                if self.last_seen != section {
+                    builder.encode_section(&section)?;
+                    self.last_seen = section;
+                }
+                builder.encode_cg1_delta_chunk(delta_chunk);
+                Ok(Async::Ready(Some(builder.into_bytes())))
+            }
+            Some(SectionEnd(_section)) | Some(End) => {
+                Ok(Async::Ready(Some(Bytes::from(&[0, 0, 0, 0][..]))))
+            }
+        }
+    }
+}
+
 /// Produce an empty changegroup chunk.
 ///
 /// Note that this is distinct from Chunk::empty() -- this is an actual chunk
@@ -122,11 +173,27 @@ impl ChunkBuilder {
         self
     }
 
+    /// Version 1 delta chunks are the same as version 2 ones, minus the delta base.
+    pub fn encode_cg1_delta_chunk(&mut self, chunk: CgDeltaChunk) -> &mut Self {
+        self.inner.put_slice(chunk.node.as_ref());
+        self.inner.put_slice(chunk.p1.as_ref());
+        self.inner.put_slice(chunk.p2.as_ref());
+        self.inner.put_slice(chunk.linknode.as_ref());
+
+        delta::encode_delta(&chunk.delta, &mut self.inner);
+
+        self
+    }
+
     pub fn build(self) -> Result<Chunk> {
+        Chunk::new(self.into_bytes())
+    }
+
+    pub fn into_bytes(self) -> Bytes {
         let len = self.inner.len() - self.len_offset;
         let mut inner = self.inner;
         BigEndian::write_i32(&mut inner[self.len_offset..], len as i32);
-        Chunk::new(inner)
+        Bytes::from(inner)
     }
 }
 
@@ -135,6 +202,43 @@ mod test {
     use super::*;
     use mercurial_types::MPath;
 
+    #[test]
+    fn test_cg1_no_delta_base() {
+        use futures::{stream, Future};
+        use mercurial_types::{Delta, NULL_HASH};
+        use mercurial_types_mocks::nodehash::{ONES_HASH, TWOS_HASH};
+
+        let chunk = CgDeltaChunk {
+            node: TWOS_HASH,
+            p1: ONES_HASH,
+            p2: NULL_HASH,
+            base: ONES_HASH,
+            linknode: TWOS_HASH,
+            delta: Delta::new_fulltext(b"abc".to_vec()),
+            flags: 0,
+        };
+        let parts = vec![
+            Part::CgChunk(Section::Changeset, chunk),
+            Part::SectionEnd(Section::Changeset),
+            Part::End,
+        ];
+        let packed: Vec<_> = Cg1Packer::new(stream::iter_ok::<_, Error>(parts))
+            .collect()
+            .wait()
+            .unwrap();
+
+        assert_eq!(packed.len(), 3);
+        // length + 4 nodes + delta header + content
+        let expected_len = 4 + 4 * 20 + 12 + 3;
+        assert_eq!(packed[0].len(), expected_len);
+        assert_eq!(BigEndian::read_i32(&packed[0][..4]), expected_len as i32);
+        assert_eq!(&packed[0][4..24], TWOS_HASH.as_ref());
+        assert_eq!(&packed[0][24..44], ONES_HASH.as_ref());
+        assert_eq!(&packed[0][64..84], TWOS_HASH.as_ref());
+        assert_eq!(&packed[1][..], &[0, 0, 0, 0]);
+        assert_eq!(&packed[2][..], &[0, 0, 0, 0]);
+    }
+
     #[test]
     fn test_empty_filelog_path() {
         let mut builder = ChunkBuilder::new();
@@ -20,11 +20,13 @@ use delta;
 use errors::*;
 use utils::BytesExt;
 
-use super::{CgDeltaChunk, Part, Section};
+use super::{CgDeltaChunk, CgVersion, Part, Section};
 
+/// Unpacker for version 2 changegroups, and for version 3 changegroups without tree manifests
 #[derive(Debug)]
 pub struct Cg2Unpacker {
     logger: slog::Logger,
+    version: CgVersion,
     state: State,
 }
 
@@ -58,13 +60,16 @@ impl Part {
 // See the chunk header definition below for the first 100 bytes. The last 4 is
 // for the length field itself.
 const CHUNK_HEADER_LEN: usize = 20 + 20 + 20 + 20 + 20 + 4;
+// Version 3 chunks also have the revlog flags after the nodes
+const CG3_FLAGS_LEN: usize = 2;
 
 impl Decoder for Cg2Unpacker {
     type Item = Part;
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
-        match Self::decode_next(buf, self.state.take()) {
+        let state = self.state.take();
+        match self.decode_next(buf, state) {
             Err(e) => {
                 self.state = State::Invalid;
                 Err(e)
@@ -108,15 +113,20 @@ impl Decoder for Cg2Unpacker {
 
 impl Cg2Unpacker {
     pub fn new(logger: slog::Logger) -> Self {
+        Self::new_with_version(logger, CgVersion::Cg2Version)
+    }
+
+    pub fn new_with_version(logger: slog::Logger, version: CgVersion) -> Self {
         Cg2Unpacker {
             logger: logger,
+            version: version,
             state: State::Changeset,
         }
     }
 
-    fn decode_next(buf: &mut BytesMut, state: State) -> Result<(Option<Part>, State)> {
+    fn decode_next(&self, buf: &mut BytesMut, state: State) -> Result<(Option<Part>, State)> {
         match state {
-            State::Changeset => match Self::decode_chunk(buf)? {
+            State::Changeset => match self.decode_chunk(buf)? {
                 None => Ok((None, State::Changeset)),
                 Some(CgChunk::Empty) => {
                     Ok((Some(Part::SectionEnd(Section::Changeset)), State::Manifest))
@@ -126,10 +136,14 @@ impl Cg2Unpacker {
                     State::Changeset,
                 )),
             },
-            State::Manifest => match Self::decode_chunk(buf)? {
+            State::Manifest => match self.decode_chunk(buf)? {
                 None => Ok((None, State::Manifest)),
                 Some(CgChunk::Empty) => {
-                    Ok((Some(Part::SectionEnd(Section::Manifest)), State::Filename))
+                    let next = match self.version {
+                        CgVersion::Cg2Version => State::Filename,
+                        CgVersion::Cg3Version => State::TreeManifests,
+                    };
+                    Ok((Some(Part::SectionEnd(Section::Manifest)), next))
                 }
                 Some(CgChunk::Delta(chunk)) => Ok((
                     Some(Part::CgChunk(Section::Manifest, chunk)),
@@ -140,18 +154,33 @@ impl Cg2Unpacker {
                 let filename = Self::decode_filename(buf)?;
                 match filename {
                     DecodeRes::None => Ok((None, State::Filename)),
-                    DecodeRes::Some(f) => Self::decode_filelog_chunk(buf, f),
+                    DecodeRes::Some(f) => self.decode_filelog_chunk(buf, f),
                     DecodeRes::End => Ok((Some(Part::End), State::End)),
                 }
             }
-            State::Filelog(filename) => Self::decode_filelog_chunk(buf, filename),
+            // Version 3 changegroups have a list of tree manifest groups, which is empty unless
+            // the client has tree manifests in its revlogs. Trees are pushed in treegroup parts
+            // instead, so only the empty list is accepted.
+            State::TreeManifests => match Self::decode_filename(buf)? {
+                DecodeRes::None => Ok((None, State::TreeManifests)),
+                DecodeRes::Some(dir) => {
+                    let msg = format!("unexpected tree manifest group for {}", dir);
+                    bail_err!(ErrorKind::Cg2Decode(msg));
+                }
+                DecodeRes::End => self.decode_next(buf, State::Filename),
+            },
+            State::Filelog(filename) => self.decode_filelog_chunk(buf, filename),
             State::End => Ok((None, State::End)),
             State::Invalid => Err(ErrorKind::Cg2Decode("byte stream corrupt".into()).into()),
         }
     }
 
-    fn decode_filelog_chunk(buf: &mut BytesMut, f: MPath) -> Result<(Option<Part>, State)> {
-        match Self::decode_chunk(buf)? {
+    fn decode_filelog_chunk(
+        &self,
+        buf: &mut BytesMut,
+        f: MPath,
+    ) -> Result<(Option<Part>, State)> {
+        match self.decode_chunk(buf)? {
             None => Ok((None, State::Filelog(f))),
             Some(CgChunk::Empty) => {
                 Ok((Some(Part::SectionEnd(Section::Filelog(f))), State::Filename))
@@ -163,7 +192,7 @@ impl Cg2Unpacker {
         }
     }
 
-    fn decode_chunk(buf: &mut BytesMut) -> Result<Option<CgChunk>> {
+    fn decode_chunk(&self, buf: &mut BytesMut) -> Result<Option<CgChunk>> {
         if buf.len() < 4 {
             return Ok(None);
         }
@@ -176,10 +205,14 @@ impl Cg2Unpacker {
             let _ = buf.drain_i32();
             return Ok(Some(CgChunk::Empty));
         }
-        if chunk_len < CHUNK_HEADER_LEN {
+        let header_len = match self.version {
+            CgVersion::Cg2Version => CHUNK_HEADER_LEN,
+            CgVersion::Cg3Version => CHUNK_HEADER_LEN + CG3_FLAGS_LEN,
+        };
+        if chunk_len < header_len {
             let msg = format!(
                 "invalid chunk: length >= {} required, found {}",
-                CHUNK_HEADER_LEN, chunk_len
+                header_len, chunk_len
             );
             bail_err!(ErrorKind::Cg2Decode(msg));
         }
@@ -196,6 +229,7 @@ impl Cg2Unpacker {
         // p2: NodeHash (20 bytes) -- NULL_HASH if only 1 parent
         // base node: NodeHash (20 bytes) (new in changegroup2)
         // link node: NodeHash (20 bytes)
+        // flags: u16 (2 bytes) (new in changegroup3)
         // ---
 
         let node = buf.drain_node();
@@ -203,8 +237,12 @@ impl Cg2Unpacker {
         let p2 = buf.drain_node();
         let base = buf.drain_node();
         let linknode = buf.drain_node();
+        let flags = match self.version {
+            CgVersion::Cg2Version => 0,
+            CgVersion::Cg3Version => buf.drain_u16(),
+        };
 
-        let delta = delta::decode_delta(buf.split_to(chunk_len - CHUNK_HEADER_LEN))?;
+        let delta = delta::decode_delta(buf.split_to(chunk_len - header_len))?;
         return Ok(Some(CgChunk::Delta(CgDeltaChunk {
             node: node,
             p1: p1,
@@ -212,6 +250,7 @@ impl Cg2Unpacker {
             base: base,
             linknode: linknode,
             delta: delta,
+            flags: flags,
         })));
     }
 
@@ -254,6 +293,7 @@ enum CgChunk {
 enum State {
     Changeset,
     Manifest,
+    TreeManifests,
     Filename,
     Filelog(MPath),
     End,
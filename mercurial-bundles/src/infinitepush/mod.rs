@@ -6,17 +6,26 @@
 
 // Codecs related to infinitepush also known as Commit Cloud.
 
-use bytes::{Bytes, BytesMut};
+use std::collections::{BTreeMap, VecDeque};
+
+use bytes::BytesMut;
+use serde_json;
 use tokio_io::codec::Decoder;
 
+use mercurial_types::{HgChangesetId, NodeHash};
+
 use utils::BytesExt;
 
 use errors::*;
 
+/// Decodes the scratch bookmarks part. Its payload is the length of a JSON object followed by
+/// the object, which maps bookmark names to hex nodes. An empty node means that the bookmark
+/// was deleted, so it's decoded as None.
 #[derive(Debug)]
 pub struct InfinitepushBookmarksUnpacker {
     finished: bool,
     expected_len: Option<usize>,
+    bookmarks: VecDeque<(String, Option<HgChangesetId>)>,
 }
 
 impl InfinitepushBookmarksUnpacker {
@@ -24,17 +33,18 @@ impl InfinitepushBookmarksUnpacker {
         Self {
             finished: false,
             expected_len: None,
+            bookmarks: VecDeque::new(),
         }
     }
 }
 
 impl Decoder for InfinitepushBookmarksUnpacker {
-    type Item = Bytes;
+    type Item = (String, Option<HgChangesetId>);
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
         if self.finished {
-            return Ok(None);
+            return Ok(self.bookmarks.pop_front());
         }
         match self.expected_len {
             Some(toread) => {
@@ -42,15 +52,79 @@ impl Decoder for InfinitepushBookmarksUnpacker {
                     Ok(None)
                 } else {
                     self.finished = true;
-                    Ok(Some(buf.split_to(toread).freeze()))
+                    self.bookmarks = decode_bookmarks(&buf.split_to(toread))?;
+                    Ok(self.bookmarks.pop_front())
                 }
             }
             None => {
                 if buf.len() >= 4 {
                     self.expected_len = Some(buf.drain_u32() as usize);
+                    self.decode(buf)
+                } else {
+                    Ok(None)
                 }
-                Ok(None)
             }
         }
     }
 }
+
+fn decode_bookmarks(json: &[u8]) -> Result<VecDeque<(String, Option<HgChangesetId>)>> {
+    let bookmarks: BTreeMap<String, String> =
+        serde_json::from_slice(json).context("invalid scratch bookmarks")?;
+    bookmarks
+        .into_iter()
+        .map(|(name, node)| {
+            if node.is_empty() {
+                Ok((name, None))
+            } else {
+                let node: NodeHash = node.parse()?;
+                Ok((name, Some(HgChangesetId::new(node))))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{BigEndian, BufMut};
+
+    use mercurial_types_mocks::nodehash::ONES_CSID;
+
+    use super::*;
+
+    fn decode_all(json: &[u8]) -> Result<Vec<(String, Option<HgChangesetId>)>> {
+        let mut buf = BytesMut::with_capacity(4 + json.len());
+        buf.put_u32::<BigEndian>(json.len() as u32);
+        buf.put_slice(json);
+
+        let mut unpacker = InfinitepushBookmarksUnpacker::new();
+        let mut decoded = Vec::new();
+        while let Some(item) = unpacker.decode_eof(&mut buf)? {
+            decoded.push(item);
+        }
+        Ok(decoded)
+    }
+
+    #[test]
+    fn test_decode_bookmarks() {
+        let json = format!(
+            r#"{{"infinitepush/backups/test/host/home/bookmarks/foo": "{}", "old": ""}}"#,
+            ONES_CSID
+        );
+        assert_eq!(
+            decode_all(json.as_bytes()).unwrap(),
+            vec![
+                (
+                    "infinitepush/backups/test/host/home/bookmarks/foo".to_string(),
+                    Some(ONES_CSID),
+                ),
+                ("old".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_bad_node() {
+        assert!(decode_all(br#"{"foo": "bar"}"#).is_err());
+    }
+}
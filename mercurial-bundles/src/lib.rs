@@ -31,6 +31,7 @@ extern crate quickcheck;
 extern crate quickcheck;
 #[cfg(test)]
 extern crate rand;
+extern crate serde_json;
 #[macro_use]
 extern crate slog;
 #[cfg(test)]
@@ -59,9 +60,12 @@ mod chunk;
 mod delta;
 pub mod parts;
 pub mod part_encode;
+pub mod obsmarkers;
+mod output;
 mod part_header;
 mod part_inner;
 mod part_outer;
+pub mod phases;
 mod quickcheck_types;
 mod stream_start;
 mod types;
@@ -76,8 +80,11 @@ mod utils;
 use std::fmt;
 
 use futures_ext::{BoxFuture, BoxStream};
+use mercurial_types::{HgChangesetId, NodeHash, Obsmarker, Phase};
 
 pub use bundle2_encode::Bundle2EncodeBuilder;
+pub use capabilities::Capabilities;
+pub use output::ClientOutput;
 pub use part_header::{PartHeader, PartHeaderType};
 pub use types::StreamHeader;
 
@@ -86,10 +93,12 @@ pub enum Bundle2Item {
     Changegroup(PartHeader, BoxStream<changegroup::Part, Error>),
     B2xInfinitepush(PartHeader, BoxStream<changegroup::Part, Error>),
     B2xTreegroup2(PartHeader, BoxStream<wirepack::Part, Error>),
-    // B2xInfinitepushBookmarks returns Bytes because this part is not going to be used.
-    B2xInfinitepushBookmarks(PartHeader, BoxStream<bytes::Bytes, Error>),
+    B2xInfinitepushBookmarks(PartHeader, BoxStream<(String, Option<HgChangesetId>), Error>),
     Replycaps(PartHeader, BoxFuture<capabilities::Capabilities, Error>),
     Pushkey(PartHeader, BoxFuture<(), Error>),
+    PhaseHeads(PartHeader, BoxStream<(Phase, NodeHash), Error>),
+    Obsmarkers(PartHeader, BoxStream<Obsmarker, Error>),
+    Pushvars(PartHeader, BoxFuture<(), Error>),
 }
 
 impl Bundle2Item {
@@ -128,6 +137,9 @@ impl fmt::Debug for Bundle2Item {
             }
             &Replycaps(ref header, _) => write!(f, "Bundle2Item::Replycaps({:?}, ...)", header),
             &Pushkey(ref header, _) => write!(f, "Bundle2Item::Pushkey({:?}, ...)", header),
+            &PhaseHeads(ref header, _) => write!(f, "Bundle2Item::PhaseHeads({:?}, ...)", header),
+            &Obsmarkers(ref header, _) => write!(f, "Bundle2Item::Obsmarkers({:?}, ...)", header),
+            &Pushvars(ref header, _) => write!(f, "Bundle2Item::Pushvars({:?}, ...)", header),
         }
     }
 }
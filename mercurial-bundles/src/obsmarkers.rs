@@ -0,0 +1,286 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Codec for the obsmarkers part. The payload is a version byte followed by the markers, and
+//! only version 1 of the marker format is supported. Each marker is:
+//!
+//! ```text
+//! total size: u32
+//! date: f64, seconds since the epoch
+//! timezone offset: i16, in minutes
+//! flags: u16
+//! number of successors: u8
+//! number of parents: u8, or 3 if the parents aren't recorded
+//! number of metadata entries: u8
+//! predecessor: 20 bytes
+//! successors: 20 bytes each
+//! parents: 20 bytes each
+//! metadata sizes: (key size: u8, value size: u8) for each entry
+//! metadata: key followed by value for each entry
+//! ```
+
+use bytes::{BigEndian, BufMut, BytesMut};
+use tokio_io::codec::Decoder;
+
+use mercurial_types::{HgChangesetId, Obsmarker};
+
+use errors::*;
+use utils::BytesExt;
+
+const VERSION: u8 = 1;
+const FIXED_SIZE: usize = 4 + 8 + 2 + 2 + 1 + 1 + 1 + 20;
+/// Number of parents that means the parents aren't recorded
+const PARENTS_NONE: u8 = 3;
+/// Flag of markers whose nodes are sha256 hashes
+const USING_SHA256: u16 = 2;
+
+#[derive(Debug)]
+pub struct ObsmarkersUnpacker {
+    version_read: bool,
+}
+
+impl ObsmarkersUnpacker {
+    pub fn new() -> Self {
+        ObsmarkersUnpacker {
+            version_read: false,
+        }
+    }
+}
+
+impl Decoder for ObsmarkersUnpacker {
+    type Item = Obsmarker;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if !self.version_read {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            let version = buf.drain_u8();
+            if version != VERSION {
+                bail_msg!("unsupported obsmarkers version {}", version);
+            }
+            self.version_read = true;
+        }
+
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let size = buf.peek_u32() as usize;
+        if size < FIXED_SIZE {
+            bail_msg!("obsmarker of {} bytes is too short", size);
+        }
+        if buf.len() < size {
+            return Ok(None);
+        }
+        let mut marker = buf.split_to(size);
+        decode_marker(&mut marker).map(Some)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
+        match self.decode(buf)? {
+            Some(item) => Ok(Some(item)),
+            None if buf.is_empty() => Ok(None),
+            None => bail_msg!("incomplete obsmarker: {} bytes left", buf.len()),
+        }
+    }
+}
+
+fn decode_marker(buf: &mut BytesMut) -> Result<Obsmarker> {
+    let size = buf.drain_u32() as usize;
+    let seconds = f64::from_bits(buf.drain_u64());
+    let tz = buf.drain_u16() as i16;
+    let flags = buf.drain_u16();
+    if flags & USING_SHA256 != 0 {
+        bail_msg!("obsmarkers with sha256 nodes are not supported");
+    }
+    let num_successors = buf.drain_u8() as usize;
+    let num_parents = buf.drain_u8();
+    let num_metadata = buf.drain_u8() as usize;
+
+    let num_parent_nodes = if num_parents == PARENTS_NONE {
+        0
+    } else {
+        num_parents as usize
+    };
+    if size < FIXED_SIZE + 20 * (num_successors + num_parent_nodes) + 2 * num_metadata {
+        bail_msg!("obsmarker of {} bytes is too short", size);
+    }
+
+    let predecessor = HgChangesetId::new(buf.drain_node());
+    let successors = (0..num_successors)
+        .map(|_| HgChangesetId::new(buf.drain_node()))
+        .collect();
+    let parents = if num_parents == PARENTS_NONE {
+        None
+    } else {
+        Some(
+            (0..num_parent_nodes)
+                .map(|_| HgChangesetId::new(buf.drain_node()))
+                .collect(),
+        )
+    };
+
+    let metadata_sizes: Vec<_> = (0..num_metadata)
+        .map(|_| (buf.drain_u8() as usize, buf.drain_u8() as usize))
+        .collect();
+    let metadata_size: usize = metadata_sizes.iter().map(|&(k, v)| k + v).sum();
+    if buf.len() != metadata_size {
+        bail_msg!(
+            "obsmarker metadata is {} bytes, expected {}",
+            buf.len(),
+            metadata_size
+        );
+    }
+    let metadata = metadata_sizes
+        .into_iter()
+        .map(|(key_size, value_size)| {
+            let key = buf.split_to(key_size).to_vec();
+            let value = buf.split_to(value_size).to_vec();
+            (key, value)
+        })
+        .collect();
+
+    Ok(Obsmarker {
+        predecessor,
+        successors,
+        parents,
+        flags,
+        date: (seconds, tz),
+        metadata,
+    })
+}
+
+pub fn encode_obsmarkers(markers: &[Obsmarker]) -> Result<BytesMut> {
+    let mut buf = BytesMut::with_capacity(1);
+    buf.put_u8(VERSION);
+    for marker in markers {
+        encode_marker(marker, &mut buf)?;
+    }
+    Ok(buf)
+}
+
+fn encode_marker(marker: &Obsmarker, buf: &mut BytesMut) -> Result<()> {
+    let num_successors = marker.successors.len();
+    let num_parents = marker.parents.as_ref().map(|parents| parents.len());
+    let num_metadata = marker.metadata.len();
+    if num_successors > 255 {
+        bail_msg!("obsmarker has {} successors", num_successors);
+    }
+    if num_parents.unwrap_or(0) >= PARENTS_NONE as usize {
+        bail_msg!("obsmarker has {} parents", num_parents.unwrap_or(0));
+    }
+    if num_metadata > 255 {
+        bail_msg!("obsmarker has {} metadata entries", num_metadata);
+    }
+    if marker
+        .metadata
+        .iter()
+        .any(|&(ref key, ref value)| key.len() > 255 || value.len() > 255)
+    {
+        bail_msg!("obsmarker metadata entries can't be longer than 255 bytes");
+    }
+
+    let metadata_size: usize = marker
+        .metadata
+        .iter()
+        .map(|&(ref key, ref value)| key.len() + value.len())
+        .sum();
+    let size = FIXED_SIZE + 20 * (num_successors + num_parents.unwrap_or(0)) + 2 * num_metadata
+        + metadata_size;
+
+    buf.reserve(size);
+    buf.put_u32::<BigEndian>(size as u32);
+    buf.put_u64::<BigEndian>(marker.date.0.to_bits());
+    buf.put_i16::<BigEndian>(marker.date.1);
+    buf.put_u16::<BigEndian>(marker.flags);
+    buf.put_u8(num_successors as u8);
+    buf.put_u8(num_parents.map(|n| n as u8).unwrap_or(PARENTS_NONE));
+    buf.put_u8(num_metadata as u8);
+    buf.put_slice(marker.predecessor.as_nodehash().as_ref());
+    for successor in &marker.successors {
+        buf.put_slice(successor.as_nodehash().as_ref());
+    }
+    for parent in marker.parents.iter().flat_map(|parents| parents.iter()) {
+        buf.put_slice(parent.as_nodehash().as_ref());
+    }
+    for &(ref key, ref value) in &marker.metadata {
+        buf.put_u8(key.len() as u8);
+        buf.put_u8(value.len() as u8);
+    }
+    for &(ref key, ref value) in &marker.metadata {
+        buf.put_slice(key);
+        buf.put_slice(value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use mercurial_types_mocks::nodehash::{ONES_CSID, THREES_CSID, TWOS_CSID};
+
+    use super::*;
+
+    fn decode_all(mut buf: BytesMut) -> Result<Vec<Obsmarker>> {
+        let mut unpacker = ObsmarkersUnpacker::new();
+        let mut decoded = Vec::new();
+        while let Some(item) = unpacker.decode_eof(&mut buf)? {
+            decoded.push(item);
+        }
+        Ok(decoded)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let markers = vec![
+            Obsmarker {
+                predecessor: ONES_CSID,
+                successors: vec![TWOS_CSID],
+                parents: None,
+                flags: 0,
+                date: (1500000000.25, -60),
+                metadata: vec![
+                    (b"operation".to_vec(), b"amend".to_vec()),
+                    (b"user".to_vec(), b"test".to_vec()),
+                ],
+            },
+            Obsmarker {
+                predecessor: THREES_CSID,
+                successors: vec![],
+                parents: Some(vec![ONES_CSID]),
+                flags: 0,
+                date: (0.0, 0),
+                metadata: vec![],
+            },
+        ];
+
+        let buf = encode_obsmarkers(&markers).unwrap();
+        assert_eq!(decode_all(buf).unwrap(), markers);
+    }
+
+    #[test]
+    fn test_bad_version() {
+        let mut buf = encode_obsmarkers(&[]).unwrap();
+        buf[0] = 0;
+        assert!(decode_all(buf).is_err());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let marker = Obsmarker {
+            predecessor: ONES_CSID,
+            successors: vec![TWOS_CSID],
+            parents: None,
+            flags: 0,
+            date: (0.0, 0),
+            metadata: vec![],
+        };
+        let mut buf = encode_obsmarkers(&[marker]).unwrap();
+        let len = buf.len();
+        buf.truncate(len - 1);
+        assert!(decode_all(buf).is_err());
+    }
+}
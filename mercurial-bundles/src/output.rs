@@ -0,0 +1,108 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Human-readable messages for the user running the hg command.
+
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use futures::sync::mpsc;
+
+/// Sink for messages that the client shows on its terminal, prefixed with "remote: ".
+///
+/// If the connection has a stderr channel (like ssh does) the messages are written to it as they
+/// come, so that the user sees them while the command is still running. Otherwise, or if the
+/// channel is closed, they're buffered until they're sent as `output` parts of a reply bundle.
+#[derive(Clone, Debug)]
+pub struct ClientOutput {
+    stderr: Option<mpsc::UnboundedSender<Bytes>>,
+    buffered: Arc<Mutex<Vec<Bytes>>>,
+}
+
+impl ClientOutput {
+    /// Output that is only ever sent in reply bundles.
+    pub fn new() -> Self {
+        ClientOutput {
+            stderr: None,
+            buffered: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Output that is written to the connection's stderr channel.
+    pub fn with_stderr(stderr: mpsc::UnboundedSender<Bytes>) -> Self {
+        ClientOutput {
+            stderr: Some(stderr),
+            buffered: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Send a message to the client. A newline is appended unless the message has one.
+    pub fn write<M: AsRef<str>>(&self, message: M) {
+        let message = message.as_ref();
+        let message = if message.ends_with('\n') {
+            Bytes::from(message)
+        } else {
+            Bytes::from(format!("{}\n", message))
+        };
+
+        let message = match self.stderr {
+            Some(ref stderr) => match stderr.unbounded_send(message) {
+                Ok(()) => return,
+                Err(err) => err.into_inner(),
+            },
+            None => message,
+        };
+        self.buffered.lock().expect("lock poisoned").push(message);
+    }
+
+    /// Take the messages that couldn't be written to stderr, to send them in a reply bundle.
+    pub fn take_buffered(&self) -> Vec<Bytes> {
+        let mut buffered = self.buffered.lock().expect("lock poisoned");
+        mem::replace(&mut *buffered, Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{Future, Stream};
+
+    use super::*;
+
+    #[test]
+    fn test_buffered() {
+        let output = ClientOutput::new();
+        output.write("pushing 3 commits");
+        output.clone().write("done\n");
+
+        assert_eq!(
+            output.take_buffered(),
+            vec![Bytes::from("pushing 3 commits\n"), Bytes::from("done\n")]
+        );
+        assert!(output.take_buffered().is_empty());
+    }
+
+    #[test]
+    fn test_stderr() {
+        let (sender, receiver) = mpsc::unbounded();
+        let output = ClientOutput::with_stderr(sender);
+        output.write("pushing 3 commits");
+        drop(output);
+
+        let written = receiver.collect().wait().unwrap();
+        assert_eq!(written, vec![Bytes::from("pushing 3 commits\n")]);
+    }
+
+    #[test]
+    fn test_stderr_closed() {
+        let (sender, receiver) = mpsc::unbounded();
+        drop(receiver);
+        let output = ClientOutput::with_stderr(sender);
+        output.write("pushing 3 commits");
+
+        assert_eq!(output.take_buffered(), vec![Bytes::from("pushing 3 commits\n")]);
+    }
+}
@@ -36,30 +36,36 @@ pub enum PartHeaderType {
     CheckHeads,
     /// Contains changegroup for infinitepush commits
     B2xInfinitepush,
-    /// Contains bookmarks for infinitepush backups (won't be used in Mononoke,
-    /// but they needs to be parsed).
+    /// Contains scratch bookmarks for infinitepush backups.
     B2xInfinitepushBookmarks,
     /// Pushkey part is used to update different namespaces: phases, bookmarks, etc.
-    /// In Mononoke it's used to update bookmarks.
+    /// In Mononoke it's used to update bookmarks and phases.
     Pushkey,
+    /// Result of a pushkey part, sent in the response to a push.
+    ReplyPushkey,
+    /// Lists the heads of the changesets in each phase.
+    PhaseHeads,
+    /// Obsolescence markers, both in pushes and in getbundle responses.
+    Obsmarkers,
+    /// Number of new obsolescence markers, sent in the response to a push.
+    ReplyObsmarkers,
+    /// Variables that the client passes to the server-side hooks of a push, as advisory
+    /// parameters. The payload is empty.
+    Pushvars,
+    /// Messages for the user, that the client prints prefixed with "remote: ". Sent in replies.
+    Output,
     // RemoteChangegroup,       // We don't wish to support this functionality
     // CheckBookmarks,          // TODO Do we want to support this?
     // CheckHeads,              // TODO Do we want to support this?
     // CheckUpdatedHeads,       // TODO Do we want to support this?
     // CheckPhases,             // TODO Do we want to support this?
-    // Output,                  // TODO Do we want to support this?
     // ErrorAbort,              // TODO Do we want to support this?
     // ErrorPushkey,            // TODO Do we want to support this?
     // ErrorUnsupportedContent, // TODO Do we want to support this?
     // ErrorPushRaced,          // TODO Do we want to support this?
     // Pushkey,                 // TODO Do we want to support this?
     // Bookmarks,               // TODO Do we want to support this?
-    // PhaseHeads,              // TODO Do we want to support this?
-    // ReplyPushkey,            // TODO Do we want to support this?
-    // Obsmarkers,              // TODO Do we want to support this?
-    // ReplyObsmarkers,         // TODO Do we want to support this?
     // HgtagsFnodes,            // TODO Do we want to support this?
-    // Pushvars,                // TODO Do we want to support this?
 }
 
 impl PartHeaderType {
@@ -75,6 +81,12 @@ impl PartHeaderType {
             "b2x:infinitepushscratchbookmarks" => Ok(B2xInfinitepushBookmarks),
             "check:heads" => Ok(CheckHeads),
             "pushkey" => Ok(Pushkey),
+            "reply:pushkey" => Ok(ReplyPushkey),
+            "phase-heads" => Ok(PhaseHeads),
+            "obsmarkers" => Ok(Obsmarkers),
+            "reply:obsmarkers" => Ok(ReplyObsmarkers),
+            "pushvars" => Ok(Pushvars),
+            "output" => Ok(Output),
             bad => bail_msg!("unknown header type {}", bad),
         }
     }
@@ -91,6 +103,12 @@ impl PartHeaderType {
             B2xInfinitepushBookmarks => "b2x:infinitepushscratchbookmarks",
             CheckHeads => "check:heads",
             Pushkey => "pushkey",
+            ReplyPushkey => "reply:pushkey",
+            PhaseHeads => "phase-heads",
+            Obsmarkers => "obsmarkers",
+            ReplyObsmarkers => "reply:obsmarkers",
+            Pushvars => "pushvars",
+            Output => "output",
         }
     }
 }
@@ -26,7 +26,9 @@ use errors::*;
 use futures_ext::{StreamExt, StreamLayeredExt};
 use infinitepush;
 use part_header::{PartHeader, PartHeaderType};
+use obsmarkers;
 use part_outer::{OuterFrame, OuterStream};
+use phases;
 use wirepack;
 
 // --- Part parameters
@@ -40,11 +42,16 @@ lazy_static! {
         // probably) be renamed T26385545. 'bookprevnode' and 'pushbackbookmarks' will be
         // removed T26384190.
         m.insert(PartHeaderType::B2xInfinitepush, hashset!{
-            "pushbackbookmarks", "cgversion", "bookmark", "bookprevnode", "create", "force"});
+            "pushbackbookmarks", "cgversion", "bookmark", "bookmarknode", "bookprevnode", "create",
+            "force"});
         m.insert(PartHeaderType::B2xInfinitepushBookmarks, hashset!{});
         m.insert(PartHeaderType::B2xTreegroup2, hashset!{"version", "cache", "category"});
         m.insert(PartHeaderType::Replycaps, hashset!{});
         m.insert(PartHeaderType::Pushkey, hashset!{ "namespace", "key", "old", "new" });
+        m.insert(PartHeaderType::PhaseHeads, hashset!{});
+        m.insert(PartHeaderType::Obsmarkers, hashset!{});
+        // The variables are advisory params, whatever their names
+        m.insert(PartHeaderType::Pushvars, hashset!{});
         m
     };
 }
@@ -76,6 +83,19 @@ pub fn validate_header(header: PartHeader) -> Result<Option<PartHeader>> {
     }
 }
 
+/// Changegroup version of a part, as given by its `param` parameter. Parts without it are
+/// version 2.
+fn cg_version(header: &PartHeader, param: &str) -> changegroup::CgVersion {
+    let version = header
+        .mparams()
+        .get(param)
+        .or_else(|| header.aparams().get(param));
+    match version {
+        Some(version) if version.as_ref() == b"03" => changegroup::CgVersion::Cg3Version,
+        _ => changegroup::CgVersion::Cg2Version,
+    }
+}
+
 /// Convert an OuterStream into an InnerStream using the part header.
 pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
     header: PartHeader,
@@ -89,15 +109,23 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
 
     let bundle2item = match header.part_type() {
         &PartHeaderType::Changegroup => {
-            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::Cg2Unpacker::new(
-                logger.new(o!("stream" => "cg2")),
-            ));
+            let version = cg_version(&header, "version");
+            let cg2_stream = wrapped_stream.decode(
+                changegroup::unpacker::Cg2Unpacker::new_with_version(
+                    logger.new(o!("stream" => "cg2")),
+                    version,
+                ),
+            );
             Bundle2Item::Changegroup(header, Box::new(cg2_stream))
         }
         &PartHeaderType::B2xInfinitepush => {
-            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::Cg2Unpacker::new(
-                logger.new(o!("stream" => "cg2")),
-            ));
+            let version = cg_version(&header, "cgversion");
+            let cg2_stream = wrapped_stream.decode(
+                changegroup::unpacker::Cg2Unpacker::new_with_version(
+                    logger.new(o!("stream" => "cg2")),
+                    version,
+                ),
+            );
             Bundle2Item::B2xInfinitepush(header, Box::new(cg2_stream))
         }
         &PartHeaderType::B2xInfinitepushBookmarks => {
@@ -130,6 +158,18 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
             let empty = wrapped_stream.decode(EmptyUnpacker).for_each(|_| Ok(()));
             Bundle2Item::Pushkey(header, Box::new(empty))
         }
+        &PartHeaderType::Pushvars => {
+            let empty = wrapped_stream.decode(EmptyUnpacker).for_each(|_| Ok(()));
+            Bundle2Item::Pushvars(header, Box::new(empty))
+        }
+        &PartHeaderType::PhaseHeads => {
+            let heads_stream = wrapped_stream.decode(phases::PhaseHeadsUnpacker);
+            Bundle2Item::PhaseHeads(header, Box::new(heads_stream))
+        }
+        &PartHeaderType::Obsmarkers => {
+            let markers_stream = wrapped_stream.decode(obsmarkers::ObsmarkersUnpacker::new());
+            Bundle2Item::Obsmarkers(header, Box::new(markers_stream))
+        }
         _ => panic!("TODO: make this an error"),
     };
 
@@ -17,10 +17,12 @@ use super::wirepack;
 use super::wirepack::packer::WirePackPacker;
 
 use errors::*;
-use mercurial_types::{BlobNode, Delta, MPath, NodeHash, RepoPath, NULL_HASH};
+use mercurial_types::{BlobNode, Delta, MPath, NodeHash, Obsmarker, Phase, RepoPath, NULL_HASH};
 use mercurial_types::manifest::Entry;
+use obsmarkers::encode_obsmarkers;
 use part_encode::PartEncodeBuilder;
 use part_header::PartHeaderType;
+use phases::encode_phase_heads;
 
 pub fn listkey_part<N, S, K, V>(namespace: N, items: S) -> Result<PartEncodeBuilder>
 where
@@ -73,6 +75,7 @@ where
             base,
             linknode,
             delta,
+            flags: 0,
         };
         Part::CgChunk(Section::Changeset, deltachunk)
     });
@@ -149,6 +152,7 @@ where
                 node,
                 delta_base: NULL_HASH,
                 delta: Delta::new_fulltext(content.to_vec()),
+                metadata: None,
             });
 
             iter_ok(vec![history_meta, history, data_meta, data].into_iter())
@@ -202,6 +206,54 @@ impl fmt::Display for ChangegroupApplyResult {
     }
 }
 
+/// Part listing the heads of the changesets in each phase. Heads that are ancestors of another
+/// head in the same phase are allowed.
+pub fn phase_heads_part<F>(heads: F) -> Result<PartEncodeBuilder>
+where
+    F: Future<Item = Vec<(Phase, NodeHash)>, Error = Error> + Send + 'static,
+{
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::PhaseHeads)?;
+    builder.set_data_future(heads.map(|heads| encode_phase_heads(&heads).freeze()));
+    Ok(builder)
+}
+
+/// Part with the obsolescence markers relevant to the changesets sent to the client
+pub fn obsmarkers_part<F>(markers: F) -> Result<PartEncodeBuilder>
+where
+    F: Future<Item = Vec<Obsmarker>, Error = Error> + Send + 'static,
+{
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::Obsmarkers)?;
+    builder.set_data_future(markers.and_then(|markers| {
+        encode_obsmarkers(&markers).map(|encoded| encoded.freeze())
+    }));
+    Ok(builder)
+}
+
+pub fn replyobsmarkers_part(new: usize, in_reply_to: u32) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::ReplyObsmarkers)?;
+    builder.add_aparam("new", format!("{}", new))?;
+    builder.add_aparam("in-reply-to", format!("{}", in_reply_to))?;
+
+    Ok(builder)
+}
+
+/// Part with a message that the client prints on the user's terminal
+pub fn output_part<B: Into<Bytes>>(message: B) -> Result<PartEncodeBuilder> {
+    // Advisory, so that a client that doesn't know the part can carry on without the message
+    let mut builder = PartEncodeBuilder::advisory(PartHeaderType::Output)?;
+    builder.set_data_bytes(message)?;
+
+    Ok(builder)
+}
+
+pub fn replypushkey_part(success: bool, in_reply_to: u32) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::ReplyPushkey)?;
+    builder.add_mparam("return", if success { "1" } else { "0" })?;
+    builder.add_mparam("in-reply-to", format!("{}", in_reply_to))?;
+
+    Ok(builder)
+}
+
 pub fn replychangegroup_part(
     res: ChangegroupApplyResult,
     in_reply_to: u32,
@@ -0,0 +1,83 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Codec for the phase-heads part. The payload is a list of fixed-size records, each one the
+//! phase as a big-endian i32 followed by a 20-byte node. The listed nodes and their ancestors
+//! are in the given phase or a more public one.
+
+use bytes::{BigEndian, BufMut, BytesMut};
+use tokio_io::codec::Decoder;
+
+use mercurial_types::{NodeHash, Phase};
+
+use errors::*;
+use utils::BytesExt;
+
+const RECORD_SIZE: usize = 4 + 20;
+
+#[derive(Debug)]
+pub struct PhaseHeadsUnpacker;
+
+impl Decoder for PhaseHeadsUnpacker {
+    type Item = (Phase, NodeHash);
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if buf.len() < RECORD_SIZE {
+            return Ok(None);
+        }
+        let phase = buf.drain_i32();
+        let node = buf.drain_node();
+        if phase < 0 {
+            bail_msg!("invalid phase {} for {}", phase, node);
+        }
+        Ok(Some((Phase::from_hg(phase as u32)?, node)))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
+        match self.decode(buf)? {
+            Some(item) => Ok(Some(item)),
+            None if buf.is_empty() => Ok(None),
+            None => bail_msg!("incomplete phase-heads record: {} bytes left", buf.len()),
+        }
+    }
+}
+
+pub fn encode_phase_heads(heads: &[(Phase, NodeHash)]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(heads.len() * RECORD_SIZE);
+    for &(phase, node) in heads {
+        buf.put_i32::<BigEndian>(phase.to_hg() as i32);
+        buf.put_slice(node.as_ref());
+    }
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use mercurial_types_mocks::nodehash::{ONES_HASH, TWOS_HASH};
+
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let heads = vec![(Phase::Public, ONES_HASH), (Phase::Draft, TWOS_HASH)];
+        let mut buf = encode_phase_heads(&heads);
+
+        let mut unpacker = PhaseHeadsUnpacker;
+        let mut decoded = Vec::new();
+        while let Some(item) = unpacker.decode_eof(&mut buf).unwrap() {
+            decoded.push(item);
+        }
+        assert_eq!(decoded, heads);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut buf = encode_phase_heads(&[(Phase::Public, ONES_HASH)]);
+        buf.truncate(10);
+        assert!(PhaseHeadsUnpacker.decode_eof(&mut buf).is_err());
+    }
+}
@@ -202,6 +202,8 @@ impl Arbitrary for changegroup::CgDeltaChunk {
             base: NodeHash::arbitrary(g),
             linknode: NodeHash::arbitrary(g),
             delta: Delta::arbitrary(g),
+            // Version 2 changegroups don't have flags
+            flags: 0,
         }
     }
 
@@ -218,6 +220,7 @@ impl Arbitrary for changegroup::CgDeltaChunk {
                     base: clone.base.clone(),
                     linknode: clone.linknode.clone(),
                     delta: delta,
+                    flags: clone.flags,
                 }),
         )
     }
@@ -92,6 +92,10 @@ const HISTORY_HEADER_SIZE: usize = HISTORY_COPY_FROM_OFFSET + 2;
 const DATA_DELTA_OFFSET: usize = 20 + 20;
 const DATA_HEADER_SIZE: usize = DATA_DELTA_OFFSET + 8;
 
+// Keys of the data entry metadata.
+const METAKEYFLAG: u8 = b'f';
+const METAKEYSIZE: u8 = b's';
+
 // TODO: move to mercurial-types
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct HistoryEntry {
@@ -225,6 +229,45 @@ pub struct DataEntry {
     pub node: NodeHash,
     pub delta_base: NodeHash,
     pub delta: Delta,
+    pub metadata: Option<DataEntryMetadata>,
+}
+
+/// Metadata of a file revision, such as its LFS flag, sent after its delta. Packs are either
+/// sent with metadata for every data entry or for none of them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DataEntryMetadata {
+    pub size: Option<u64>,
+    pub flags: Option<u64>,
+}
+
+impl DataEntryMetadata {
+    // Metadata has:
+    // ---
+    // metadata len: u32 (4 bytes)
+    // (key: u8 (1 byte), value len: u16 (2 bytes), value: big endian integer)*
+    // ---
+    // Keys are sorted, and values have no leading zero bytes.
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let mut entries = vec![];
+        if let Some(flags) = self.flags {
+            entries.push((METAKEYFLAG, flags));
+        }
+        if let Some(size) = self.size {
+            entries.push((METAKEYSIZE, size));
+        }
+
+        let mut encoded: Vec<u8> = vec![];
+        for (key, value) in entries {
+            let mut value_buf = [0u8; 8];
+            BigEndian::write_u64(&mut value_buf, value);
+            let start = value_buf.iter().position(|b| *b != 0).unwrap_or(8);
+            encoded.put_u8(key);
+            encoded.put_u16::<BigEndian>((8 - start) as u16);
+            encoded.put_slice(&value_buf[start..]);
+        }
+        buf.put_u32::<BigEndian>(encoded.len() as u32);
+        buf.put_slice(&encoded);
+    }
 }
 
 impl DataEntry {
@@ -243,6 +286,7 @@ impl DataEntry {
         // There's a bit of a wart in the current format: if delta base is NULL_HASH, instead of
         // storing a delta with start = 0 and end = 0, we store the full text directly. This
         // should be fixed in a future wire protocol revision.
+        // Packs with metadata are only sent, never received, so it isn't decoded.
         let delta_len = BigEndian::read_u64(&buf[DATA_DELTA_OFFSET..DATA_HEADER_SIZE]) as usize;
         if buf.len() < DATA_HEADER_SIZE + delta_len {
             return Ok(None);
@@ -263,6 +307,7 @@ impl DataEntry {
             node,
             delta_base,
             delta,
+            metadata: None,
         }))
     }
 
@@ -285,6 +330,9 @@ impl DataEntry {
             buf.put_u64::<BigEndian>(delta::encoded_len(&self.delta) as u64);
             delta::encode_delta(&self.delta, buf);
         }
+        if let Some(ref metadata) = self.metadata {
+            metadata.encode(buf);
+        }
         Ok(())
     }
 
@@ -306,13 +354,18 @@ impl DataEntry {
 mod test {
     use std::cmp;
 
+    use futures::{stream, Future, Stream};
     use quickcheck::{Gen, StdGen};
     use rand::{self, Rng};
+    use slog::{Discard, Logger};
+    use tokio_io::codec::Decoder;
 
     use mercurial_types::delta::Fragment;
     use mercurial_types_mocks::nodehash::{AS_HASH, BS_HASH};
 
     use super::*;
+    use super::packer::WirePackPacker;
+    use super::quickcheck_types::WirePackPartSequence;
 
     #[test]
     fn test_history_verify_basic() {
@@ -424,6 +477,7 @@ mod test {
                 node: BS_HASH,
                 delta_base,
                 delta,
+                metadata: None,
             };
             let result = entry.verify();
             if is_valid {
@@ -434,7 +488,54 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_data_encode_metadata() {
+        let entry = DataEntry {
+            node: AS_HASH,
+            delta_base: NULL_HASH,
+            delta: Delta::new_fulltext(b"abc".to_vec()),
+            metadata: Some(DataEntryMetadata {
+                size: Some(3),
+                flags: Some(1 << 13),
+            }),
+        };
+        let mut encoded = vec![];
+        entry
+            .encode(&mut encoded)
+            .expect("encoding this data entry should succeed");
+
+        let mut expected = vec![];
+        expected.extend_from_slice(AS_HASH.as_ref());
+        expected.extend_from_slice(NULL_HASH.as_ref());
+        expected.extend_from_slice(b"\0\0\0\0\0\0\0\x03abc");
+        expected.extend_from_slice(b"\0\0\0\x09f\0\x02\x20\0s\0\x01\x03");
+        assert_eq!(encoded, expected);
+    }
+
     quickcheck! {
+        fn test_wirepack_roundtrip(seq: WirePackPartSequence) -> bool {
+            let parts = seq.to_parts();
+            let chunks = WirePackPacker::new(stream::iter_ok::<_, Error>(parts.clone()), seq.kind)
+                .collect()
+                .wait()
+                .expect("packing these parts should succeed");
+
+            let mut encoded = BytesMut::new();
+            for chunk in chunks {
+                encoded.extend_from_slice(&chunk.into_bytes().expect("expected normal chunk"));
+            }
+
+            let mut unpacker = unpacker::new(Logger::root(Discard, o!()), seq.kind);
+            let mut decoded = vec![];
+            while let Some(part) = unpacker
+                .decode_eof(&mut encoded)
+                .expect("unpacking the packed parts should succeed")
+            {
+                decoded.push(part);
+            }
+            decoded == parts
+        }
+
         fn test_data_verify_arbitrary(entry: DataEntry) -> bool {
             entry.verify().is_ok()
         }
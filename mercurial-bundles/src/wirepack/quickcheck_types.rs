@@ -10,7 +10,7 @@ use quickcheck::{Arbitrary, Gen};
 
 use mercurial_types::{Delta, MPath, NodeHash, RepoPath, NULL_HASH};
 
-use super::{DataEntry, HistoryEntry, Kind};
+use super::{DataEntry, HistoryEntry, Kind, Part};
 
 #[derive(Clone, Debug)]
 pub struct WirePackPartSequence {
@@ -18,6 +18,27 @@ pub struct WirePackPartSequence {
     pub files: Vec<FileEntries>,
 }
 
+impl WirePackPartSequence {
+    /// The parts of a wirepack with these entries, as they are packed and unpacked
+    pub fn to_parts(&self) -> Vec<Part> {
+        let mut parts = Vec::new();
+        for file in &self.files {
+            parts.push(Part::HistoryMeta {
+                path: file.filename.clone(),
+                entry_count: file.history.len() as u32,
+            });
+            parts.extend(file.history.iter().cloned().map(Part::History));
+            parts.push(Part::DataMeta {
+                path: file.filename.clone(),
+                entry_count: file.data.len() as u32,
+            });
+            parts.extend(file.data.iter().cloned().map(Part::Data));
+        }
+        parts.push(Part::End);
+        parts
+    }
+}
+
 impl Arbitrary for WirePackPartSequence {
     fn arbitrary<G: Gen>(g: &mut G) -> Self {
         let size = g.size();
@@ -153,6 +174,7 @@ impl Arbitrary for DataEntry {
             node: NodeHash::arbitrary(g),
             delta_base,
             delta,
+            metadata: None,
         }
     }
 
@@ -165,6 +187,7 @@ impl Arbitrary for DataEntry {
                 node: node,
                 delta_base: delta_base,
                 delta,
+                metadata: None,
             }
         }))
     }
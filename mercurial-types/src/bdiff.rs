@@ -4,6 +4,8 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::cmp::min;
+
 /// A single delta in a revlog or bundle.
 ///
 /// The range from `start`-`end` is replaced with the `content`.
@@ -46,9 +48,195 @@ pub fn apply(text: &[u8], deltas: &[Delta]) -> Vec<u8> {
     ret
 }
 
+/// A run of `len` lines that are the same in two texts, starting at line `old_start` of the old
+/// text and at line `new_start` of the new text.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Block {
+    pub old_start: usize,
+    pub new_start: usize,
+    pub len: usize,
+}
+
+/// Split a text into lines, each including its trailing newline. The last line has no newline
+/// if the text doesn't end with one.
+pub fn lines(text: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (idx, byte) in text.iter().enumerate() {
+        if *byte == b'\n' {
+            lines.push(&text[start..idx + 1]);
+            start = idx + 1;
+        }
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+    }
+    lines
+}
+
+/// The lines that are kept between `old` and `new`, as blocks in increasing order. The lines
+/// between the blocks were removed from `old` or added in `new`.
+pub fn blocks(old: &[u8], new: &[u8]) -> Vec<Block> {
+    let old_lines = lines(old);
+    let new_lines = lines(new);
+
+    // Most changes only touch a small part of a file, so the common prefix and suffix are
+    // matched directly and only the rest is diffed
+    let prefix = old_lines
+        .iter()
+        .zip(new_lines.iter())
+        .take_while(|&(o, n)| o == n)
+        .count();
+    let suffix = old_lines[prefix..]
+        .iter()
+        .rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|&(o, n)| o == n)
+        .count();
+
+    let mut matches: Vec<_> = (0..prefix).map(|idx| (idx, idx)).collect();
+    let old_middle = &old_lines[prefix..old_lines.len() - suffix];
+    let new_middle = &new_lines[prefix..new_lines.len() - suffix];
+    matches.extend(
+        common_lines(old_middle, new_middle)
+            .into_iter()
+            .map(|(o, n)| (o + prefix, n + prefix)),
+    );
+    matches.extend((0..suffix).map(|idx| {
+        (
+            old_lines.len() - suffix + idx,
+            new_lines.len() - suffix + idx,
+        )
+    }));
+
+    let mut blocks: Vec<Block> = Vec::new();
+    for (o, n) in matches {
+        if let Some(last) = blocks.last_mut() {
+            if last.old_start + last.len == o && last.new_start + last.len == n {
+                last.len += 1;
+                continue;
+            }
+        }
+        blocks.push(Block {
+            old_start: o,
+            new_start: n,
+            len: 1,
+        });
+    }
+    blocks
+}
+
+/// The `Delta`s that turn `old` into `new`. Whole lines are replaced, like Mercurial's bdiff
+/// does, so `apply(old, &diff(old, new))` is `new`.
+pub fn diff(old: &[u8], new: &[u8]) -> Vec<Delta> {
+    let old_lines = lines(old);
+    let new_lines = lines(new);
+
+    // Byte offset of each line of `old`, and of the end of the text
+    let mut old_offsets = Vec::with_capacity(old_lines.len() + 1);
+    let mut offset = 0;
+    for line in old_lines.iter() {
+        old_offsets.push(offset);
+        offset += line.len();
+    }
+    old_offsets.push(offset);
+
+    let end = Block {
+        old_start: old_lines.len(),
+        new_start: new_lines.len(),
+        len: 0,
+    };
+    let mut deltas = Vec::new();
+    let (mut old_idx, mut new_idx) = (0, 0);
+    for block in blocks(old, new).into_iter().chain(Some(end)) {
+        if block.old_start > old_idx || block.new_start > new_idx {
+            deltas.push(Delta {
+                start: old_offsets[old_idx],
+                end: old_offsets[block.old_start],
+                content: new_lines[new_idx..block.new_start].concat(),
+            });
+        }
+        old_idx = block.old_start + block.len;
+        new_idx = block.new_start + block.len;
+    }
+    deltas
+}
+
+/// Largest number of added and removed lines that `common_lines` looks for. Finding `d` changes
+/// takes O(d^2) memory, so texts that differ more than this are treated as entirely different.
+const MAX_EDIT_DISTANCE: isize = 2000;
+
+/// Pairs of indexes of equal lines in `old` and `new` that make up a longest common
+/// subsequence, found with Myers' algorithm. If the texts differ by more than
+/// `MAX_EDIT_DISTANCE` lines, no lines are matched.
+fn common_lines(old: &[&[u8]], new: &[&[u8]]) -> Vec<(usize, usize)> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max = n + m;
+    // Furthest reaching x on each diagonal k = x - y, indexed by k + max + 1
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    // The diagonals -d - 1 to d + 1 of `v` before each step d, which are all the backtracking
+    // looks at
+    let mut trace = Vec::new();
+    let mut found = false;
+
+    'search: for d in 0..min(max, MAX_EDIT_DISTANCE) + 1 {
+        trace.push(v[(max - d) as usize..(max + d + 3) as usize].to_vec());
+        let mut k = -d;
+        while k <= d {
+            let idx = (k + max + 1) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                found = true;
+                break 'search;
+            }
+            k += 2;
+        }
+    }
+    if !found {
+        return Vec::new();
+    }
+
+    // Walk back through the furthest reaching paths to find the matched lines
+    let mut matches = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (0..trace.len() as isize).rev() {
+        let v = &trace[d as usize];
+        // Index of diagonal k in the saved part of `v`
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            matches.push((x as usize, y as usize));
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    matches.reverse();
+    matches
+}
+
 #[cfg(test)]
 mod test {
-    use super::{apply, Delta};
+    use super::{apply, blocks, diff, lines, Block, Delta, MAX_EDIT_DISTANCE};
 
     #[test]
     fn test_1() {
@@ -154,4 +342,89 @@ mod test {
         assert_eq!(&res[..], b"aaaa\ncccc\n");
     }
 
+    #[test]
+    fn test_lines() {
+        assert_eq!(lines(b""), Vec::<&[u8]>::new());
+        assert_eq!(lines(b"a\nb\n"), vec![&b"a\n"[..], &b"b\n"[..]]);
+        assert_eq!(lines(b"a\n\nb"), vec![&b"a\n"[..], &b"\n"[..], &b"b"[..]]);
+    }
+
+    #[test]
+    fn test_blocks() {
+        let old = b"aaaa\nbbbb\ncccc\ndddd\n";
+        let new = b"aaaa\nxxxx\ncccc\ndddd\neeee\n";
+        assert_eq!(
+            blocks(old, new),
+            vec![
+                Block {
+                    old_start: 0,
+                    new_start: 0,
+                    len: 1,
+                },
+                Block {
+                    old_start: 2,
+                    new_start: 2,
+                    len: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = b"aaaa\nbbbb\ncccc\n";
+        let new = b"aaaa\nxxxx\ncccc\ndddd\n";
+        assert_eq!(
+            diff(old, new),
+            vec![
+                Delta {
+                    start: 5,
+                    end: 10,
+                    content: (&b"xxxx\n"[..]).into(),
+                },
+                Delta {
+                    start: 15,
+                    end: 15,
+                    content: (&b"dddd\n"[..]).into(),
+                },
+            ]
+        );
+        assert_eq!(diff(old, old), vec![]);
+    }
+
+    #[test]
+    fn test_diff_too_different() {
+        // Every other line changes, so there are more changes than `common_lines` looks for
+        let old: Vec<u8> = (0..2 * MAX_EDIT_DISTANCE)
+            .flat_map(|idx| format!("line {}\n", idx).into_bytes())
+            .collect();
+        let new: Vec<u8> = (0..2 * MAX_EDIT_DISTANCE)
+            .flat_map(|idx| match idx % 2 {
+                0 => format!("line {}\n", idx).into_bytes(),
+                _ => format!("changed {}\n", idx).into_bytes(),
+            })
+            .collect();
+
+        // Only the unchanged first line is matched
+        assert_eq!(
+            blocks(&old, &new),
+            vec![
+                Block {
+                    old_start: 0,
+                    new_start: 0,
+                    len: 1,
+                },
+            ]
+        );
+        assert_eq!(apply(&old, &diff(&old, &new)), new);
+    }
+
+    quickcheck! {
+        fn diff_roundtrip(old: Vec<u8>, new: Vec<u8>) -> bool {
+            // Few distinct bytes and many newlines, so that the texts share lines
+            let old: Vec<u8> = old.into_iter().map(|b| b"ab\n"[b as usize % 3]).collect();
+            let new: Vec<u8> = new.into_iter().map(|b| b"ab\n"[b as usize % 3]).collect();
+            apply(&old, &diff(&old, &new)) == new
+        }
+    }
 }
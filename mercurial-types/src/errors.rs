@@ -10,6 +10,9 @@ pub use failure::{Error, ResultExt};
 pub enum ErrorKind {
     #[fail(display = "invalid sha-1 input: {}", _0)] InvalidSha1Input(String),
     #[fail(display = "invalid fragment list: {}", _0)] InvalidFragmentList(String),
+    #[fail(display = "invalid sha-256 input: {}", _0)] InvalidSha256Input(String),
+    #[fail(display = "invalid lfs pointer: {}", _0)] InvalidLfsPointer(String),
+    #[fail(display = "invalid phase: {}", _0)] InvalidPhase(String),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
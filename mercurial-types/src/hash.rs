@@ -10,7 +10,7 @@ use std::str::FromStr;
 use ascii::{AsciiStr, AsciiString};
 use quickcheck::{single_shrinker, Arbitrary, Gen};
 use rust_crypto::digest::Digest;
-use rust_crypto::sha1;
+use rust_crypto::{sha1, sha2};
 
 use errors::*;
 
@@ -26,6 +26,33 @@ pub struct Sha1([u8; 20]);
 
 const HEX_CHARS: &[u8] = b"0123456789abcdef";
 
+fn encode_hex(bytes: &[u8]) -> AsciiString {
+    let mut v = Vec::with_capacity(bytes.len() * 2);
+    for &byte in bytes {
+        v.push(HEX_CHARS[(byte >> 4) as usize]);
+        v.push(HEX_CHARS[(byte & 0xf) as usize]);
+    }
+
+    unsafe {
+        // A hex string is always a pure ASCII string.
+        AsciiString::from_ascii_unchecked(v)
+    }
+}
+
+/// Fill `out` from the hex digits at the start of `s`, which must have enough of them. Returns
+/// false if a digit is bad.
+fn decode_hex(s: &str, out: &mut [u8]) -> bool {
+    for idx in 0..out.len() {
+        out[idx] = match s.get((idx * 2)..(idx * 2 + 2))
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+        {
+            Some(v) => v,
+            None => return false,
+        }
+    }
+    true
+}
+
 impl Sha1 {
     /// Construct a `Sha1` from an array of 20 bytes containing a
     /// SHA-1 (ie, *not* a hash of the bytes).
@@ -53,16 +80,7 @@ impl Sha1 {
     }
 
     pub fn to_hex(&self) -> AsciiString {
-        let mut v = Vec::with_capacity(40);
-        for &byte in self.as_ref() {
-            v.push(HEX_CHARS[(byte >> 4) as usize]);
-            v.push(HEX_CHARS[(byte & 0xf) as usize]);
-        }
-
-        unsafe {
-            // A hex string is always a pure ASCII string.
-            AsciiString::from_ascii_unchecked(v)
-        }
+        encode_hex(self.as_ref())
     }
 }
 
@@ -100,14 +118,9 @@ impl FromStr for Sha1 {
         }
 
         let mut ret = Sha1([0; 20]);
-
-        for idx in 0..ret.0.len() {
-            ret.0[idx] = match u8::from_str_radix(&s[(idx * 2)..(idx * 2 + 2)], 16) {
-                Ok(v) => v,
-                Err(_) => bail!(ErrorKind::InvalidSha1Input("bad digit".into())),
-            }
+        if !decode_hex(s, &mut ret.0) {
+            bail!(ErrorKind::InvalidSha1Input("bad digit".into()));
         }
-
         Ok(ret)
     }
 }
@@ -161,10 +174,83 @@ impl Context {
     }
 }
 
+/// Raw SHA-256 hash
+///
+/// Used where other systems address content by SHA-256, f.e. for the content of lfs files.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Serialize, Deserialize)]
+pub struct Sha256([u8; 32]);
+
+impl Sha256 {
+    /// Construct a `Sha256` from an array of 32 bytes containing a
+    /// SHA-256 (ie, *not* a hash of the bytes).
+    pub fn from_bytes<B: AsRef<[u8]>>(bytes: B) -> Result<Sha256> {
+        let bytes = bytes.as_ref();
+        if bytes.len() != 32 {
+            bail!(ErrorKind::InvalidSha256Input("need exactly 32 bytes".into()));
+        } else {
+            let mut ret = Sha256([0; 32]);
+            &mut ret.0[..].copy_from_slice(bytes);
+            Ok(ret)
+        }
+    }
+
+    pub fn to_hex(&self) -> AsciiString {
+        encode_hex(self.as_ref())
+    }
+}
+
+/// Compute the `Sha256` for a slice of bytes.
+impl<'a> From<&'a [u8]> for Sha256 {
+    fn from(data: &[u8]) -> Sha256 {
+        let mut sha256 = sha2::Sha256::new();
+        sha256.input(data);
+
+        let mut ret = Sha256([0; 32]);
+        sha256.result(&mut ret.0[..]);
+        ret
+    }
+}
+
+impl AsRef<[u8]> for Sha256 {
+    fn as_ref(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+impl FromStr for Sha256 {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Sha256> {
+        if s.len() != 64 {
+            bail!(ErrorKind::InvalidSha256Input(
+                "need exactly 64 hex digits".into()
+            ));
+        }
+
+        let mut ret = Sha256([0; 32]);
+        if !decode_hex(s, &mut ret.0) {
+            bail!(ErrorKind::InvalidSha256Input("bad digit".into()));
+        }
+        Ok(ret)
+    }
+}
+
+impl Display for Sha256 {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(&self.to_hex(), fmt)
+    }
+}
+
+impl Debug for Sha256 {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Sha256({})", self)
+    }
+}
 
 #[cfg(test)]
 mod test {
-    use super::{Sha1, NULL};
+    use super::{Sha1, Sha256, NULL};
     use quickcheck::TestResult;
     use std::str::FromStr;
 
@@ -242,6 +328,17 @@ mod test {
         };
     }
 
+    #[test]
+    fn test_sha256() {
+        let hash = Sha256::from(&b"abc"[..]);
+        assert_eq!(
+            hash.to_hex().as_str(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(hash.to_string().parse::<Sha256>().unwrap(), hash);
+        assert!("ba7816bf".parse::<Sha256>().is_err());
+    }
+
     quickcheck! {
         fn parse_roundtrip(v: Vec<u8>) -> TestResult {
             if v.len() != 20 {
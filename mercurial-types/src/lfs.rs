@@ -0,0 +1,150 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Support for files stored with Mercurial's lfs extension.
+//!
+//! The filelog revision of an lfs file is a small pointer in the Git-LFS format that identifies
+//! the actual content by its SHA-256 hash (the "oid"). The content itself is transferred out of
+//! band using the Git-LFS batch API.
+
+use std::collections::BTreeMap;
+use std::str;
+
+use errors::*;
+use hash::Sha256;
+
+/// Revlog flag set on revisions whose content is stored externally (REVIDX_EXTSTORED)
+pub const LFS_FLAG: u16 = 1 << 13;
+
+const POINTER_VERSION: &str = "https://git-lfs.github.com/spec/v1";
+const OID_PREFIX: &str = "sha256:";
+/// Prefix of the pointer keys that hold Mercurial's filelog metadata, f.e. `x-hg-copy`
+const HG_METADATA_PREFIX: &str = "x-hg-";
+
+/// Git-LFS pointer, as stored in the filelog instead of the content of an lfs file
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LfsPointer {
+    pub oid: Sha256,
+    pub size: u64,
+}
+
+impl LfsPointer {
+    pub fn new(oid: Sha256, size: u64) -> Self {
+        LfsPointer { oid, size }
+    }
+
+    /// Parse a pointer. Keys other than `oid` and `size` (f.e. the `x-hg-copy` metadata written
+    /// by Mercurial) are allowed and ignored.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let text = str::from_utf8(bytes)
+            .map_err(|_| ErrorKind::InvalidLfsPointer("not utf-8".into()))?;
+
+        let mut lines = text.lines();
+        match lines.next() {
+            Some(line) if line == format!("version {}", POINTER_VERSION) => {}
+            _ => bail!(ErrorKind::InvalidLfsPointer("unknown version".into())),
+        }
+
+        let mut oid = None;
+        let mut size = None;
+        for line in lines {
+            let mut kv = line.splitn(2, ' ');
+            match (kv.next(), kv.next()) {
+                (Some("oid"), Some(value)) => {
+                    if !value.starts_with(OID_PREFIX) {
+                        bail!(ErrorKind::InvalidLfsPointer(format!("bad oid {}", value)));
+                    }
+                    oid = Some(value[OID_PREFIX.len()..].parse()?);
+                }
+                (Some("size"), Some(value)) => {
+                    size = Some(value.parse().map_err(|_| {
+                        ErrorKind::InvalidLfsPointer(format!("bad size {}", value))
+                    })?);
+                }
+                (Some(_), Some(_)) => {}
+                _ => bail!(ErrorKind::InvalidLfsPointer(format!("bad line {}", line))),
+            }
+        }
+
+        match (oid, size) {
+            (Some(oid), Some(size)) => Ok(LfsPointer { oid, size }),
+            _ => bail!(ErrorKind::InvalidLfsPointer("missing oid or size".into())),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        format!(
+            "version {}\noid {}{}\nsize {}\n",
+            POINTER_VERSION,
+            OID_PREFIX,
+            self.oid,
+            self.size
+        ).into_bytes()
+    }
+}
+
+/// The text that the filenode of an lfs revision is the hash of: the `content` that `pointer`
+/// refers to, with the `x-hg-` keys of the pointer in front of it as filelog metadata, like
+/// Mercurial's lfs extension does
+pub fn filelog_text(pointer: &[u8], content: &[u8]) -> Vec<u8> {
+    let mut metadata = BTreeMap::new();
+    for line in pointer.split(|byte| *byte == b'\n') {
+        if !line.starts_with(HG_METADATA_PREFIX.as_bytes()) {
+            continue;
+        }
+        let line = &line[HG_METADATA_PREFIX.len()..];
+        if let Some(idx) = line.iter().position(|byte| *byte == b' ') {
+            metadata.insert(&line[..idx], &line[idx + 1..]);
+        }
+    }
+
+    let mut text = Vec::with_capacity(content.len());
+    if !metadata.is_empty() || content.starts_with(b"\x01\n") {
+        text.extend_from_slice(b"\x01\n");
+        for (key, value) in metadata {
+            text.extend_from_slice(key);
+            text.extend_from_slice(b": ");
+            text.extend_from_slice(value);
+            text.push(b'\n');
+        }
+        text.extend_from_slice(b"\x01\n");
+    }
+    text.extend_from_slice(content);
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pointer_roundtrip() {
+        let pointer = LfsPointer::new(Sha256::from(&b"content"[..]), 7);
+        assert_eq!(LfsPointer::from_bytes(&pointer.to_bytes()).unwrap(), pointer);
+    }
+
+    #[test]
+    fn test_pointer_parse() {
+        let text = b"version https://git-lfs.github.com/spec/v1\n\
+                     oid sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\n\
+                     size 3\n\
+                     x-hg-copy foo\n";
+        let pointer = LfsPointer::from_bytes(text).unwrap();
+        assert_eq!(pointer.oid, Sha256::from(&b"abc"[..]));
+        assert_eq!(pointer.size, 3);
+
+        assert_eq!(
+            filelog_text(text, b"abc"),
+            b"\x01\ncopy: foo\n\x01\nabc".to_vec()
+        );
+
+        assert!(LfsPointer::from_bytes(b"abc").is_err());
+        assert!(
+            LfsPointer::from_bytes(b"version https://git-lfs.github.com/spec/v1\nsize 3\n")
+                .is_err()
+        );
+    }
+}
@@ -83,7 +83,10 @@ pub mod delta;
 pub mod errors;
 pub mod fsencode;
 pub mod hash;
+pub mod lfs;
 pub mod nodehash;
+pub mod obsmarker;
+pub mod phase;
 pub mod utils;
 pub mod manifest;
 pub mod manifest_utils;
@@ -99,9 +102,13 @@ pub use blobnode::{BlobNode, Parents};
 pub use changeset::{Changeset, Time};
 pub use delta::Delta;
 pub use fsencode::{fncache_fsencode, simple_fsencode};
+pub use hash::Sha256;
+pub use lfs::LfsPointer;
 pub use manifest::{Entry, Manifest, Type};
 pub use node::Node;
 pub use nodehash::{EntryId, HgChangesetId, HgManifestId, NodeHash, NULL_HASH};
+pub use obsmarker::Obsmarker;
+pub use phase::Phase;
 pub use repo::RepositoryId;
 pub use utils::percent_encode;
 
@@ -0,0 +1,66 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Obsolescence markers, which record that a changeset was rewritten (amended, rebased, ...)
+//! into other changesets, or that it was pruned. Clients that use evolve exchange them, so that
+//! rewritten draft changesets are hidden everywhere.
+
+use std::hash::{Hash, Hasher};
+
+use nodehash::HgChangesetId;
+
+#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize)]
+pub struct Obsmarker {
+    /// The changeset that was rewritten
+    pub predecessor: HgChangesetId,
+    /// What the predecessor was rewritten into. There are none if it was pruned.
+    pub successors: Vec<HgChangesetId>,
+    /// Parents of the predecessor. Mercurial only records them for prune markers.
+    pub parents: Option<Vec<HgChangesetId>>,
+    pub flags: u16,
+    /// Seconds since the epoch, and the timezone offset in minutes
+    pub date: (f64, i16),
+    pub metadata: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Obsmarker {
+    /// Everything that identifies the marker. The date is compared bit for bit, so that markers
+    /// can be hashed.
+    fn key(
+        &self,
+    ) -> (
+        &HgChangesetId,
+        &[HgChangesetId],
+        &Option<Vec<HgChangesetId>>,
+        u16,
+        (u64, i16),
+        &[(Vec<u8>, Vec<u8>)],
+    ) {
+        (
+            &self.predecessor,
+            &self.successors,
+            &self.parents,
+            self.flags,
+            (self.date.0.to_bits(), self.date.1),
+            &self.metadata,
+        )
+    }
+}
+
+impl PartialEq for Obsmarker {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Obsmarker {}
+
+impl Hash for Obsmarker {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
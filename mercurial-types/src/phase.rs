@@ -0,0 +1,74 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Phases of changesets. Public changesets are part of the permanent history, draft changesets
+//! (f.e. infinitepush commits) can still be rewritten. Mercurial's secret phase is never served,
+//! so it isn't represented.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use errors::*;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Phase {
+    Public,
+    Draft,
+}
+
+impl Phase {
+    /// Number of the phase, as used in the wire protocol
+    pub fn to_hg(&self) -> u32 {
+        match *self {
+            Phase::Public => 0,
+            Phase::Draft => 1,
+        }
+    }
+
+    pub fn from_hg(phase: u32) -> Result<Self> {
+        match phase {
+            0 => Ok(Phase::Public),
+            1 => Ok(Phase::Draft),
+            _ => bail!(ErrorKind::InvalidPhase(phase.to_string())),
+        }
+    }
+}
+
+impl Display for Phase {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Phase::Public => write!(fmt, "public"),
+            Phase::Draft => write!(fmt, "draft"),
+        }
+    }
+}
+
+impl FromStr for Phase {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "public" => Ok(Phase::Public),
+            "draft" => Ok(Phase::Draft),
+            _ => bail!(ErrorKind::InvalidPhase(s.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        for phase in &[Phase::Public, Phase::Draft] {
+            assert_eq!(Phase::from_hg(phase.to_hg()).unwrap(), *phase);
+            assert_eq!(phase.to_string().parse::<Phase>().unwrap(), *phase);
+        }
+        assert!(Phase::from_hg(2).is_err());
+        assert!("secret".parse::<Phase>().is_err());
+    }
+}
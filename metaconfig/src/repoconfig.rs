@@ -35,6 +35,46 @@ pub struct RepoConfig {
     pub repoid: i32,
     /// Scuba table for logging performance of operations
     pub scuba_table: Option<String>,
+    /// Compression types that may be used for bundle2 sent to clients, in order of preference.
+    /// Clients that don't advertise support for any of them get uncompressed bundle2.
+    pub bundle2_compression: Vec<Bundle2Compression>,
+    /// Directory with a pre-generated snapshot of revlog store files that is used to serve
+    /// stream clones. Stream clones are disabled if it's not set.
+    pub stream_clone_snapshot: Option<PathBuf>,
+    /// Pre-generated bundles that are advertised to clients in the clonebundles manifest. Clients
+    /// clone from one of them and then pull the rest of the commits from the server.
+    pub clonebundles: Vec<CloneBundle>,
+    /// Maximum number of files that a getfiles request fetches at the same time
+    pub getfiles_concurrency: usize,
+    /// Maximum size in bytes of the fetched files that a getfiles request holds while the client
+    /// is not reading them. No new files are fetched until the client catches up.
+    pub getfiles_buffer_size: usize,
+    /// Whether obsolescence markers are exchanged with clients. They are only accepted and served
+    /// if clients are told about them, which is done when this is set.
+    pub obsmarkers: bool,
+    /// Names of the pushvars that clients may send with a push, to be passed to the hooks.
+    /// Pushes with any other pushvar are rejected.
+    pub pushvars: Vec<String>,
+}
+
+/// An entry of the clonebundles manifest
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+pub struct CloneBundle {
+    /// Url the client downloads the bundle from
+    pub url: String,
+    /// Mercurial bundle specification of the bundle, f.e. "zstd-v2"
+    pub bundlespec: Option<String>,
+}
+
+/// Compression types supported for bundle2 sent over the wire
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+pub enum Bundle2Compression {
+    /// Zstandard compression
+    #[serde(rename = "zstd")] Zstd,
+    /// Gzip compression
+    #[serde(rename = "gzip")] Gzip,
+    /// Bzip2 compression
+    #[serde(rename = "bzip2")] Bzip2,
 }
 
 /// Types of repositories supported
@@ -187,6 +227,13 @@ struct RawRepoConfig {
     manifold_prefix: Option<String>,
     repoid: i32,
     scuba_table: Option<String>,
+    bundle2_compression: Option<Vec<Bundle2Compression>>,
+    stream_clone_snapshot: Option<PathBuf>,
+    clonebundles: Option<Vec<CloneBundle>>,
+    getfiles_concurrency: Option<usize>,
+    getfiles_buffer_size: Option<usize>,
+    obsmarkers: Option<bool>,
+    pushvars: Option<Vec<String>>,
 }
 
 /// Types of repositories supported
@@ -223,12 +270,36 @@ impl TryFrom<RawRepoConfig> for RepoConfig {
         let generation_cache_size = this.generation_cache_size.unwrap_or(10 * 1024 * 1024);
         let repoid = this.repoid;
         let scuba_table = this.scuba_table;
+        let bundle2_compression = this.bundle2_compression.unwrap_or_default();
+        let stream_clone_snapshot = this.stream_clone_snapshot;
+        let clonebundles = this.clonebundles.unwrap_or_default();
+        let getfiles_concurrency = this.getfiles_concurrency.unwrap_or(100);
+        if getfiles_concurrency == 0 {
+            bail_err!(ErrorKind::InvalidConfig(
+                "getfiles_concurrency must be positive".into()
+            ));
+        }
+        let getfiles_buffer_size = this.getfiles_buffer_size.unwrap_or(100 * 1024 * 1024);
+        if getfiles_buffer_size == 0 {
+            bail_err!(ErrorKind::InvalidConfig(
+                "getfiles_buffer_size must be positive".into()
+            ));
+        }
+        let obsmarkers = this.obsmarkers.unwrap_or(false);
+        let pushvars = this.pushvars.unwrap_or_default();
 
         Ok(RepoConfig {
             repotype,
             generation_cache_size,
             repoid,
             scuba_table,
+            bundle2_compression,
+            stream_clone_snapshot,
+            clonebundles,
+            getfiles_concurrency,
+            getfiles_buffer_size,
+            obsmarkers,
+            pushvars,
         })
     }
 }
@@ -250,6 +321,16 @@ mod test {
             generation_cache_size=1048576
             repoid=0
             scuba_table="scuba_table"
+            bundle2_compression=["zstd", "gzip"]
+            stream_clone_snapshot="/tmp/fbsource_snapshot"
+            clonebundles=[
+                {url="https://example.com/fbsource.hg", bundlespec="zstd-v2"},
+                {url="https://example.com/fbsource-none.hg"},
+            ]
+            getfiles_concurrency=10
+            getfiles_buffer_size=1048576
+            obsmarkers=true
+            pushvars=["BYPASS_REVIEW"]
         "#;
         let www_content = r#"
             path="/tmp/www"
@@ -289,6 +370,22 @@ mod test {
                 generation_cache_size: 1024 * 1024,
                 repoid: 0,
                 scuba_table: Some("scuba_table".to_string()),
+                bundle2_compression: vec![Bundle2Compression::Zstd, Bundle2Compression::Gzip],
+                stream_clone_snapshot: Some("/tmp/fbsource_snapshot".into()),
+                clonebundles: vec![
+                    CloneBundle {
+                        url: "https://example.com/fbsource.hg".to_string(),
+                        bundlespec: Some("zstd-v2".to_string()),
+                    },
+                    CloneBundle {
+                        url: "https://example.com/fbsource-none.hg".to_string(),
+                        bundlespec: None,
+                    },
+                ],
+                getfiles_concurrency: 10,
+                getfiles_buffer_size: 1024 * 1024,
+                obsmarkers: true,
+                pushvars: vec!["BYPASS_REVIEW".to_string()],
             },
         );
         repos.insert(
@@ -298,6 +395,13 @@ mod test {
                 generation_cache_size: 10 * 1024 * 1024,
                 repoid: 1,
                 scuba_table: Some("scuba_table".to_string()),
+                bundle2_compression: vec![],
+                stream_clone_snapshot: None,
+                clonebundles: vec![],
+                getfiles_concurrency: 100,
+                getfiles_buffer_size: 100 * 1024 * 1024,
+                obsmarkers: false,
+                pushvars: vec![],
             },
         );
         assert_eq!(
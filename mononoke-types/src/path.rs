@@ -291,6 +291,16 @@ impl MPath {
         self.elements.is_empty()
     }
 
+    /// Whether this path is equal to `other` or is one of its parent directories. The empty path
+    /// is a prefix of every path.
+    pub fn is_prefix_of(&self, other: &MPath) -> bool {
+        self.elements.len() <= other.elements.len()
+            && self.elements
+                .iter()
+                .zip(other.elements.iter())
+                .all(|(a, b)| a == b)
+    }
+
     pub(crate) fn into_thrift(self) -> thrift::MPath {
         thrift::MPath(
             self.elements
@@ -469,6 +479,10 @@ mod test {
             p.len() == p.to_vec().len()
         }
 
+        fn path_prefix_of_join(p: MPath, q: MPath) -> bool {
+            p.is_prefix_of(&p.join(&q)) && (q.is_empty() || !p.join(&q).is_prefix_of(&p))
+        }
+
         fn path_thrift_roundtrip(p: MPath) -> bool {
             let thrift_path = p.clone().into_thrift();
             let p2 = MPath::from_thrift(thrift_path)
@@ -498,6 +512,16 @@ mod test {
         }
     }
 
+    #[test]
+    fn path_prefix() {
+        let path = MPath::new("a/bc").unwrap();
+        assert!(MPath::empty().is_prefix_of(&path));
+        assert!(MPath::new("a").unwrap().is_prefix_of(&path));
+        assert!(path.is_prefix_of(&path));
+        assert!(!MPath::new("a/b").unwrap().is_prefix_of(&path));
+        assert!(!MPath::new("a/bc/d").unwrap().is_prefix_of(&path));
+    }
+
     #[test]
     fn path_make() {
         let path = MPath::new(b"1234abc");
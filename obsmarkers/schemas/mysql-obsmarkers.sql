@@ -0,0 +1,15 @@
+CREATE TABLE obsmarkers (
+  id BIGINT PRIMARY KEY AUTO_INCREMENT NOT NULL,
+  repo_id INTEGER NOT NULL,
+  predecessor BINARY(20) NOT NULL,
+  marker_hash BINARY(20) NOT NULL,
+  marker BLOB NOT NULL,
+  UNIQUE (repo_id, marker_hash),
+  INDEX (repo_id, predecessor)
+);
+
+CREATE TABLE obsmarkersuccessors (
+  marker_id BIGINT NOT NULL,
+  successor BINARY(20) NOT NULL,
+  PRIMARY KEY (successor, marker_id)
+);
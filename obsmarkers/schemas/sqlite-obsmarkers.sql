@@ -0,0 +1,16 @@
+CREATE TABLE obsmarkers (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  repo_id INTEGER NOT NULL,
+  predecessor BINARY(20) NOT NULL,
+  marker_hash BINARY(20) NOT NULL,
+  marker BLOB NOT NULL,
+  UNIQUE (repo_id, marker_hash)
+);
+
+CREATE INDEX obsmarkers_predecessor ON obsmarkers (repo_id, predecessor);
+
+CREATE TABLE obsmarkersuccessors (
+  marker_id BIGINT NOT NULL,
+  successor BINARY(20) NOT NULL,
+  PRIMARY KEY (successor, marker_id)
+);
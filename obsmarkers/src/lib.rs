@@ -0,0 +1,266 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bincode;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+
+extern crate db;
+extern crate futures_ext;
+extern crate mercurial_types;
+
+use std::collections::BTreeMap;
+use std::result;
+use std::sync::Mutex;
+
+use diesel::{insert_into, sql_query, Connection, MysqlConnection, SqliteConnection};
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use failure::ResultExt;
+use futures::future;
+
+use db::ConnectionParams;
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::{HgChangesetId, Obsmarker, RepositoryId};
+use mercurial_types::hash::Sha1;
+
+mod errors;
+mod schema;
+mod models;
+mod wrappers;
+
+pub use errors::*;
+use models::{ObsmarkerInsertRow, ObsmarkerRow, ObsmarkerSuccessorRow, TableNameRow};
+use schema::{obsmarkers, obsmarkersuccessors};
+
+/// How many nodes to look up in a single query. SQLite limits the number of variables in a
+/// statement to 999 by default.
+const MAX_NODES_PER_QUERY: usize = 500;
+
+/// Interface to storage of obsolescence markers. Markers are only ever added, and adding a
+/// marker that is stored already does nothing.
+pub trait Obsmarkers: Send + Sync {
+    /// Add markers to the store. Returns how many of them weren't stored yet.
+    fn add(&self, repo_id: RepositoryId, markers: Vec<Obsmarker>) -> BoxFuture<usize, Error>;
+
+    /// Retrieve the markers whose predecessor or one of whose successors is one of `nodes`.
+    fn get(
+        &self,
+        repo_id: RepositoryId,
+        nodes: Vec<HgChangesetId>,
+    ) -> BoxFuture<Vec<Obsmarker>, Error>;
+}
+
+pub struct SqliteObsmarkers {
+    connection: Mutex<SqliteConnection>,
+}
+
+impl SqliteObsmarkers {
+    /// Open a SQLite database. This is synchronous because the SQLite backend hits local
+    /// disk or memory.
+    pub fn open<P: AsRef<str>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let conn = SqliteConnection::establish(path)?;
+        Ok(Self {
+            connection: Mutex::new(conn),
+        })
+    }
+
+    /// Create a new SQLite database.
+    pub fn create<P: AsRef<str>>(path: P) -> Result<Self> {
+        let obsmarkers = Self::open(path)?;
+
+        let up_query = include_str!("../schemas/sqlite-obsmarkers.sql");
+        obsmarkers
+            .connection
+            .lock()
+            .expect("lock poisoned")
+            .batch_execute(&up_query)?;
+
+        Ok(obsmarkers)
+    }
+
+    /// Open a SQLite database, creating the tables if they don't exist yet. Repos made before
+    /// the obsmarkers store was added have no tables to open.
+    pub fn open_or_create<P: AsRef<str>>(path: P) -> Result<Self> {
+        let obsmarkers = Self::open(path)?;
+
+        {
+            let connection = obsmarkers.connection.lock().expect("lock poisoned");
+            let tables = sql_query(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'obsmarkers'",
+            ).load::<TableNameRow>(&*connection)?;
+            if tables.is_empty() {
+                let up_query = include_str!("../schemas/sqlite-obsmarkers.sql");
+                connection.batch_execute(&up_query)?;
+            }
+        }
+
+        Ok(obsmarkers)
+    }
+
+    /// Create a new in-memory empty database. Great for tests.
+    pub fn in_memory() -> Result<Self> {
+        Self::create(":memory:")
+    }
+}
+
+pub struct MysqlObsmarkers {
+    connection: Mutex<MysqlConnection>,
+}
+
+impl MysqlObsmarkers {
+    pub fn open(params: ConnectionParams) -> Result<Self> {
+        let url = params.to_diesel_url()?;
+        let conn = MysqlConnection::establish(&url)?;
+        Ok(Self {
+            connection: Mutex::new(conn),
+        })
+    }
+
+    pub fn create_test_db<P: AsRef<str>>(prefix: P) -> Result<Self> {
+        let params = db::create_test_db(prefix)?;
+        Self::create(params)
+    }
+
+    fn create(params: ConnectionParams) -> Result<Self> {
+        let obsmarkers = Self::open(params)?;
+
+        let up_query = include_str!("../schemas/mysql-obsmarkers.sql");
+        obsmarkers
+            .connection
+            .lock()
+            .expect("lock poisoned")
+            .batch_execute(&up_query)?;
+
+        Ok(obsmarkers)
+    }
+}
+
+/// Using a macro here is unfortunate, but it appears to be the only way to share this code
+/// between SQLite and MySQL.
+macro_rules! impl_obsmarkers {
+    ($struct: ty) => {
+        impl Obsmarkers for $struct {
+            /// Insert the markers that aren't stored yet, together with their successors.
+            fn add(
+                &self,
+                repo_id: RepositoryId,
+                markers: Vec<Obsmarker>,
+            ) -> BoxFuture<usize, Error> {
+                let connection = self.connection.lock().expect("lock poisoned");
+
+                // This code is written in this style to allow easy porting to futures.
+                let txn_result = connection.transaction::<_, Error, _>(|| {
+                    let mut added = 0;
+                    for marker in markers {
+                        let blob = bincode::serialize(&marker)?;
+                        let marker_hash = Sha1::from(&blob[..]).as_ref().to_vec();
+                        let marker_insert = ObsmarkerInsertRow {
+                            repo_id,
+                            predecessor: marker.predecessor,
+                            marker_hash: marker_hash.clone(),
+                            marker: blob,
+                        };
+
+                        let result = insert_into(obsmarkers::table)
+                            .values(&marker_insert)
+                            .execute(&*connection);
+                        if !map_add_result(result)? {
+                            continue;
+                        }
+                        added += 1;
+
+                        // See the changesets store for why this is queried rather than using
+                        // the last insert ID.
+                        let marker_row = obsmarkers::table
+                            .filter(obsmarkers::repo_id.eq(repo_id))
+                            .filter(obsmarkers::marker_hash.eq(&marker_hash))
+                            .first::<ObsmarkerRow>(&*connection)?;
+
+                        let successor_inserts: Vec<_> = marker
+                            .successors
+                            .iter()
+                            .map(|successor| ObsmarkerSuccessorRow {
+                                marker_id: marker_row.id,
+                                successor: *successor,
+                            })
+                            .collect();
+                        insert_into(obsmarkersuccessors::table)
+                            .values(&successor_inserts)
+                            .execute(&*connection)?;
+                    }
+                    Ok(added)
+                });
+
+                future::result(txn_result).boxify()
+            }
+
+            /// Retrieve the markers about any of the given changesets.
+            fn get(
+                &self,
+                repo_id: RepositoryId,
+                nodes: Vec<HgChangesetId>,
+            ) -> BoxFuture<Vec<Obsmarker>, Error> {
+                // TODO: don't block -- send this to another thread
+                let connection = self.connection.lock().expect("lock poisoned");
+
+                // A marker is found twice if both its predecessor and a successor are asked
+                // for. Sorting by id returns them in the order they were added.
+                let rows: Result<BTreeMap<_, _>> = (|| {
+                    let mut rows = BTreeMap::new();
+                    for chunk in nodes.chunks(MAX_NODES_PER_QUERY) {
+                        let by_predecessor = obsmarkers::table
+                            .filter(obsmarkers::repo_id.eq(repo_id))
+                            .filter(obsmarkers::predecessor.eq_any(chunk))
+                            .load::<ObsmarkerRow>(&*connection)?;
+                        let by_successor = obsmarkersuccessors::table
+                            .filter(obsmarkersuccessors::successor.eq_any(chunk))
+                            .inner_join(obsmarkers::table)
+                            .filter(obsmarkers::repo_id.eq(repo_id))
+                            .load::<(ObsmarkerSuccessorRow, ObsmarkerRow)>(&*connection)?;
+                        rows.extend(
+                            by_predecessor
+                                .into_iter()
+                                .chain(by_successor.into_iter().map(|(_, row)| row))
+                                .map(|row| (row.id, row.marker)),
+                        );
+                    }
+                    Ok(rows)
+                })();
+                let markers = rows.and_then(|rows| {
+                    rows.values()
+                        .map(|blob| {
+                            let marker = bincode::deserialize(blob)
+                                .context(ErrorKind::InvalidStoredData)?;
+                            Ok(marker)
+                        })
+                        .collect()
+                });
+                future::result(markers).boxify()
+            }
+        }
+    }
+}
+
+impl_obsmarkers!(MysqlObsmarkers);
+impl_obsmarkers!(SqliteObsmarkers);
+
+/// Whether the marker was inserted. A marker that is stored already isn't an error.
+#[inline]
+fn map_add_result(result: result::Result<usize, DieselError>) -> Result<bool> {
+    match result {
+        Ok(_rows) => Ok(true),
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
@@ -0,0 +1,46 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use diesel::sql_types::Text;
+
+use mercurial_types::{HgChangesetId, RepositoryId};
+
+use schema::{obsmarkers, obsmarkersuccessors};
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable)]
+pub(crate) struct ObsmarkerRow {
+    pub id: i64,
+    pub repo_id: RepositoryId,
+    pub predecessor: HgChangesetId,
+    pub marker_hash: Vec<u8>,
+    pub marker: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Insertable)]
+#[table_name = "obsmarkers"]
+pub(crate) struct ObsmarkerInsertRow {
+    pub repo_id: RepositoryId,
+    pub predecessor: HgChangesetId,
+    pub marker_hash: Vec<u8>,
+    pub marker: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable, Insertable)]
+#[table_name = "obsmarkersuccessors"]
+pub(crate) struct ObsmarkerSuccessorRow {
+    pub marker_id: i64,
+    pub successor: HgChangesetId,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(QueryableByName)]
+pub(crate) struct TableNameRow {
+    #[sql_type = "Text"]
+    pub name: String,
+}
@@ -0,0 +1,37 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The `table!` macros in this module describe the schemas for these tables in SQL storage
+//! (MySQL or SQLite). These descriptions are *not* the source of truth, so if the schema ever
+//! changes it will need to be updated here as well.
+
+table! {
+    use diesel::sql_types::{BigInt, Binary, Integer};
+
+    use mercurial_types::sql_types::NodeHashSql;
+
+    obsmarkers {
+        id -> BigInt,
+        repo_id -> Integer,
+        predecessor -> NodeHashSql,
+        marker_hash -> Binary,
+        marker -> Binary,
+    }
+}
+
+table! {
+    use diesel::sql_types::BigInt;
+
+    use mercurial_types::sql_types::NodeHashSql;
+
+    obsmarkersuccessors (successor, marker_id) {
+        marker_id -> BigInt,
+        successor -> NodeHashSql,
+    }
+}
+
+joinable!(obsmarkersuccessors -> obsmarkers (marker_id));
+allow_tables_to_appear_in_same_query!(obsmarkers, obsmarkersuccessors);
@@ -0,0 +1,29 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Implementations for wrappers that enable dynamic dispatch. Add more as necessary.
+
+use std::sync::Arc;
+
+use futures_ext::BoxFuture;
+use mercurial_types::{HgChangesetId, Obsmarker, RepositoryId};
+
+use Obsmarkers;
+use errors::*;
+
+impl Obsmarkers for Arc<Obsmarkers> {
+    fn add(&self, repo_id: RepositoryId, markers: Vec<Obsmarker>) -> BoxFuture<usize, Error> {
+        (**self).add(repo_id, markers)
+    }
+
+    fn get(
+        &self,
+        repo_id: RepositoryId,
+        nodes: Vec<HgChangesetId>,
+    ) -> BoxFuture<Vec<Obsmarker>, Error> {
+        (**self).get(repo_id, nodes)
+    }
+}
@@ -0,0 +1,218 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for the Obsmarkers store.
+
+#![deny(warnings)]
+
+extern crate futures;
+
+extern crate mercurial_types;
+extern crate mercurial_types_mocks;
+extern crate obsmarkers;
+
+use std::sync::Arc;
+
+use futures::Future;
+
+use mercurial_types::{HgChangesetId, NodeHash, Obsmarker};
+use mercurial_types::hash::Sha1;
+use mercurial_types_mocks::nodehash::*;
+use mercurial_types_mocks::repo::*;
+use obsmarkers::{MysqlObsmarkers, Obsmarkers, SqliteObsmarkers};
+
+fn marker(predecessor: HgChangesetId, successors: Vec<HgChangesetId>) -> Obsmarker {
+    Obsmarker {
+        predecessor,
+        successors,
+        parents: None,
+        flags: 0,
+        date: (1500000000.5, -60),
+        metadata: vec![(b"user".to_vec(), b"test".to_vec())],
+    }
+}
+
+fn add_and_get<O: Obsmarkers>(obsmarkers: O) {
+    let amend = marker(ONES_CSID, vec![TWOS_CSID]);
+    let added = obsmarkers
+        .add(REPO_ZERO, vec![amend.clone()])
+        .wait()
+        .expect("Adding new marker failed");
+    assert_eq!(added, 1);
+
+    for node in vec![ONES_CSID, TWOS_CSID] {
+        let result = obsmarkers
+            .get(REPO_ZERO, vec![node])
+            .wait()
+            .expect("Get failed");
+        assert_eq!(result, vec![amend.clone()]);
+    }
+}
+
+fn missing<O: Obsmarkers>(obsmarkers: O) {
+    obsmarkers
+        .add(REPO_ZERO, vec![marker(ONES_CSID, vec![TWOS_CSID])])
+        .wait()
+        .expect("Adding new marker failed");
+
+    let result = obsmarkers
+        .get(REPO_ZERO, vec![THREES_CSID])
+        .wait()
+        .expect("Get failed");
+    assert_eq!(result, vec![]);
+
+    let result = obsmarkers
+        .get(REPO_ONE, vec![ONES_CSID])
+        .wait()
+        .expect("Get failed");
+    assert_eq!(result, vec![]);
+}
+
+fn duplicate<O: Obsmarkers>(obsmarkers: O) {
+    let amend = marker(ONES_CSID, vec![TWOS_CSID]);
+    let prune = marker(THREES_CSID, vec![]);
+    obsmarkers
+        .add(REPO_ZERO, vec![amend.clone()])
+        .wait()
+        .expect("Adding new marker failed");
+
+    let added = obsmarkers
+        .add(REPO_ZERO, vec![amend.clone(), prune.clone()])
+        .wait()
+        .expect("Adding duplicate marker failed (should be ignored)");
+    assert_eq!(added, 1);
+
+    let result = obsmarkers
+        .get(REPO_ZERO, vec![ONES_CSID, TWOS_CSID, THREES_CSID])
+        .wait()
+        .expect("Get failed");
+    assert_eq!(result, vec![amend, prune]);
+}
+
+fn split<O: Obsmarkers>(obsmarkers: O) {
+    let split = marker(ONES_CSID, vec![TWOS_CSID, THREES_CSID]);
+    let amend = marker(THREES_CSID, vec![FOURS_CSID]);
+    obsmarkers
+        .add(REPO_ZERO, vec![split.clone(), amend.clone()])
+        .wait()
+        .expect("Adding new markers failed");
+
+    let result = obsmarkers
+        .get(REPO_ZERO, vec![TWOS_CSID])
+        .wait()
+        .expect("Get failed");
+    assert_eq!(result, vec![split.clone()]);
+
+    let result = obsmarkers
+        .get(REPO_ZERO, vec![THREES_CSID])
+        .wait()
+        .expect("Get failed");
+    assert_eq!(result, vec![split, amend]);
+}
+
+fn many_nodes<O: Obsmarkers>(obsmarkers: O) {
+    let amend = marker(ONES_CSID, vec![TWOS_CSID]);
+    let prune = marker(THREES_CSID, vec![]);
+    obsmarkers
+        .add(REPO_ZERO, vec![amend.clone(), prune.clone()])
+        .wait()
+        .expect("Adding new markers failed");
+
+    // More nodes than fit into a single SQLite query.
+    let mut nodes: Vec<_> = (0..2000)
+        .map(|i| HgChangesetId::new(NodeHash::new(Sha1::from(format!("{}", i).as_bytes()))))
+        .collect();
+    nodes.insert(0, TWOS_CSID);
+    nodes.push(THREES_CSID);
+
+    let result = obsmarkers
+        .get(REPO_ZERO, nodes)
+        .wait()
+        .expect("Get failed");
+    assert_eq!(result, vec![amend, prune]);
+}
+
+macro_rules! obsmarkers_test_impl {
+    ($mod_name: ident => {
+        new: $new_cb: expr,
+    }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_add_and_get() {
+                add_and_get($new_cb());
+            }
+
+            #[test]
+            fn test_missing() {
+                missing($new_cb());
+            }
+
+            #[test]
+            fn test_duplicate() {
+                duplicate($new_cb());
+            }
+
+            #[test]
+            fn test_split() {
+                split($new_cb());
+            }
+
+            #[test]
+            fn test_many_nodes() {
+                many_nodes($new_cb());
+            }
+        }
+    }
+}
+
+obsmarkers_test_impl! {
+    sqlite_test => {
+        new: new_sqlite,
+    }
+}
+
+obsmarkers_test_impl! {
+    sqlite_arced_test => {
+        new: new_sqlite_arced,
+    }
+}
+
+obsmarkers_test_impl! {
+    mysql_test => {
+        new: new_mysql,
+    }
+}
+
+obsmarkers_test_impl! {
+    mysql_arced_test => {
+        new: new_mysql_arced,
+    }
+}
+
+fn new_sqlite() -> SqliteObsmarkers {
+    SqliteObsmarkers::in_memory().expect("Creating an in-memory SQLite database failed")
+}
+
+#[test]
+fn sqlite_open_or_create() {
+    let obsmarkers = SqliteObsmarkers::open_or_create(":memory:")
+        .expect("Opening an in-memory SQLite database failed");
+    add_and_get(obsmarkers);
+}
+
+fn new_sqlite_arced() -> Arc<Obsmarkers> {
+    Arc::new(new_sqlite())
+}
+
+fn new_mysql() -> MysqlObsmarkers {
+    MysqlObsmarkers::create_test_db("obsmarkers_test").expect("Failed to create test database")
+}
+
+fn new_mysql_arced() -> Arc<Obsmarkers> {
+    Arc::new(new_mysql())
+}
@@ -0,0 +1,290 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Phases of changesets, as served to clients.
+//!
+//! A changeset is public if it's an ancestor of a bookmark. Only changesets pushed with
+//! infinitepush can be anything else, so they are recorded as draft when they are pushed and
+//! everything that isn't recorded is public. A draft changeset becomes public when a pushkey
+//! or a push makes it public, and is served as public as soon as it's an ancestor of a bookmark.
+//! This is shared by the server and the push resolver, so that both agree on the phases.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+
+extern crate blobrepo;
+#[cfg(test)]
+extern crate linear;
+extern crate mercurial_types;
+extern crate repoinfo;
+extern crate revset;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use futures::{future, Future, Stream};
+use futures::future::Loop;
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::BlobRepo;
+use mercurial_types::{Changeset, HgChangesetId, NodeHash, Phase, NULL_HASH};
+use repoinfo::RepoGenCache;
+use revset::SkiplistIndex;
+
+pub use failure::{Error, Result};
+
+/// Whether `node` is public. Only the changesets recorded as draft need a look at the
+/// bookmarks. Nothing is recorded: a draft changeset that turns out to be an ancestor of a
+/// bookmark is recorded as public by the next push or pushkey that makes it public.
+pub fn is_public(
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    skiplist: SkiplistIndex,
+    node: NodeHash,
+) -> BoxFuture<bool, Error> {
+    repo.get_recorded_phase(&HgChangesetId::new(node))
+        .and_then(move |phase| match phase {
+            Some(Phase::Draft) => is_ancestor_of_bookmark(repo, repo_generation, skiplist, node),
+            _ => future::ok(true).boxify(),
+        })
+        .boxify()
+}
+
+pub fn get_phase(
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    skiplist: SkiplistIndex,
+    node: NodeHash,
+) -> BoxFuture<Phase, Error> {
+    is_public(repo, repo_generation, skiplist, node)
+        .map(|public| if public { Phase::Public } else { Phase::Draft })
+        .boxify()
+}
+
+/// Whether `node` is an ancestor of (or the same as) the value of any bookmark. Scratch
+/// bookmarks don't publish anything, so they aren't looked at. Each bookmark is checked by
+/// jumping along the skip edges of `skiplist`, which is warmed with the bookmarks when the
+/// server starts.
+fn is_ancestor_of_bookmark(
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    skiplist: SkiplistIndex,
+    node: NodeHash,
+) -> BoxFuture<bool, Error> {
+    repo.get_bookmarks()
+        .and_then(move |(_, csid)| {
+            skiplist.is_ancestor(&repo, repo_generation.clone(), node, csid.into_nodehash())
+        })
+        .filter(|is_ancestor| *is_ancestor)
+        .into_future()
+        .map(|(found, _)| found.is_some())
+        .map_err(|(err, _)| err)
+        .boxify()
+}
+
+/// Draft changesets that are ancestors of the given heads, with their parents, and the public
+/// changesets right below them.
+struct DraftAncestors {
+    drafts: HashMap<NodeHash, Vec<NodeHash>>,
+    public: Vec<NodeHash>,
+}
+
+type DraftWalkState = (Vec<NodeHash>, HashSet<NodeHash>, DraftAncestors);
+
+/// Walk the draft ancestors of `heads`. Draft changesets are rare and are never far from public
+/// ones, so the walk is short.
+fn draft_ancestors(
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    skiplist: SkiplistIndex,
+    heads: Vec<NodeHash>,
+) -> BoxFuture<DraftAncestors, Error> {
+    let result = DraftAncestors {
+        drafts: HashMap::new(),
+        public: Vec::new(),
+    };
+
+    future::loop_fn(
+        (heads, HashSet::new(), result),
+        move |(nodes, mut seen, mut result): DraftWalkState| {
+            let nodes: Vec<_> = nodes
+                .into_iter()
+                .filter(|n| *n != NULL_HASH && seen.insert(*n))
+                .collect();
+            if nodes.is_empty() {
+                return future::ok(Loop::Break(result)).boxify();
+            }
+
+            let phases = nodes.into_iter().map({
+                let repo = repo.clone();
+                let repo_generation = repo_generation.clone();
+                let skiplist = skiplist.clone();
+                move |node| {
+                    let repo = repo.clone();
+                    let phase = get_phase(
+                        repo.clone(),
+                        repo_generation.clone(),
+                        skiplist.clone(),
+                        node,
+                    );
+                    phase.and_then(
+                        move |phase| match phase {
+                            Phase::Public => future::ok((node, None)).boxify(),
+                            Phase::Draft => repo.get_changeset_by_changesetid(
+                                &HgChangesetId::new(node),
+                            ).map(move |cs| (node, Some(cs.parents().into_iter().collect())))
+                                .boxify(),
+                        },
+                    )
+                }
+            });
+
+            future::join_all(phases)
+                .map(move |phases| {
+                    let mut next = Vec::new();
+                    for (node, parents) in phases {
+                        match parents {
+                            None => result.public.push(node),
+                            Some(parents) => {
+                                next.extend(&parents);
+                                result.drafts.insert(node, parents);
+                            }
+                        }
+                    }
+                    Loop::Continue((next, seen, result))
+                })
+                .boxify()
+        },
+    ).boxify()
+}
+
+/// Heads of the public and the draft ancestors of `heads`, for the phase-heads part
+pub fn phase_heads(
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    skiplist: SkiplistIndex,
+    heads: Vec<NodeHash>,
+) -> BoxFuture<Vec<(Phase, NodeHash)>, Error> {
+    draft_ancestors(repo, repo_generation, skiplist, heads.clone())
+        .map(move |ancestors| {
+            let mut phase_heads: Vec<_> = ancestors
+                .public
+                .into_iter()
+                .map(|node| (Phase::Public, node))
+                .collect();
+            phase_heads.extend(
+                heads
+                    .into_iter()
+                    .filter(|head| ancestors.drafts.contains_key(head))
+                    .map(|head| (Phase::Draft, head)),
+            );
+            phase_heads
+        })
+        .boxify()
+}
+
+/// Content of the phases listkeys namespace: the roots of the draft changesets, with their
+/// phase. Like any non-publishing server, the `publishing` key isn't set. Draft changesets are
+/// only reachable from the scratch bookmarks when they were pushed with infinitepush, so those
+/// are walked as well as the heads.
+pub fn listkeys(
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    skiplist: SkiplistIndex,
+) -> BoxFuture<HashMap<Vec<u8>, Vec<u8>>, Error> {
+    let scratch_heads = repo.get_scratch_bookmarks_with_prefix(&b"")
+        .map(|(_, csid)| csid.into_nodehash());
+    repo.get_heads()
+        .select(scratch_heads)
+        .collect()
+        .and_then(move |heads| draft_ancestors(repo, repo_generation, skiplist, heads))
+        .map(|ancestors| {
+            let drafts = &ancestors.drafts;
+            drafts
+                .iter()
+                .filter(|&(_, parents)| !parents.iter().any(|p| drafts.contains_key(p)))
+                .map(|(root, _)| {
+                    let root: Vec<u8> = root.to_hex().into();
+                    let phase = Phase::Draft.to_hg().to_string().into_bytes();
+                    (root, phase)
+                })
+                .collect()
+        })
+        .boxify()
+}
+
+/// Handle a pushkey in the phases namespace. Like in Mercurial, changesets can only be made more
+/// public, and the result is whether the changeset ends up in the `new` phase.
+pub fn pushkey(
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    skiplist: SkiplistIndex,
+    node: NodeHash,
+    old: Phase,
+    new: Phase,
+) -> BoxFuture<bool, Error> {
+    get_phase(repo.clone(), repo_generation, skiplist, node)
+        .and_then(move |current| {
+            if current == new {
+                future::ok(true).boxify()
+            } else if current == old && new < old {
+                repo.make_public(vec![node]).map(|()| true).boxify()
+            } else {
+                future::ok(false).boxify()
+            }
+        })
+        .boxify()
+}
+
+/// Parse a phase as it's sent in pushkeys
+pub fn parse_phase(phase: &[u8]) -> Result<Phase> {
+    let phase = String::from_utf8_lossy(phase);
+    let phase = phase
+        .trim()
+        .parse()
+        .map_err(|_| format_err!("invalid phase {}", phase))?;
+    Ok(Phase::from_hg(phase)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use linear;
+
+    fn nodehash(hash: &str) -> NodeHash {
+        hash.parse().expect("Invalid node hash")
+    }
+
+    #[test]
+    fn listkeys_scratch_bookmark() {
+        let repo = Arc::new(linear::getrepo(None));
+        let draft = nodehash("eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b");
+        let csid = HgChangesetId::new(draft);
+
+        let roots = listkeys(repo.clone(), RepoGenCache::new(10), SkiplistIndex::new())
+            .wait()
+            .expect("listkeys failed");
+        assert!(roots.is_empty());
+
+        repo.record_phase(&csid, Phase::Draft)
+            .wait()
+            .expect("record_phase failed");
+        repo.set_scratch_bookmark(b"scratch/test".to_vec(), Some(csid))
+            .wait()
+            .expect("set_scratch_bookmark failed");
+
+        let roots = listkeys(repo, RepoGenCache::new(10), SkiplistIndex::new())
+            .wait()
+            .expect("listkeys failed");
+        let root: Vec<u8> = draft.to_hex().into();
+        let expected: HashMap<_, _> = vec![(root, b"1".to_vec())].into_iter().collect();
+        assert_eq!(roots, expected);
+    }
+}
@@ -6,23 +6,25 @@
 
 //! Construct generation numbers for changesets within a repo
 //!
-//! A generation number for a changeset is 1 + max(parents, 0). This number is computed for each
-//! changeset and memoized for efficiency.
+//! A generation number for a changeset is 1 + max(parents, 0). This number is stored in the
+//! changesets table for each complete changeset, computed from the parents for any other
+//! changeset, and memoized for efficiency.
 
 use std::sync::Arc;
 use std::usize;
 
-use failure::{err_msg, Error};
+use failure::Error;
 use futures::IntoFuture;
-use futures::future::{Either, Future};
+use futures::future::{join_all, Either, Future};
 
-use futures_ext::FutureExt;
+use futures_ext::{BoxFuture, FutureExt};
 
 use asyncmemo::{Asyncmemo, Filler};
 use blobrepo::BlobRepo;
 use mercurial_types::{HgChangesetId, NodeHash, NULL_HASH};
 
 use nodehashkey::Key;
+use ptrwrap::PtrWrap;
 
 /// Generation number
 ///
@@ -31,6 +33,13 @@ use nodehashkey::Key;
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, HeapSizeOf)]
 pub struct Generation(u64);
 
+impl Generation {
+    /// The generation `distance` generations below this one, or 0 if there is none
+    pub fn below(self, distance: u64) -> Generation {
+        Generation(self.0.saturating_sub(distance))
+    }
+}
+
 /// Cache of generation numbers
 ///
 /// Allows generation numbers for a changeset to be computed lazily and cached.
@@ -66,6 +75,36 @@ impl RepoGenCache {
             Either::B(self.cache.get((repo, nodeid.clone())))
         }
     }
+
+    /// Get a `Future` for the `Generation` numbers of several changesets in a repo, in the same
+    /// order. Generation numbers in the changesets table are read with a single query and added
+    /// to the cache, and only the others are computed through the cache.
+    pub fn get_many(
+        &self,
+        repo: &Arc<BlobRepo>,
+        nodeids: Vec<NodeHash>,
+    ) -> impl Future<Item = Vec<Generation>, Error = Error> + Send {
+        let cs_ids: Vec<_> = nodeids
+            .iter()
+            .filter(|nodeid| **nodeid != NULL_HASH)
+            .map(|nodeid| HgChangesetId::new(*nodeid))
+            .collect();
+        let stored = repo.get_generation_numbers(&cs_ids);
+        let this = self.clone();
+        let repo = repo.clone();
+
+        stored.and_then(move |stored| {
+            for (cs_id, gen) in &stored {
+                this.cache.insert((&repo, cs_id.into_nodehash()), Generation(*gen));
+            }
+            join_all(nodeids.into_iter().map(move |nodeid| {
+                match stored.get(&HgChangesetId::new(nodeid)) {
+                    Some(gen) => Either::A(Ok(Generation(*gen)).into_future()),
+                    None => Either::B(this.get(&repo, nodeid)),
+                }
+            }))
+        })
+    }
 }
 
 pub struct GenFiller {}
@@ -82,13 +121,46 @@ impl Filler for GenFiller {
 
     fn fill(
         &self,
-        _cache: &Asyncmemo<Self>,
+        cache: &Asyncmemo<Self>,
         &Key(ref repo, ref nodeid): &Self::Key,
     ) -> Self::Value {
         let cs = HgChangesetId::new(*nodeid);
-        repo.get_generation_number(&cs)
-            .and_then(move |genopt| genopt.ok_or_else(|| err_msg(format!("{} not found", cs))))
-            .map(|gen| Generation(gen))
+        let cache = cache.clone();
+        let repo = repo.clone();
+        let stored = repo.get_generation_number(&cs);
+
+        stored
+            .and_then(move |genopt| match genopt {
+                Some(gen) => Either::A(Ok(Generation(gen)).into_future()),
+                // Changesets are only stored once they're complete, so fall back to the parents
+                None => Either::B(compute_generation(cache, repo, cs)),
+            })
             .boxify()
     }
 }
+
+/// Compute the generation number of a changeset that isn't in the changesets table from the
+/// generation numbers of its parents, which are looked up together with `get_many`.
+fn compute_generation(
+    cache: Asyncmemo<GenFiller>,
+    repo: PtrWrap<BlobRepo>,
+    cs: HgChangesetId,
+) -> BoxFuture<Generation, Error> {
+    let parents = repo.get_changeset_parents(&cs);
+
+    parents
+        .and_then(move |parents| {
+            let parents = parents.into_iter().map(|parent| parent.into_nodehash()).collect();
+            let repo: &Arc<BlobRepo> = repo.as_ref();
+            RepoGenCache { cache }.get_many(repo, parents)
+        })
+        .map(|parent_gens| {
+            let max_parent_gen = parent_gens
+                .into_iter()
+                .map(|Generation(gen)| gen)
+                .max()
+                .unwrap_or(0);
+            Generation(max_parent_gen + 1)
+        })
+        .boxify()
+}
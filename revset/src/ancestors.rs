@@ -13,83 +13,138 @@ use std::collections::hash_set::IntoIter;
 use std::sync::Arc;
 
 use futures::{Async, Poll};
-use futures::future::Future;
-use futures::stream::{iter_ok, Stream};
+use futures::future::{self, join_all, Future};
+use futures::stream::{self, iter_ok, Stream};
+use futures_ext::{FutureExt, StreamExt};
 
 use blobrepo::BlobRepo;
-use mercurial_types::{Changeset, NodeHash};
-use mercurial_types::nodehash::HgChangesetId;
+use mercurial_types::NodeHash;
 use repoinfo::{Generation, RepoGenCache};
 
 use IntersectNodeStream;
 use NodeStream;
 use errors::*;
+use ordered::{limit, OrderedNodeStream};
+use skiplist::{fetch_parents, SkiplistIndex};
 
 pub struct AncestorsNodeStream {
     repo: Arc<BlobRepo>,
     repo_generation: RepoGenCache,
+    skiplist: Option<SkiplistIndex>,
     next_generation: BTreeMap<Generation, HashSet<NodeHash>>,
     pending_changesets: Box<Stream<Item = (NodeHash, Generation), Error = Error> + Send>,
     drain: IntoIter<NodeHash>,
+    drain_generation: Option<Generation>,
 }
 
 fn make_pending(
     repo: Arc<BlobRepo>,
     repo_generation: RepoGenCache,
+    skiplist: Option<SkiplistIndex>,
     hashes: IntoIter<NodeHash>,
 ) -> Box<Stream<Item = (NodeHash, Generation), Error = Error> + Send> {
     let size = hashes.size_hint().0;
-    let new_repo = repo.clone();
 
     Box::new(
         iter_ok::<_, Error>(hashes)
             .map(move |hash| {
-                new_repo
-                    .get_changeset_by_changesetid(&HgChangesetId::new(hash))
-                    .map(|cs| cs.parents().clone())
-                    .map_err(|err| err.context(ErrorKind::ParentsFetchFailed).into())
+                // Indexed nodes already know their parents and their generations
+                match skiplist.as_ref().and_then(|skiplist| skiplist.get_parents(&hash)) {
+                    Some(parents) => future::ok(parents).boxify(),
+                    None => fetch_parents(&repo, repo_generation.clone(), hash),
+                }
             })
             .buffered(size)
             .map(|parents| iter_ok::<_, Error>(parents.into_iter()))
-            .flatten()
-            .and_then(move |node_hash| {
-                repo_generation
-                    .get(&repo, node_hash)
-                    .map(move |gen_id| (node_hash, gen_id))
-                    .map_err(|err| err.context(ErrorKind::GenerationFetchFailed).into())
-            }),
+            .flatten(),
     )
 }
 
 impl AncestorsNodeStream {
     pub fn new(repo: &Arc<BlobRepo>, repo_generation: RepoGenCache, hash: NodeHash) -> Self {
-        let node_set: HashSet<NodeHash> = hashset!{hash};
+        Self::new_with_skiplist(repo, repo_generation, None, hash)
+    }
+
+    /// Like `new`, but takes the parents of nodes in `skiplist` from the index instead of
+    /// fetching their changesets
+    pub fn with_skiplist(
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        skiplist: SkiplistIndex,
+        hash: NodeHash,
+    ) -> Self {
+        Self::new_with_skiplist(repo, repo_generation, Some(skiplist), hash)
+    }
+
+    /// The ancestors of any of `hashes`, in one walk. Ancestors that are shared by several of
+    /// `hashes` are only output once.
+    pub fn from_heads(
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        hashes: Vec<NodeHash>,
+    ) -> Self {
+        let starts = iter_ok::<_, Error>(hashes).and_then({
+            let repo = repo.clone();
+            let repo_generation = repo_generation.clone();
+            move |hash| {
+                repo_generation
+                    .get(&repo, hash)
+                    .map(move |generation| (hash, generation))
+                    .map_err(|err| err.context(ErrorKind::GenerationFetchFailed))
+                    .from_err()
+            }
+        });
         AncestorsNodeStream {
             repo: repo.clone(),
-            repo_generation: repo_generation.clone(),
+            repo_generation,
+            skiplist: None,
             next_generation: BTreeMap::new(),
-            pending_changesets: make_pending(
-                repo.clone(),
-                repo_generation,
-                node_set.clone().into_iter(),
-            ),
-            drain: node_set.into_iter(),
+            pending_changesets: Box::new(starts),
+            drain: HashSet::new().into_iter(),
+            drain_generation: None,
+        }
+    }
+
+    fn new_with_skiplist(
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        skiplist: Option<SkiplistIndex>,
+        hash: NodeHash,
+    ) -> Self {
+        // The starting node goes through the same path as the parents, so that its generation
+        // is known when it is output
+        let start = repo_generation
+            .get(repo, hash)
+            .map(move |generation| (hash, generation))
+            .map_err(|err| err.context(ErrorKind::GenerationFetchFailed))
+            .from_err();
+        AncestorsNodeStream {
+            repo: repo.clone(),
+            repo_generation,
+            skiplist,
+            next_generation: BTreeMap::new(),
+            pending_changesets: Box::new(start.into_stream()),
+            drain: HashSet::new().into_iter(),
+            drain_generation: None,
         }
     }
 
     pub fn boxed(self) -> Box<NodeStream> {
         Box::new(self)
     }
-}
 
-impl Stream for AncestorsNodeStream {
-    type Item = NodeHash;
-    type Error = Error;
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    /// The ancestors with their generations, which are known while walking them
+    pub fn ordered(self) -> Box<OrderedNodeStream> {
+        let mut ancestors = self;
+        Box::new(stream::poll_fn(move || ancestors.poll_ordered()))
+    }
+
+    fn poll_ordered(&mut self) -> Poll<Option<(NodeHash, Generation)>, Error> {
         // Empty the drain if any - return all items for this generation
-        let next_in_drain = self.drain.next();
-        if next_in_drain.is_some() {
-            return Ok(Async::Ready(next_in_drain));
+        if let Some(generation) = self.drain_generation {
+            if let Some(hash) = self.drain.next() {
+                return Ok(Async::Ready(Some((hash, generation))));
+            }
         }
 
         // Wait until we've drained pending_changesets - we can't continue until we know about all
@@ -122,12 +177,24 @@ impl Stream for AncestorsNodeStream {
         self.pending_changesets = make_pending(
             self.repo.clone(),
             self.repo_generation.clone(),
+            self.skiplist.clone(),
             current_generation.clone().into_iter(),
         );
         self.drain = current_generation.into_iter();
-        Ok(Async::Ready(Some(self.drain.next().expect(
-            "Cannot create a generation without at least one node hash",
-        ))))
+        self.drain_generation = Some(highest_generation);
+        let hash = self.drain
+            .next()
+            .expect("Cannot create a generation without at least one node hash");
+        Ok(Async::Ready(Some((hash, highest_generation))))
+    }
+}
+
+impl Stream for AncestorsNodeStream {
+    type Item = NodeHash;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let next = self.poll_ordered()?;
+        Ok(next.map(|next| next.map(|(hash, _)| hash)))
     }
 }
 
@@ -141,9 +208,9 @@ where
 {
     let nodes_iter = nodes.into_iter().map({
         let repo_generation = repo_generation.clone();
-        move |node| AncestorsNodeStream::new(repo, repo_generation.clone(), node).boxed()
+        move |node| AncestorsNodeStream::new(repo, repo_generation.clone(), node).ordered()
     });
-    IntersectNodeStream::new(repo, repo_generation, nodes_iter).boxed()
+    IntersectNodeStream::from_ordered(nodes_iter).boxed()
 }
 
 pub fn greatest_common_ancestor<I>(
@@ -154,12 +221,85 @@ pub fn greatest_common_ancestor<I>(
 where
     I: IntoIterator<Item = NodeHash>,
 {
-    Box::new(common_ancestors(repo, repo_generation, nodes).take(1))
+    limit(common_ancestors(repo, repo_generation, nodes), 1)
+}
+
+/// Like `common_ancestors`, but only walks the ancestors of the node with the lowest
+/// generation. Every ancestor it outputs is checked against the other nodes by jumping along the
+/// skip edges of `skiplist`, so the linear history above the lowest node isn't walked.
+pub fn common_ancestors_with_skiplist<I>(
+    repo: &Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    skiplist: SkiplistIndex,
+    nodes: I,
+) -> Box<NodeStream>
+where
+    I: IntoIterator<Item = NodeHash>,
+{
+    let nodes: Vec<_> = nodes.into_iter().collect();
+    let indexed = join_all(
+        nodes
+            .iter()
+            .map(|node| skiplist.add_node(repo, repo_generation.clone(), *node))
+            .collect::<Vec<_>>(),
+    );
+    let generations = repo_generation
+        .get_many(repo, nodes.clone())
+        .map_err(|err| err.context(ErrorKind::GenerationFetchFailed).into());
+
+    let repo = repo.clone();
+    Box::new(
+        indexed
+            .join(generations)
+            .map(move |(_, generations)| {
+                let mut heads: Vec<_> = nodes.into_iter().zip(generations).collect();
+                heads.sort_by_key(|&(_, gen_id)| gen_id);
+                if heads.is_empty() {
+                    return stream::empty().boxify();
+                }
+                let (lowest, _) = heads.remove(0);
+                let ancestors = AncestorsNodeStream::with_skiplist(
+                    &repo,
+                    repo_generation,
+                    skiplist.clone(),
+                    lowest,
+                );
+                ancestors
+                    .ordered()
+                    .and_then(move |(hash, gen_id)| {
+                        let common = skiplist.is_common_ancestor(hash, gen_id, &heads)?;
+                        Ok(if common { Some(hash) } else { None })
+                    })
+                    .filter_map(|hash| hash)
+                    .boxify()
+            })
+            .flatten_stream(),
+    )
+}
+
+/// Like `greatest_common_ancestor`, but jumps over linear history with `skiplist` instead of
+/// walking every generation
+pub fn greatest_common_ancestor_with_skiplist<I>(
+    repo: &Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    skiplist: SkiplistIndex,
+    nodes: I,
+) -> Box<NodeStream>
+where
+    I: IntoIterator<Item = NodeHash>,
+{
+    Box::new(
+        skiplist
+            .greatest_common_ancestors(repo, repo_generation, nodes)
+            .map(|ancestors| iter_ok(ancestors.into_iter().take(1)))
+            .flatten_stream(),
+    )
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use futures::executor::spawn;
     use linear;
     use merge_uneven;
     use tests::assert_node_sequence;
@@ -250,6 +390,35 @@ mod test {
             nodestream,
         )
     }
+
+    #[test]
+    fn ancestors_from_heads() {
+        let repo = Arc::new(merge_uneven::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        // The second head is an ancestor of the first one, so the walks overlap
+        let nodestream = AncestorsNodeStream::from_heads(
+            &repo,
+            repo_generation.clone(),
+            vec![
+                string_to_nodehash("16839021e338500b3cf7c9b871c8a07351697d68"),
+                string_to_nodehash("3cda5c78aa35f0f5b09780d971197b51cad4613a"),
+            ],
+        ).boxed();
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("16839021e338500b3cf7c9b871c8a07351697d68"),
+                string_to_nodehash("1d8a907f7b4bf50c6a09c16361e2205047ecc5e5"),
+                string_to_nodehash("3cda5c78aa35f0f5b09780d971197b51cad4613a"),
+                string_to_nodehash("15c40d0abc36d47fb51c8eaec51ac7aad31f669c"),
+            ],
+            nodestream,
+        )
+    }
+
     #[test]
     fn unshared_merge_all() {
         // The unshared_merge_uneven fixture has a commit after the merge. Pull in everything
@@ -401,4 +570,87 @@ mod test {
             nodestream,
         );
     }
+
+    #[test]
+    fn linear_ancestors_with_skiplist() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+        let skiplist = SkiplistIndex::new();
+
+        let head = string_to_nodehash("3c15267ebf11807f3d772eb891272b911ec68759");
+        spawn(skiplist.add_node(&repo, repo_generation.clone(), head))
+            .wait_future()
+            .expect("indexing failed");
+
+        let nodestream = AncestorsNodeStream::with_skiplist(
+            &repo,
+            repo_generation.clone(),
+            skiplist,
+            string_to_nodehash("cb15ca4a43a59acff5388cea9648c162afde8372"),
+        ).boxed();
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("cb15ca4a43a59acff5388cea9648c162afde8372"),
+                string_to_nodehash("d0a361e9022d226ae52f689667bd7d212a19cfe0"),
+                string_to_nodehash("607314ef579bd2407752361ba1b0c1729d08b281"),
+                string_to_nodehash("3e0e761030db6e479a7fb58b12881883f9f8c63f"),
+                string_to_nodehash("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536"),
+            ],
+            nodestream,
+        )
+    }
+
+    #[test]
+    fn greatest_common_ancestor_with_skiplist_different_branches() {
+        let repo = Arc::new(merge_uneven::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = greatest_common_ancestor_with_skiplist(
+            &repo,
+            repo_generation.clone(),
+            SkiplistIndex::new(),
+            vec![
+                string_to_nodehash("4f7f3fd428bec1a48f9314414b063c706d9c1aed"),
+                string_to_nodehash("3cda5c78aa35f0f5b09780d971197b51cad4613a"),
+            ],
+        );
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("15c40d0abc36d47fb51c8eaec51ac7aad31f669c"),
+            ],
+            nodestream,
+        );
+    }
+
+    #[test]
+    fn all_common_ancestors_with_skiplist_same_branch() {
+        let repo = Arc::new(merge_uneven::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = common_ancestors_with_skiplist(
+            &repo,
+            repo_generation.clone(),
+            SkiplistIndex::new(),
+            vec![
+                string_to_nodehash("4f7f3fd428bec1a48f9314414b063c706d9c1aed"),
+                string_to_nodehash("264f01429683b3dd8042cb3979e8bf37007118bc"),
+            ],
+        );
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("4f7f3fd428bec1a48f9314414b063c706d9c1aed"),
+                string_to_nodehash("b65231269f651cfe784fd1d97ef02a049a37b8a0"),
+                string_to_nodehash("d7542c9db7f4c77dab4b315edd328edf1514952f"),
+                string_to_nodehash("15c40d0abc36d47fb51c8eaec51ac7aad31f669c"),
+            ],
+            nodestream,
+        );
+    }
 }
@@ -0,0 +1,278 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// The descendants of a node are itself, plus the union of all descendants of all children.
+// Children only come from the children index, so the whole set has to be found before the first
+// node can be output from the highest generation, like the other node streams do.
+
+use std::collections::{BTreeMap, HashSet};
+use std::collections::hash_set::IntoIter;
+use std::mem::replace;
+use std::sync::Arc;
+
+use futures::{Async, Poll};
+use futures::future::Future;
+use futures::stream::{self, iter_ok, Stream};
+
+use blobrepo::BlobRepo;
+use mercurial_types::NodeHash;
+use mercurial_types::nodehash::HgChangesetId;
+use repoinfo::{Generation, RepoGenCache};
+
+use NodeStream;
+use errors::*;
+
+pub struct DescendantsNodeStream {
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    // Descendants found so far
+    seen: HashSet<NodeHash>,
+    // Descendants whose children haven't been looked up yet
+    next_nodes: Vec<NodeHash>,
+    pending_children: Box<Stream<Item = (NodeHash, Generation), Error = Error> + Send>,
+    // Set once all the descendants are found
+    output_nodes: Option<BTreeMap<Generation, HashSet<NodeHash>>>,
+    found_nodes: BTreeMap<Generation, HashSet<NodeHash>>,
+    drain: IntoIter<NodeHash>,
+}
+
+fn make_pending(
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    hashes: Vec<NodeHash>,
+) -> Box<Stream<Item = (NodeHash, Generation), Error = Error> + Send> {
+    let size = hashes.len();
+    let new_repo = repo.clone();
+
+    Box::new(
+        iter_ok::<_, Error>(hashes)
+            .map(move |hash| {
+                new_repo
+                    .get_children(&HgChangesetId::new(hash))
+                    .map_err(|err| err.context(ErrorKind::ChildrenFetchFailed).into())
+            })
+            .buffered(size)
+            .map(|children| iter_ok::<_, Error>(children.into_iter()))
+            .flatten()
+            .and_then(move |changesetid| {
+                let node_hash = changesetid.into_nodehash();
+                repo_generation
+                    .get(&repo, node_hash)
+                    .map(move |gen_id| (node_hash, gen_id))
+                    .map_err(|err| err.context(ErrorKind::GenerationFetchFailed).into())
+            }),
+    )
+}
+
+impl DescendantsNodeStream {
+    pub fn new(repo: &Arc<BlobRepo>, repo_generation: RepoGenCache, hash: NodeHash) -> Self {
+        // The node is its own first descendant
+        let start = repo_generation
+            .get(repo, hash)
+            .map(move |gen_id| (hash, gen_id))
+            .map_err(|err| err.context(ErrorKind::GenerationFetchFailed).into())
+            .into_stream();
+
+        DescendantsNodeStream {
+            repo: repo.clone(),
+            repo_generation,
+            seen: HashSet::new(),
+            next_nodes: Vec::new(),
+            pending_children: Box::new(start),
+            output_nodes: None,
+            found_nodes: BTreeMap::new(),
+            drain: HashSet::new().into_iter(),
+        }
+    }
+
+    pub fn boxed(self) -> Box<NodeStream> {
+        Box::new(self)
+    }
+}
+
+impl Stream for DescendantsNodeStream {
+    type Item = NodeHash;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // Empty the drain if any - return all items for this generation
+        let next_in_drain = self.drain.next();
+        if next_in_drain.is_some() {
+            return Ok(Async::Ready(next_in_drain));
+        }
+
+        // Stage 1 - walk down the children until there are no new nodes
+        while self.output_nodes.is_none() {
+            match self.pending_children.poll()? {
+                Async::Ready(Some((hash, generation))) => if self.seen.insert(hash) {
+                    self.found_nodes
+                        .entry(generation)
+                        .or_insert_with(HashSet::new)
+                        .insert(hash);
+                    self.next_nodes.push(hash);
+                },
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(None) => if self.next_nodes.is_empty() {
+                    self.output_nodes = Some(replace(&mut self.found_nodes, BTreeMap::new()));
+                } else {
+                    let next_nodes = replace(&mut self.next_nodes, Vec::new());
+                    self.pending_children = make_pending(
+                        self.repo.clone(),
+                        self.repo_generation.clone(),
+                        next_nodes,
+                    );
+                },
+            }
+        }
+
+        // Stage 2 - output the descendants from the highest generation to the lowest
+        let current_generation = match self.output_nodes {
+            Some(ref mut nodes) => {
+                let highest_generation = match nodes.keys().next_back() {
+                    Some(generation) => *generation,
+                    None => return Ok(Async::Ready(None)),
+                };
+                nodes
+                    .remove(&highest_generation)
+                    .expect("Highest generation doesn't exist")
+            }
+            None => panic!("No output_nodes"),
+        };
+        self.drain = current_generation.into_iter();
+        Ok(Async::Ready(Some(self.drain.next().expect(
+            "Cannot create a generation without at least one node hash",
+        ))))
+    }
+}
+
+/// The changesets that have one of `nodes` as a parent, in generation order
+pub fn children<I>(
+    repo: &Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    nodes: I,
+) -> Box<NodeStream>
+where
+    I: IntoIterator<Item = NodeHash>,
+{
+    let nodes: Vec<_> = nodes.into_iter().collect();
+    if nodes.is_empty() {
+        return Box::new(stream::empty());
+    }
+
+    let by_generation: BTreeMap<Generation, HashSet<NodeHash>> = BTreeMap::new();
+    Box::new(
+        make_pending(repo.clone(), repo_generation, nodes)
+            .fold(by_generation, |mut by_generation, (hash, generation)| {
+                by_generation
+                    .entry(generation)
+                    .or_insert_with(HashSet::new)
+                    .insert(hash);
+                Ok::<_, Error>(by_generation)
+            })
+            .map(|by_generation| {
+                iter_ok(
+                    by_generation
+                        .into_iter()
+                        .rev()
+                        .flat_map(|(_, hashes)| hashes.into_iter()),
+                )
+            })
+            .flatten_stream(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use linear;
+    use merge_uneven;
+    use tests::assert_node_sequence;
+    use tests::string_to_nodehash;
+
+    #[test]
+    fn linear_descendants() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = DescendantsNodeStream::new(
+            &repo,
+            repo_generation.clone(),
+            string_to_nodehash("0ed509bf086fadcb8a8a5384dc3b550729b0fc17"),
+        ).boxed();
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("a5ffa77602a066db7d5cfb9fb5823a0895717c5a"),
+                string_to_nodehash("3c15267ebf11807f3d772eb891272b911ec68759"),
+                string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+                string_to_nodehash("0ed509bf086fadcb8a8a5384dc3b550729b0fc17"),
+            ],
+            nodestream,
+        )
+    }
+
+    #[test]
+    fn linear_head_descendants() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let head = string_to_nodehash("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+        let nodestream = DescendantsNodeStream::new(&repo, repo_generation.clone(), head).boxed();
+
+        assert_node_sequence(repo_generation, &repo, vec![head], nodestream)
+    }
+
+    #[test]
+    fn merge_descendants() {
+        let repo = Arc::new(merge_uneven::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = DescendantsNodeStream::new(
+            &repo,
+            repo_generation.clone(),
+            string_to_nodehash("3cda5c78aa35f0f5b09780d971197b51cad4613a"),
+        ).boxed();
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("75742e6fc286a359b39a89fdfa437cc7e2a0e1ce"),
+                string_to_nodehash("16839021e338500b3cf7c9b871c8a07351697d68"),
+                string_to_nodehash("1d8a907f7b4bf50c6a09c16361e2205047ecc5e5"),
+                string_to_nodehash("3cda5c78aa35f0f5b09780d971197b51cad4613a"),
+            ],
+            nodestream,
+        )
+    }
+
+    #[test]
+    fn merge_children() {
+        let repo = Arc::new(merge_uneven::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = children(
+            &repo,
+            repo_generation.clone(),
+            vec![
+                string_to_nodehash("15c40d0abc36d47fb51c8eaec51ac7aad31f669c"),
+                string_to_nodehash("16839021e338500b3cf7c9b871c8a07351697d68"),
+            ],
+        );
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("75742e6fc286a359b39a89fdfa437cc7e2a0e1ce"),
+                string_to_nodehash("3cda5c78aa35f0f5b09780d971197b51cad4613a"),
+                string_to_nodehash("d7542c9db7f4c77dab4b315edd328edf1514952f"),
+            ],
+            nodestream,
+        )
+    }
+}
@@ -6,11 +6,19 @@
 
 pub use failure::{Error, Result};
 
-use mercurial_types::NodeHash;
+use mercurial_types::{MPath, NodeHash};
 
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "repo error checking for node: {}", _0)] RepoError(NodeHash),
     #[fail(display = "could not fetch node generation")] GenerationFetchFailed,
     #[fail(display = "failed to fetch parent nodes")] ParentsFetchFailed,
+    #[fail(display = "failed to fetch child nodes")] ChildrenFetchFailed,
+    #[fail(display = "failed to parse revset: {}", _0)] RevsetParseError(String),
+    #[fail(display = "unknown revset function {}", _0)] UnknownRevsetFunction(String),
+    #[fail(display = "unknown revision {}", _0)] UnknownRevision(String),
+    #[fail(display = "node {} is missing from the skiplist index", _0)]
+    SkiplistIndexMissing(NodeHash),
+    #[fail(display = "range has more than {} nodes left to check", _0)] RangeTooWide(usize),
+    #[fail(display = "path {} not found in {}", _0, _1)] PathNotFound(MPath, NodeHash),
 }
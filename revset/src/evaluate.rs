@@ -0,0 +1,536 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::{HashSet, VecDeque};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use futures::future::{self, Future};
+use futures::stream::{self, Stream};
+
+use blobrepo::BlobRepo;
+use mercurial_types::NodeHash;
+use mercurial_types::nodehash::HgChangesetId;
+use repoinfo::RepoGenCache;
+
+use {children, limit, without_generations, AncestorsNodeStream, DescendantsNodeStream,
+     IntersectNodeStream, NodeStream, OrderedNodeStream, RangeNodeStream, SetDifferenceNodeStream,
+     SingleNodeHash, UnionNodeStream};
+use errors::*;
+use expr::Revset;
+
+type NodesFuture = Box<Future<Item = Vec<NodeHash>, Error = Error> + Send>;
+
+impl Revset {
+    /// The changesets of this revset, from the highest generation to the lowest like the other
+    /// node streams. The stream fails if a symbol is neither a changeset nor a bookmark.
+    pub fn evaluate(
+        &self,
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+    ) -> Box<NodeStream> {
+        match *self {
+            Revset::Symbol(ref name) => Box::new(
+                resolve_symbol(repo, name.clone())
+                    .map(|node| stream::once(Ok(node)))
+                    .flatten_stream(),
+            ),
+            Revset::All => all(repo, repo_generation),
+            Revset::Ancestors(ref revset) => {
+                let nodes = collect(revset.evaluate(repo, repo_generation.clone()));
+                ancestors(repo, repo_generation, nodes)
+            }
+            Revset::Descendants(ref revset) => {
+                let nodes = collect(revset.evaluate(repo, repo_generation.clone()));
+                descendants(repo, repo_generation, nodes)
+            }
+            Revset::Children(ref revset) => {
+                let repo = repo.clone();
+                let nodes = collect(revset.evaluate(&repo, repo_generation.clone()));
+                Box::new(
+                    nodes
+                        .map(move |nodes| children(&repo, repo_generation, nodes))
+                        .flatten_stream(),
+                )
+            }
+            Revset::Range(ref start, ref end) => {
+                let starts = collect(start.evaluate(repo, repo_generation.clone()));
+                let ends = collect(end.evaluate(repo, repo_generation.clone()));
+                range(repo, repo_generation, starts, ends)
+            }
+            Revset::Only(ref revset, ref excluded) => {
+                let nodes = collect(revset.evaluate(repo, repo_generation.clone()));
+                let excluded = collect(excluded.evaluate(repo, repo_generation.clone()));
+                SetDifferenceNodeStream::from_ordered(
+                    ordered_ancestors(repo, repo_generation.clone(), nodes),
+                    ordered_ancestors(repo, repo_generation, excluded),
+                ).boxed()
+            }
+            Revset::Heads(ref revset) => {
+                // The heads of the repo are known without walking all the changesets
+                let nodes: NodesFuture = if **revset == Revset::All {
+                    Box::new(repo.get_heads().collect())
+                } else {
+                    heads(repo, collect(revset.evaluate(repo, repo_generation.clone())))
+                };
+                single_nodes(repo, repo_generation, nodes)
+            }
+            Revset::Roots(ref revset) => {
+                let nodes = roots(repo, collect(revset.evaluate(repo, repo_generation.clone())));
+                single_nodes(repo, repo_generation, nodes)
+            }
+            Revset::Limit(ref revset, n) => oldest(revset.evaluate(repo, repo_generation), n),
+            Revset::Last(ref revset, n) => limit(revset.evaluate(repo, repo_generation), n),
+            Revset::And(ref revset, ref other) => match **other {
+                Revset::Not(ref excluded) => SetDifferenceNodeStream::new(
+                    repo,
+                    repo_generation.clone(),
+                    revset.evaluate(repo, repo_generation.clone()),
+                    excluded.evaluate(repo, repo_generation),
+                ).boxed(),
+                _ => {
+                    let inputs = vec![
+                        revset.evaluate(repo, repo_generation.clone()),
+                        other.evaluate(repo, repo_generation.clone()),
+                    ];
+                    IntersectNodeStream::new(repo, repo_generation, inputs).boxed()
+                }
+            },
+            Revset::Or(ref revset, ref other) => {
+                let inputs = vec![
+                    revset.evaluate(repo, repo_generation.clone()),
+                    other.evaluate(repo, repo_generation.clone()),
+                ];
+                UnionNodeStream::new(repo, repo_generation, inputs).boxed()
+            }
+            Revset::Not(ref excluded) => SetDifferenceNodeStream::new(
+                repo,
+                repo_generation.clone(),
+                all(repo, repo_generation.clone()),
+                excluded.evaluate(repo, repo_generation),
+            ).boxed(),
+        }
+    }
+}
+
+/// The last `n` nodes of the stream, which are the oldest ones. Only those are buffered.
+fn oldest(nodes: Box<NodeStream>, n: usize) -> Box<NodeStream> {
+    Box::new(
+        nodes
+            .fold(VecDeque::new(), move |mut oldest, node| {
+                oldest.push_back(node);
+                if oldest.len() > n {
+                    oldest.pop_front();
+                }
+                Ok::<_, Error>(oldest)
+            })
+            .map(stream::iter_ok)
+            .flatten_stream(),
+    )
+}
+
+/// A changeset hash or, failing that, a bookmark name
+fn resolve_symbol(
+    repo: &Arc<BlobRepo>,
+    name: String,
+) -> Box<Future<Item = NodeHash, Error = Error> + Send> {
+    match NodeHash::from_str(&name) {
+        Ok(node) => Box::new(repo.changeset_exists(&HgChangesetId::new(node)).and_then(
+            move |exists| {
+                if exists {
+                    Ok(node)
+                } else {
+                    Err(ErrorKind::UnknownRevision(name).into())
+                }
+            },
+        )),
+        Err(_) => {
+            let bookmark = repo.get_bookmark_value(&name);
+            Box::new(bookmark.and_then(move |bookmark| match bookmark {
+                Some((changesetid, _)) => Ok(changesetid.into_nodehash()),
+                None => Err(ErrorKind::UnknownRevision(name).into()),
+            }))
+        }
+    }
+}
+
+fn collect(nodes: Box<NodeStream>) -> NodesFuture {
+    Box::new(nodes.collect())
+}
+
+/// The nodes in generation order. The node streams need their inputs in that order, and the
+/// union of single node streams is a cheap way to sort them.
+fn single_nodes(
+    repo: &Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    nodes: NodesFuture,
+) -> Box<NodeStream> {
+    let repo = repo.clone();
+    Box::new(
+        nodes
+            .map(move |nodes| {
+                let inputs: Vec<_> = nodes
+                    .into_iter()
+                    .map(|node| SingleNodeHash::new(node, &repo).boxed())
+                    .collect();
+                UnionNodeStream::new(&repo, repo_generation, inputs)
+            })
+            .flatten_stream(),
+    )
+}
+
+fn all(repo: &Arc<BlobRepo>, repo_generation: RepoGenCache) -> Box<NodeStream> {
+    ancestors(repo, repo_generation, Box::new(repo.get_heads().collect()))
+}
+
+fn ancestors(
+    repo: &Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    nodes: NodesFuture,
+) -> Box<NodeStream> {
+    without_generations(ordered_ancestors(repo, repo_generation, nodes))
+}
+
+/// The ancestors of `nodes` with their generations, so that they can be combined with other
+/// streams without looking the generations up again
+fn ordered_ancestors(
+    repo: &Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    nodes: NodesFuture,
+) -> Box<OrderedNodeStream> {
+    let repo = repo.clone();
+    Box::new(
+        nodes
+            .map(move |nodes| {
+                let inputs: Vec<_> = nodes
+                    .into_iter()
+                    .map(|node| {
+                        AncestorsNodeStream::new(&repo, repo_generation.clone(), node).ordered()
+                    })
+                    .collect();
+                UnionNodeStream::from_ordered(inputs).ordered()
+            })
+            .flatten_stream(),
+    )
+}
+
+fn descendants(
+    repo: &Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    nodes: NodesFuture,
+) -> Box<NodeStream> {
+    let repo = repo.clone();
+    Box::new(
+        nodes
+            .map(move |nodes| {
+                let inputs: Vec<_> = nodes
+                    .into_iter()
+                    .map(|node| {
+                        DescendantsNodeStream::new(&repo, repo_generation.clone(), node).boxed()
+                    })
+                    .collect();
+                UnionNodeStream::new(&repo, repo_generation, inputs)
+            })
+            .flatten_stream(),
+    )
+}
+
+fn range(
+    repo: &Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    starts: NodesFuture,
+    ends: NodesFuture,
+) -> Box<NodeStream> {
+    let repo = repo.clone();
+    Box::new(
+        starts
+            .join(ends)
+            .map(move |(starts, ends)| {
+                let mut inputs = Vec::new();
+                for start in starts {
+                    for end in &ends {
+                        inputs.push(
+                            RangeNodeStream::new(&repo, repo_generation.clone(), start, *end)
+                                .boxed(),
+                        );
+                    }
+                }
+                UnionNodeStream::new(&repo, repo_generation, inputs)
+            })
+            .flatten_stream(),
+    )
+}
+
+fn with_parents(
+    repo: &Arc<BlobRepo>,
+    nodes: NodesFuture,
+) -> Box<Future<Item = Vec<(NodeHash, Vec<NodeHash>)>, Error = Error> + Send> {
+    let repo = repo.clone();
+    Box::new(nodes.and_then(move |nodes| {
+        future::join_all(nodes.into_iter().map(move |node| {
+            repo.get_changeset_parents(&HgChangesetId::new(node))
+                .map(move |parents| {
+                    let parents = parents.into_iter().map(|parent| parent.into_nodehash());
+                    (node, parents.collect())
+                })
+                .map_err(|err| err.context(ErrorKind::ParentsFetchFailed).into())
+        }))
+    }))
+}
+
+/// The nodes that aren't parents of any of the other nodes
+fn heads(repo: &Arc<BlobRepo>, nodes: NodesFuture) -> NodesFuture {
+    Box::new(with_parents(repo, nodes).map(|nodes| {
+        let parents: HashSet<_> = nodes
+            .iter()
+            .flat_map(|&(_, ref parents)| parents.iter().cloned())
+            .collect();
+        nodes
+            .into_iter()
+            .map(|(node, _)| node)
+            .filter(|node| !parents.contains(node))
+            .collect()
+    }))
+}
+
+/// The nodes that have none of the other nodes as parents
+fn roots(repo: &Arc<BlobRepo>, nodes: NodesFuture) -> NodesFuture {
+    Box::new(with_parents(repo, nodes).map(|nodes| {
+        let all: HashSet<_> = nodes.iter().map(|&(node, _)| node).collect();
+        nodes
+            .into_iter()
+            .filter(|&(_, ref parents)| !parents.iter().any(|parent| all.contains(parent)))
+            .map(|(node, _)| node)
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::spawn;
+    use linear;
+    use merge_uneven;
+    use tests::assert_node_sequence;
+    use tests::string_to_nodehash;
+
+    fn evaluate(
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        revset: &str,
+    ) -> Box<NodeStream> {
+        revset
+            .parse::<Revset>()
+            .expect("revset should parse")
+            .evaluate(repo, repo_generation)
+    }
+
+    #[test]
+    fn linear_ancestors() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = evaluate(
+            &repo,
+            repo_generation.clone(),
+            "last(::a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157, 3)",
+        );
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+                string_to_nodehash("0ed509bf086fadcb8a8a5384dc3b550729b0fc17"),
+                string_to_nodehash("eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b"),
+            ],
+            nodestream,
+        )
+    }
+
+    #[test]
+    fn linear_limit() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = evaluate(
+            &repo,
+            repo_generation.clone(),
+            "limit(::0ed509bf086fadcb8a8a5384dc3b550729b0fc17, 2)",
+        );
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("3e0e761030db6e479a7fb58b12881883f9f8c63f"),
+                string_to_nodehash("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536"),
+            ],
+            nodestream,
+        )
+    }
+
+    #[test]
+    fn linear_intersect_range() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = evaluate(
+            &repo,
+            repo_generation.clone(),
+            "::cb15ca4a43a59acff5388cea9648c162afde8372 and \
+             d0a361e9022d226ae52f689667bd7d212a19cfe0::a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157",
+        );
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("cb15ca4a43a59acff5388cea9648c162afde8372"),
+                string_to_nodehash("d0a361e9022d226ae52f689667bd7d212a19cfe0"),
+            ],
+            nodestream,
+        )
+    }
+
+    #[test]
+    fn linear_heads_and_not() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let head = vec![string_to_nodehash("a5ffa77602a066db7d5cfb9fb5823a0895717c5a")];
+        let nodestream = evaluate(&repo, repo_generation.clone(), "heads(all())");
+        assert_node_sequence(repo_generation.clone(), &repo, head.clone(), nodestream);
+
+        let nodestream = evaluate(
+            &repo,
+            repo_generation.clone(),
+            "not ::3c15267ebf11807f3d772eb891272b911ec68759",
+        );
+        assert_node_sequence(repo_generation, &repo, head, nodestream);
+    }
+
+    #[test]
+    fn merge_descendants_and_children() {
+        let repo = Arc::new(merge_uneven::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = evaluate(
+            &repo,
+            repo_generation.clone(),
+            "1d8a907f7b4bf50c6a09c16361e2205047ecc5e5:: - 75742e6fc286a359b39a89fdfa437cc7e2a0e1ce",
+        );
+        assert_node_sequence(
+            repo_generation.clone(),
+            &repo,
+            vec![
+                string_to_nodehash("16839021e338500b3cf7c9b871c8a07351697d68"),
+                string_to_nodehash("1d8a907f7b4bf50c6a09c16361e2205047ecc5e5"),
+            ],
+            nodestream,
+        );
+
+        let nodestream = evaluate(
+            &repo,
+            repo_generation.clone(),
+            "children(15c40d0abc36d47fb51c8eaec51ac7aad31f669c)",
+        );
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("3cda5c78aa35f0f5b09780d971197b51cad4613a"),
+                string_to_nodehash("d7542c9db7f4c77dab4b315edd328edf1514952f"),
+            ],
+            nodestream,
+        );
+    }
+
+    #[test]
+    fn merge_only() {
+        let repo = Arc::new(merge_uneven::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = evaluate(
+            &repo,
+            repo_generation.clone(),
+            "75742e6fc286a359b39a89fdfa437cc7e2a0e1ce % 16839021e338500b3cf7c9b871c8a07351697d68",
+        );
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("75742e6fc286a359b39a89fdfa437cc7e2a0e1ce"),
+                string_to_nodehash("264f01429683b3dd8042cb3979e8bf37007118bc"),
+                string_to_nodehash("5d43888a3c972fe68c224f93d41b30e9f888df7c"),
+                string_to_nodehash("fc2cef43395ff3a7b28159007f63d6529d2f41ca"),
+                string_to_nodehash("bc7b4d0f858c19e2474b03e442b8495fd7aeef33"),
+                string_to_nodehash("795b8133cf375f6d68d27c6c23db24cd5d0cd00f"),
+                string_to_nodehash("4f7f3fd428bec1a48f9314414b063c706d9c1aed"),
+                string_to_nodehash("b65231269f651cfe784fd1d97ef02a049a37b8a0"),
+                string_to_nodehash("d7542c9db7f4c77dab4b315edd328edf1514952f"),
+            ],
+            nodestream,
+        )
+    }
+
+    #[test]
+    fn merge_heads_and_roots() {
+        let repo = Arc::new(merge_uneven::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = evaluate(
+            &repo,
+            repo_generation.clone(),
+            "heads(16839021e338500b3cf7c9b871c8a07351697d68 + \
+             4f7f3fd428bec1a48f9314414b063c706d9c1aed + 1d8a907f7b4bf50c6a09c16361e2205047ecc5e5)",
+        );
+        assert_node_sequence(
+            repo_generation.clone(),
+            &repo,
+            vec![
+                string_to_nodehash("16839021e338500b3cf7c9b871c8a07351697d68"),
+                string_to_nodehash("4f7f3fd428bec1a48f9314414b063c706d9c1aed"),
+            ],
+            nodestream,
+        );
+
+        let nodestream = evaluate(
+            &repo,
+            repo_generation.clone(),
+            "roots(::75742e6fc286a359b39a89fdfa437cc7e2a0e1ce - \
+             15c40d0abc36d47fb51c8eaec51ac7aad31f669c)",
+        );
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("3cda5c78aa35f0f5b09780d971197b51cad4613a"),
+                string_to_nodehash("d7542c9db7f4c77dab4b315edd328edf1514952f"),
+            ],
+            nodestream,
+        );
+    }
+
+    #[test]
+    fn unknown_revision() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        for revset in &["master", "::1000000000000000000000000000000000000000"] {
+            let mut nodestream = spawn(evaluate(&repo, repo_generation.clone(), revset));
+            let err = nodestream
+                .wait_stream()
+                .expect("Unexpected end of stream")
+                .expect_err("Unexpected success");
+            match err.downcast::<ErrorKind>() {
+                Ok(ErrorKind::UnknownRevision(_)) => {}
+                other => panic!("unexpected result for {:?}: {:?}", revset, other),
+            }
+        }
+    }
+}
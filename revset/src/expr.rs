@@ -0,0 +1,489 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! A subset of the Mercurial revset language (see `hg help revsets`):
+//!
+//! - `x` is a changeset hash or a bookmark name. Names with characters other than letters, digits
+//!   and `_./@` must be quoted, like `"release-1.0"`.
+//! - `::x` and `ancestors(x)` are the ancestors of `x`, `x::` and `descendants(x)` its
+//!   descendants, and `children(x)` its children.
+//! - `x::y` are the changesets that are both descendants of `x` and ancestors of `y`.
+//! - `x % y` and `only(x, y)` are the ancestors of `x` that aren't ancestors of `y`.
+//! - `heads(x)` and `roots(x)` are the changesets of `x` without children, or without parents, in
+//!   `x`.
+//! - `limit(x[, n])` is the first `n` (default 1) changesets of `x` and `last(x[, n])` the last
+//!   ones. Like in Mercurial, sets are ordered from the oldest changeset to the newest, so
+//!   `limit` has to walk all of `x` while `last` only walks the newest changesets.
+//! - `all()` is every changeset in the repo.
+//! - `x and y`, `x & y`, `x or y`, `x | y`, `x + y`, `not x`, `!x` and `x - y`.
+//!
+//! Like in Mercurial, `not` binds tighter than `and`, `-` and `%`, which bind tighter than `or`.
+
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+use errors::*;
+
+/// A parsed revset expression. Parse one with `str::parse`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Revset {
+    /// A changeset hash or a bookmark name
+    Symbol(String),
+    All,
+    Ancestors(Box<Revset>),
+    Descendants(Box<Revset>),
+    Children(Box<Revset>),
+    /// The changesets that are descendants of the first revset and ancestors of the second one
+    Range(Box<Revset>, Box<Revset>),
+    /// The ancestors of the first revset that aren't ancestors of the second one
+    Only(Box<Revset>, Box<Revset>),
+    Heads(Box<Revset>),
+    Roots(Box<Revset>),
+    /// The given number of oldest changesets of the revset
+    Limit(Box<Revset>, usize),
+    /// The given number of newest changesets of the revset
+    Last(Box<Revset>, usize),
+    And(Box<Revset>, Box<Revset>),
+    Or(Box<Revset>, Box<Revset>),
+    Not(Box<Revset>),
+}
+
+impl FromStr for Revset {
+    type Err = Error;
+
+    fn from_str(revset: &str) -> Result<Self> {
+        let tokens = tokenize(revset)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            depth: 0,
+        };
+        let parsed = parser.parse_or()?;
+        match parser.next() {
+            None => Ok(parsed),
+            Some(token) => Err(parse_error(format!("unexpected {:?}", token))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    Symbol(String),
+    String(String),
+    LParen,
+    RParen,
+    Comma,
+    DagRange,
+    Only,
+    Minus,
+    Not,
+    And,
+    Or,
+}
+
+fn parse_error<S: Into<String>>(msg: S) -> Error {
+    ErrorKind::RevsetParseError(msg.into()).into()
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.' || c == '/' || c == '@'
+}
+
+fn tokenize(revset: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = revset.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            ',' => Token::Comma,
+            '%' => Token::Only,
+            '-' => Token::Minus,
+            '!' => Token::Not,
+            '&' => Token::And,
+            '|' | '+' => Token::Or,
+            ':' => {
+                if chars.next() != Some(':') {
+                    return Err(parse_error("expected '::'"));
+                }
+                Token::DagRange
+            }
+            '\'' | '"' => Token::String(tokenize_string(c, &mut chars)?),
+            c if is_symbol_char(c) => {
+                let mut symbol = c.to_string();
+                loop {
+                    let next = match chars.peek() {
+                        Some(&next) if is_symbol_char(next) => next,
+                        _ => break,
+                    };
+                    symbol.push(next);
+                    chars.next();
+                }
+                match symbol.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    _ => Token::Symbol(symbol),
+                }
+            }
+            c => return Err(parse_error(format!("unexpected character {:?}", c))),
+        };
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn tokenize_string(quote: char, chars: &mut Peekable<Chars>) -> Result<String> {
+    let mut string = String::new();
+    loop {
+        match chars.next() {
+            Some(c) if c == quote => return Ok(string),
+            Some(c) => string.push(c),
+            None => return Err(parse_error("unterminated string")),
+        }
+    }
+}
+
+/// Maximum nesting of parentheses, function calls, `not`s and binary operators, so that parsing,
+/// evaluating and dropping a revset can't overflow the stack. The operands of a chain like
+/// `a or b or c` are nested too.
+const MAX_DEPTH: usize = 100;
+
+// Recursive descent, from the operators with the lowest precedence to the highest:
+// or, then and, - and %, then not, ::, and then symbols, functions and parenthesized revsets.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// Go one level deeper, or fail if the revset is nested too deeply
+    fn descend(&mut self) -> Result<()> {
+        if self.depth >= MAX_DEPTH {
+            return Err(parse_error("revset is nested too deeply"));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Run `parse` one level deeper
+    fn nested<F>(&mut self, parse: F) -> Result<Revset>
+    where
+        F: FnOnce(&mut Self) -> Result<Revset>,
+    {
+        self.descend()?;
+        let revset = parse(self);
+        self.depth -= 1;
+        revset
+    }
+
+    // Each operator of a chain nests the revset parsed so far one level deeper, so the rest of
+    // the chain is parsed one level deeper as well
+    fn parse_or(&mut self) -> Result<Revset> {
+        let depth = self.depth;
+        let mut revset = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            self.descend()?;
+            let rhs = self.parse_and()?;
+            revset = Revset::Or(Box::new(revset), Box::new(rhs));
+        }
+        self.depth = depth;
+        Ok(revset)
+    }
+
+    fn parse_and(&mut self) -> Result<Revset> {
+        let depth = self.depth;
+        let mut revset = self.parse_not()?;
+        loop {
+            let operator = match self.peek() {
+                Some(&Token::And) | Some(&Token::Minus) | Some(&Token::Only) => {
+                    self.next().expect("token was peeked")
+                }
+                _ => break,
+            };
+            self.descend()?;
+            let lhs = Box::new(revset);
+            let rhs = Box::new(self.parse_not()?);
+            revset = match operator {
+                Token::And => Revset::And(lhs, rhs),
+                // x - y is x and not y
+                Token::Minus => Revset::And(lhs, Box::new(Revset::Not(rhs))),
+                _ => Revset::Only(lhs, rhs),
+            };
+        }
+        self.depth = depth;
+        Ok(revset)
+    }
+
+    fn parse_not(&mut self) -> Result<Revset> {
+        if self.peek() == Some(&Token::Not) {
+            self.pos += 1;
+            let revset = self.nested(Parser::parse_not)?;
+            Ok(Revset::Not(Box::new(revset)))
+        } else {
+            self.parse_dagrange()
+        }
+    }
+
+    fn parse_dagrange(&mut self) -> Result<Revset> {
+        if self.peek() == Some(&Token::DagRange) {
+            self.pos += 1;
+            let revset = self.parse_primary()?;
+            return Ok(Revset::Ancestors(Box::new(revset)));
+        }
+
+        let revset = self.parse_primary()?;
+        if self.peek() != Some(&Token::DagRange) {
+            return Ok(revset);
+        }
+        self.pos += 1;
+        match self.peek() {
+            Some(&Token::Symbol(_)) | Some(&Token::String(_)) | Some(&Token::LParen) => {}
+            _ => return Ok(Revset::Descendants(Box::new(revset))),
+        }
+        let end = self.parse_primary()?;
+        Ok(Revset::Range(Box::new(revset), Box::new(end)))
+    }
+
+    fn parse_primary(&mut self) -> Result<Revset> {
+        match self.next() {
+            Some(Token::LParen) => {
+                let revset = self.nested(Parser::parse_or)?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(revset),
+                    _ => Err(parse_error("expected ')'")),
+                }
+            }
+            Some(Token::String(name)) => Ok(Revset::Symbol(name)),
+            Some(Token::Symbol(name)) => {
+                if self.peek() == Some(&Token::LParen) {
+                    self.pos += 1;
+                    let args = self.parse_args()?;
+                    function(name, args)
+                } else {
+                    Ok(Revset::Symbol(name))
+                }
+            }
+            Some(token) => Err(parse_error(format!("unexpected {:?}", token))),
+            None => Err(parse_error("unexpected end of revset")),
+        }
+    }
+
+    // The arguments of a function, after its opening parenthesis
+    fn parse_args(&mut self) -> Result<Vec<Revset>> {
+        let mut args = Vec::new();
+        if self.peek() == Some(&Token::RParen) {
+            self.pos += 1;
+            return Ok(args);
+        }
+        loop {
+            args.push(self.nested(Parser::parse_or)?);
+            match self.next() {
+                Some(Token::Comma) => continue,
+                Some(Token::RParen) => return Ok(args),
+                _ => return Err(parse_error("expected ',' or ')'")),
+            }
+        }
+    }
+}
+
+fn function(name: String, args: Vec<Revset>) -> Result<Revset> {
+    let nargs = args.len();
+    let mut args = args.into_iter().map(Box::new);
+    let mut arg = || args.next().expect("number of args was checked");
+
+    let revset = match (name.as_str(), nargs) {
+        ("all", 0) => Revset::All,
+        ("ancestors", 1) => Revset::Ancestors(arg()),
+        ("descendants", 1) => Revset::Descendants(arg()),
+        ("children", 1) => Revset::Children(arg()),
+        ("heads", 1) => Revset::Heads(arg()),
+        ("roots", 1) => Revset::Roots(arg()),
+        ("only", 2) => Revset::Only(arg(), arg()),
+        ("limit", 1) => Revset::Limit(arg(), 1),
+        ("limit", 2) => Revset::Limit(arg(), count(&name, *arg())?),
+        ("last", 1) => Revset::Last(arg(), 1),
+        ("last", 2) => Revset::Last(arg(), count(&name, *arg())?),
+        ("all", _) | ("ancestors", _) | ("descendants", _) | ("children", _) | ("heads", _)
+        | ("roots", _) | ("only", _) | ("limit", _) | ("last", _) => {
+            return Err(parse_error(format!(
+                "wrong number of arguments to {}: {}",
+                name, nargs
+            )))
+        }
+        _ => bail_err!(ErrorKind::UnknownRevsetFunction(name)),
+    };
+    Ok(revset)
+}
+
+/// The number of changesets that `limit` or `last` take
+fn count(function: &str, arg: Revset) -> Result<usize> {
+    let n = match arg {
+        Revset::Symbol(n) => n.parse().ok(),
+        _ => None,
+    };
+    n.ok_or_else(|| parse_error(format!("{} expects a number", function)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn symbol(name: &str) -> Box<Revset> {
+        Box::new(Revset::Symbol(name.into()))
+    }
+
+    fn parse(revset: &str) -> Revset {
+        revset.parse().expect("revset should parse")
+    }
+
+    #[test]
+    fn parse_symbols() {
+        assert_eq!(parse("master"), *symbol("master"));
+        assert_eq!(
+            parse("a5ffa77602a066db7d5cfb9fb5823a0895717c5a"),
+            *symbol("a5ffa77602a066db7d5cfb9fb5823a0895717c5a")
+        );
+        assert_eq!(parse("\"release-1.0\""), *symbol("release-1.0"));
+        assert_eq!(parse(" 'and' "), *symbol("and"));
+    }
+
+    #[test]
+    fn parse_dag_operators() {
+        assert_eq!(parse("::master"), Revset::Ancestors(symbol("master")));
+        assert_eq!(parse("a::b"), Revset::Range(symbol("a"), symbol("b")));
+        assert_eq!(parse("a::"), Revset::Descendants(symbol("a")));
+        assert_eq!(
+            parse("a:: and b"),
+            Revset::And(Box::new(Revset::Descendants(symbol("a"))), symbol("b"))
+        );
+        assert_eq!(
+            parse("::a % b"),
+            Revset::Only(Box::new(Revset::Ancestors(symbol("a"))), symbol("b"))
+        );
+    }
+
+    #[test]
+    fn parse_functions() {
+        assert_eq!(parse("all()"), Revset::All);
+        assert_eq!(parse("ancestors(a)"), Revset::Ancestors(symbol("a")));
+        assert_eq!(parse("descendants(a)"), Revset::Descendants(symbol("a")));
+        assert_eq!(parse("children(a)"), Revset::Children(symbol("a")));
+        assert_eq!(parse("only(a, b)"), Revset::Only(symbol("a"), symbol("b")));
+        assert_eq!(
+            parse("heads(roots(a))"),
+            Revset::Heads(Box::new(Revset::Roots(symbol("a"))))
+        );
+        assert_eq!(parse("limit(a)"), Revset::Limit(symbol("a"), 1));
+        assert_eq!(parse("limit(::a, 10)"), Revset::Limit(Box::new(parse("::a")), 10));
+        assert_eq!(parse("last(a)"), Revset::Last(symbol("a"), 1));
+        assert_eq!(parse("last(::a, 10)"), Revset::Last(Box::new(parse("::a")), 10));
+    }
+
+    #[test]
+    fn parse_precedence() {
+        assert_eq!(
+            parse("a or b and not c"),
+            Revset::Or(
+                symbol("a"),
+                Box::new(Revset::And(
+                    symbol("b"),
+                    Box::new(Revset::Not(symbol("c")))
+                ))
+            )
+        );
+        assert_eq!(parse("a | b & !c"), parse("a or b and not c"));
+        assert_eq!(parse("a + b - c"), parse("a or (b and not c)"));
+        assert_eq!(
+            parse("(a or b) and c"),
+            Revset::And(Box::new(Revset::Or(symbol("a"), symbol("b"))), symbol("c"))
+        );
+        assert_eq!(
+            parse("not a % b"),
+            Revset::Only(Box::new(Revset::Not(symbol("a"))), symbol("b"))
+        );
+        assert_eq!(
+            parse("a % b and c"),
+            Revset::And(Box::new(Revset::Only(symbol("a"), symbol("b"))), symbol("c"))
+        );
+        assert_eq!(
+            parse("a and b % c"),
+            Revset::Only(Box::new(Revset::And(symbol("a"), symbol("b"))), symbol("c"))
+        );
+        assert_eq!(parse("a % b or c"), parse("(a % b) or c"));
+    }
+
+    #[test]
+    fn parse_depth_limit() {
+        let nested = |depth| format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(parse(&nested(MAX_DEPTH)), *symbol("a"));
+        assert!(nested(MAX_DEPTH + 1).parse::<Revset>().is_err());
+
+        let nots = |depth| format!("{}a", "not ".repeat(depth));
+        assert!(nots(MAX_DEPTH).parse::<Revset>().is_ok());
+        assert!(nots(MAX_DEPTH + 1).parse::<Revset>().is_err());
+        assert!(nested(100_000).parse::<Revset>().is_err());
+
+        let chain = |operator: &str, depth: usize| {
+            let operands = vec!["a"; depth + 1];
+            operands.join(operator)
+        };
+        for operator in &[" or ", " and ", " - ", " % "] {
+            assert!(chain(*operator, MAX_DEPTH).parse::<Revset>().is_ok());
+            assert!(chain(*operator, MAX_DEPTH + 1).parse::<Revset>().is_err());
+            assert!(chain(*operator, 100_000).parse::<Revset>().is_err());
+        }
+        assert!(format!("({})", chain(" or ", MAX_DEPTH)).parse::<Revset>().is_err());
+    }
+
+    #[test]
+    fn parse_errors() {
+        for revset in &[
+            "",
+            "a:b",
+            "(a",
+            "a)",
+            "a b",
+            "::",
+            "'a",
+            "a and",
+            "a # b",
+            "limit(a, b)",
+            "last(a, b)",
+            "only(a)",
+            "all(a)",
+        ] {
+            let err = revset.parse::<Revset>().expect_err("revset should not parse");
+            match err.downcast::<ErrorKind>() {
+                Ok(ErrorKind::RevsetParseError(_)) => {}
+                other => panic!("unexpected result for {:?}: {:?}", revset, other),
+            }
+        }
+
+        for name in &["foo", "first"] {
+            let revset = format!("{}(a)", name);
+            match revset.parse::<Revset>().unwrap_err().downcast::<ErrorKind>() {
+                Ok(ErrorKind::UnknownRevsetFunction(ref unknown)) if unknown == name => {}
+                other => panic!("unexpected result for {:?}: {:?}", revset, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,405 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The changesets that modified a path, as shown by `hg log PATH`.
+//!
+//! The history of a file is walked through its file revisions: the linknode of each revision is
+//! the changeset that introduced it. Revisions are visited from the highest linknode generation
+//! to the lowest, like the ancestors of a changeset, so the changesets come out in the order of
+//! the other node streams. Directories have no file revisions to walk, so the ancestors of the
+//! starting changeset are checked for changed files below the directory instead.
+
+use std::cmp::min;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+use futures::Poll;
+use futures::future::{self, join_all, Future, IntoFuture};
+use futures::stream::{self, iter_ok, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::BlobRepo;
+use mercurial_types::{Changeset, MPath, NodeHash, RepoPath, Type};
+use mercurial_types::nodehash::HgChangesetId;
+use repoinfo::{Generation, RepoGenCache};
+
+use AncestorsNodeStream;
+use NodeStream;
+use errors::*;
+
+/// Number of ancestors whose changed files are fetched at the same time
+const CHANGESET_CONCURRENCY: usize = 100;
+
+/// A revision of a file, with the changeset that introduced it
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct FileRevision {
+    path: MPath,
+    filenode: NodeHash,
+    linknode: NodeHash,
+}
+
+pub struct FileHistoryNodeStream {
+    nodes: Box<NodeStream>,
+}
+
+impl FileHistoryNodeStream {
+    /// The ancestors of `start` (including itself) that modified `path`, which is either a file
+    /// or a directory. Fails with `PathNotFound` if `path` doesn't exist in `start`.
+    pub fn new(
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        start: NodeHash,
+        path: MPath,
+    ) -> Self {
+        Self::new_with_copies(repo, repo_generation, start, path, false)
+    }
+
+    /// Like `new`, but once the history of a file reaches the revision that copied or renamed
+    /// it, the history continues with the file it was copied from
+    pub fn following_copies(
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        start: NodeHash,
+        path: MPath,
+    ) -> Self {
+        Self::new_with_copies(repo, repo_generation, start, path, true)
+    }
+
+    fn new_with_copies(
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        start: NodeHash,
+        path: MPath,
+        follow_copies: bool,
+    ) -> Self {
+        // The history of the root would be every ancestor of `start`, found by walking them all
+        if path.is_empty() {
+            let err: Error = ErrorKind::PathNotFound(path, start).into();
+            return FileHistoryNodeStream {
+                nodes: Box::new(stream::once(Err(err))),
+            };
+        }
+
+        let repo = repo.clone();
+        let entry = repo.get_changeset_by_changesetid(&HgChangesetId::new(start))
+            .and_then({
+                let repo = repo.clone();
+                move |cs| repo.get_manifest_by_nodeid(&cs.manifestid().into_nodehash())
+            })
+            .and_then({
+                let path = path.clone();
+                move |manifest| manifest.lookup(&path)
+            });
+
+        let nodes = entry
+            .and_then(move |entry| -> BoxFuture<Box<NodeStream>, Error> {
+                match entry {
+                    None => future::err(ErrorKind::PathNotFound(path, start).into()).boxify(),
+                    Some(ref entry) if entry.get_type() != Type::Tree => {
+                        let filenode = entry.get_hash().into_nodehash();
+                        file_revision(&repo, repo_generation.clone(), path, filenode)
+                            .map(move |start| {
+                                file_history(repo, repo_generation, start, follow_copies)
+                            })
+                            .boxify()
+                    }
+                    Some(_) => {
+                        future::ok(directory_history(repo, repo_generation, start, path))
+                            .boxify()
+                    }
+                }
+            })
+            .flatten_stream();
+
+        FileHistoryNodeStream {
+            nodes: Box::new(nodes),
+        }
+    }
+
+    pub fn boxed(self) -> Box<NodeStream> {
+        Box::new(self)
+    }
+}
+
+impl Stream for FileHistoryNodeStream {
+    type Item = NodeHash;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.nodes.poll()
+    }
+}
+
+/// The linknodes of `start` and its ancestor revisions. The parents of a file revision were
+/// introduced before it, so their linknodes have lower generations and walking the revisions by
+/// generation outputs every linknode after its descendants.
+///
+/// A file revision is stored once even when several changesets introduce the same content, and
+/// its linknode is whichever of them was stored first. That changeset need not be an ancestor of
+/// the starting one, so like `hg log` without linkrev adjustment, the history can contain such
+/// aliased changesets. Their generation can also be higher than the revision's children, in
+/// which case they're output with the children rather than out of order.
+fn file_history(
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    start: (Generation, FileRevision),
+    follow_copies: bool,
+) -> Box<NodeStream> {
+    let (start_generation, start_revision) = start;
+    let mut frontier: BTreeMap<Generation, HashSet<FileRevision>> = BTreeMap::new();
+    frontier.insert(start_generation, hashset!{start_revision});
+
+    let generation_nodes = stream::unfold(frontier, move |mut frontier| {
+        let highest_generation = match frontier.keys().next_back() {
+            Some(generation) => *generation,
+            None => return None,
+        };
+        let revisions = frontier
+            .remove(&highest_generation)
+            .expect("Highest generation doesn't exist");
+
+        let parents: Vec<_> = revisions
+            .iter()
+            .map(|revision| {
+                parent_revisions(&repo, repo_generation.clone(), revision, follow_copies)
+            })
+            .collect();
+        // Revisions of several files can share a linknode
+        let linknodes: HashSet<_> = revisions
+            .into_iter()
+            .map(|revision| revision.linknode)
+            .collect();
+
+        Some(join_all(parents).map(move |parents| {
+            for (generation, revision) in parents.into_iter().flat_map(|p| p) {
+                // See the aliasing note above: never go back to a generation already output
+                let generation = min(generation, highest_generation);
+                frontier
+                    .entry(generation)
+                    .or_insert_with(HashSet::new)
+                    .insert(revision);
+            }
+            (linknodes, frontier)
+        }))
+    });
+
+    Box::new(
+        generation_nodes
+            .map(|nodes| iter_ok::<_, Error>(nodes))
+            .flatten(),
+    )
+}
+
+/// The parent revisions of `revision`, and the revision it was copied from if `follow_copies`
+fn parent_revisions(
+    repo: &Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    revision: &FileRevision,
+    follow_copies: bool,
+) -> BoxFuture<Vec<(Generation, FileRevision)>, Error> {
+    let copy = if follow_copies {
+        repo.get_file_copy(&revision.filenode)
+    } else {
+        future::ok(None).boxify()
+    };
+
+    let repo = repo.clone();
+    let path = revision.path.clone();
+    repo.get_parents(&revision.filenode)
+        .join(copy)
+        .and_then(move |(parents, copy)| {
+            let mut revisions: Vec<_> = parents
+                .into_iter()
+                .map(|parent| (path.clone(), parent))
+                .collect();
+            revisions.extend(copy);
+
+            let revisions: Vec<_> = revisions
+                .into_iter()
+                .map(|(path, filenode)| {
+                    file_revision(&repo, repo_generation.clone(), path, filenode)
+                })
+                .collect();
+            join_all(revisions)
+        })
+        .boxify()
+}
+
+/// The revision `filenode` of `path`, with the generation of its linknode
+fn file_revision(
+    repo: &Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    path: MPath,
+    filenode: NodeHash,
+) -> BoxFuture<(Generation, FileRevision), Error> {
+    let repo = repo.clone();
+    RepoPath::file(path.clone())
+        .into_future()
+        .and_then({
+            let repo = repo.clone();
+            move |repopath| repo.get_linknode(repopath, &filenode)
+        })
+        .and_then(move |linknode| {
+            repo_generation
+                .get(&repo, linknode)
+                .map_err(|err| err.context(ErrorKind::GenerationFetchFailed))
+                .from_err()
+                .map(move |generation| {
+                    let revision = FileRevision {
+                        path,
+                        filenode,
+                        linknode,
+                    };
+                    (generation, revision)
+                })
+        })
+        .boxify()
+}
+
+/// The ancestors of `start` that changed a file below `path`
+fn directory_history(
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    start: NodeHash,
+    path: MPath,
+) -> Box<NodeStream> {
+    let changed = AncestorsNodeStream::new(&repo, repo_generation, start)
+        .map(move |node| {
+            let path = path.clone();
+            repo.get_changeset_by_changesetid(&HgChangesetId::new(node))
+                .map(move |cs| (node, cs.files().iter().any(|file| path.is_prefix_of(file))))
+        })
+        .buffered(CHANGESET_CONCURRENCY)
+        .filter_map(|(node, changed)| if changed { Some(node) } else { None });
+    Box::new(changed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use linear;
+    use merge_uneven;
+    use tests::assert_node_sequence;
+    use tests::string_to_nodehash;
+
+    #[test]
+    fn linear_file_history() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = FileHistoryNodeStream::new(
+            &repo,
+            repo_generation.clone(),
+            string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+            MPath::new("files").unwrap(),
+        ).boxed();
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+                string_to_nodehash("0ed509bf086fadcb8a8a5384dc3b550729b0fc17"),
+                string_to_nodehash("eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b"),
+                string_to_nodehash("cb15ca4a43a59acff5388cea9648c162afde8372"),
+                string_to_nodehash("d0a361e9022d226ae52f689667bd7d212a19cfe0"),
+                string_to_nodehash("607314ef579bd2407752361ba1b0c1729d08b281"),
+                string_to_nodehash("3e0e761030db6e479a7fb58b12881883f9f8c63f"),
+                string_to_nodehash("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536"),
+            ],
+            nodestream,
+        );
+    }
+
+    #[test]
+    fn linear_single_change_history() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        // "5" was added by cb15ca4a and never modified again
+        let nodestream = FileHistoryNodeStream::following_copies(
+            &repo,
+            repo_generation.clone(),
+            string_to_nodehash("a5ffa77602a066db7d5cfb9fb5823a0895717c5a"),
+            MPath::new("5").unwrap(),
+        ).boxed();
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("cb15ca4a43a59acff5388cea9648c162afde8372"),
+            ],
+            nodestream,
+        );
+    }
+
+    #[test]
+    fn merge_file_history() {
+        let repo = Arc::new(merge_uneven::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = FileHistoryNodeStream::new(
+            &repo,
+            repo_generation.clone(),
+            string_to_nodehash("75742e6fc286a359b39a89fdfa437cc7e2a0e1ce"),
+            MPath::new("branch").unwrap(),
+        ).boxed();
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("75742e6fc286a359b39a89fdfa437cc7e2a0e1ce"),
+                string_to_nodehash("16839021e338500b3cf7c9b871c8a07351697d68"),
+                string_to_nodehash("1d8a907f7b4bf50c6a09c16361e2205047ecc5e5"),
+                string_to_nodehash("b65231269f651cfe784fd1d97ef02a049a37b8a0"),
+                string_to_nodehash("d7542c9db7f4c77dab4b315edd328edf1514952f"),
+                string_to_nodehash("3cda5c78aa35f0f5b09780d971197b51cad4613a"),
+            ],
+            nodestream,
+        );
+    }
+
+    fn assert_path_not_found(nodestream: Box<NodeStream>) {
+        match nodestream.collect().wait() {
+            Err(err) => match err.downcast::<ErrorKind>() {
+                Ok(ErrorKind::PathNotFound(..)) => (),
+                other => panic!("unexpected error: {:?}", other),
+            },
+            Ok(nodes) => panic!("unexpected history: {:?}", nodes),
+        }
+    }
+
+    #[test]
+    fn root_history() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = FileHistoryNodeStream::new(
+            &repo,
+            repo_generation,
+            string_to_nodehash("0ed509bf086fadcb8a8a5384dc3b550729b0fc17"),
+            MPath::empty(),
+        ).boxed();
+
+        assert_path_not_found(nodestream);
+    }
+
+    #[test]
+    fn missing_path_history() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = FileHistoryNodeStream::new(
+            &repo,
+            repo_generation,
+            string_to_nodehash("a5ffa77602a066db7d5cfb9fb5823a0895717c5a"),
+            MPath::new("dir/missing").unwrap(),
+        ).boxed();
+
+        assert_path_not_found(nodestream);
+    }
+}
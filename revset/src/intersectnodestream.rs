@@ -7,7 +7,7 @@
 use blobrepo::BlobRepo;
 use futures::Async;
 use futures::Poll;
-use futures::stream::Stream;
+use futures::stream::{self, Stream};
 use mercurial_types::NodeHash;
 use repoinfo::{Generation, RepoGenCache};
 use std::boxed::Box;
@@ -19,11 +19,13 @@ use std::sync::Arc;
 
 use NodeStream;
 use errors::*;
+use ordered::OrderedNodeStream;
 use setcommon::*;
 
 pub struct IntersectNodeStream {
     inputs: Vec<(InputStream, Poll<Option<(NodeHash, Generation)>, Error>)>,
     current_generation: Option<Generation>,
+    accumulator_generation: Option<Generation>,
     accumulator: HashMap<NodeHash, usize>,
     drain: Option<IntoIter<NodeHash, usize>>,
 }
@@ -33,17 +35,22 @@ impl IntersectNodeStream {
     where
         I: IntoIterator<Item = Box<NodeStream>>,
     {
-        let hash_and_gen = inputs.into_iter().map({
-            move |i| {
-                (
-                    add_generations(i, repo_generation.clone(), repo.clone()),
-                    Ok(Async::NotReady),
-                )
-            }
-        });
+        let inputs = inputs
+            .into_iter()
+            .map(|i| add_generations(i, repo_generation.clone(), repo.clone()));
+        Self::from_ordered(inputs)
+    }
+
+    /// Like `new`, but the inputs already know the generations of their nodes
+    pub fn from_ordered<I>(inputs: I) -> Self
+    where
+        I: IntoIterator<Item = Box<OrderedNodeStream>>,
+    {
+        let inputs = inputs.into_iter().map(|i| (i, Ok(Async::NotReady)));
         IntersectNodeStream {
-            inputs: hash_and_gen.collect(),
+            inputs: inputs.collect(),
             current_generation: None,
+            accumulator_generation: None,
             accumulator: HashMap::new(),
             drain: None,
         }
@@ -53,6 +60,11 @@ impl IntersectNodeStream {
         Box::new(self)
     }
 
+    pub fn ordered(self) -> Box<OrderedNodeStream> {
+        let mut nodes = self;
+        Box::new(stream::poll_fn(move || nodes.poll_ordered()))
+    }
+
     fn update_current_generation(&mut self) {
         if all_inputs_ready(&self.inputs) {
             self.current_generation = self.inputs
@@ -72,6 +84,7 @@ impl IntersectNodeStream {
             if let Ok(Async::Ready(Some((hash, gen_id)))) = *state {
                 if Some(gen_id) == self.current_generation {
                     *self.accumulator.entry(hash).or_insert(0) += 1;
+                    self.accumulator_generation = self.current_generation;
                 }
                 // Inputs of higher generation than the current one get consumed and dropped
                 if Some(gen_id) >= self.current_generation {
@@ -98,12 +111,8 @@ impl IntersectNodeStream {
                 .any(|done| done)
         }
     }
-}
 
-impl Stream for IntersectNodeStream {
-    type Item = NodeHash;
-    type Error = Error;
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    fn poll_ordered(&mut self) -> Poll<Option<(NodeHash, Generation)>, Error> {
         // This feels wrong, but in practice it's fine - it should be quick to hit a return, and
         // the standard futures::executor expects you to only return NotReady if blocked on I/O.
         loop {
@@ -116,7 +125,9 @@ impl Stream for IntersectNodeStream {
                 if next_in_drain.is_some() {
                     let (hash, count) = next_in_drain.expect("is_some() said this was safe");
                     if count == self.inputs.len() {
-                        return Ok(Async::Ready(Some(hash)));
+                        let generation = self.accumulator_generation
+                            .expect("Nodes accumulated without a generation");
+                        return Ok(Async::Ready(Some((hash, generation))));
                     }
                 } else {
                     self.drain = None;
@@ -157,6 +168,15 @@ impl Stream for IntersectNodeStream {
     }
 }
 
+impl Stream for IntersectNodeStream {
+    type Item = NodeHash;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let next = self.poll_ordered()?;
+        Ok(next.map(|next| next.map(|(hash, _)| hash)))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -9,6 +9,7 @@ extern crate blobrepo;
 #[macro_use]
 extern crate failure_ext as failure;
 extern crate futures;
+extern crate futures_ext;
 #[macro_use]
 extern crate maplit;
 extern crate mercurial_types;
@@ -36,14 +37,33 @@ pub use errors::{Error, ErrorKind};
 
 pub type NodeStream = Stream<Item = NodeHash, Error = errors::Error> + Send + 'static;
 
+mod ordered;
+pub use ordered::{first, limit, with_generations, without_generations, LimitNodeStream,
+                  OrderedNodeStream};
+
 mod validation;
 pub use validation::ValidateNodeStream;
 
 mod ancestors;
-pub use ancestors::{common_ancestors, greatest_common_ancestor, AncestorsNodeStream};
+pub use ancestors::{common_ancestors, common_ancestors_with_skiplist, greatest_common_ancestor,
+                    greatest_common_ancestor_with_skiplist, AncestorsNodeStream};
+
+mod skiplist;
+pub use skiplist::{fetch_parents, SkiplistIndex};
 
 mod range;
-pub use range::RangeNodeStream;
+pub use range::{LazyRangeNodeStream, RangeNodeStream};
+
+mod descendants;
+pub use descendants::{children, DescendantsNodeStream};
+
+mod filehistory;
+pub use filehistory::FileHistoryNodeStream;
+
+mod expr;
+pub use expr::Revset;
+
+mod evaluate;
 
 #[cfg(test)]
 extern crate ascii;
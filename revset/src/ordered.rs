@@ -0,0 +1,193 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Node streams that carry the generation of each node. Like every node stream, they output
+//! their nodes from the highest generation to the lowest and each node at most once, but the
+//! generations let the set operations merge them without looking the generations up again.
+
+use std::sync::Arc;
+
+use futures::{Async, Poll};
+use futures::future::Future;
+use futures::stream::Stream;
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::BlobRepo;
+use mercurial_types::NodeHash;
+use repoinfo::{Generation, RepoGenCache};
+
+use NodeStream;
+use errors::*;
+use setcommon::add_generations;
+
+pub type OrderedNodeStream = Stream<Item = (NodeHash, Generation), Error = Error> + Send + 'static;
+
+/// Look up the generation of every node of `nodes`
+pub fn with_generations(
+    nodes: Box<NodeStream>,
+    repo: &Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+) -> Box<OrderedNodeStream> {
+    add_generations(nodes, repo_generation, repo.clone())
+}
+
+pub fn without_generations(nodes: Box<OrderedNodeStream>) -> Box<NodeStream> {
+    Box::new(nodes.map(|(hash, _)| hash))
+}
+
+/// The first `limit` items of a stream. Unlike `Stream::take`, the input is dropped as soon as
+/// the limit is reached, so none of its pending work is kept alive.
+pub struct LimitNodeStream<S> {
+    input: Option<S>,
+    remaining: usize,
+}
+
+impl<S: Stream> LimitNodeStream<S> {
+    pub fn new(input: S, limit: usize) -> Self {
+        LimitNodeStream {
+            input: if limit > 0 { Some(input) } else { None },
+            remaining: limit,
+        }
+    }
+}
+
+impl<S: Stream> Stream for LimitNodeStream<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let next = match self.input {
+            None => return Ok(Async::Ready(None)),
+            Some(ref mut input) => match input.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(next) => next,
+            },
+        };
+
+        match next {
+            Some(_) => self.remaining -= 1,
+            None => self.remaining = 0,
+        }
+        if self.remaining == 0 {
+            self.input = None;
+        }
+        Ok(Async::Ready(next))
+    }
+}
+
+pub fn limit(nodes: Box<NodeStream>, limit: usize) -> Box<NodeStream> {
+    Box::new(LimitNodeStream::new(nodes, limit))
+}
+
+/// The first item of a stream, or `None` if it is empty. The rest of the stream is not polled.
+pub fn first<S>(input: S) -> BoxFuture<Option<S::Item>, S::Error>
+where
+    S: Stream + Send + 'static,
+    S::Item: Send,
+    S::Error: Send,
+{
+    input
+        .into_future()
+        .map(|(item, _)| item)
+        .map_err(|(err, _)| err)
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use AncestorsNodeStream;
+    use futures::stream::iter_ok;
+    use linear;
+    use setcommon::NotReadyEmptyStream;
+    use tests::{assert_node_sequence, string_to_nodehash};
+
+    #[test]
+    fn limit_ancestors() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = limit(
+            AncestorsNodeStream::new(
+                &repo,
+                repo_generation.clone(),
+                string_to_nodehash("a5ffa77602a066db7d5cfb9fb5823a0895717c5a"),
+            ).boxed(),
+            3,
+        );
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("a5ffa77602a066db7d5cfb9fb5823a0895717c5a"),
+                string_to_nodehash("3c15267ebf11807f3d772eb891272b911ec68759"),
+                string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+            ],
+            nodestream,
+        );
+    }
+
+    #[test]
+    fn limit_drops_input() {
+        let input = iter_ok::<_, Error>(vec![1, 2, 3])
+            .chain(NotReadyEmptyStream { poll_count: 1 }.map(|_| 0));
+        let mut nodestream = LimitNodeStream::new(input, 3);
+
+        for expected in 1..4 {
+            match nodestream.poll() {
+                Ok(Async::Ready(Some(item))) => assert_eq!(item, expected, "Wrong item"),
+                x => panic!("Unexpected poll result {:?}", x),
+            }
+        }
+        // The input would not be ready if it was polled again
+        match nodestream.poll() {
+            Ok(Async::Ready(None)) => (),
+            x => panic!("Unexpected poll result {:?}", x),
+        }
+        assert!(nodestream.input.is_none());
+    }
+
+    #[test]
+    fn limit_zero() {
+        let mut nodestream = LimitNodeStream::new(NotReadyEmptyStream { poll_count: 1 }, 0);
+        match nodestream.poll() {
+            Ok(Async::Ready(None)) => (),
+            x => panic!("Unexpected poll result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn first_ancestor() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+        let head = string_to_nodehash("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+
+        let nodestream = AncestorsNodeStream::new(&repo, repo_generation, head).boxed();
+        assert_eq!(first(nodestream).wait().unwrap(), Some(head));
+
+        let empty = iter_ok::<NodeHash, Error>(vec![]);
+        assert_eq!(first(empty).wait().unwrap(), None);
+    }
+
+    #[test]
+    fn ordered_ancestors() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = AncestorsNodeStream::new(
+            &repo,
+            repo_generation.clone(),
+            string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+        ).ordered();
+
+        let nodes = nodestream.collect().wait().unwrap();
+        assert_eq!(nodes.len(), 8);
+        for (hash, gen) in nodes {
+            assert_eq!(repo_generation.get(&repo, hash).wait().unwrap(), gen);
+        }
+    }
+}
@@ -5,6 +5,8 @@
 // GNU General Public License version 2 or any later version.
 
 use futures::executor::spawn;
+use futures::future::Future;
+use futures::stream::Stream;
 use quickcheck::{quickcheck, Arbitrary, Gen};
 use rand::Rng;
 use rand::distributions::Sample;
@@ -27,12 +29,30 @@ use unshared_merge_even;
 use unshared_merge_uneven;
 
 use NodeStream;
+use ancestors::{common_ancestors, common_ancestors_with_skiplist, AncestorsNodeStream};
 use intersectnodestream::IntersectNodeStream;
+use ordered::{with_generations, OrderedNodeStream};
+use range::{LazyRangeNodeStream, RangeNodeStream};
 use setdifferencenodestream::SetDifferenceNodeStream;
 use singlenodehash::SingleNodeHash;
+use skiplist::SkiplistIndex;
 use unionnodestream::UnionNodeStream;
 use validation::ValidateNodeStream;
 
+fn get_all_changesets(repo: &BlobRepo) -> Vec<NodeHash> {
+    let mut all_changesets_executor = spawn(repo.get_changesets());
+    let mut all_changesets: Vec<NodeHash> = Vec::new();
+    loop {
+        all_changesets.push(match all_changesets_executor.wait_stream() {
+            None => break,
+            Some(changeset) => changeset.expect("Failed to get changesets from repo"),
+        });
+    }
+
+    assert!(!all_changesets.is_empty(), "Repo has no changesets");
+    all_changesets
+}
+
 #[derive(Clone, Copy, Debug)]
 enum RevsetEntry {
     SingleNode(Option<NodeHash>),
@@ -51,16 +71,7 @@ impl RevsetSpec {
     where
         G: Rng,
     {
-        let mut all_changesets_executor = spawn(repo.get_changesets());
-        let mut all_changesets: Vec<NodeHash> = Vec::new();
-        loop {
-            all_changesets.push(match all_changesets_executor.wait_stream() {
-                None => break,
-                Some(changeset) => changeset.expect("Failed to get changesets from repo"),
-            });
-        }
-
-        assert!(!all_changesets.is_empty(), "Repo has no changesets");
+        let all_changesets = get_all_changesets(repo);
 
         for elem in self.rp_entries.iter_mut() {
             if let &mut RevsetEntry::SingleNode(None) = elem {
@@ -149,6 +160,45 @@ impl RevsetSpec {
         );
         output.pop().expect("No revset entries")
     }
+
+    /// Like `as_revset`, but the set operations are combined through their ordered streams
+    pub fn as_ordered_revset(
+        &self,
+        repo: Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+    ) -> Box<OrderedNodeStream> {
+        let mut output: Vec<Box<OrderedNodeStream>> = Vec::with_capacity(self.rp_entries.len());
+        for entry in self.rp_entries.iter() {
+            let next_node = match entry {
+                &RevsetEntry::SingleNode(None) => panic!("You need to add_hashes first!"),
+                &RevsetEntry::SingleNode(Some(hash)) => with_generations(
+                    SingleNodeHash::new(hash, &*repo.clone()).boxed(),
+                    &repo,
+                    repo_generation.clone(),
+                ),
+                &RevsetEntry::SetDifference => {
+                    let keep = output.pop().expect("No keep for setdifference");
+                    let remove = output.pop().expect("No remove for setdifference");
+                    SetDifferenceNodeStream::from_ordered(keep, remove).ordered()
+                }
+                &RevsetEntry::Union(size) => {
+                    let idx = output.len() - size;
+                    UnionNodeStream::from_ordered(output.split_off(idx)).ordered()
+                }
+                &RevsetEntry::Intersect(size) => {
+                    let idx = output.len() - size;
+                    IntersectNodeStream::from_ordered(output.split_off(idx)).ordered()
+                }
+            };
+            output.push(next_node);
+        }
+        assert!(
+            output.len() == 1,
+            "output should have been length 1, was {}",
+            output.len()
+        );
+        output.pop().expect("No revset entries")
+    }
 }
 
 impl Arbitrary for RevsetSpec {
@@ -228,6 +278,31 @@ fn match_hashset_to_revset(repo: Arc<BlobRepo>, mut set: RevsetSpec) -> bool {
     nodestream.wait_stream().is_none() && hashes.is_empty()
 }
 
+fn match_hashset_to_ordered_revset(repo: Arc<BlobRepo>, mut set: RevsetSpec) -> bool {
+    let repo_generation = RepoGenCache::new(10);
+
+    set.add_hashes(&*repo, &mut thread_rng());
+    let mut hashes = set.as_hashes();
+    let nodestream = set.as_ordered_revset(repo.clone(), repo_generation.clone());
+    let mut nodestream = spawn(nodestream.and_then({
+        let repo = repo.clone();
+        let repo_generation = repo_generation.clone();
+        move |(hash, generation)| {
+            repo_generation
+                .get(&repo, hash)
+                .map(move |expected| (hash, generation == expected))
+        }
+    }));
+
+    while let Some(node) = nodestream.wait_stream() {
+        let (hash, generation_matches) = node.expect("Unexpected error");
+        if !generation_matches || !hashes.remove(&hash) {
+            return false;
+        }
+    }
+    hashes.is_empty()
+}
+
 // This is slightly icky. I would like to construct $test_name as setops_$repo, but concat_idents!
 // does not work the way I'd like it to. For now, make the user of this macro pass in both idents
 macro_rules! quickcheck_setops {
@@ -251,3 +326,195 @@ quickcheck_setops!(setops_merge_even, merge_even);
 quickcheck_setops!(setops_merge_uneven, merge_uneven);
 quickcheck_setops!(setops_unshared_merge_even, unshared_merge_even);
 quickcheck_setops!(setops_unshared_merge_uneven, unshared_merge_uneven);
+
+// The common ancestors with the highest generation, found by walking every generation
+fn naive_greatest_common_ancestors(
+    repo: &Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    nodes: Vec<NodeHash>,
+) -> Vec<NodeHash> {
+    let mut ancestors = spawn(common_ancestors(repo, repo_generation.clone(), nodes));
+    let mut highest_generation = None;
+    let mut result = Vec::new();
+
+    while let Some(node) = ancestors.wait_stream() {
+        let node = node.expect("Unexpected error");
+        let generation = spawn(repo_generation.get(repo, node))
+            .wait_future()
+            .expect("Failed to get generation");
+        match highest_generation {
+            None => highest_generation = Some(generation),
+            Some(highest) if highest != generation => break,
+            Some(_) => (),
+        }
+        result.push(node);
+    }
+    result.sort();
+    result
+}
+
+fn naive_is_ancestor(
+    repo: &Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    ancestor: NodeHash,
+    descendant: NodeHash,
+) -> bool {
+    let mut ancestors = spawn(AncestorsNodeStream::new(repo, repo_generation, descendant));
+    while let Some(node) = ancestors.wait_stream() {
+        if node.expect("Unexpected error") == ancestor {
+            return true;
+        }
+    }
+    false
+}
+
+fn match_skiplist_to_naive(repo: Arc<BlobRepo>, choices: Vec<usize>) -> bool {
+    if choices.is_empty() {
+        return true;
+    }
+    let repo_generation = RepoGenCache::new(10);
+    // A small number of edges per node, so that the fixtures use all of them
+    let skiplist = SkiplistIndex::with_skip_edges_per_node(3);
+
+    let all_changesets = get_all_changesets(&*repo);
+    let nodes: Vec<_> = choices
+        .iter()
+        .map(|choice| all_changesets[choice % all_changesets.len()])
+        .collect();
+
+    let ancestor = nodes[0];
+    let descendant = nodes[nodes.len() - 1];
+    let is_ancestor = spawn(skiplist.is_ancestor(
+        &repo,
+        repo_generation.clone(),
+        ancestor,
+        descendant,
+    )).wait_future()
+        .expect("is_ancestor failed");
+    if is_ancestor != naive_is_ancestor(&repo, repo_generation.clone(), ancestor, descendant) {
+        return false;
+    }
+
+    let collect = |nodestream: Box<NodeStream>| {
+        let mut nodestream = spawn(nodestream);
+        let mut nodes = HashSet::new();
+        while let Some(node) = nodestream.wait_stream() {
+            nodes.insert(node.expect("Unexpected error"));
+        }
+        nodes
+    };
+    let common = collect(common_ancestors_with_skiplist(
+        &repo,
+        repo_generation.clone(),
+        skiplist.clone(),
+        nodes.clone(),
+    ));
+    if common != collect(common_ancestors(&repo, repo_generation.clone(), nodes.clone())) {
+        return false;
+    }
+
+    let greatest_common_ancestors = spawn(skiplist.greatest_common_ancestors(
+        &repo,
+        repo_generation.clone(),
+        nodes.clone(),
+    )).wait_future()
+        .expect("greatest_common_ancestors failed");
+    greatest_common_ancestors == naive_greatest_common_ancestors(&repo, repo_generation, nodes)
+}
+
+macro_rules! quickcheck_skiplist {
+    ($test_name:ident, $repo:ident) => {
+        #[test]
+        fn $test_name() {
+            fn prop(choices: Vec<usize>) -> bool {
+                let repo = Arc::new($repo::getrepo(None));
+                match_skiplist_to_naive(repo, choices)
+            }
+            quickcheck(prop as fn(Vec<usize>) -> bool)
+        }
+    };
+}
+
+quickcheck_skiplist!(skiplist_branch_even, branch_even);
+quickcheck_skiplist!(skiplist_branch_uneven, branch_uneven);
+quickcheck_skiplist!(skiplist_branch_wide, branch_wide);
+quickcheck_skiplist!(skiplist_linear, linear);
+quickcheck_skiplist!(skiplist_merge_even, merge_even);
+quickcheck_skiplist!(skiplist_merge_uneven, merge_uneven);
+quickcheck_skiplist!(skiplist_unshared_merge_even, unshared_merge_even);
+quickcheck_skiplist!(skiplist_unshared_merge_uneven, unshared_merge_uneven);
+
+fn match_lazy_range_to_range(repo: Arc<BlobRepo>, choices: (usize, usize, usize)) -> bool {
+    let repo_generation = RepoGenCache::new(10);
+    let all_changesets = get_all_changesets(&*repo);
+    let (start, end, max_pending) = choices;
+    let start = all_changesets[start % all_changesets.len()];
+    let end = all_changesets[end % all_changesets.len()];
+
+    let mut range = spawn(RangeNodeStream::new(&repo, repo_generation.clone(), start, end));
+    let mut expected = HashSet::new();
+    while let Some(node) = range.wait_stream() {
+        expected.insert(node.expect("Unexpected error"));
+    }
+
+    // Validation checks that the nodes come out in generation order
+    let lazy_range = LazyRangeNodeStream::with_max_pending(
+        &repo,
+        repo_generation.clone(),
+        SkiplistIndex::with_skip_edges_per_node(3),
+        start,
+        end,
+        max_pending % 3 + 1,
+    ).boxed();
+    let mut lazy_range = spawn(ValidateNodeStream::new(lazy_range, &repo, repo_generation));
+    while let Some(node) = lazy_range.wait_stream() {
+        if !expected.remove(&node.expect("Unexpected error")) {
+            return false;
+        }
+    }
+    expected.is_empty()
+}
+
+macro_rules! quickcheck_lazy_range {
+    ($test_name:ident, $repo:ident) => {
+        #[test]
+        fn $test_name() {
+            fn prop(choices: (usize, usize, usize)) -> bool {
+                let repo = Arc::new($repo::getrepo(None));
+                match_lazy_range_to_range(repo, choices)
+            }
+            quickcheck(prop as fn((usize, usize, usize)) -> bool)
+        }
+    };
+}
+
+quickcheck_lazy_range!(lazy_range_branch_even, branch_even);
+quickcheck_lazy_range!(lazy_range_branch_uneven, branch_uneven);
+quickcheck_lazy_range!(lazy_range_branch_wide, branch_wide);
+quickcheck_lazy_range!(lazy_range_linear, linear);
+quickcheck_lazy_range!(lazy_range_merge_even, merge_even);
+quickcheck_lazy_range!(lazy_range_merge_uneven, merge_uneven);
+quickcheck_lazy_range!(lazy_range_unshared_merge_even, unshared_merge_even);
+quickcheck_lazy_range!(lazy_range_unshared_merge_uneven, unshared_merge_uneven);
+
+macro_rules! quickcheck_ordered_setops {
+    ($test_name:ident, $repo:ident) => {
+        #[test]
+        fn $test_name() {
+            fn prop(set: RevsetSpec) -> bool {
+                let repo = Arc::new($repo::getrepo(None));
+                match_hashset_to_ordered_revset(repo, set)
+            }
+            quickcheck(prop as fn(RevsetSpec) -> bool)
+        }
+    };
+}
+
+quickcheck_ordered_setops!(ordered_setops_branch_even, branch_even);
+quickcheck_ordered_setops!(ordered_setops_branch_uneven, branch_uneven);
+quickcheck_ordered_setops!(ordered_setops_branch_wide, branch_wide);
+quickcheck_ordered_setops!(ordered_setops_linear, linear);
+quickcheck_ordered_setops!(ordered_setops_merge_even, merge_even);
+quickcheck_ordered_setops!(ordered_setops_merge_uneven, merge_uneven);
+quickcheck_ordered_setops!(ordered_setops_unshared_merge_even, unshared_merge_even);
+quickcheck_ordered_setops!(ordered_setops_unshared_merge_uneven, unshared_merge_uneven);
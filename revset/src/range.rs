@@ -11,16 +11,17 @@ use std::mem::replace;
 use std::sync::Arc;
 
 use futures::{Async, Poll};
-use futures::future::Future;
+use futures::future::{join_all, Future};
 use futures::stream::{self, iter_ok, Stream};
 
 use blobrepo::BlobRepo;
-use mercurial_types::{Changeset, NodeHash};
+use mercurial_types::NodeHash;
 use mercurial_types::nodehash::HgChangesetId;
 use repoinfo::{Generation, RepoGenCache};
 
 use NodeStream;
 use errors::*;
+use skiplist::SkiplistIndex;
 
 #[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
 struct HashGen {
@@ -54,8 +55,12 @@ fn make_pending(
     Box::new(
         {
             let repo = repo.clone();
-            repo.get_changeset_by_changesetid(&HgChangesetId::new(child.hash))
-                .map(move |cs| (child, cs.parents().clone()))
+            repo.get_changeset_parents(&HgChangesetId::new(child.hash))
+                .map(move |parents| {
+                    let parents: Vec<_> =
+                        parents.into_iter().map(|parent| parent.into_nodehash()).collect();
+                    (child, parents)
+                })
                 .map_err(|err| err.context(ErrorKind::ParentsFetchFailed).into())
         }.map(|(child, parents)| iter_ok::<_, Error>(iter::repeat(child).zip(parents.into_iter())))
             .flatten_stream()
@@ -228,9 +233,174 @@ impl Stream for RangeNodeStream {
     }
 }
 
+const DEFAULT_MAX_PENDING: usize = 1000;
+const DEFAULT_MAX_FRONTIER: usize = 100_000;
+
+/// Like `RangeNodeStream`, but outputs each node as soon as it's proven to be in the range,
+/// instead of after the whole range has been walked. The ancestors of the end node are walked
+/// down from the highest generation, and a node is in the range if the skiplist shows that the
+/// start node is one of its ancestors. Ancestors of a node that isn't in the range can't be in
+/// it either, so only the parents of nodes in the range are walked. The skiplist only indexes
+/// ancestors down to the generation of the start node, so neither the time to the first node
+/// nor the memory used depend on the history below the range.
+///
+/// This is a library API: the server still answers its range queries with `RangeNodeStream`.
+pub struct LazyRangeNodeStream {
+    nodes: Box<NodeStream>,
+}
+
+impl LazyRangeNodeStream {
+    pub fn new(
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        skiplist: SkiplistIndex,
+        start_node: NodeHash,
+        end_node: NodeHash,
+    ) -> Self {
+        Self::with_max_pending(
+            repo,
+            repo_generation,
+            skiplist,
+            start_node,
+            end_node,
+            DEFAULT_MAX_PENDING,
+        )
+    }
+
+    /// Like `new`, but checks at most `max_pending` nodes at once
+    pub fn with_max_pending(
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        skiplist: SkiplistIndex,
+        start_node: NodeHash,
+        end_node: NodeHash,
+        max_pending: usize,
+    ) -> Self {
+        Self::with_limits(
+            repo,
+            repo_generation,
+            skiplist,
+            start_node,
+            end_node,
+            max_pending,
+            DEFAULT_MAX_FRONTIER,
+        )
+    }
+
+    /// Like `new`, but checks at most `max_pending` nodes at once, and fails with
+    /// `ErrorKind::RangeTooWide` instead of keeping more than `max_frontier` nodes to check
+    pub fn with_limits(
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        skiplist: SkiplistIndex,
+        start_node: NodeHash,
+        end_node: NodeHash,
+        max_pending: usize,
+        max_frontier: usize,
+    ) -> Self {
+        assert!(max_pending > 0, "max_pending must be positive");
+        let generations = repo_generation
+            .get(repo, start_node)
+            .join(repo_generation.get(repo, end_node))
+            .map_err(|err| err.context(ErrorKind::GenerationFetchFailed).into());
+
+        let repo = repo.clone();
+        let nodes = generations
+            .map(move |(start_generation, end_generation)| {
+                let mut frontier: BTreeMap<Generation, HashSet<NodeHash>> = BTreeMap::new();
+                if end_generation >= start_generation {
+                    frontier.insert(end_generation, hashset!{end_node});
+                }
+
+                let frontier_size = frontier.len();
+                let generation_nodes = stream::unfold((frontier, frontier_size), move |state| {
+                    let (mut frontier, mut frontier_size) = state;
+                    let highest_generation = match frontier.keys().next_back() {
+                        Some(generation) => *generation,
+                        None => return None,
+                    };
+                    let nodes: Vec<_> = {
+                        let current = frontier
+                            .get_mut(&highest_generation)
+                            .expect("Highest generation doesn't exist");
+                        let nodes: Vec<_> = current.iter().take(max_pending).cloned().collect();
+                        for node in nodes.iter() {
+                            current.remove(node);
+                        }
+                        nodes
+                    };
+                    frontier_size -= nodes.len();
+                    if frontier[&highest_generation].is_empty() {
+                        frontier.remove(&highest_generation);
+                    }
+
+                    let checks: Vec<_> = nodes
+                        .into_iter()
+                        .map(|node| {
+                            skiplist
+                                .is_ancestor(&repo, repo_generation.clone(), start_node, node)
+                                .map(move |in_range| (node, in_range))
+                        })
+                        .collect();
+                    let skiplist = skiplist.clone();
+                    Some(join_all(checks).and_then(move |checked| {
+                        let in_range: Vec<_> = checked
+                            .into_iter()
+                            .filter(|&(_, in_range)| in_range)
+                            .map(|(node, _)| node)
+                            .collect();
+                        for node in in_range.iter() {
+                            // Checking the node indexed it, so its parents are in the index
+                            let parents = skiplist
+                                .get_parents(node)
+                                .ok_or(ErrorKind::SkiplistIndexMissing(*node))?;
+                            for (parent, generation) in parents {
+                                if generation >= start_generation {
+                                    let added = frontier
+                                        .entry(generation)
+                                        .or_insert_with(HashSet::new)
+                                        .insert(parent);
+                                    if added {
+                                        frontier_size += 1;
+                                    }
+                                }
+                            }
+                        }
+                        ensure_err!(
+                            frontier_size <= max_frontier,
+                            ErrorKind::RangeTooWide(max_frontier)
+                        );
+                        Ok((in_range, (frontier, frontier_size)))
+                    }))
+                });
+                generation_nodes
+                    .map(|nodes| iter_ok::<_, Error>(nodes))
+                    .flatten()
+            })
+            .flatten_stream();
+
+        LazyRangeNodeStream {
+            nodes: Box::new(nodes),
+        }
+    }
+
+    pub fn boxed(self) -> Box<NodeStream> {
+        Box::new(self)
+    }
+}
+
+impl Stream for LazyRangeNodeStream {
+    type Item = NodeHash;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.nodes.poll()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use futures::executor::spawn;
     use linear;
     use merge_uneven;
     use tests::assert_node_sequence;
@@ -380,4 +550,100 @@ mod test {
             nodestream,
         )
     }
+
+    #[test]
+    fn linear_lazy_range() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        let nodestream = LazyRangeNodeStream::new(
+            &repo,
+            repo_generation.clone(),
+            SkiplistIndex::new(),
+            string_to_nodehash("d0a361e9022d226ae52f689667bd7d212a19cfe0"),
+            string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+        ).boxed();
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+                string_to_nodehash("0ed509bf086fadcb8a8a5384dc3b550729b0fc17"),
+                string_to_nodehash("eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b"),
+                string_to_nodehash("cb15ca4a43a59acff5388cea9648c162afde8372"),
+                string_to_nodehash("d0a361e9022d226ae52f689667bd7d212a19cfe0"),
+            ],
+            nodestream,
+        )
+    }
+
+    #[test]
+    fn linear_lazy_empty_range() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        // These are swapped, so won't find anything
+        let nodestream = LazyRangeNodeStream::new(
+            &repo,
+            repo_generation.clone(),
+            SkiplistIndex::new(),
+            string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+            string_to_nodehash("d0a361e9022d226ae52f689667bd7d212a19cfe0"),
+        ).boxed();
+
+        assert_node_sequence(repo_generation, &repo, vec![], nodestream)
+    }
+
+    #[test]
+    fn merge_lazy_range_from_merge() {
+        let repo = Arc::new(merge_uneven::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        // One node at a time, so that each generation is split up
+        let nodestream = LazyRangeNodeStream::with_max_pending(
+            &repo,
+            repo_generation.clone(),
+            SkiplistIndex::new(),
+            string_to_nodehash("1d8a907f7b4bf50c6a09c16361e2205047ecc5e5"),
+            string_to_nodehash("75742e6fc286a359b39a89fdfa437cc7e2a0e1ce"),
+            1,
+        ).boxed();
+
+        assert_node_sequence(
+            repo_generation,
+            &repo,
+            vec![
+                string_to_nodehash("75742e6fc286a359b39a89fdfa437cc7e2a0e1ce"),
+                string_to_nodehash("16839021e338500b3cf7c9b871c8a07351697d68"),
+                string_to_nodehash("1d8a907f7b4bf50c6a09c16361e2205047ecc5e5"),
+            ],
+            nodestream,
+        )
+    }
+
+    #[test]
+    fn merge_lazy_range_too_wide() {
+        let repo = Arc::new(merge_uneven::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+
+        // Both parents of the merge have to be checked, which is more than allowed
+        let nodestream = LazyRangeNodeStream::with_limits(
+            &repo,
+            repo_generation,
+            SkiplistIndex::new(),
+            string_to_nodehash("15c40d0abc36d47fb51c8eaec51ac7aad31f669c"),
+            string_to_nodehash("75742e6fc286a359b39a89fdfa437cc7e2a0e1ce"),
+            1,
+            1,
+        );
+
+        let err = spawn(nodestream.collect())
+            .wait_future()
+            .expect_err("range should be too wide");
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::RangeTooWide(1)) => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
 }
@@ -14,10 +14,11 @@ use std::sync::Arc;
 
 use NodeStream;
 use errors::*;
+use ordered::OrderedNodeStream;
 
 use futures::{Async, Poll};
 
-pub type InputStream = Box<Stream<Item = (NodeHash, Generation), Error = Error> + 'static + Send>;
+pub type InputStream = Box<OrderedNodeStream>;
 
 pub fn add_generations(
     stream: Box<NodeStream>,
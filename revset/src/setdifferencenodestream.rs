@@ -6,7 +6,7 @@
 
 use blobrepo::BlobRepo;
 use futures::{Async, Poll};
-use futures::stream::Stream;
+use futures::stream::{self, Stream};
 use mercurial_types::NodeHash;
 use repoinfo::{Generation, RepoGenCache};
 use std::boxed::Box;
@@ -15,6 +15,7 @@ use std::sync::Arc;
 
 use NodeStream;
 use errors::*;
+use ordered::OrderedNodeStream;
 use setcommon::*;
 
 pub struct SetDifferenceNodeStream {
@@ -34,11 +35,22 @@ impl SetDifferenceNodeStream {
         repo_generation: RepoGenCache,
         keep_input: Box<NodeStream>,
         remove_input: Box<NodeStream>,
+    ) -> SetDifferenceNodeStream {
+        Self::from_ordered(
+            add_generations(keep_input, repo_generation.clone(), repo.clone()),
+            add_generations(remove_input, repo_generation, repo.clone()),
+        )
+    }
+
+    /// Like `new`, but the inputs already know the generations of their nodes
+    pub fn from_ordered(
+        keep_input: Box<OrderedNodeStream>,
+        remove_input: Box<OrderedNodeStream>,
     ) -> SetDifferenceNodeStream {
         SetDifferenceNodeStream {
-            keep_input: add_generations(keep_input, repo_generation.clone(), repo.clone()),
+            keep_input,
             next_keep: Async::NotReady,
-            remove_input: add_generations(remove_input, repo_generation, repo.clone()),
+            remove_input,
             next_remove: Async::NotReady,
 
             remove_nodes: HashSet::new(),
@@ -50,6 +62,11 @@ impl SetDifferenceNodeStream {
         return Box::new(self);
     }
 
+    pub fn ordered(self) -> Box<OrderedNodeStream> {
+        let mut nodes = self;
+        Box::new(stream::poll_fn(move || nodes.poll_ordered()))
+    }
+
     fn next_keep(&mut self) -> Result<&Async<Option<(NodeHash, Generation)>>> {
         if self.next_keep.is_not_ready() {
             self.next_keep = self.keep_input.poll()?;
@@ -63,12 +80,8 @@ impl SetDifferenceNodeStream {
         }
         Ok(&self.next_remove)
     }
-}
 
-impl Stream for SetDifferenceNodeStream {
-    type Item = NodeHash;
-    type Error = Error;
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    fn poll_ordered(&mut self) -> Poll<Option<(NodeHash, Generation)>, Error> {
         // This feels wrong, but in practice it's fine - it should be quick to hit a return, and
         // the standard futures::executor expects you to only return NotReady if blocked on I/O.
         loop {
@@ -104,12 +117,21 @@ impl Stream for SetDifferenceNodeStream {
             self.next_keep = Async::NotReady; // will cause polling of keep_input
 
             if !self.remove_nodes.contains(&keep_hash) {
-                return Ok(Async::Ready(Some(keep_hash)));
+                return Ok(Async::Ready(Some((keep_hash, keep_gen))));
             }
         }
     }
 }
 
+impl Stream for SetDifferenceNodeStream {
+    type Item = NodeHash;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let next = self.poll_ordered()?;
+        Ok(next.map(|next| next.map(|(hash, _)| hash)))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
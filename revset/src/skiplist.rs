@@ -0,0 +1,660 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Skip edges for changesets with a single parent. The first edge is the parent, and each edge
+// after it is the same numbered edge of the node the previous edge points to, so the edges of a
+// node in linear history point 1, 2, 4, 8... generations down. Merges (and roots) only record
+// their parents. The index is built lazily: adding a node indexes all of its unindexed
+// ancestors, so every ancestor of an indexed node is indexed too. The exception is the nodes
+// added above a generation, whose ancestors are only indexed down to that generation: they are
+// recorded as incomplete, and walked again by anything that needs their lower ancestors.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use futures::future::{self, join_all, Future, Loop};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::BlobRepo;
+use mercurial_types::NodeHash;
+use mercurial_types::nodehash::HgChangesetId;
+use repoinfo::{Generation, RepoGenCache};
+
+use errors::*;
+
+const DEFAULT_EDGE_COUNT: usize = 16;
+
+#[derive(Clone, Debug)]
+enum SkiplistNodeType {
+    // Edges of a changeset with a single parent, at exponentially increasing distances
+    SkipEdges(Vec<(NodeHash, Generation)>),
+    // Parents of a merge or a root
+    ParentEdges(Vec<(NodeHash, Generation)>),
+}
+
+// Nodes still being walked, grouped by generation
+type Frontier = BTreeMap<Generation, HashSet<NodeHash>>;
+
+// Nodes to visit, nodes seen, nodes found with their generations and parents, and whether some
+// ancestors were left out of the walk
+type IndexWalkState = (
+    Vec<NodeHash>,
+    HashSet<NodeHash>,
+    Vec<(NodeHash, Generation, Vec<(NodeHash, Generation)>)>,
+    bool,
+);
+
+/// Index of skip edges, used to answer ancestry queries without visiting every generation of
+/// long linear histories
+#[derive(Clone)]
+pub struct SkiplistIndex {
+    skip_list_edges: Arc<Mutex<HashMap<NodeHash, SkiplistNodeType>>>,
+    // Indexed nodes whose ancestors are only indexed down to a generation. This is always locked
+    // after `skip_list_edges`.
+    incomplete: Arc<Mutex<HashMap<NodeHash, Generation>>>,
+    skip_edges_per_node: usize,
+}
+
+/// The parents of `hash` with their generation numbers
+pub fn fetch_parents(
+    repo: &Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    hash: NodeHash,
+) -> BoxFuture<Vec<(NodeHash, Generation)>, Error> {
+    let repo = repo.clone();
+    let parents = repo.get_changeset_parents(&HgChangesetId::new(hash))
+        .map(|parents| {
+            parents
+                .into_iter()
+                .map(|parent| parent.into_nodehash())
+                .collect::<Vec<_>>()
+        })
+        .map_err(|err| err.context(ErrorKind::ParentsFetchFailed).into());
+
+    parents
+        .and_then(move |parents| {
+            repo_generation
+                .get_many(&repo, parents.clone())
+                .map(move |gen_ids| parents.into_iter().zip(gen_ids).collect())
+                .map_err(|err| err.context(ErrorKind::GenerationFetchFailed).into())
+        })
+        .boxify()
+}
+
+fn generation(
+    repo: &Arc<BlobRepo>,
+    repo_generation: &RepoGenCache,
+    hash: NodeHash,
+) -> BoxFuture<Generation, Error> {
+    repo_generation
+        .get(repo, hash)
+        .map_err(|err| err.context(ErrorKind::GenerationFetchFailed).into())
+        .boxify()
+}
+
+fn compute_edges(
+    edges: &HashMap<NodeHash, SkiplistNodeType>,
+    parents: Vec<(NodeHash, Generation)>,
+    edge_count: usize,
+) -> SkiplistNodeType {
+    if parents.len() != 1 {
+        return SkiplistNodeType::ParentEdges(parents);
+    }
+
+    let mut node_edges = parents;
+    while node_edges.len() < edge_count {
+        let idx = node_edges.len() - 1;
+        let next = match edges.get(&node_edges[idx].0) {
+            Some(&SkiplistNodeType::SkipEdges(ref next_edges)) if next_edges.len() > idx => {
+                next_edges[idx]
+            }
+            _ => break,
+        };
+        node_edges.push(next);
+    }
+    SkiplistNodeType::SkipEdges(node_edges)
+}
+
+// Replace the nodes at the highest generation of `frontier` with the lowest nodes reachable
+// through their edges that are still at or above `target`. If there is no such edge, the parents
+// are used.
+fn advance_frontier(
+    edges: &HashMap<NodeHash, SkiplistNodeType>,
+    frontier: &mut Frontier,
+    target: Generation,
+) -> Result<()> {
+    let highest_generation = match frontier.keys().next_back() {
+        Some(generation) => *generation,
+        None => return Ok(()),
+    };
+    let nodes = frontier
+        .remove(&highest_generation)
+        .expect("Highest generation doesn't exist");
+
+    for node in nodes {
+        let next: Vec<(NodeHash, Generation)> = match edges.get(&node) {
+            Some(&SkiplistNodeType::SkipEdges(ref node_edges)) => {
+                let farthest = node_edges
+                    .iter()
+                    .take_while(|&&(_, gen_id)| gen_id >= target)
+                    .last()
+                    .unwrap_or(&node_edges[0]);
+                vec![*farthest]
+            }
+            Some(&SkiplistNodeType::ParentEdges(ref parents)) => parents.clone(),
+            None => bail_err!(ErrorKind::SkiplistIndexMissing(node)),
+        };
+        for (hash, gen_id) in next {
+            frontier
+                .entry(gen_id)
+                .or_insert_with(HashSet::new)
+                .insert(hash);
+        }
+    }
+    Ok(())
+}
+
+// Whether `ancestor` is reachable from `descendant`, jumping along the edges of indexed nodes
+fn reaches(
+    edges: &HashMap<NodeHash, SkiplistNodeType>,
+    (ancestor, ancestor_gen): (NodeHash, Generation),
+    (descendant, descendant_gen): (NodeHash, Generation),
+) -> Result<bool> {
+    let mut frontier = single_frontier(descendant, descendant_gen);
+    loop {
+        let highest_generation = match frontier.keys().next_back() {
+            Some(generation) => *generation,
+            None => return Ok(false),
+        };
+        if highest_generation < ancestor_gen {
+            return Ok(false);
+        }
+        if highest_generation == ancestor_gen {
+            return Ok(frontier[&highest_generation].contains(&ancestor));
+        }
+        advance_frontier(edges, &mut frontier, ancestor_gen)?;
+    }
+}
+
+// Whether `gen_id` is low enough to be left out of a walk down to `min_generation`
+fn is_below(gen_id: Generation, min_generation: Option<Generation>) -> bool {
+    min_generation.map_or(false, |min_generation| gen_id < min_generation)
+}
+
+fn single_frontier(hash: NodeHash, gen_id: Generation) -> Frontier {
+    let mut frontier = BTreeMap::new();
+    frontier.insert(gen_id, hashset!{hash});
+    frontier
+}
+
+impl SkiplistIndex {
+    pub fn new() -> Self {
+        Self::with_skip_edges_per_node(DEFAULT_EDGE_COUNT)
+    }
+
+    /// An index that keeps at most `skip_edges_per_node` edges for each changeset, so jumps
+    /// cover at most 2^(skip_edges_per_node - 1) generations
+    pub fn with_skip_edges_per_node(skip_edges_per_node: usize) -> Self {
+        assert!(skip_edges_per_node > 0, "skiplist needs at least one edge per node");
+        SkiplistIndex {
+            skip_list_edges: Arc::new(Mutex::new(HashMap::new())),
+            incomplete: Arc::new(Mutex::new(HashMap::new())),
+            skip_edges_per_node,
+        }
+    }
+
+    pub fn is_indexed(&self, node: &NodeHash) -> bool {
+        self.skip_list_edges
+            .lock()
+            .expect("lock poisoned")
+            .contains_key(node)
+    }
+
+    /// The parents of `node` with their generations, if it's indexed
+    pub fn get_parents(&self, node: &NodeHash) -> Option<Vec<(NodeHash, Generation)>> {
+        let edges = self.skip_list_edges.lock().expect("lock poisoned");
+        edges.get(node).map(|node_type| match node_type {
+            &SkiplistNodeType::SkipEdges(ref node_edges) => vec![node_edges[0]],
+            &SkiplistNodeType::ParentEdges(ref parents) => parents.clone(),
+        })
+    }
+
+    /// Index `node` and all of its ancestors that aren't indexed yet
+    pub fn add_node(
+        &self,
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        node: NodeHash,
+    ) -> BoxFuture<(), Error> {
+        self.index_ancestors(repo, repo_generation, node, None)
+    }
+
+    /// Index `node` and its ancestors down to `min_generation`. The walk doesn't go any lower,
+    /// so its cost depends on the distance to `min_generation` and not on the size of the
+    /// history.
+    pub fn add_node_above(
+        &self,
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        node: NodeHash,
+        min_generation: Generation,
+    ) -> BoxFuture<(), Error> {
+        self.index_ancestors(repo, repo_generation, node, Some(min_generation))
+    }
+
+    /// Index `node` and its ancestors at most `depth` generations below it. Like with
+    /// `add_node_above`, the lower ancestors are left to the walks that need them.
+    pub fn add_node_with_depth(
+        &self,
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        node: NodeHash,
+        depth: u64,
+    ) -> BoxFuture<(), Error> {
+        let this = self.clone();
+        let repo = repo.clone();
+        generation(&repo, &repo_generation, node)
+            .and_then(move |gen_id| {
+                this.add_node_above(&repo, repo_generation, node, gen_id.below(depth))
+            })
+            .boxify()
+    }
+
+    fn index_ancestors(
+        &self,
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        node: NodeHash,
+        min_generation: Option<Generation>,
+    ) -> BoxFuture<(), Error> {
+        let skip_list_edges = self.skip_list_edges.clone();
+        let incomplete = self.incomplete.clone();
+        let edge_count = self.skip_edges_per_node;
+        let repo = repo.clone();
+
+        let walk = future::loop_fn((vec![node], HashSet::new(), Vec::new(), false), {
+            let skip_list_edges = skip_list_edges.clone();
+            let incomplete = incomplete.clone();
+            move |(to_visit, mut seen, mut found, mut partial): IndexWalkState| {
+                let to_fetch: Vec<_> = {
+                    let edges = skip_list_edges.lock().expect("lock poisoned");
+                    let incomplete = incomplete.lock().expect("lock poisoned");
+                    let mut to_fetch = Vec::new();
+                    for hash in to_visit {
+                        if !edges.contains_key(&hash) {
+                            if seen.insert(hash) {
+                                to_fetch.push(hash);
+                            }
+                            continue;
+                        }
+                        // Indexed nodes are only walked again if their ancestors aren't indexed
+                        // as far down as this walk goes
+                        if let Some(floor) = incomplete.get(&hash) {
+                            let deep_enough = min_generation.map_or(false, |min| *floor <= min);
+                            if deep_enough {
+                                partial = true;
+                            } else if seen.insert(hash) {
+                                to_fetch.push(hash);
+                            }
+                        }
+                    }
+                    to_fetch
+                };
+                if to_fetch.is_empty() {
+                    return future::ok(Loop::Break((found, partial))).boxify();
+                }
+
+                let fetched = to_fetch.into_iter().map({
+                    let repo = repo.clone();
+                    let repo_generation = repo_generation.clone();
+                    move |hash| {
+                        generation(&repo, &repo_generation, hash)
+                            .join(fetch_parents(&repo, repo_generation.clone(), hash))
+                            .map(move |(gen_id, parents)| (hash, gen_id, parents))
+                    }
+                });
+                join_all(fetched)
+                    .map(move |fetched| {
+                        let mut to_visit = Vec::new();
+                        for &(_, _, ref parents) in fetched.iter() {
+                            for &(parent, gen_id) in parents {
+                                if is_below(gen_id, min_generation) {
+                                    partial = true;
+                                } else {
+                                    to_visit.push(parent);
+                                }
+                            }
+                        }
+                        found.extend(fetched);
+                        Loop::Continue((to_visit, seen, found, partial))
+                    })
+                    .boxify()
+            }
+        });
+
+        walk.map(move |(mut found, partial)| {
+            // Parents have lower generations than their children, so they get indexed first
+            found.sort_by_key(|&(_, gen_id, _)| gen_id);
+            let mut edges = skip_list_edges.lock().expect("lock poisoned");
+            let mut incomplete = incomplete.lock().expect("lock poisoned");
+            for (hash, _, parents) in found {
+                let node_edges = compute_edges(&edges, parents, edge_count);
+                edges.insert(hash, node_edges);
+                match min_generation {
+                    Some(min_generation) if partial => incomplete.insert(hash, min_generation),
+                    _ => incomplete.remove(&hash),
+                };
+            }
+        }).boxify()
+    }
+
+    /// Whether `ancestor` is an ancestor of (or the same as) `descendant`
+    pub fn is_ancestor(
+        &self,
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        ancestor: NodeHash,
+        descendant: NodeHash,
+    ) -> BoxFuture<bool, Error> {
+        let skip_list_edges = self.skip_list_edges.clone();
+        let this = self.clone();
+        let repo = repo.clone();
+        let generations = generation(&repo, &repo_generation, ancestor)
+            .join(generation(&repo, &repo_generation, descendant));
+
+        generations
+            .and_then(move |(ancestor_gen, descendant_gen)| {
+                // Nothing below `ancestor` is needed to find it
+                this.add_node_above(&repo, repo_generation, descendant, ancestor_gen)
+                    .map(move |()| (ancestor_gen, descendant_gen))
+            })
+            .and_then(move |(ancestor_gen, descendant_gen)| {
+                let edges = skip_list_edges.lock().expect("lock poisoned");
+                reaches(
+                    &edges,
+                    (ancestor, ancestor_gen),
+                    (descendant, descendant_gen),
+                )
+            })
+            .boxify()
+    }
+
+    /// Whether `ancestor`, at generation `ancestor_gen`, is an ancestor of (or the same as) all
+    /// of `descendants`, which must already be indexed. This only looks at the index, so it
+    /// can be used on every node of a stream.
+    pub fn is_common_ancestor(
+        &self,
+        ancestor: NodeHash,
+        ancestor_gen: Generation,
+        descendants: &[(NodeHash, Generation)],
+    ) -> Result<bool> {
+        let edges = self.skip_list_edges.lock().expect("lock poisoned");
+        for descendant in descendants {
+            if !reaches(&edges, (ancestor, ancestor_gen), *descendant)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The common ancestors of `nodes` with the highest generation, in sorted order. Every
+    /// frontier above the lowest one jumps down to it, since no common ancestor can be above
+    /// a frontier that it isn't reachable from.
+    pub fn greatest_common_ancestors<I>(
+        &self,
+        repo: &Arc<BlobRepo>,
+        repo_generation: RepoGenCache,
+        nodes: I,
+    ) -> BoxFuture<Vec<NodeHash>, Error>
+    where
+        I: IntoIterator<Item = NodeHash>,
+    {
+        let nodes: Vec<_> = nodes.into_iter().collect();
+        if nodes.is_empty() {
+            return future::ok(Vec::new()).boxify();
+        }
+
+        let skip_list_edges = self.skip_list_edges.clone();
+        let indexed = join_all(
+            nodes
+                .iter()
+                .map(|node| self.add_node(repo, repo_generation.clone(), *node))
+                .collect::<Vec<_>>(),
+        );
+        let generations = join_all(
+            nodes
+                .iter()
+                .map(|node| generation(repo, &repo_generation, *node))
+                .collect::<Vec<_>>(),
+        );
+
+        indexed
+            .join(generations)
+            .and_then(move |(_, generations)| {
+                let edges = skip_list_edges.lock().expect("lock poisoned");
+                let mut frontiers: Vec<Frontier> = nodes
+                    .into_iter()
+                    .zip(generations)
+                    .map(|(node, gen_id)| single_frontier(node, gen_id))
+                    .collect();
+
+                loop {
+                    let highest_generations: Option<Vec<Generation>> = frontiers
+                        .iter()
+                        .map(|frontier| frontier.keys().next_back().cloned())
+                        .collect();
+                    let highest_generations = match highest_generations {
+                        Some(highest_generations) => highest_generations,
+                        // One of the nodes ran out of ancestors
+                        None => return Ok(Vec::new()),
+                    };
+                    let lowest = *highest_generations
+                        .iter()
+                        .min()
+                        .expect("No frontiers");
+                    let highest = *highest_generations
+                        .iter()
+                        .max()
+                        .expect("No frontiers");
+
+                    if lowest == highest {
+                        let mut common = frontiers[0][&highest].clone();
+                        for frontier in frontiers.iter().skip(1) {
+                            common = common
+                                .intersection(&frontier[&highest])
+                                .cloned()
+                                .collect();
+                        }
+                        if !common.is_empty() {
+                            let mut common: Vec<_> = common.into_iter().collect();
+                            common.sort();
+                            return Ok(common);
+                        }
+                    }
+
+                    for (frontier, highest_generation) in
+                        frontiers.iter_mut().zip(highest_generations)
+                    {
+                        if highest_generation == highest {
+                            advance_frontier(&edges, frontier, lowest)?;
+                        }
+                    }
+                }
+            })
+            .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::spawn;
+    use linear;
+    use merge_uneven;
+    use tests::string_to_nodehash;
+    use unshared_merge_uneven;
+
+    #[test]
+    fn linear_skip_edges() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+        let skiplist = SkiplistIndex::with_skip_edges_per_node(4);
+
+        let head = string_to_nodehash("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+        spawn(skiplist.add_node(&repo, repo_generation, head))
+            .wait_future()
+            .expect("indexing failed");
+
+        let edges = skiplist.skip_list_edges.lock().unwrap();
+        match edges[&head] {
+            SkiplistNodeType::SkipEdges(ref head_edges) => assert_eq!(
+                head_edges.iter().map(|&(hash, _)| hash).collect::<Vec<_>>(),
+                vec![
+                    string_to_nodehash("3c15267ebf11807f3d772eb891272b911ec68759"),
+                    string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+                    string_to_nodehash("eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b"),
+                    string_to_nodehash("3e0e761030db6e479a7fb58b12881883f9f8c63f"),
+                ]
+            ),
+            ref other => panic!("unexpected head edges {:?}", other),
+        }
+        match edges[&string_to_nodehash("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536")] {
+            SkiplistNodeType::ParentEdges(ref parents) => assert!(parents.is_empty()),
+            ref other => panic!("unexpected root edges {:?}", other),
+        }
+    }
+
+    #[test]
+    fn linear_add_node_above() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+        let skiplist = SkiplistIndex::new();
+
+        let root = string_to_nodehash("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536");
+        let middle = string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157");
+        let head = string_to_nodehash("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+        let middle_gen = spawn(repo_generation.get(&repo, middle))
+            .wait_future()
+            .expect("Failed to get generation");
+
+        spawn(skiplist.add_node_above(&repo, repo_generation.clone(), head, middle_gen))
+            .wait_future()
+            .expect("indexing failed");
+        assert!(skiplist.is_indexed(&middle));
+        assert!(!skiplist.is_indexed(&root));
+
+        // Without a bound, the rest of the ancestors get indexed
+        spawn(skiplist.add_node(&repo, repo_generation, head))
+            .wait_future()
+            .expect("indexing failed");
+        assert!(skiplist.is_indexed(&root));
+    }
+
+    #[test]
+    fn linear_add_node_with_depth() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+        let skiplist = SkiplistIndex::new();
+
+        let root = string_to_nodehash("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536");
+        let parent = string_to_nodehash("3c15267ebf11807f3d772eb891272b911ec68759");
+        let grandparent = string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157");
+        let head = string_to_nodehash("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+
+        spawn(skiplist.add_node_with_depth(&repo, repo_generation.clone(), head, 1))
+            .wait_future()
+            .expect("indexing failed");
+        assert!(skiplist.is_indexed(&parent));
+        assert!(!skiplist.is_indexed(&grandparent));
+
+        // Ancestry checks below the indexed generations still walk down to them
+        let is_ancestor = skiplist.is_ancestor(&repo, repo_generation, root, head);
+        assert!(spawn(is_ancestor).wait_future().expect("is_ancestor failed"));
+        assert!(skiplist.is_indexed(&root));
+    }
+
+    #[test]
+    fn linear_is_ancestor() {
+        let repo = Arc::new(linear::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+        let skiplist = SkiplistIndex::new();
+
+        let root = string_to_nodehash("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536");
+        let head = string_to_nodehash("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+        let check = |ancestor, descendant| {
+            spawn(skiplist.is_ancestor(&repo, repo_generation.clone(), ancestor, descendant))
+                .wait_future()
+                .expect("is_ancestor failed")
+        };
+
+        assert!(check(root, head));
+        assert!(check(head, head));
+        assert!(!check(head, root));
+    }
+
+    #[test]
+    fn merge_is_ancestor() {
+        let repo = Arc::new(merge_uneven::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+        let skiplist = SkiplistIndex::new();
+
+        let merge = string_to_nodehash("75742e6fc286a359b39a89fdfa437cc7e2a0e1ce");
+        let branch = string_to_nodehash("16839021e338500b3cf7c9b871c8a07351697d68");
+        let other_branch = string_to_nodehash("4f7f3fd428bec1a48f9314414b063c706d9c1aed");
+        let check = |ancestor, descendant| {
+            spawn(skiplist.is_ancestor(&repo, repo_generation.clone(), ancestor, descendant))
+                .wait_future()
+                .expect("is_ancestor failed")
+        };
+
+        assert!(check(branch, merge));
+        assert!(check(other_branch, merge));
+        assert!(!check(branch, other_branch));
+    }
+
+    #[test]
+    fn merge_greatest_common_ancestors() {
+        let repo = Arc::new(merge_uneven::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+        let skiplist = SkiplistIndex::new();
+
+        let gcas = spawn(skiplist.greatest_common_ancestors(
+            &repo,
+            repo_generation,
+            vec![
+                string_to_nodehash("4f7f3fd428bec1a48f9314414b063c706d9c1aed"),
+                string_to_nodehash("264f01429683b3dd8042cb3979e8bf37007118bc"),
+            ],
+        )).wait_future()
+            .expect("greatest_common_ancestors failed");
+
+        assert_eq!(
+            gcas,
+            vec![
+                string_to_nodehash("4f7f3fd428bec1a48f9314414b063c706d9c1aed"),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_greatest_common_ancestors() {
+        let repo = Arc::new(unshared_merge_uneven::getrepo(None));
+        let repo_generation = RepoGenCache::new(10);
+        let skiplist = SkiplistIndex::new();
+
+        let gcas = spawn(skiplist.greatest_common_ancestors(
+            &repo,
+            repo_generation,
+            vec![
+                string_to_nodehash("64011f64aaf9c2ad2e674f57c033987da4016f51"),
+                string_to_nodehash("1700524113b1a3b1806560341009684b4378660b"),
+            ],
+        )).wait_future()
+            .expect("greatest_common_ancestors failed");
+
+        assert!(gcas.is_empty());
+    }
+}
@@ -7,7 +7,7 @@
 use blobrepo::BlobRepo;
 use futures::Async;
 use futures::Poll;
-use futures::stream::Stream;
+use futures::stream::{self, Stream};
 use mercurial_types::NodeHash;
 use repoinfo::{Generation, RepoGenCache};
 use std::boxed::Box;
@@ -20,11 +20,13 @@ use std::sync::Arc;
 use failure::Error;
 
 use NodeStream;
+use ordered::OrderedNodeStream;
 use setcommon::*;
 
 pub struct UnionNodeStream {
     inputs: Vec<(InputStream, Poll<Option<(NodeHash, Generation)>, Error>)>,
     current_generation: Option<Generation>,
+    accumulator_generation: Option<Generation>,
     accumulator: HashSet<NodeHash>,
     drain: Option<IntoIter<NodeHash>>,
 }
@@ -34,17 +36,22 @@ impl UnionNodeStream {
     where
         I: IntoIterator<Item = Box<NodeStream>>,
     {
-        let hash_and_gen = inputs.into_iter().map({
-            move |i| {
-                (
-                    add_generations(i, repo_generation.clone(), repo.clone()),
-                    Ok(Async::NotReady),
-                )
-            }
-        });
+        let inputs = inputs
+            .into_iter()
+            .map(|i| add_generations(i, repo_generation.clone(), repo.clone()));
+        Self::from_ordered(inputs)
+    }
+
+    /// Like `new`, but the inputs already know the generations of their nodes
+    pub fn from_ordered<I>(inputs: I) -> Self
+    where
+        I: IntoIterator<Item = Box<OrderedNodeStream>>,
+    {
+        let inputs = inputs.into_iter().map(|i| (i, Ok(Async::NotReady)));
         UnionNodeStream {
-            inputs: hash_and_gen.collect(),
+            inputs: inputs.collect(),
             current_generation: None,
+            accumulator_generation: None,
             accumulator: HashSet::new(),
             drain: None,
         }
@@ -55,6 +62,11 @@ impl UnionNodeStream {
         Box::new(self)
     }
 
+    pub fn ordered(self) -> Box<OrderedNodeStream> {
+        let mut nodes = self;
+        Box::new(stream::poll_fn(move || nodes.poll_ordered()))
+    }
+
     fn gc_finished_inputs(&mut self) {
         self.inputs
             .retain(|&(_, ref state)| if let Ok(Async::Ready(None)) = *state {
@@ -88,17 +100,14 @@ impl UnionNodeStream {
                 }
             }
         }
-        if !found_hashes {
+        if found_hashes {
+            self.accumulator_generation = self.current_generation;
+        } else {
             self.current_generation = None;
         }
     }
-}
 
-impl Stream for UnionNodeStream {
-    type Item = NodeHash;
-    type Error = Error;
-
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    fn poll_ordered(&mut self) -> Poll<Option<(NodeHash, Generation)>, Error> {
         // This feels wrong, but in practice it's fine - it should be quick to hit a return, and
         // the standard futures::executor expects you to only return NotReady if blocked on I/O.
         loop {
@@ -107,8 +116,10 @@ impl Stream for UnionNodeStream {
 
             // Empty the drain if any - return all items for this generation
             let next_in_drain = self.drain.as_mut().and_then(|drain| drain.next());
-            if next_in_drain.is_some() {
-                return Ok(Async::Ready(next_in_drain));
+            if let Some(hash) = next_in_drain {
+                let generation = self.accumulator_generation
+                    .expect("Nodes accumulated without a generation");
+                return Ok(Async::Ready(Some((hash, generation))));
             } else {
                 self.drain = None;
             }
@@ -149,6 +160,16 @@ impl Stream for UnionNodeStream {
     }
 }
 
+impl Stream for UnionNodeStream {
+    type Item = NodeHash;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let next = self.poll_ordered()?;
+        Ok(next.map(|next| next.map(|(hash, _)| hash)))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
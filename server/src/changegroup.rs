@@ -0,0 +1,518 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Version 1 changegroups, as sent by the legacy `changegroup` and `changegroupsubset` commands.
+//!
+//! Unlike getbundle, which sends only the changelog to clients that fetch trees and files
+//! separately, the clients of these commands expect the manifests and files of the changesets as
+//! well. A version 1 changegroup is made of a changelog section, a manifest section with flat
+//! manifests, and a section per file.
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use blobrepo::BlobRepo;
+use mercurial;
+use mercurial_bundles::changegroup::{CgDeltaChunk, Part, Section};
+use mercurial_bundles::changegroup::packer::Cg1Packer;
+use mercurial_types::{Changeset, Delta, Entry, HgChangesetId, HgManifestId, MPath, NodeHash,
+                      Parents, Type, NULL_HASH};
+use mercurial_types::delta::Fragment;
+use mercurial_types::manifest_utils::recursive_entry_stream;
+use repoinfo::RepoGenCache;
+use revset::{AncestorsNodeStream, RangeNodeStream, UnionNodeStream};
+
+use errors::*;
+
+/// A revision of the changelog, a manifest or a file
+struct Revision {
+    node: NodeHash,
+    p1: NodeHash,
+    p2: NodeHash,
+    linknode: NodeHash,
+    text: Bytes,
+}
+
+impl Revision {
+    fn new(node: NodeHash, parents: &Parents, linknode: NodeHash, text: Bytes) -> Self {
+        let (p1, p2) = parents.get_nodes();
+        Revision {
+            node,
+            p1: *p1.unwrap_or(&NULL_HASH),
+            p2: *p2.unwrap_or(&NULL_HASH),
+            linknode,
+            text,
+        }
+    }
+}
+
+/// The manifest of a changeset, and the filenodes of the files it changed
+struct ChangedFiles {
+    node: NodeHash,
+    manifestid: HgManifestId,
+    files: Vec<(MPath, NodeHash)>,
+}
+
+/// The version 1 changegroup with all the changesets that are descendants of `bases` and
+/// ancestors of `heads`
+pub fn changegroup1(
+    hgrepo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    bases: Vec<NodeHash>,
+    heads: Vec<NodeHash>,
+) -> BoxFuture<Bytes, Error> {
+    let nodestosend = if bases.contains(&NULL_HASH) {
+        // Every changeset is a descendant of the null revision
+        let heads_ancestors = heads.iter().map(|head| {
+            AncestorsNodeStream::new(&hgrepo, repo_generation.clone(), *head).ordered()
+        });
+        UnionNodeStream::from_ordered(heads_ancestors)
+    } else {
+        let mut ranges = Vec::new();
+        for base in &bases {
+            for head in &heads {
+                ranges.push(
+                    RangeNodeStream::new(&hgrepo, repo_generation.clone(), *base, *head).boxed(),
+                );
+            }
+        }
+        UnionNodeStream::new(&hgrepo, repo_generation.clone(), ranges)
+    };
+
+    // TODO(stash): avoid collecting all the changelogs in the vector - T25767311
+    let parts = nodestosend
+        .collect()
+        .map(move |nodes| {
+            // Changesets must be sent parents first
+            let nodes: Vec<_> = nodes.into_iter().rev().collect();
+
+            let changelog = changelog_section(hgrepo.clone(), nodes.clone());
+            let changed_files = stream::iter_ok(nodes)
+                .and_then({
+                    let hgrepo = hgrepo.clone();
+                    move |node| changed_files(&hgrepo, node)
+                })
+                .collect();
+            let manifests_and_files = changed_files
+                .map(move |changesets| manifest_and_filelog_sections(hgrepo, changesets))
+                .flatten_stream();
+
+            changelog
+                .chain(manifests_and_files)
+                .chain(stream::once(Ok(Part::End)))
+        })
+        .flatten_stream();
+
+    Cg1Packer::new(parts).concat2().boxify()
+}
+
+fn changelog_section(hgrepo: Arc<BlobRepo>, nodes: Vec<NodeHash>) -> BoxStream<Part, Error> {
+    let revisions = stream::iter_ok(nodes)
+        .and_then({
+            let hgrepo = hgrepo.clone();
+            move |node| changelog_revision(&hgrepo, node)
+        })
+        .boxify();
+
+    section_parts(Section::Changeset, revisions, move |p1| {
+        changelog_revision(&hgrepo, p1)
+            .map(|revision| revision.text.len())
+            .boxify()
+    })
+}
+
+fn changelog_revision(hgrepo: &BlobRepo, node: NodeHash) -> BoxFuture<Revision, Error> {
+    hgrepo
+        .get_changeset_by_changesetid(&HgChangesetId::new(node))
+        .and_then(move |cs| {
+            let mut text = Vec::new();
+            mercurial::changeset::serialize_cs(&cs, &mut text)?;
+            Ok(Revision::new(node, cs.parents(), node, Bytes::from(text)))
+        })
+        .boxify()
+}
+
+fn changed_files(hgrepo: &Arc<BlobRepo>, node: NodeHash) -> BoxFuture<ChangedFiles, Error> {
+    let hgrepo = hgrepo.clone();
+    let cs = hgrepo.get_changeset_by_changesetid(&HgChangesetId::new(node));
+
+    cs.and_then(move |cs| {
+        let manifestid = *cs.manifestid();
+        let files = cs.files().to_vec();
+        hgrepo
+            .get_manifest_by_nodeid(&manifestid.into_nodehash())
+            .and_then(move |manifest| {
+                // Removed files are in the list of files too, but not in the manifest
+                let filenodes = files.into_iter().map(|path| {
+                    let entry = manifest.lookup(&path);
+                    entry.map(move |entry| match entry {
+                        Some(ref entry) if entry.get_type() != Type::Tree => {
+                            Some((path, entry.get_hash().into_nodehash()))
+                        }
+                        _ => None,
+                    })
+                });
+                future::join_all(filenodes)
+            })
+            .map(move |filenodes| ChangedFiles {
+                node,
+                manifestid,
+                files: filenodes.into_iter().filter_map(|filenode| filenode).collect(),
+            })
+    }).boxify()
+}
+
+/// The manifest section and the file sections for `changesets`, which are in the order they
+/// are sent in. Each manifest and file revision is sent once, with the first changeset that
+/// has it as linknode.
+fn manifest_and_filelog_sections(
+    hgrepo: Arc<BlobRepo>,
+    changesets: Vec<ChangedFiles>,
+) -> BoxStream<Part, Error> {
+    let mut seen_manifests = HashSet::new();
+    let mut manifests = Vec::new();
+    let mut seen_filenodes = HashSet::new();
+    let mut filelogs = BTreeMap::new();
+
+    for changeset in changesets {
+        if seen_manifests.insert(changeset.manifestid) {
+            manifests.push((changeset.manifestid, changeset.node));
+        }
+        for (path, filenode) in changeset.files {
+            if seen_filenodes.insert((path.clone(), filenode)) {
+                filelogs
+                    .entry(path)
+                    .or_insert_with(Vec::new)
+                    .push((filenode, changeset.node));
+            }
+        }
+    }
+
+    let manifest_revisions = stream::iter_ok(manifests)
+        .and_then({
+            let hgrepo = hgrepo.clone();
+            move |(manifestid, linknode)| manifest_revision(&hgrepo, manifestid, linknode)
+        })
+        .boxify();
+    let manifest_section = section_parts(Section::Manifest, manifest_revisions, {
+        let hgrepo = hgrepo.clone();
+        move |p1| {
+            flat_manifest_text(&hgrepo, HgManifestId::new(p1))
+                .map(|text| text.len())
+                .boxify()
+        }
+    });
+
+    let filelog_sections = stream::iter_ok::<_, Error>(filelogs)
+        .map(move |(path, revisions)| {
+            let revisions = stream::iter_ok(revisions)
+                .and_then({
+                    let hgrepo = hgrepo.clone();
+                    move |(filenode, linknode)| filelog_revision(&hgrepo, filenode, linknode)
+                })
+                .boxify();
+            let hgrepo = hgrepo.clone();
+            section_parts(Section::Filelog(path), revisions, move |p1| {
+                hgrepo
+                    .get_raw_file_content(&p1)
+                    .map(|text| text.len())
+                    .boxify()
+            })
+        })
+        .flatten();
+
+    manifest_section.chain(filelog_sections).boxify()
+}
+
+fn manifest_revision(
+    hgrepo: &BlobRepo,
+    manifestid: HgManifestId,
+    linknode: NodeHash,
+) -> BoxFuture<Revision, Error> {
+    let parents = hgrepo.get_root_entry(&manifestid).get_parents();
+    parents
+        .join(flat_manifest_text(hgrepo, manifestid))
+        .map(move |(parents, text)| {
+            Revision::new(manifestid.into_nodehash(), &parents, linknode, text)
+        })
+        .boxify()
+}
+
+/// Text of a manifest in the flat format: a line with the path, hash and flag of every file of
+/// the repo, sorted by path
+fn flat_manifest_text(hgrepo: &BlobRepo, manifestid: HgManifestId) -> BoxFuture<Bytes, Error> {
+    recursive_entry_stream(MPath::empty(), hgrepo.get_root_entry(&manifestid))
+        .filter(|&(_, ref entry)| entry.get_type() != Type::Tree)
+        .map(|(path, entry)| {
+            let mut line = path.join_element(entry.get_name()).to_vec();
+            line.push(b'\0');
+            let details = format!("{}{}\n", entry.get_hash().into_nodehash(), entry.get_type());
+            line.extend_from_slice(details.as_bytes());
+            line
+        })
+        .collect()
+        .map(|mut lines| {
+            // The path ends at the NUL byte, so sorting the lines sorts them by path
+            lines.sort();
+            Bytes::from(lines.concat())
+        })
+        .boxify()
+}
+
+fn filelog_revision(
+    hgrepo: &BlobRepo,
+    filenode: NodeHash,
+    linknode: NodeHash,
+) -> BoxFuture<Revision, Error> {
+    hgrepo
+        .get_parents(&filenode)
+        .join(hgrepo.get_raw_file_content(&filenode))
+        .map(move |(parents, text)| Revision::new(filenode, &parents, linknode, text))
+        .boxify()
+}
+
+/// A section of a version 1 changegroup, followed by its end marker. There are no explicit
+/// delta bases in version 1 changegroups: every delta is against the previous revision of the
+/// section, or against p1 for the first one. Each revision is sent as a delta that replaces the
+/// whole text of its base, so the only thing that `text_len` has to look up is the length of
+/// the text of that p1.
+fn section_parts<F>(
+    section: Section,
+    revisions: BoxStream<Revision, Error>,
+    text_len: F,
+) -> BoxStream<Part, Error>
+where
+    F: FnOnce(NodeHash) -> BoxFuture<usize, Error> + Send + 'static,
+{
+    let section_end = Part::SectionEnd(section.clone());
+
+    revisions
+        .into_future()
+        .map_err(|(err, _)| err)
+        .and_then(move |(first, rest)| {
+            let p1 = first.as_ref().map_or(NULL_HASH, |revision| revision.p1);
+            let base = if p1 == NULL_HASH {
+                future::ok((NULL_HASH, 0)).boxify()
+            } else {
+                text_len(p1).map(move |len| (p1, len)).boxify()
+            };
+
+            base.map(move |base| {
+                let mut base = base;
+                stream::iter_ok(first).chain(rest).and_then(move |revision| {
+                    let delta = Delta::new(vec![
+                        Fragment {
+                            start: 0,
+                            end: base.1,
+                            content: revision.text.to_vec(),
+                        },
+                    ])?;
+                    let deltachunk = CgDeltaChunk {
+                        node: revision.node,
+                        p1: revision.p1,
+                        p2: revision.p2,
+                        base: base.0,
+                        linknode: revision.linknode,
+                        delta,
+                        flags: 0,
+                    };
+                    base = (revision.node, revision.text.len());
+                    Ok(Part::CgChunk(section.clone(), deltachunk))
+                })
+            })
+        })
+        .flatten_stream()
+        .chain(stream::once(Ok(section_end)))
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use branch_even;
+    use mercurial_types::BlobNode;
+    use mercurial_types::bdiff;
+
+    /// (node, p1, p2, linknode, text) of each revision of a section
+    type Group = Vec<(NodeHash, NodeHash, NodeHash, NodeHash, Vec<u8>)>;
+
+    /// Decoder for version 1 changegroups
+    struct Reader<'a> {
+        data: &'a [u8],
+    }
+
+    impl<'a> Reader<'a> {
+        fn read_u32(&mut self) -> usize {
+            let (value, rest) = self.data.split_at(4);
+            self.data = rest;
+            value.iter().fold(0, |acc, byte| (acc << 8) | *byte as usize)
+        }
+
+        /// The payload of the next chunk. Empty chunks end groups.
+        fn chunk(&mut self) -> &'a [u8] {
+            let len = self.read_u32();
+            if len == 0 {
+                return &[];
+            }
+            let (chunk, rest) = self.data.split_at(len - 4);
+            self.data = rest;
+            chunk
+        }
+
+        /// The revisions of a group, with their texts rebuilt from the deltas
+        fn group(&mut self) -> Group {
+            let mut group: Group = Vec::new();
+            loop {
+                let chunk = self.chunk();
+                if chunk.is_empty() {
+                    return group;
+                }
+
+                let node = |idx: usize| NodeHash::from_bytes(&chunk[idx * 20..(idx + 1) * 20]);
+                let (node, p1, p2, linknode) = (
+                    node(0).unwrap(),
+                    node(1).unwrap(),
+                    node(2).unwrap(),
+                    node(3).unwrap(),
+                );
+
+                let mut deltas = Vec::new();
+                let mut fragments = Reader {
+                    data: &chunk[80..],
+                };
+                while !fragments.data.is_empty() {
+                    let start = fragments.read_u32();
+                    let end = fragments.read_u32();
+                    let len = fragments.read_u32();
+                    let (content, rest) = fragments.data.split_at(len);
+                    fragments.data = rest;
+                    deltas.push(bdiff::Delta {
+                        start,
+                        end,
+                        content: content.to_vec(),
+                    });
+                }
+
+                let base = match group.last() {
+                    Some(previous) => previous.4.clone(),
+                    None => {
+                        assert_eq!(p1, NULL_HASH, "first revision of a group is not a root");
+                        Vec::new()
+                    }
+                };
+                let text = bdiff::apply(&base, &deltas);
+                group.push((node, p1, p2, linknode, text));
+            }
+        }
+    }
+
+    fn nodehash(hash: &str) -> NodeHash {
+        hash.parse().expect("Invalid node hash")
+    }
+
+    fn revlog_hash(p1: NodeHash, p2: NodeHash, text: &[u8]) -> NodeHash {
+        let parent = |p: &NodeHash| if *p == NULL_HASH { None } else { Some(*p) };
+        let (p1, p2) = (parent(&p1), parent(&p2));
+        BlobNode::new(Bytes::from(text), p1.as_ref(), p2.as_ref())
+            .nodeid()
+            .expect("node without data")
+    }
+
+    #[test]
+    fn changegroup1_branch_even() {
+        let repo = Arc::new(branch_even::getrepo(None));
+        let head = nodehash("4f7f3fd428bec1a48f9314414b063c706d9c1aed");
+
+        let cg = changegroup1(
+            repo.clone(),
+            RepoGenCache::new(10),
+            vec![NULL_HASH],
+            vec![head],
+        ).wait()
+            .expect("changegroup failed");
+
+        let mut reader = Reader { data: cg.as_ref() };
+        let changelog = reader.group();
+        let manifests = reader.group();
+        let mut filelogs = HashMap::new();
+        loop {
+            let name = reader.chunk();
+            if name.is_empty() {
+                break;
+            }
+            filelogs.insert(MPath::new(name).unwrap(), reader.group());
+        }
+        assert!(reader.data.is_empty(), "trailing data after the changegroup");
+
+        // All the ancestors of the head, parents first
+        let ancestors: HashSet<_> = AncestorsNodeStream::new(&repo, RepoGenCache::new(10), head)
+            .collect()
+            .wait()
+            .unwrap()
+            .into_iter()
+            .collect();
+        let mut seen = HashSet::new();
+        for &(node, p1, p2, linknode, _) in changelog.iter() {
+            assert_eq!(node, linknode);
+            assert!(p1 == NULL_HASH || seen.contains(&p1), "parent sent after child");
+            assert!(p2 == NULL_HASH || seen.contains(&p2), "parent sent after child");
+            seen.insert(node);
+        }
+        assert_eq!(seen, ancestors);
+
+        // The manifest of every changeset was sent, with the changeset as its linknode
+        let manifest_nodes: HashSet<_> = manifests.iter().map(|revision| revision.0).collect();
+        assert_eq!(manifest_nodes.len(), manifests.len(), "manifest sent twice");
+        for &(node, _, _, _, ref text) in changelog.iter() {
+            let manifest = nodehash(::std::str::from_utf8(&text[..40]).unwrap());
+            let revision = manifests
+                .iter()
+                .find(|revision| revision.0 == manifest)
+                .expect("manifest was not sent");
+            assert_eq!(revision.3, node);
+        }
+
+        // The texts rebuilt from the deltas are the original texts
+        let groups = vec![&changelog, &manifests];
+        for group in groups.into_iter().chain(filelogs.values()) {
+            for &(node, p1, p2, _, ref text) in group.iter() {
+                assert_eq!(revlog_hash(p1, p2, text), node);
+            }
+        }
+
+        // Every file of the head's manifest was sent, with a linknode that was sent too
+        let head_cs = repo.get_changeset_by_changesetid(&HgChangesetId::new(head))
+            .wait()
+            .unwrap();
+        let head_manifest = head_cs.manifestid().into_nodehash();
+        let manifest_text = manifests
+            .iter()
+            .find(|revision| revision.0 == head_manifest)
+            .map(|revision| revision.4.clone())
+            .expect("head manifest was not sent");
+        for line in manifest_text.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+            let mut fields = line.splitn(2, |b| *b == 0);
+            let path = MPath::new(fields.next().unwrap()).unwrap();
+            let filenode = nodehash(::std::str::from_utf8(&fields.next().unwrap()[..40]).unwrap());
+            let filelog = filelogs.get(&path).expect("file was not sent");
+            let revision = filelog
+                .iter()
+                .find(|revision| revision.0 == filenode)
+                .expect("file revision was not sent");
+            assert!(seen.contains(&revision.3), "unknown linknode");
+        }
+        for revision in manifests.iter() {
+            assert!(seen.contains(&revision.3), "unknown linknode");
+        }
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Clonebundles: full-repo bundles that are generated ahead of time and downloaded by clients
+//! instead of running an expensive full getbundle. The client then pulls the commits that were
+//! added after the bundle was generated.
+//!
+//! Bundles are generated by the `generate-clonebundle` subcommand and stored on disk, from where
+//! they are expected to be published to the urls listed in the repo's `clonebundles` config.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use futures::{Future, IntoFuture};
+use futures_ext::{BoxFuture, FutureExt};
+
+use hgproto::GetbundleArgs;
+use mercurial_types::{percent_encode, HgChangesetId};
+use metaconfig::repoconfig::{Bundle2Compression, CloneBundle};
+
+use errors::*;
+use repo::{compressor_type, HgRepo};
+
+/// Render the clonebundles manifest sent to clients. Each line is a url followed by
+/// space-separated percent-encoded `KEY=value` attributes.
+pub fn manifest(bundles: &[CloneBundle]) -> String {
+    let mut manifest = String::new();
+    for bundle in bundles {
+        manifest.push_str(&bundle.url);
+        if let Some(ref bundlespec) = bundle.bundlespec {
+            manifest.push_str(&format!(" BUNDLESPEC={}", percent_encode(bundlespec)));
+        }
+        manifest.push('\n');
+    }
+    manifest
+}
+
+/// Bundle specification of the bundles created by `generate`, to be used in the manifest
+pub fn bundlespec(compression: Option<Bundle2Compression>) -> &'static str {
+    match compression {
+        Some(Bundle2Compression::Zstd) => "zstd-v2",
+        Some(Bundle2Compression::Gzip) => "gzip-v2",
+        Some(Bundle2Compression::Bzip2) => "bzip2-v2",
+        None => "none-v2",
+    }
+}
+
+/// Generate a bundle with all the commits up to and including `changeset` and store it in
+/// `output`. The bundle is written to a temporary file first and then renamed, so a bundle that's
+/// being published never appears partially written.
+pub fn generate(
+    repo: &HgRepo,
+    changeset: HgChangesetId,
+    compression: Option<Bundle2Compression>,
+    output: &Path,
+) -> BoxFuture<(), Error> {
+    let args = GetbundleArgs {
+        heads: vec![changeset.into_nodehash()],
+        common: vec![],
+        bundlecaps: vec![],
+        listkeys: vec![],
+        // Clients that don't understand the phase-heads part can use the bundles too. The
+        // phases are fetched by the pull that follows.
+        phases: false,
+        obsmarkers: false,
+    };
+
+    let bundle = match repo.create_bundle(args, compression.map(compressor_type)) {
+        Ok(bundle) => bundle,
+        Err(err) => return Err(err).into_future().boxify(),
+    };
+
+    let output = output.to_path_buf();
+    bundle
+        .and_then(move |bundle| {
+            let mut tmp_output = output.clone().into_os_string();
+            tmp_output.push(".tmp");
+            File::create(&tmp_output)?.write_all(&bundle)?;
+            fs::rename(&tmp_output, &output)?;
+            Ok(())
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let bundles = vec![
+            CloneBundle {
+                url: "https://example.com/repo.hg".to_string(),
+                bundlespec: Some("zstd-v2".to_string()),
+            },
+            CloneBundle {
+                url: "https://example.com/repo-other.hg".to_string(),
+                bundlespec: None,
+            },
+        ];
+
+        assert_eq!(
+            manifest(&bundles),
+            "https://example.com/repo.hg BUNDLESPEC=zstd-v2\n\
+             https://example.com/repo-other.hg\n"
+        );
+        assert_eq!(manifest(&[]), "");
+    }
+}
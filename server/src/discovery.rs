@@ -0,0 +1,184 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Discovery queries answered from the changesets table.
+//!
+//! Changesets are only added to the changesets table once they're complete, so a changeset is
+//! known to the server if it's stored there, unless it's a draft that was only pushed to a
+//! scratch bookmark. Only the drafts need a look at the bookmarks, along the skip edges.
+
+use std::sync::Arc;
+
+use futures::{future, Future};
+use futures::future::Loop;
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::BlobRepo;
+use mercurial_types::{HgChangesetId, NodeHash, Phase, NULL_HASH};
+use phases;
+use repoinfo::RepoGenCache;
+use revset::SkiplistIndex;
+
+use errors::*;
+
+/// Whether each of `nodes` is known, in the same order. This is answered from the changesets
+/// table and the recorded phases. Only the changesets recorded as draft are checked against
+/// the bookmarks.
+pub fn known(
+    repo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    skiplist: SkiplistIndex,
+    nodes: Vec<NodeHash>,
+) -> BoxFuture<Vec<bool>, Error> {
+    let cs_ids: Vec<_> = nodes.iter().map(|node| HgChangesetId::new(*node)).collect();
+    let stored = repo.get_stored_changesets(&cs_ids);
+
+    stored
+        .and_then({
+            let repo = repo.clone();
+            move |stored| {
+                let stored_ids: Vec<_> = cs_ids
+                    .into_iter()
+                    .filter(|cs_id| stored.contains(cs_id))
+                    .collect();
+                repo.get_recorded_phases(&stored_ids)
+                    .map(move |recorded| (stored, recorded))
+            }
+        })
+        .and_then(move |(stored, recorded)| {
+            let known = nodes.into_iter().map(move |node| {
+                let cs_id = HgChangesetId::new(node);
+                if !stored.contains(&cs_id) {
+                    future::ok(false).boxify()
+                } else if recorded.get(&cs_id) == Some(&Phase::Draft) {
+                    // Scratch changesets are recorded as draft, and are hidden from discovery
+                    // until they are public
+                    phases::is_public(
+                        repo.clone(),
+                        repo_generation.clone(),
+                        skiplist.clone(),
+                        node,
+                    )
+                } else {
+                    future::ok(true).boxify()
+                }
+            });
+            future::join_all(known.collect::<Vec<_>>())
+        })
+        .boxify()
+}
+
+// Current node, its distance from the top, the distance of the next sample and the samples
+type BetweenState = (NodeHash, u64, u64, Vec<NodeHash>);
+
+/// The first-parent ancestors of `top` at distances 1, 2, 4, 8... from it, stopping before
+/// `bottom`
+pub fn between(
+    repo: Arc<BlobRepo>,
+    top: NodeHash,
+    bottom: NodeHash,
+) -> BoxFuture<Vec<NodeHash>, Error> {
+    future::loop_fn(
+        (top, 0, 1, Vec::new()),
+        move |(node, distance, mut next_sample, mut samples): BetweenState| {
+            if node == bottom || node == NULL_HASH {
+                return future::ok(Loop::Break(samples)).boxify();
+            }
+            if distance == next_sample {
+                samples.push(node);
+                next_sample *= 2;
+            }
+
+            repo.get_changeset_parents(&HgChangesetId::new(node))
+                .map(move |parents| {
+                    let p1 = parents
+                        .first()
+                        .map(|p1| p1.into_nodehash())
+                        .unwrap_or(NULL_HASH);
+                    Loop::Continue((p1, distance + 1, next_sample, samples))
+                })
+                .boxify()
+        },
+    ).boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use branch_even;
+    use branch_uneven;
+
+    fn nodehash(hash: &str) -> NodeHash {
+        hash.parse().expect("Invalid node hash")
+    }
+
+    #[test]
+    fn known_branch_even() {
+        let repo = Arc::new(branch_even::getrepo(None));
+        let nodes = vec![
+            nodehash("4f7f3fd428bec1a48f9314414b063c706d9c1aed"),
+            nodehash("1d8a907f7b4bf50c6a09c16361e2205047ecc5e5"),
+            nodehash("1111111111111111111111111111111111111111"),
+            nodehash("15c40d0abc36d47fb51c8eaec51ac7aad31f669c"),
+        ];
+
+        let known = known(repo, RepoGenCache::new(10), SkiplistIndex::new(), nodes)
+            .wait()
+            .expect("known failed");
+        assert_eq!(known, vec![true, true, false, true]);
+    }
+
+    #[test]
+    fn known_draft() {
+        let repo = Arc::new(branch_even::getrepo(None));
+        let draft = nodehash("4f7f3fd428bec1a48f9314414b063c706d9c1aed");
+        repo.record_phase(&HgChangesetId::new(draft), Phase::Draft)
+            .wait()
+            .expect("record_phase failed");
+
+        // There are no bookmarks, so the draft changeset isn't public yet
+        let known = known(repo, RepoGenCache::new(10), SkiplistIndex::new(), vec![draft])
+            .wait()
+            .expect("known failed");
+        assert_eq!(known, vec![false]);
+    }
+
+    #[test]
+    fn between_branch_uneven() {
+        let repo = Arc::new(branch_uneven::getrepo(None));
+        let head = nodehash("264f01429683b3dd8042cb3979e8bf37007118bc");
+        let root = nodehash("15c40d0abc36d47fb51c8eaec51ac7aad31f669c");
+
+        let samples = between(repo.clone(), head, root)
+            .wait()
+            .expect("between failed");
+        assert_eq!(
+            samples,
+            vec![
+                nodehash("5d43888a3c972fe68c224f93d41b30e9f888df7c"),
+                nodehash("fc2cef43395ff3a7b28159007f63d6529d2f41ca"),
+                nodehash("795b8133cf375f6d68d27c6c23db24cd5d0cd00f"),
+            ]
+        );
+
+        let samples = between(
+            repo.clone(),
+            nodehash("4f7f3fd428bec1a48f9314414b063c706d9c1aed"),
+            NULL_HASH,
+        ).wait()
+            .expect("between failed");
+        assert_eq!(
+            samples,
+            vec![
+                nodehash("b65231269f651cfe784fd1d97ef02a049a37b8a0"),
+                nodehash("d7542c9db7f4c77dab4b315edd328edf1514952f"),
+            ]
+        );
+
+        let samples = between(repo, root, root).wait().expect("between failed");
+        assert!(samples.is_empty());
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Backfill of the precomputed file history that getfiles and getpackv1 are served from.
+//!
+//! The history of pushed file revisions is derived when they are pushed, but the revisions that
+//! were imported or pushed before that have no stored fragment, and serving their history derives
+//! it again on every request. The `backfill-file-history` subcommand derives and stores the
+//! fragments of all the file revisions of a repo.
+
+use std::sync::Arc;
+
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+
+use blobrepo::{BlobRepo, ErrorKind as BlobRepoErrorKind};
+use mercurial_types::{Changeset, HgChangesetId, Type};
+
+use errors::*;
+
+/// Number of file revisions of a changeset whose history is derived at the same time
+const BACKFILL_CONCURRENCY: usize = 100;
+
+/// Number of changesets whose generation numbers are fetched in one query
+const GENERATION_CHUNK_SIZE: usize = 500;
+
+/// Derive the history of every file revision in the repo. Returns the number of file revisions
+/// that were processed.
+pub fn backfill(repo: Arc<BlobRepo>, logger: Logger) -> BoxFuture<usize, Error> {
+    repo.get_changesets()
+        .chunks(GENERATION_CHUNK_SIZE)
+        .and_then({
+            let repo = repo.clone();
+            move |chunk| {
+                let cs_ids: Vec<_> = chunk.into_iter().map(HgChangesetId::new).collect();
+                repo.get_generation_numbers(&cs_ids)
+                    .and_then(move |generations| {
+                        cs_ids
+                            .into_iter()
+                            .map(|cs_id| match generations.get(&cs_id) {
+                                Some(gen) => Ok((*gen, cs_id)),
+                                None => Err(BlobRepoErrorKind::ChangesetMissing(cs_id).into()),
+                            })
+                            .collect::<Result<Vec<_>>>()
+                    })
+            }
+        })
+        .concat2()
+        .and_then(move |mut changesets| {
+            // Parents have lower generation numbers than their children, so going through the
+            // changesets by generation derives the history of parents first, which lets the
+            // history of children reuse it.
+            changesets.sort();
+            let total = changesets.len();
+
+            stream::iter_ok(changesets.into_iter().enumerate())
+                .and_then(move |(idx, (_, cs_id))| {
+                    if idx % 1000 == 0 {
+                        info!(logger, "Backfilling file history: {}/{} changesets", idx, total);
+                    }
+                    backfill_changeset(repo.clone(), cs_id)
+                })
+                .fold(0, |count, filenodes| Ok::<_, Error>(count + filenodes))
+        })
+        .boxify()
+}
+
+/// Derive the history of the file revisions added in a changeset
+fn backfill_changeset(repo: Arc<BlobRepo>, cs_id: HgChangesetId) -> BoxFuture<usize, Error> {
+    repo.get_changeset_by_changesetid(&cs_id)
+        .and_then({
+            let repo = repo.clone();
+            move |cs| {
+                repo.get_manifest_by_nodeid(&cs.manifestid().into_nodehash())
+                    .map(move |manifest| (cs, manifest))
+            }
+        })
+        .and_then(move |(cs, manifest)| {
+            let files = cs.files()
+                .iter()
+                .cloned()
+                .map(move |path| {
+                    let repo = repo.clone();
+                    manifest.lookup(&path).and_then(move |entry| match entry {
+                        // Files removed in the changeset are listed too
+                        Some(ref entry) if entry.get_type() != Type::Tree => repo
+                            .derive_file_history(path, entry.get_hash().into_nodehash())
+                            .map(|()| 1)
+                            .boxify(),
+                        _ => future::ok(0).boxify(),
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            stream::iter_ok(files)
+                .buffered(BACKFILL_CONCURRENCY)
+                .fold(0, |count, derived| Ok::<_, Error>(count + derived))
+        })
+        .boxify()
+}
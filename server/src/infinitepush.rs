@@ -0,0 +1,97 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Scratch bookmarks of infinitepush (commit cloud), as served to clients.
+//!
+//! Clients don't list all the scratch bookmarks, they use `listkeyspatterns` to look up those
+//! they are interested in, and `lookup` to resolve a scratch bookmark they pull.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt, StreamExt};
+
+use blobrepo::BlobRepo;
+use mercurial_types::NodeHash;
+
+use errors::*;
+
+/// The bookmarks, public or scratch, whose names match one of `patterns`. A pattern that ends
+/// with '*' matches the names that start with the rest of it, which are looked up by prefix,
+/// other patterns match exactly. Public bookmarks shadow the scratch bookmarks of the same name.
+pub fn listkeyspatterns(
+    repo: Arc<BlobRepo>,
+    patterns: Vec<String>,
+) -> BoxFuture<HashMap<Vec<u8>, Vec<u8>>, Error> {
+    let lookups: Vec<_> = patterns
+        .into_iter()
+        .map(|pattern| match pattern_prefix(pattern.as_bytes()) {
+            Some(prefix) => repo.get_scratch_bookmarks_with_prefix(&prefix)
+                .chain(repo.get_bookmarks_with_prefix(&prefix))
+                .boxify(),
+            None => {
+                let name = pattern.into_bytes();
+                repo.get_scratch_bookmark_value(&name)
+                    .join(repo.get_bookmark_value(&name))
+                    .map(move |(scratch, public)| {
+                        let values: Vec<_> = scratch
+                            .into_iter()
+                            .chain(public)
+                            .map(|(csid, _version)| (name.clone(), csid))
+                            .collect();
+                        stream::iter_ok(values)
+                    })
+                    .flatten_stream()
+                    .boxify()
+            }
+        })
+        .collect();
+
+    stream::iter_ok::<_, Error>(lookups)
+        .flatten()
+        .map(|(name, csid)| (name, csid.to_hex().into()))
+        .collect::<Vec<(Vec<u8>, Vec<u8>)>>()
+        .map(|bookmarks| bookmarks.into_iter().collect())
+        .boxify()
+}
+
+/// The prefix that a pattern ending with '*' matches
+fn pattern_prefix(pattern: &[u8]) -> Option<Vec<u8>> {
+    match pattern.split_last() {
+        Some((&b'*', prefix)) => Some(prefix.to_vec()),
+        _ => None,
+    }
+}
+
+/// Resolve a bookmark name to the node it points at. Public bookmarks shadow the scratch
+/// bookmarks of the same name.
+pub fn lookup_bookmark(repo: Arc<BlobRepo>, name: String) -> BoxFuture<Option<NodeHash>, Error> {
+    let public = repo.get_bookmark_value(&name);
+
+    public
+        .and_then(move |value| match value {
+            Some((csid, _version)) => future::ok(Some(csid)).boxify(),
+            None => repo.get_scratch_bookmark_value(&name)
+                .map(|value| value.map(|(csid, _version)| csid))
+                .boxify(),
+        })
+        .map(|csid| csid.map(|csid| csid.into_nodehash()))
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pattern_prefix() {
+        assert_eq!(pattern_prefix(b"scratch/*"), Some(b"scratch/".to_vec()));
+        assert_eq!(pattern_prefix(b"*"), Some(vec![]));
+        assert_eq!(pattern_prefix(b"master"), None);
+        assert_eq!(pattern_prefix(b""), None);
+    }
+}
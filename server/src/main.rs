@@ -12,6 +12,8 @@
 extern crate failure_ext as failure;
 #[macro_use]
 extern crate futures;
+extern crate futures_cpupool;
+#[macro_use]
 extern crate futures_ext;
 extern crate futures_stats;
 extern crate tokio_core;
@@ -35,6 +37,10 @@ extern crate maplit;
 
 extern crate async_compression;
 extern crate blobrepo;
+#[cfg(test)]
+extern crate branch_even;
+#[cfg(test)]
+extern crate branch_uneven;
 extern crate bundle2_resolver;
 extern crate bytes;
 extern crate hgproto;
@@ -46,6 +52,7 @@ extern crate mercurial_types;
 #[cfg(test)]
 extern crate mercurial_types_mocks;
 extern crate metaconfig;
+extern crate phases;
 extern crate pylz4;
 extern crate repoinfo;
 extern crate revset;
@@ -53,10 +60,19 @@ extern crate scuba;
 extern crate services;
 extern crate sshrelay;
 extern crate stats;
+#[cfg(test)]
+extern crate tempdir;
 
+mod changegroup;
+mod clonebundles;
+mod discovery;
 mod errors;
+mod filehistory;
+mod infinitepush;
+mod obsmarkers;
 mod repo;
 mod listener;
+mod streamclone;
 
 use std::io;
 use std::panic;
@@ -70,7 +86,7 @@ use futures::{Future, Sink, Stream};
 use futures::sink::Wait;
 use futures::sync::mpsc;
 
-use clap::{App, ArgGroup, ArgMatches};
+use clap::{App, ArgGroup, ArgMatches, SubCommand};
 
 use slog::{Drain, Level, Logger};
 use slog_glog_fmt::{kv_categorizer, kv_defaults, GlogFormat};
@@ -80,9 +96,10 @@ use slog_logview::LogViewDrain;
 use bytes::Bytes;
 use hgproto::{sshproto, HgProtoHandler};
 use mercurial::RevlogRepo;
-use mercurial_types::RepositoryId;
+use mercurial_bundles::ClientOutput;
 use metaconfig::RepoConfigs;
-use metaconfig::repoconfig::RepoType;
+use metaconfig::repoconfig::{Bundle2Compression, RepoConfig};
+use revset::Revset;
 
 use errors::*;
 
@@ -138,6 +155,37 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
                 .args(&["crbookmark", "crhash"])
                 .required(true),
         )
+        .subcommand(
+            SubCommand::with_name("generate-clonebundle")
+                .about("generate a clonebundle of a repo instead of serving repos")
+                .args_from_usage(
+                    r#"
+                    <repo>          --repo [REPO]           'name of the repo in the config repo'
+                    <changeset>     --changeset [HASH]      'commit the bundle is generated up to'
+                    <output>        --output [PATH]         'path to store the bundle at'
+                    [compression]   --compression [TYPE]    'zstd, gzip, bzip2 or none (default)'
+                "#,
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backfill-file-history")
+                .about("derive the file history of all the file revisions of a repo")
+                .args_from_usage(
+                    r#"
+                    <repo>          --repo [REPO]           'name of the repo in the config repo'
+                "#,
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("revset")
+                .about("print the changesets of a revset, from the newest to the oldest")
+                .args_from_usage(
+                    r#"
+                    <repo>          --repo [REPO]           'name of the repo in the config repo'
+                    <REVSET>                                'revset, like "::master - ::stable"'
+                "#,
+                ),
+        )
 }
 
 fn setup_logger<'a>(matches: &ArgMatches<'a>) -> Logger {
@@ -225,9 +273,94 @@ fn get_config<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<RepoConfi
         .wait()
 }
 
+fn generate_clonebundle<'a>(
+    logger: &Logger,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+) -> Result<()> {
+    let mut config = get_config(logger, matches)?;
+
+    let reponame = sub_m.value_of("repo").unwrap();
+    let repo_config = config
+        .repos
+        .remove(reponame)
+        .ok_or_else(|| format_err!("repo {} not found in the config", reponame))?;
+    let changeset =
+        mercurial_types::nodehash::HgChangesetId::from_str(sub_m.value_of("changeset").unwrap())?;
+    let output = PathBuf::from(sub_m.value_of("output").unwrap());
+    let compression = match sub_m.value_of("compression") {
+        None | Some("none") => None,
+        Some("zstd") => Some(Bundle2Compression::Zstd),
+        Some("gzip") => Some(Bundle2Compression::Gzip),
+        Some("bzip2") => Some(Bundle2Compression::Bzip2),
+        Some(other) => bail_msg!("unknown compression type: {}", other),
+    };
+
+    let mut core = tokio_core::reactor::Core::new()?;
+    let repo = repo::HgRepo::new(logger, &repo_config, &core.remote())?;
+    core.run(clonebundles::generate(
+        &repo,
+        changeset,
+        compression,
+        &output,
+    ))?;
+
+    info!(
+        logger,
+        "Generated clonebundle {}, advertise it with bundlespec {}",
+        output.display(),
+        clonebundles::bundlespec(compression)
+    );
+    Ok(())
+}
+
+fn backfill_file_history<'a>(
+    logger: &Logger,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+) -> Result<()> {
+    let mut config = get_config(logger, matches)?;
+
+    let reponame = sub_m.value_of("repo").unwrap();
+    let repo_config = config
+        .repos
+        .remove(reponame)
+        .ok_or_else(|| format_err!("repo {} not found in the config", reponame))?;
+
+    let mut core = tokio_core::reactor::Core::new()?;
+    let repo = repo::HgRepo::new(logger, &repo_config, &core.remote())?;
+    let filenodes = core.run(filehistory::backfill(repo.blobrepo(), logger.clone()))?;
+
+    info!(logger, "Backfilled the history of {} file revisions", filenodes);
+    Ok(())
+}
+
+fn print_revset<'a>(
+    logger: &Logger,
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+) -> Result<()> {
+    let mut config = get_config(logger, matches)?;
+
+    let reponame = sub_m.value_of("repo").unwrap();
+    let repo_config = config
+        .repos
+        .remove(reponame)
+        .ok_or_else(|| format_err!("repo {} not found in the config", reponame))?;
+    let revset: Revset = sub_m.value_of("REVSET").unwrap().parse()?;
+
+    let mut core = tokio_core::reactor::Core::new()?;
+    let repo = repo::HgRepo::new(logger, &repo_config, &core.remote())?;
+    core.run(repo.evaluate_revset(&revset).for_each(|node| {
+        println!("{}", node);
+        Ok(())
+    }))?;
+    Ok(())
+}
+
 fn start_repo_listeners<I>(repos: I, root_log: &Logger) -> Result<Vec<JoinHandle<!>>>
 where
-    I: IntoIterator<Item = (RepoType, usize, i32, Option<String>)>,
+    I: IntoIterator<Item = RepoConfig>,
 {
     // Given the list of paths to repos:
     // - create a thread for it
@@ -236,22 +369,14 @@ where
 
     let handles: Vec<_> = repos
         .into_iter()
-        .map(move |(repotype, cache_size, repoid, scuba_table)| {
+        .map(move |config| {
             // start a thread for each repo to own the reactor and start listening for
             // connections and detach it
             thread::Builder::new()
-                .name(format!("listener_{:?}", repotype))
+                .name(format!("listener_{:?}", config.repotype))
                 .spawn({
                     let root_log = root_log.clone();
-                    move || {
-                        repo_listen(
-                            repotype,
-                            cache_size,
-                            root_log.clone(),
-                            RepositoryId::new(repoid),
-                            scuba_table,
-                        )
-                    }
+                    move || repo_listen(config, root_log.clone())
                 })
                 .map_err(Error::from)
         })
@@ -270,28 +395,27 @@ where
 }
 
 // Listener thread for a specific repo
-fn repo_listen(
-    repotype: RepoType,
-    cache_size: usize,
-    root_log: Logger,
-    repoid: RepositoryId,
-    scuba_table: Option<String>,
-) -> ! {
+fn repo_listen(config: RepoConfig, root_log: Logger) -> ! {
     let mut core = tokio_core::reactor::Core::new().expect("failed to create tokio core");
-    let (sockname, repo) = repo::init_repo(
-        &root_log,
-        &repotype,
-        cache_size,
-        &core.remote(),
-        repoid,
-        scuba_table,
-    ).expect("failed to initialize repo");
+    let (sockname, repo) =
+        repo::init_repo(&root_log, &config, &core.remote()).expect("failed to initialize repo");
 
     let listen_log = root_log.new(o!("repo" => repo.path().clone()));
 
     let handle = core.handle();
     let repo = Arc::new(repo);
 
+    handle.spawn(repo.warm_skiplist().then({
+        let listen_log = listen_log.clone();
+        move |res| {
+            match res {
+                Ok(()) => info!(listen_log, "Skiplist index warmed"),
+                Err(err) => error!(listen_log, "Failed to warm skiplist index"; SlogKVError(err)),
+            }
+            Ok(())
+        }
+    }));
+
     let server = listener::listener(sockname, &handle)
         .expect("failed to create listener")
         .map_err(Error::from)
@@ -321,10 +445,19 @@ fn repo_listen(
             let drain = slog::Duplicate::new(drain, listen_log.clone()).fuse();
             let conn_log = Logger::root(drain, o![]);
 
+            // Messages for the user are shown by the client as they arrive on stderr
+            let (output, output_stderr) = mpsc::unbounded();
+            handle.spawn(
+                output_stderr
+                    .forward(stderr.clone().sink_map_err(|_| ()))
+                    .map(|_| ()),
+            );
+            let output = ClientOutput::with_stderr(output);
+
             // Construct a hg protocol handler
             let proto_handler = HgProtoHandler::new(
                 stdin,
-                repo::RepoClient::new(repo.clone(), &conn_log),
+                repo::RepoClient::new(repo.clone(), &conn_log, output),
                 sshproto::HgSshCommandDecode,
                 sshproto::HgSshCommandEncode,
                 &conn_log,
@@ -365,6 +498,30 @@ fn main() {
     let matches = setup_app().get_matches();
     let root_log = setup_logger(&matches);
 
+    if let Some(sub_m) = matches.subcommand_matches("generate-clonebundle") {
+        if let Err(e) = generate_clonebundle(&root_log, &matches, sub_m) {
+            crit!(root_log, "Failed to generate clonebundle"; SlogKVError(e));
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("backfill-file-history") {
+        if let Err(e) = backfill_file_history(&root_log, &matches, sub_m) {
+            crit!(root_log, "Failed to backfill file history"; SlogKVError(e));
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("revset") {
+        if let Err(e) = print_revset(&root_log, &matches, sub_m) {
+            crit!(root_log, "Failed to evaluate revset"; SlogKVError(e));
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+
     fn run_server<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<!> {
         info!(root_log, "Starting up");
 
@@ -375,13 +532,8 @@ fn main() {
         };
 
         let config = get_config(root_log, &matches)?;
-        let repo_listeners = start_repo_listeners(
-            config
-                .repos
-                .into_iter()
-                .map(|(_, c)| (c.repotype, c.generation_cache_size, c.repoid, c.scuba_table)),
-            root_log,
-        )?;
+        let repo_listeners =
+            start_repo_listeners(config.repos.into_iter().map(|(_, c)| c), root_log)?;
 
         for handle in vec![stats_aggregation]
             .into_iter()
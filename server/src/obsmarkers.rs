@@ -0,0 +1,53 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Obsolescence markers, as served to clients.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use futures::{future, Future};
+use futures::future::Loop;
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::BlobRepo;
+use mercurial_types::{HgChangesetId, NodeHash, Obsmarker};
+
+use errors::*;
+
+/// The markers that are relevant to `nodes`: those whose successor or predecessor is one of
+/// them, and then those whose successor is the predecessor of a relevant marker, and so on.
+/// This way the client learns about the whole chain of rewrites that lead to the changesets it
+/// pulls.
+pub fn relevant_markers(
+    repo: Arc<BlobRepo>,
+    nodes: Vec<NodeHash>,
+) -> BoxFuture<Vec<Obsmarker>, Error> {
+    let frontier: Vec<_> = nodes.into_iter().map(HgChangesetId::new).collect();
+    let seen: HashSet<_> = frontier.iter().cloned().collect();
+
+    future::loop_fn(
+        (frontier, seen, HashSet::new()),
+        move |(frontier, mut seen, mut markers): (_, HashSet<_>, HashSet<Obsmarker>)| {
+            if frontier.is_empty() {
+                return future::ok(Loop::Break(markers.into_iter().collect())).boxify();
+            }
+
+            repo.get_obsmarkers(frontier)
+                .map(move |found| {
+                    let mut frontier = Vec::new();
+                    for marker in found {
+                        if seen.insert(marker.predecessor) {
+                            frontier.push(marker.predecessor);
+                        }
+                        markers.insert(marker);
+                    }
+                    Loop::Continue((frontier, seen, markers))
+                })
+                .boxify()
+        },
+    ).boxify()
+}
@@ -6,17 +6,18 @@
 
 //! State for a single source control Repo
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Debug};
 use std::io::{Cursor, Write};
-use std::mem;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
+use async_compression::{Bzip2Compression, CompressorType, FlateCompression};
 use bytes::{BufMut, Bytes, BytesMut};
-use failure::err_msg;
-use futures::{future, stream, Async, Future, IntoFuture, Poll, Stream};
+use futures::{future, stream, Future, IntoFuture, Stream};
+use futures::future::Loop;
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use futures_stats::{Stats, Timed};
 use pylz4;
@@ -26,28 +27,47 @@ use tokio_core::reactor::Remote;
 use slog::{self, Drain, Logger};
 use slog_scuba::ScubaDrain;
 
-use blobrepo::BlobChangeset;
 use bundle2_resolver;
 use mercurial;
-use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item};
-use mercurial_types::{percent_encode, BlobNode, Changeset, Entry, HgChangesetId, HgManifestId,
-                      MPath, NodeHash, Parents, RepoPath, RepositoryId, Type, NULL_HASH};
-use mercurial_types::manifest_utils::{changed_entry_stream, EntryStatus};
-use metaconfig::repoconfig::RepoType;
-
-use hgproto::{self, GetbundleArgs, GettreepackArgs, HgCommandRes, HgCommands};
+use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item, Capabilities, ClientOutput};
+use mercurial_bundles::wirepack;
+use mercurial_bundles::wirepack::packer::WirePackPacker;
+use mercurial_types::{percent_encode, BlobNode, Changeset, Delta, Entry, HgChangesetId,
+                      HgManifestId, MPath, NodeHash, Parents, RepoPath, RepositoryId, Type,
+                      NULL_HASH};
+use mercurial_types::lfs::LFS_FLAG;
+use mercurial_types::manifest::Content;
+use metaconfig::repoconfig::{Bundle2Compression, CloneBundle, RepoConfig, RepoType};
+
+use hgproto::{self, BranchRes, GetbundleArgs, GettreepackArgs, HgCommandRes, HgCommands};
 
 use blobrepo::BlobRepo;
 
+use changegroup;
+use clonebundles;
+use discovery;
 use errors::*;
+use infinitepush;
+use obsmarkers;
+use phases;
+use streamclone::{stream_out_not_allowed, StreamCloneSnapshot};
 
 use repoinfo::RepoGenCache;
-use revset::{AncestorsNodeStream, IntersectNodeStream, NodeStream, SetDifferenceNodeStream,
-             SingleNodeHash, UnionNodeStream};
+use revset::{AncestorsNodeStream, ErrorKind as RevsetErrorKind, NodeStream, OrderedNodeStream,
+             Revset, SetDifferenceNodeStream, SkiplistIndex, UnionNodeStream};
 
 const METAKEYFLAG: &str = "f";
 const METAKEYSIZE: &str = "s";
 
+/// Number of files that getpackv1 fetches at the same time
+const GETPACK_FETCH_CONCURRENCY: usize = 100;
+
+/// Number of generations below each bookmark that are indexed in the skiplist at startup
+const SKIPLIST_WARM_GENERATIONS: u64 = 10_000;
+
+/// Heads of each named branch
+type BranchMap = HashMap<String, HashSet<NodeHash>>;
+
 mod ops {
     pub const HELLO: &str = "hello";
     pub const UNBUNDLE: &str = "unbundle";
@@ -58,28 +78,25 @@ mod ops {
     pub const GETBUNDLE: &str = "getbundle";
     pub const GETTREEPACK: &str = "gettreepack";
     pub const GETFILES: &str = "getfiles";
+    pub const GETPACKV1: &str = "getpackv1";
+    pub const BRANCHMAP: &str = "branchmap";
+    pub const CHANGEGROUP: &str = "changegroup";
+    pub const LISTKEYS: &str = "listkeys";
+    pub const LISTKEYSPATTERNS: &str = "listkeyspatterns";
+    pub const PUSHKEY: &str = "pushkey";
 }
 
 pub fn init_repo(
     parent_logger: &Logger,
-    repotype: &RepoType,
-    cache_size: usize,
+    config: &RepoConfig,
     remote: &Remote,
-    repoid: RepositoryId,
-    scuba_table: Option<String>,
 ) -> Result<(PathBuf, HgRepo)> {
-    let repopath = repotype.path();
+    let repopath = config.repotype.path();
 
     let mut sock = repopath.join(".hg");
 
-    let repo = HgRepo::new(
-        parent_logger,
-        repotype,
-        cache_size,
-        remote,
-        repoid,
-        scuba_table,
-    ).with_context(|_| format!("Failed to initialize repo {:?}", repopath))?;
+    let repo = HgRepo::new(parent_logger, config, remote)
+        .with_context(|_| format!("Failed to initialize repo {:?}", repopath))?;
 
     sock.push("mononoke.sock");
 
@@ -133,33 +150,61 @@ fn add_common_stats_and_send_to_scuba(
     }
 }
 
+pub fn compressor_type(compression: Bundle2Compression) -> CompressorType {
+    match compression {
+        Bundle2Compression::Zstd => CompressorType::Zstd { level: 3 },
+        Bundle2Compression::Gzip => CompressorType::Gzip(FlateCompression::default()),
+        Bundle2Compression::Bzip2 => CompressorType::Bzip2(Bzip2Compression::Default),
+    }
+}
+
 pub struct HgRepo {
     path: String,
     hgrepo: Arc<BlobRepo>,
     repo_generation: RepoGenCache,
+    // Skip edges for ancestry checks, warmed with the bookmarks by `warm_skiplist`
+    skiplist: SkiplistIndex,
     scuba: Option<Arc<ScubaClient>>,
+    bundle2_compression: Vec<CompressorType>,
+    stream_clone_snapshot: Option<StreamCloneSnapshot>,
+    clonebundles: Vec<CloneBundle>,
+    getfiles_concurrency: usize,
+    getfiles_buffer_size: usize,
+    obsmarkers: bool,
+    pushvars: Vec<String>,
+    // The last branchmap, with the sorted heads of the repo it was computed for
+    branchmap: Arc<Mutex<Option<(Vec<NodeHash>, BranchMap)>>>,
 }
 
 fn wireprotocaps() -> Vec<String> {
     vec![
         "lookup".to_string(),
+        "changegroupsubset".to_string(),
+        "branchmap".to_string(),
         "known".to_string(),
         "getbundle".to_string(),
         "unbundle=HG10GZ,HG10BZ,HG10UN".to_string(),
         "gettreepack".to_string(),
         "remotefilelog".to_string(),
+        "getpackv1".to_string(),
+        "listkeyspatterns".to_string(),
     ]
 }
 
-fn bundle2caps() -> String {
-    let caps = vec![
+fn bundle2caps(obsmarkers: bool) -> String {
+    let mut caps = vec![
         ("HG20", vec![]),
         ("listkeys", vec![]),
-        ("changegroup", vec!["02"]),
+        ("changegroup", vec!["02", "03"]),
         ("b2x:infinitepush", vec![]),
         ("b2x:infinitepushscratchbookmarks", vec![]),
         ("pushkey", vec![]),
+        ("phases", vec!["heads"]),
+        ("pushvars", vec![]),
     ];
+    if obsmarkers {
+        caps.push(("obsmarkers", vec!["V1"]));
+    }
 
     let mut encodedcaps = vec![];
 
@@ -177,14 +222,10 @@ fn bundle2caps() -> String {
 }
 
 impl HgRepo {
-    pub fn new(
-        parent_logger: &Logger,
-        repo: &RepoType,
-        cache_size: usize,
-        remote: &Remote,
-        repoid: RepositoryId,
-        scuba_table: Option<String>,
-    ) -> Result<Self> {
+    pub fn new(parent_logger: &Logger, config: &RepoConfig, remote: &Remote) -> Result<Self> {
+        let repo = &config.repotype;
+        let scuba_table = config.scuba_table.clone();
+        let repoid = RepositoryId::new(config.repoid);
         let path = repo.path().to_owned();
         let logger = {
             let kv = o!("repo" => format!("{}", path.display()));
@@ -201,11 +242,28 @@ impl HgRepo {
         Ok(HgRepo {
             path: format!("{}", path.display()),
             hgrepo: Arc::new(repo.open(logger, remote, repoid)?),
-            repo_generation: RepoGenCache::new(cache_size),
+            repo_generation: RepoGenCache::new(config.generation_cache_size),
+            skiplist: SkiplistIndex::new(),
             scuba: match scuba_table {
                 Some(name) => Some(Arc::new(ScubaClient::new(name))),
                 None => None,
             },
+            bundle2_compression: config
+                .bundle2_compression
+                .iter()
+                .cloned()
+                .map(compressor_type)
+                .collect(),
+            stream_clone_snapshot: config
+                .stream_clone_snapshot
+                .as_ref()
+                .map(StreamCloneSnapshot::new),
+            clonebundles: config.clonebundles.clone(),
+            getfiles_concurrency: config.getfiles_concurrency,
+            getfiles_buffer_size: config.getfiles_buffer_size,
+            obsmarkers: config.obsmarkers,
+            pushvars: config.pushvars.clone(),
+            branchmap: Arc::new(Mutex::new(None)),
         })
     }
 
@@ -213,113 +271,114 @@ impl HgRepo {
         &self.path
     }
 
-    fn scuba_sample(&self, op: &str) -> ScubaSample {
-        let mut sample = ScubaSample::new();
-        sample.add("operation", op);
-        sample
-    }
-}
-
-impl Debug for HgRepo {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "Repo({})", self.path)
+    pub fn blobrepo(&self) -> Arc<BlobRepo> {
+        self.hgrepo.clone()
     }
-}
-
-pub struct RepoClient {
-    repo: Arc<HgRepo>,
-    logger: Logger,
-}
 
-impl RepoClient {
-    pub fn new(repo: Arc<HgRepo>, parent_logger: &Logger) -> Self {
-        RepoClient {
-            repo: repo,
-            logger: parent_logger.new(o!()), // connection details?
-        }
+    /// The changesets of `revset`, from the newest to the oldest
+    pub fn evaluate_revset(&self, revset: &Revset) -> Box<NodeStream> {
+        revset.evaluate(&self.hgrepo, self.repo_generation.clone())
     }
 
-    #[allow(dead_code)]
-    pub fn get_logger(&self) -> &Logger {
-        &self.logger
+    /// Index the recent ancestors of the bookmarks in the skiplist, so that the first phase
+    /// lookups after a restart don't have to walk the recent history. Older ancestors are
+    /// indexed by the lookups that reach them.
+    pub fn warm_skiplist(&self) -> BoxFuture<(), Error> {
+        let hgrepo = self.hgrepo.clone();
+        let repo_generation = self.repo_generation.clone();
+        let skiplist = self.skiplist.clone();
+        self.hgrepo
+            .get_bookmarks()
+            .for_each(move |(_, csid)| {
+                skiplist.add_node_with_depth(
+                    &hgrepo,
+                    repo_generation.clone(),
+                    csid.into_nodehash(),
+                    SKIPLIST_WARM_GENERATIONS,
+                )
+            })
+            .boxify()
     }
 
-    fn create_bundle(&self, args: GetbundleArgs) -> hgproto::Result<HgCommandRes<Bytes>> {
+    /// Build the bundle2 for a getbundle request. This is also used to pre-generate
+    /// clonebundles, so that they are exactly what a full getbundle would send.
+    pub fn create_bundle(
+        &self,
+        args: GetbundleArgs,
+        compression: Option<CompressorType>,
+    ) -> hgproto::Result<HgCommandRes<Bytes>> {
         let writer = Cursor::new(Vec::new());
         let mut bundle = Bundle2EncodeBuilder::new(writer);
-        // Mercurial currently hangs while trying to read compressed bundles over the wire:
-        // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
-        // TODO: possibly enable compression support once this is fixed.
-        bundle.set_compressor_type(None);
+        bundle.set_compressor_type(compression);
 
-        let repo_generation = &self.repo.repo_generation;
-        let hgrepo = &self.repo.hgrepo;
+        let repo_generation = &self.repo_generation;
+        let hgrepo = &self.hgrepo;
 
-        let ancestors_stream = |nodes: &Vec<NodeHash>| -> Box<NodeStream> {
+        let ancestors_stream = |nodes: &Vec<NodeHash>| -> Box<OrderedNodeStream> {
             let heads_ancestors = nodes.iter().map(|head| {
-                AncestorsNodeStream::new(&hgrepo, repo_generation.clone(), *head).boxed()
+                AncestorsNodeStream::new(&hgrepo, repo_generation.clone(), *head).ordered()
             });
-            Box::new(UnionNodeStream::new(
-                &hgrepo,
-                repo_generation.clone(),
-                heads_ancestors,
-            ))
+            UnionNodeStream::from_ordered(heads_ancestors).ordered()
         };
 
-        let heads_ancestors = ancestors_stream(&args.heads);
-        let common_ancestors = ancestors_stream(&args.common);
-
-        let nodestosend = Box::new(SetDifferenceNodeStream::new(
-            hgrepo,
-            repo_generation.clone(),
-            heads_ancestors,
-            common_ancestors,
-        ));
-
         // TODO(stash): avoid collecting all the changelogs in the vector - T25767311
-        let nodestosend = nodestosend
-            .collect()
-            .map(|nodes| stream::iter_ok(nodes.into_iter().rev()))
-            .flatten_stream();
+        // The obsmarkers part needs the same nodes as the changegroup, so they're only walked once
+        let nodes_to_send = SetDifferenceNodeStream::from_ordered(
+            ancestors_stream(&args.heads),
+            ancestors_stream(&args.common),
+        ).collect()
+            .map(Arc::new)
+            .map_err(Error::compat)
+            .boxify()
+            .shared();
 
-        let changelogentries = nodestosend
-            .and_then({
-                let hgrepo = hgrepo.clone();
-                move |node| hgrepo.get_changeset_by_changesetid(&HgChangesetId::new(node))
+        let nodestosend = nodes_to_send
+            .clone()
+            .map(|nodes| {
+                let nodes: Vec<_> = nodes.iter().rev().cloned().collect();
+                stream::iter_ok::<_, Error>(nodes)
             })
-            .and_then(|cs| {
-                let mut v = Vec::new();
-                mercurial::changeset::serialize_cs(&cs, &mut v)?;
-                let parents = cs.parents().get_nodes();
-                Ok(BlobNode::new(Bytes::from(v), parents.0, parents.1))
-            });
+            .from_err()
+            .flatten_stream();
+
+        let changelogentries = nodestosend.and_then({
+            let hgrepo = hgrepo.clone();
+            move |node| changelog_entry(&hgrepo, node)
+        });
 
         bundle.add_part(parts::changegroup_part(changelogentries)?);
 
-        // TODO: generalize this to other listkey types
         // (note: just calling &b"bookmarks"[..] doesn't work because https://fburl.com/0p0sq6kp)
         if args.listkeys.contains(&b"bookmarks".to_vec()) {
-            let hgrepo = self.repo.hgrepo.clone();
-            let bookmark_names = hgrepo.get_bookmark_keys();
-            let items = bookmark_names.and_then(move |name| {
-                // For each bookmark name, grab the corresponding value.
-                hgrepo.get_bookmark_value(&name).and_then(|result| {
-                    // If the name somehow wasn't found, it's possible a race happened. where the
-                    // bookmark was deleted from underneath. Skip it.
-                    // Boxing is necessary here to make the match arms return the same types.
-                    match result {
-                        Some((hash, _version)) => {
-                            // AsciiString doesn't currently implement AsRef<[u8]>, so switch to
-                            // Vec which does
-                            let hash: Vec<u8> = hash.to_hex().into();
-                            Ok((name, hash)).into_future().boxify()
-                        }
-                        None => future::empty().boxify(),
-                    }
-                })
-            });
+            let items = self.hgrepo
+                .get_bookmarks()
+                .map(|(name, csid)| -> (Vec<u8>, Vec<u8>) { (name, csid.to_hex().into()) });
             bundle.add_part(parts::listkey_part("bookmarks", items)?);
         }
+        if args.listkeys.contains(&b"phases".to_vec()) {
+            let items = phases::listkeys(
+                self.hgrepo.clone(),
+                repo_generation.clone(),
+                self.skiplist.clone(),
+            ).map(|keys| stream::iter_ok(keys.into_iter()))
+                .flatten_stream();
+            bundle.add_part(parts::listkey_part("phases", items)?);
+        }
+        if args.phases {
+            bundle.add_part(parts::phase_heads_part(phases::phase_heads(
+                self.hgrepo.clone(),
+                repo_generation.clone(),
+                self.skiplist.clone(),
+                args.heads.clone(),
+            ))?);
+        }
+        if args.obsmarkers && self.obsmarkers {
+            let markers = nodes_to_send.from_err().and_then({
+                let hgrepo = hgrepo.clone();
+                move |nodes| obsmarkers::relevant_markers(hgrepo, (**nodes).clone())
+            });
+            bundle.add_part(parts::obsmarkers_part(markers)?);
+        }
         // TODO(stash): handle includepattern= and excludepattern=
 
         let encode_fut = bundle.build();
@@ -330,49 +389,103 @@ impl RepoClient {
             .boxify())
     }
 
-    fn gettreepack_untimed(&self, params: GettreepackArgs) -> HgCommandRes<Bytes> {
-        info!(self.logger, "gettreepack {:?}", params);
+    /// Build the version 1 changegroup sent by the legacy `changegroup` and `changegroupsubset`
+    /// commands. It has all the changesets that are descendants of `bases` and ancestors of
+    /// `heads`, with their manifests and files.
+    fn create_changegroup1(
+        &self,
+        bases: Vec<NodeHash>,
+        heads: Vec<NodeHash>,
+    ) -> HgCommandRes<Bytes> {
+        changegroup::changegroup1(
+            self.hgrepo.clone(),
+            self.repo_generation.clone(),
+            bases,
+            heads,
+        )
+    }
+
+    fn scuba_sample(&self, op: &str) -> ScubaSample {
+        let mut sample = ScubaSample::new();
+        sample.add("operation", op);
+        sample
+    }
+}
+
+impl Debug for HgRepo {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Repo({})", self.path)
+    }
+}
+
+pub struct RepoClient {
+    repo: Arc<HgRepo>,
+    logger: Logger,
+    // Bundle2 capabilities the client advertised during this connection
+    client_caps: Mutex<Option<Capabilities>>,
+    // Messages for the user running the hg command
+    output: ClientOutput,
+}
 
-        if !params.directories.is_empty() {
-            // This param is not used by core hg, don't worry about implementing it now
-            return Err(err_msg("directories param is not supported"))
-                .into_future()
-                .boxify();
+impl RepoClient {
+    pub fn new(repo: Arc<HgRepo>, parent_logger: &Logger, output: ClientOutput) -> Self {
+        RepoClient {
+            repo: repo,
+            logger: parent_logger.new(o!()), // connection details?
+            client_caps: Mutex::new(None),
+            output,
         }
+    }
 
-        // TODO(stash): T25850889 only one basemfnodes is used. That means that trees that client
-        // already has can be sent to the client.
-        let basemfnode = params.basemfnodes.get(0).unwrap_or(&NULL_HASH);
+    #[allow(dead_code)]
+    pub fn get_logger(&self) -> &Logger {
+        &self.logger
+    }
 
-        if params.rootdir.len() != 0 {
-            // For now, only root repo
-            return Err(err_msg("only empty rootdir is supported"))
-                .into_future()
-                .boxify();
+    /// Pick the compression for a bundle2 sent to this client. Not every command carries the
+    /// client's bundle2 capabilities (f.e. gettreepack doesn't), so the last ones advertised on
+    /// this connection are remembered. Clients that never advertised compression support get
+    /// uncompressed bundles.
+    fn bundle2_compression(&self, caps: Option<Capabilities>) -> Option<CompressorType> {
+        let mut client_caps = self.client_caps.lock().expect("lock poisoned");
+        if caps.is_some() {
+            *client_caps = caps;
         }
+        client_caps
+            .as_ref()
+            .and_then(|caps| caps.negotiate_compression(&self.repo.bundle2_compression))
+    }
+
+    fn create_bundle(&self, args: GetbundleArgs) -> hgproto::Result<HgCommandRes<Bytes>> {
+        let client_caps = Capabilities::from_bundlecaps(&args.bundlecaps)?;
+        let compression = self.bundle2_compression(client_caps);
+        self.repo.create_bundle(args, compression)
+    }
+
+    fn gettreepack_untimed(&self, params: GettreepackArgs) -> HgCommandRes<Bytes> {
+        info!(self.logger, "gettreepack {:?}", params);
+
+        let rootdir = try_boxfuture!(MPath::new(&params.rootdir));
+        let directories = try_boxfuture!(
+            params
+                .directories
+                .iter()
+                .map(MPath::new)
+                .collect::<Result<Vec<_>>>()
+        );
 
         let writer = Cursor::new(Vec::new());
         let mut bundle = Bundle2EncodeBuilder::new(writer);
-        // Mercurial currently hangs while trying to read compressed bundles over the wire:
-        // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
-        // TODO: possibly enable compression support once this is fixed.
-        bundle.set_compressor_type(None);
-
-        // TODO(stash): T25850889 same entries will be generated over and over again.
-        // Potentially it can be very inefficient.
-        let changed_entries = params.mfnodes.iter().fold(
-            stream::empty().boxify(),
-            |cur_stream, manifest_id| {
-                let new_stream =
-                    get_changed_entry_stream(self.repo.hgrepo.clone(), manifest_id, basemfnode);
-                cur_stream.select(new_stream).boxify()
-            },
-        );
+        bundle.set_compressor_type(self.bundle2_compression(None));
 
-        let changed_entries = changed_entries.filter({
-            let mut used_hashes = HashSet::new();
-            move |entry| used_hashes.insert(*entry.0.get_hash())
-        });
+        let changed_entries = get_changed_trees_stream(
+            self.repo.hgrepo.clone(),
+            rootdir,
+            params.mfnodes,
+            params.basemfnodes,
+            directories,
+            params.depth,
+        );
 
         parts::treepack_part(changed_entries)
             .into_future()
@@ -391,97 +504,106 @@ impl HgCommands for RepoClient {
     fn between(&self, pairs: Vec<(NodeHash, NodeHash)>) -> HgCommandRes<Vec<Vec<NodeHash>>> {
         info!(self.logger, "between pairs {:?}", pairs);
 
-        struct ParentStream<CS> {
-            repo: Arc<HgRepo>,
-            n: NodeHash,
-            bottom: NodeHash,
-            wait_cs: Option<CS>,
-        };
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.repo.scuba_sample(ops::BETWEEN);
 
-        impl<CS> ParentStream<CS> {
-            fn new(repo: &Arc<HgRepo>, top: NodeHash, bottom: NodeHash) -> Self {
-                ParentStream {
-                    repo: repo.clone(),
-                    n: top,
-                    bottom: bottom,
-                    wait_cs: None,
-                }
-            }
-        }
+        let hgrepo = self.repo.hgrepo.clone();
+        let samples = pairs
+            .into_iter()
+            .map(move |(top, bottom)| discovery::between(hgrepo.clone(), top, bottom));
+        future::join_all(samples)
+            .timed(move |stats, _| {
+                add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+            })
+            .boxify()
+    }
 
-        impl Stream for ParentStream<BoxFuture<BlobChangeset, hgproto::Error>> {
-            type Item = NodeHash;
-            type Error = hgproto::Error;
+    // @wireprotocommand('branchmap')
+    fn branchmap(&self) -> HgCommandRes<BranchMap> {
+        info!(self.logger, "branchmap");
 
-            fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-                if self.n == self.bottom || self.n == NULL_HASH {
-                    return Ok(Async::Ready(None));
+        let hgrepo = self.repo.hgrepo.clone();
+        let repo_generation = self.repo.repo_generation.clone();
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.repo.scuba_sample(ops::BRANCHMAP);
+
+        // The branchmap only changes when the heads of the repo change, so the last one is
+        // cached together with the heads it was computed for
+        let cache = self.repo.branchmap.clone();
+        let heads = hgrepo.get_heads().collect();
+        heads
+            .and_then(move |mut heads| {
+                heads.sort();
+                let cached = match *cache.lock().expect("lock poisoned") {
+                    Some((ref cached_heads, ref branchmap)) if *cached_heads == heads => {
+                        Some(branchmap.clone())
+                    }
+                    _ => None,
+                };
+                if let Some(branchmap) = cached {
+                    return future::ok(branchmap).boxify();
                 }
 
-                self.wait_cs = self.wait_cs.take().or_else(|| {
-                    Some(
-                        self.repo
-                            .hgrepo
-                            .get_changeset_by_changesetid(&HgChangesetId::new(self.n)),
-                    )
-                });
-                let cs = try_ready!(self.wait_cs.as_mut().unwrap().poll());
-                self.wait_cs = None; // got it
-
-                let p = match cs.parents() {
-                    &Parents::None => NULL_HASH,
-                    &Parents::One(ref p) => *p,
-                    &Parents::Two(ref p, _) => *p,
-                };
+                compute_branchmap(hgrepo, repo_generation, heads.clone())
+                    .map(move |branchmap| {
+                        *cache.lock().expect("lock poisoned") = Some((heads, branchmap.clone()));
+                        branchmap
+                    })
+                    .boxify()
+            })
+            .timed(move |stats, _| {
+                add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+            })
+            .boxify()
+    }
 
-                let prev_n = mem::replace(&mut self.n, p);
+    // @wireprotocommand('branches', 'nodes')
+    fn branches(&self, nodes: Vec<NodeHash>) -> HgCommandRes<Vec<BranchRes>> {
+        info!(self.logger, "branches: {:?}", nodes);
 
-                Ok(Async::Ready(Some(prev_n)))
-            }
-        }
+        find_branches(self.repo.hgrepo.clone(), nodes)
+    }
 
-        let scuba = self.repo.scuba.clone();
-        let mut sample = self.repo.scuba_sample(ops::BETWEEN);
+    // @wireprotocommand('changegroup', 'roots')
+    fn changegroup(&self, roots: Vec<NodeHash>) -> HgCommandRes<Bytes> {
+        info!(self.logger, "changegroup roots {:?}", roots);
 
-        // TODO(jsgf): do pairs in parallel?
-        // TODO: directly return stream of streams
         let repo = self.repo.clone();
-        stream::iter_ok(pairs.into_iter())
-            .and_then(move |(top, bottom)| {
-                let mut f = 1;
-                ParentStream::new(&repo, top, bottom)
-                    .enumerate()
-                    .filter(move |&(i, _)| {
-                        if i == f {
-                            f *= 2;
-                            true
-                        } else {
-                            false
-                        }
-                    })
-                    .map(|(_, v)| v)
-                    .collect()
-            })
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.repo.scuba_sample(ops::CHANGEGROUP);
+
+        // This is changegroupsubset up to all the heads of the repo
+        self.repo
+            .hgrepo
+            .get_heads()
             .collect()
+            .and_then(move |heads| repo.create_changegroup1(roots, heads))
             .timed(move |stats, _| {
                 add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
             })
             .boxify()
     }
 
-    // @wireprotocommand('branchmap')
-    fn branchmap(&self) -> HgCommandRes<HashMap<String, HashSet<NodeHash>>> {
-        // We have no plans to support mercurial branches and hence no plans for branchmap,
-        // so just return fake response.
-        future::ok(HashMap::new()).boxify()
-    }
+    // @wireprotocommand('changegroupsubset', 'bases heads')
+    fn changegroupsubset(
+        &self,
+        bases: Vec<NodeHash>,
+        heads: Vec<NodeHash>,
+    ) -> HgCommandRes<Bytes> {
+        info!(
+            self.logger,
+            "changegroupsubset bases {:?} heads {:?}", bases, heads
+        );
 
-    // @wireprotocommand('changegroup', 'roots')
-    fn changegroup(&self, roots: Vec<NodeHash>) -> HgCommandRes<()> {
-        // TODO: streaming something
-        info!(self.logger, "changegroup roots {:?}", roots);
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.repo.scuba_sample(ops::CHANGEGROUP);
 
-        future::ok(()).boxify()
+        self.repo
+            .create_changegroup1(bases, heads)
+            .timed(move |stats, _| {
+                add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+            })
+            .boxify()
     }
 
     // @wireprotocommand('heads')
@@ -506,107 +628,149 @@ impl HgCommands for RepoClient {
 
     // @wireprotocommand('lookup', 'key')
     fn lookup(&self, key: String) -> HgCommandRes<Bytes> {
-        // TODO(stash): T25928839 lookup should support bookmarks and prefixes too
+        // TODO(stash): T25928839 lookup should support prefixes too
         let repo = self.repo.hgrepo.clone();
         let scuba = self.repo.scuba.clone();
         let mut sample = self.repo.scuba_sample(ops::LOOKUP);
-        NodeHash::from_str(&key)
-            .into_future()
-            .and_then(move |node| {
+        let node = match NodeHash::from_str(&key) {
+            Ok(node) => {
                 let csid = HgChangesetId::new(node);
                 repo.changeset_exists(&csid)
-                    .map(move |exists| (node, exists))
-            })
-            .and_then(|(node, exists)| {
-                if exists {
-                    let mut buf = BytesMut::with_capacity(node.to_hex().len() + 3);
-                    buf.put(b'1');
-                    buf.put(b' ');
-                    buf.extend_from_slice(node.to_hex().as_bytes());
-                    buf.put(b'\n');
-                    Ok(buf.freeze())
-                } else {
-                    let err_msg = format!("{} not found", node);
-                    let mut buf = BytesMut::with_capacity(err_msg.len() + 3);
-                    buf.put(b'0');
-                    buf.put(b' ');
-                    buf.extend_from_slice(err_msg.as_bytes());
-                    buf.put(b'\n');
-                    Ok(buf.freeze())
-                }
-            })
-            .timed(move |stats, _| {
+                    .map(move |exists| if exists { Some(node) } else { None })
+                    .boxify()
+            }
+            // Anything that isn't a hash may be a bookmark, scratch bookmarks included, and
+            // then a revset that has a single changeset
+            Err(_) => {
+                let revset = match key.parse() {
+                    Ok(Revset::Symbol(_)) | Err(_) => None,
+                    Ok(revset) => Some(self.repo.evaluate_revset(&revset)),
+                };
+                infinitepush::lookup_bookmark(repo, key.clone())
+                    .and_then(move |node| match (node, revset) {
+                        (None, Some(revset)) => lookup_revset(revset),
+                        (node, _) => future::ok(node).boxify(),
+                    })
+                    .boxify()
+            }
+        };
+
+        node.map(move |node| match node {
+            Some(node) => {
+                let mut buf = BytesMut::with_capacity(node.to_hex().len() + 3);
+                buf.put(b'1');
+                buf.put(b' ');
+                buf.extend_from_slice(node.to_hex().as_bytes());
+                buf.put(b'\n');
+                buf.freeze()
+            }
+            None => {
+                let err_msg = format!("{} not found", key);
+                let mut buf = BytesMut::with_capacity(err_msg.len() + 3);
+                buf.put(b'0');
+                buf.put(b' ');
+                buf.extend_from_slice(err_msg.as_bytes());
+                buf.put(b'\n');
+                buf.freeze()
+            }
+        }).timed(move |stats, _| {
                 add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
             })
             .boxify()
     }
 
+    // @wireprotocommand('listkeys', 'namespace')
+    fn listkeys(&self, namespace: String) -> HgCommandRes<HashMap<Vec<u8>, Vec<u8>>> {
+        info!(self.logger, "listkeys: {}", namespace);
+        let hgrepo = self.repo.hgrepo.clone();
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.repo.scuba_sample(ops::LISTKEYS);
+
+        let res = match namespace.as_str() {
+            "bookmarks" => hgrepo
+                .get_bookmarks()
+                .map(|(name, csid)| -> (Vec<u8>, Vec<u8>) { (name, csid.to_hex().into()) })
+                .collect()
+                .map(|bookmarks| bookmarks.into_iter().collect())
+                .boxify(),
+            "phases" => phases::listkeys(
+                hgrepo,
+                self.repo.repo_generation.clone(),
+                self.repo.skiplist.clone(),
+            ),
+            // Like Mercurial, unknown namespaces are empty
+            _ => future::ok(HashMap::new()).boxify(),
+        };
+
+        res.timed(move |stats, _| {
+            add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+        }).boxify()
+    }
+
+    // @wireprotocommand('listkeyspatterns', 'namespace patterns')
+    fn listkeyspatterns(
+        &self,
+        namespace: String,
+        patterns: Vec<String>,
+    ) -> HgCommandRes<HashMap<Vec<u8>, Vec<u8>>> {
+        info!(self.logger, "listkeyspatterns: {} {:?}", namespace, patterns);
+        let hgrepo = self.repo.hgrepo.clone();
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.repo.scuba_sample(ops::LISTKEYSPATTERNS);
+
+        let res = match namespace.as_str() {
+            "bookmarks" => infinitepush::listkeyspatterns(hgrepo, patterns),
+            _ => future::ok(HashMap::new()).boxify(),
+        };
+
+        res.timed(move |stats, _| {
+            add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+        }).boxify()
+    }
+
+    // @wireprotocommand('pushkey', 'namespace key old new')
+    fn pushkey(
+        &self,
+        namespace: String,
+        key: String,
+        old: Bytes,
+        new: Bytes,
+    ) -> HgCommandRes<bool> {
+        info!(self.logger, "pushkey: {} {} {:?} {:?}", namespace, key, old, new);
+        let hgrepo = self.repo.hgrepo.clone();
+        let repo_generation = self.repo.repo_generation.clone();
+        let skiplist = self.repo.skiplist.clone();
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.repo.scuba_sample(ops::PUSHKEY);
+
+        let res = match namespace.as_str() {
+            "phases" => (|| -> Result<_> {
+                let node = NodeHash::from_str(&key)?;
+                Ok((node, phases::parse_phase(&old)?, phases::parse_phase(&new)?))
+            })().into_future()
+                .and_then(move |(node, old, new)| {
+                    phases::pushkey(hgrepo, repo_generation, skiplist, node, old, new)
+                })
+                .boxify(),
+            // Bookmarks are only updated by pushes, and nothing else can be pushed
+            _ => future::ok(false).boxify(),
+        };
+
+        res.timed(move |stats, _| {
+            add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+        }).boxify()
+    }
+
     // @wireprotocommand('known', 'nodes *'), but the '*' is ignored
     fn known(&self, nodes: Vec<NodeHash>) -> HgCommandRes<Vec<bool>> {
         info!(self.logger, "known: {:?}", nodes);
-        let repo_generation = &self.repo.repo_generation;
-        let hgrepo = &self.repo.hgrepo;
         let scuba = self.repo.scuba.clone();
         let mut sample = self.repo.scuba_sample(ops::KNOWN);
 
-        // Ultimately, this block takes all ancestors of heads in this repo intersected with
-        // the nodes passed in by the client, and then returns a Vec<bool>, true if the
-        // intersection contains the matching node in nodes, false if it does not.
-        // Note that revsets are lazy, and will not generate unnecessary nodes.
-        hgrepo
-            .get_heads()
-            // Convert Stream<Heads> into Stream<Ancestors<Heads>>
-            .map({
-                let repo_generation = repo_generation.clone();
-                let hgrepo = hgrepo.clone();
-                move |hash| AncestorsNodeStream::new(&hgrepo, repo_generation.clone(), hash).boxed()
-            })
-            // Convert Stream<Ancestors<Heads>>> into Future<Vec<Ancestors<Heads>>>
-            .collect()
-            // Do the next few steps inside the Future; the parameter to the closure is
-            // Vec<Ancestors<Heads>>
-            .map({
-                let repo_generation = repo_generation.clone();
-                let hgrepo = hgrepo.clone();
-                let nodes = nodes.clone();
-                move |vec| {
-                    // Intersect the union of the Vec<Ancestors<Heads>> that's passed in, with
-                    // a union of the known nodes the client asked about.
-                    IntersectNodeStream::new(
-                        &hgrepo,
-                        repo_generation.clone(),
-                        vec![
-                            // This is the union of all ancestors of heads
-                            UnionNodeStream::new(&hgrepo, repo_generation.clone(), vec).boxed(),
-                            // This is the union of all passed in nodes.
-                            UnionNodeStream::new(
-                                &hgrepo,
-                                repo_generation,
-                                nodes.into_iter().map({
-                                    let hgrepo = hgrepo.clone();
-                                    move |node| SingleNodeHash::new(node, &hgrepo).boxed()
-                                }),
-                            ).boxed(),
-                        ],
-                        // collect() below will result in a Future<Vec<NodeHash>> which is all
-                        // nodes that are both an ancestor of a get_heads() head and were
-                        // passed in by the client
-                    ).collect()
-                         .from_err::<hgproto::Error>()
-                }
-            })
-            // We have a Future<Future<Vec<NodeHash>>> - collapse one layer of Future.
-            .flatten()
-            // Finally, within the Future, use the Vec<NodeHash> that's only nodes that were
-            // passed in by the client and that are ancestors of a get_heads() head to convert
-            // the Vec of client known nodes to a Vec<bool> telling the client if we also
-            // know of the nodes it asked us about.
-            .map(move |known| {
-                nodes
-                    .iter()
-                    .map(|node| known.contains(node))
-                    .collect::<Vec<bool>>()
-            })
+        let hgrepo = self.repo.hgrepo.clone();
+        let repo_generation = self.repo.repo_generation.clone();
+        let skiplist = self.repo.skiplist.clone();
+        discovery::known(hgrepo, repo_generation, skiplist, nodes)
             .timed(move |stats, _| {
                 add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
             })
@@ -633,20 +797,51 @@ impl HgCommands for RepoClient {
     fn hello(&self) -> HgCommandRes<HashMap<String, Vec<String>>> {
         info!(self.logger, "Hello -> capabilities");
 
-        let mut res = HashMap::new();
         let mut caps = wireprotocaps();
-        caps.push(format!("bundle2={}", bundle2caps()));
-        res.insert("capabilities".to_string(), caps);
+        caps.push(format!("bundle2={}", bundle2caps(self.repo.obsmarkers)));
+        if !self.repo.clonebundles.is_empty() {
+            caps.push("clonebundles".to_string());
+        }
+        // The snapshot requirements are read on the snapshot's thread pool
+        let stream_cap = match self.repo.stream_clone_snapshot {
+            Some(ref snapshot) => {
+                let logger = self.logger.clone();
+                snapshot
+                    .capability()
+                    .then(move |cap| match cap {
+                        Ok(cap) => Ok(Some(cap)),
+                        Err(err) => {
+                            warn!(logger, "stream clone snapshot is not available: {}", err);
+                            Ok(None)
+                        }
+                    })
+                    .boxify()
+            }
+            None => future::ok(None).boxify(),
+        };
 
         let scuba = self.repo.scuba.clone();
         let mut sample = self.repo.scuba_sample(ops::HELLO);
-        future::ok(res)
+        stream_cap
+            .map(move |stream_cap| {
+                caps.extend(stream_cap);
+                let mut res = HashMap::new();
+                res.insert("capabilities".to_string(), caps);
+                res
+            })
             .timed(move |stats, _| {
                 add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
             })
             .boxify()
     }
 
+    // @wireprotocommand('clonebundles', '')
+    fn clonebundles(&self) -> HgCommandRes<String> {
+        info!(self.logger, "clonebundles");
+
+        future::ok(clonebundles::manifest(&self.repo.clonebundles)).boxify()
+    }
+
     // @wireprotocommand('unbundle')
     fn unbundle(
         &self,
@@ -655,17 +850,33 @@ impl HgCommands for RepoClient {
     ) -> HgCommandRes<Bytes> {
         let res = bundle2_resolver::resolve(
             self.repo.hgrepo.clone(),
+            self.repo.repo_generation.clone(),
+            self.repo.skiplist.clone(),
             self.logger.new(o!("command" => "unbundle")),
             heads,
             stream,
+            self.repo.bundle2_compression.clone(),
+            self.repo.pushvars.clone(),
+            self.repo.obsmarkers,
+            self.output.clone(),
         );
 
         let scuba = self.repo.scuba.clone();
         let mut sample = self.repo.scuba_sample(ops::UNBUNDLE);
 
-        res.timed(move |stats, _| {
+        res.timed(move |stats, result| {
+            if let Ok(response) = result {
+                let mut pushvars: Vec<_> = response
+                    .pushvars
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, value))
+                    .collect();
+                pushvars.sort();
+                sample.add("pushvars", pushvars.join(",").as_str());
+            }
             add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
-        }).boxify()
+        }).map(|response| response.bytes)
+            .boxify()
     }
 
     // @wireprotocommand('gettreepack', 'rootdir mfnodes basemfnodes directories')
@@ -684,66 +895,337 @@ impl HgCommands for RepoClient {
     fn getfiles(&self, params: BoxStream<(NodeHash, MPath), Error>) -> BoxStream<Bytes, Error> {
         info!(self.logger, "getfiles");
         let repo = self.repo.clone();
+        // Files are fetched concurrently, but sent in the order they were requested. The output
+        // is only produced as fast as the client reads it, so the size limit keeps a slow
+        // client from making the fetched files pile up in memory.
         params
-            .and_then(move |(node, path)| {
+            .map(move |(node, path)| {
                 let repo = repo.clone();
                 create_remotefilelog_blob(repo.hgrepo.clone(), node, path).timed(move |stats, _| {
                     let mut sample = repo.scuba_sample(ops::GETFILES);
                     add_common_stats_and_send_to_scuba(repo.scuba.clone(), &mut sample, &stats);
                 })
             })
+            .buffered_weight_limited(
+                self.repo.getfiles_concurrency,
+                self.repo.getfiles_buffer_size,
+                |blob: &Bytes| blob.len(),
+            )
+            .boxify()
+    }
+
+    // @wireprotocommand('getpackv1', '*')
+    fn getpackv1(
+        &self,
+        params: BoxStream<(MPath, Vec<NodeHash>), Error>,
+    ) -> BoxStream<Bytes, Error> {
+        info!(self.logger, "getpackv1");
+        let repo = self.repo.clone();
+        // A request can have thousands of files, so their stats are logged together
+        let request_stats = Arc::new(Mutex::new(GetpackStats::new(self.repo.clone())));
+        let parts = params
+            .map(move |(path, nodes)| {
+                let request_stats = request_stats.clone();
+                let node_count = nodes.len();
+                create_file_wirepack_parts(repo.hgrepo.clone(), path, nodes).timed(
+                    move |stats, _| {
+                        request_stats
+                            .lock()
+                            .expect("lock poisoned")
+                            .add_file(node_count, &stats);
+                    },
+                )
+            })
+            .buffered(GETPACK_FETCH_CONCURRENCY)
+            .map(stream::iter_ok::<_, Error>)
+            .flatten()
+            .chain(stream::once(Ok(wirepack::Part::End)));
+
+        WirePackPacker::new(parts, wirepack::Kind::File)
+            .and_then(|chunk| chunk.into_bytes())
             .boxify()
     }
+
+    // @wireprotocommand('stream_out')
+    fn stream_out(&self) -> BoxStream<Bytes, Error> {
+        info!(self.logger, "stream_out");
+
+        // The snapshot can be large, so it's streamed to the client as it's read rather than
+        // being collected first.
+        match self.repo.stream_clone_snapshot {
+            Some(ref snapshot) => snapshot.stream_out(),
+            None => stream_out_not_allowed(),
+        }
+    }
 }
 
-fn get_changed_entry_stream(
-    repo: Arc<BlobRepo>,
-    mfid: &NodeHash,
-    basemfid: &NodeHash,
-) -> BoxStream<(Box<Entry + Sync>, NodeHash, MPath), Error> {
-    let manifest = repo.get_manifest_by_nodeid(mfid);
-    let basemanifest = repo.get_manifest_by_nodeid(basemfid);
+/// The changeset of a revset that has exactly one. Only the first two changesets are looked at.
+fn lookup_revset(nodes: Box<NodeStream>) -> BoxFuture<Option<NodeHash>, Error> {
+    nodes
+        .take(2)
+        .collect()
+        .map(|nodes| if nodes.len() == 1 { Some(nodes[0]) } else { None })
+        .or_else(|err| match err.downcast_ref::<RevsetErrorKind>() {
+            Some(&RevsetErrorKind::UnknownRevision(_)) => Ok(None),
+            _ => Err(err),
+        })
+        .boxify()
+}
 
-    let changed_entries = manifest
-        .join(basemanifest)
-        .map(|(mf, basemf)| changed_entry_stream(&mf, &basemf, MPath::empty()))
-        .flatten_stream();
+/// Stats of the files of a getpackv1 request. They are sent to scuba as a single sample when
+/// the request is done, i.e. when the response is dropped.
+struct GetpackStats {
+    repo: Arc<HgRepo>,
+    start: Instant,
+    files: usize,
+    nodes: usize,
+    poll_time_ns: i64,
+    poll_count: u64,
+}
+
+impl GetpackStats {
+    fn new(repo: Arc<HgRepo>) -> Self {
+        GetpackStats {
+            repo,
+            start: Instant::now(),
+            files: 0,
+            nodes: 0,
+            poll_time_ns: 0,
+            poll_count: 0,
+        }
+    }
+
+    fn add_file(&mut self, nodes: usize, stats: &Stats) {
+        self.files += 1;
+        self.nodes += nodes;
+        self.poll_time_ns += stats.poll_time.num_nanoseconds().unwrap_or(0);
+        self.poll_count += stats.poll_count as u64;
+    }
+}
+
+impl Drop for GetpackStats {
+    fn drop(&mut self) {
+        if let Some(ref scuba) = self.repo.scuba {
+            let elapsed = self.start.elapsed();
+            let elapsed_ms =
+                elapsed.as_secs() as i64 * 1000 + (elapsed.subsec_nanos() / 1_000_000) as i64;
+
+            let mut sample = self.repo.scuba_sample(ops::GETPACKV1);
+            sample.add("time_elapsed_ms", elapsed_ms);
+            sample.add("poll_time_ns", self.poll_time_ns);
+            sample.add("poll_count", self.poll_count);
+            sample.add("files", self.files as i64);
+            sample.add("nodes", self.nodes as i64);
+            scuba.log(&sample);
+        }
+    }
+}
+
+/// Fetch a changeset and serialize it the way it's stored in the changelog
+fn changelog_entry(hgrepo: &BlobRepo, node: NodeHash) -> BoxFuture<BlobNode, Error> {
+    hgrepo
+        .get_changeset_by_changesetid(&HgChangesetId::new(node))
+        .and_then(|cs| {
+            let mut v = Vec::new();
+            mercurial::changeset::serialize_cs(&cs, &mut v)?;
+            let parents = cs.parents().get_nodes();
+            Ok(BlobNode::new(Bytes::from(v), parents.0, parents.1))
+        })
+        .boxify()
+}
+
+/// Heads of every named branch of the repo. Named branches are only recorded in changeset
+/// extras, so this has to look at every changeset of the repo. Changesets are visited children
+/// first: a changeset is a head of its branch if none of the already visited children is on the
+/// same branch.
+fn compute_branchmap(
+    hgrepo: Arc<BlobRepo>,
+    repo_generation: RepoGenCache,
+    heads: Vec<NodeHash>,
+) -> BoxFuture<BranchMap, Error> {
+    let heads_ancestors = heads.into_iter().map(|head| {
+        AncestorsNodeStream::new(&hgrepo, repo_generation.clone(), head).ordered()
+    });
 
-    let changed_entries = changed_entries
-        .filter_map(move |entry_status| match entry_status.status {
-            EntryStatus::Added(entry) => {
-                if entry.get_type() == Type::Tree {
-                    Some((entry, entry_status.path))
-                } else {
-                    None
+    UnionNodeStream::from_ordered(heads_ancestors)
+        .and_then(move |node| {
+            hgrepo
+                .get_changeset_by_changesetid(&HgChangesetId::new(node))
+                .map(move |cs| (node, cs))
+        })
+        .fold(
+            (HashMap::new(), HashMap::new()),
+            |(mut branchmap, mut child_branches), (node, cs)| {
+                let branch = branch_name(&cs);
+                let child_branches_of_node: HashSet<String> =
+                    child_branches.remove(&node).unwrap_or_default();
+                if !child_branches_of_node.contains(&branch) {
+                    branchmap
+                        .entry(branch.clone())
+                        .or_insert_with(HashSet::new)
+                        .insert(node);
                 }
-            }
-            EntryStatus::Modified(entry, _) => {
-                if entry.get_type() == Type::Tree {
-                    Some((entry, entry_status.path))
-                } else {
-                    None
+                for parent in cs.parents() {
+                    child_branches
+                        .entry(parent)
+                        .or_insert_with(HashSet::new)
+                        .insert(branch.clone());
                 }
-            }
-            EntryStatus::Deleted(..) => None,
-        })
-        .and_then({
-            let hgrepo = repo.clone();
-            move |(entry, path)| fetch_linknode(hgrepo.clone(), entry, path)
+                Ok::<_, Error>((branchmap, child_branches))
+            },
+        )
+        .map(|(branchmap, _)| branchmap)
+        .boxify()
+}
+
+/// Named branch of the changeset. Changesets without an explicit branch are on "default".
+fn branch_name<C: Changeset>(cs: &C) -> String {
+    cs.extra()
+        .get(&b"branch"[..])
+        .map(|branch| String::from_utf8_lossy(branch).into_owned())
+        .unwrap_or_else(|| "default".to_string())
+}
+
+/// Trees that are reachable from `mfnodes` but not from any of `basemfnodes`, together with their
+/// linknodes. Both `mfnodes` and `basemfnodes` are manifests of `rootdir`.
+///
+/// Trees are compared with the trees at the same path in every base, so a subtree that's
+/// unchanged in any of the bases is skipped together with everything below it. A tree is sent
+/// only once per path, no matter from how many of `mfnodes` it's reachable, but identical trees
+/// at different paths are all sent.
+fn get_changed_trees_stream(
+    repo: Arc<BlobRepo>,
+    rootdir: MPath,
+    mfnodes: Vec<NodeHash>,
+    basemfnodes: Vec<NodeHash>,
+    directories: Vec<MPath>,
+    depth: Option<usize>,
+) -> BoxStream<(Box<Entry + Sync>, NodeHash, MPath), Error> {
+    let walk = Arc::new(TreeWalk {
+        repo: repo.clone(),
+        directories,
+        visited: Mutex::new(HashSet::new()),
+    });
+    let bases: Vec<_> = basemfnodes
+        .into_iter()
+        .filter(|basemfnode| *basemfnode != NULL_HASH)
+        .collect();
+
+    stream::iter_ok(mfnodes)
+        .map(move |mfnode| {
+            let root = walk.repo.get_root_entry(&HgManifestId::new(mfnode));
+            walk_tree(walk.clone(), root, rootdir.clone(), bases.clone(), depth)
         })
-        .map(|(entry, linknode, basepath)| (entry, linknode, basepath));
-
-    // Append root manifest
-    let root_entry_stream = Ok(repo.get_root_entry(&HgManifestId::new(*mfid)))
-        .into_future()
-        .and_then({
-            let hgrepo = repo.clone();
-            move |entry| fetch_linknode(hgrepo.clone(), entry, MPath::empty())
+        .flatten()
+        .and_then(move |(entry, basepath)| fetch_linknode(repo.clone(), entry, basepath))
+        .boxify()
+}
+
+struct TreeWalk {
+    repo: Arc<BlobRepo>,
+    // Only these directories and their subdirectories are sent. Empty means everything.
+    directories: Vec<MPath>,
+    // Trees already walked, keyed on their path as well, since the same tree can be in several
+    // directories and each of them has to be sent
+    visited: Mutex<HashSet<(MPath, NodeHash)>>,
+}
+
+impl TreeWalk {
+    fn should_send(&self, path: &MPath) -> bool {
+        self.directories.is_empty() || self.directories.iter().any(|dir| dir.is_prefix_of(path))
+    }
+
+    fn should_descend(&self, path: &MPath) -> bool {
+        self.should_send(path) || self.directories.iter().any(|dir| path.is_prefix_of(dir))
+    }
+}
+
+/// Walk the tree `entry` located in `basepath`. `bases` are the hashes of the trees at the same
+/// path in the base manifests, `depth` is the number of levels left to send.
+fn walk_tree(
+    walk: Arc<TreeWalk>,
+    entry: Box<Entry + Sync>,
+    basepath: MPath,
+    bases: Vec<NodeHash>,
+    depth: Option<usize>,
+) -> BoxStream<(Box<Entry + Sync>, MPath), Error> {
+    let path = basepath.join_element(entry.get_name());
+    let hash = entry.get_hash().into_nodehash();
+
+    if depth == Some(0) || bases.contains(&hash) || !walk.should_descend(&path)
+        || !walk.visited
+            .lock()
+            .expect("lock poisoned")
+            .insert((path.clone(), hash))
+    {
+        return stream::empty().boxify();
+    }
+
+    let this = if walk.should_send(&path) {
+        stream::once(Ok((entry, basepath))).boxify()
+    } else {
+        stream::empty().boxify()
+    };
+
+    if depth == Some(1) {
+        return this;
+    }
+
+    let base_subtrees = future::join_all(bases.into_iter().map({
+        let repo = walk.repo.clone();
+        move |base| {
+            list_subtrees(&repo, base).map(|subtrees| {
+                subtrees
+                    .into_iter()
+                    .map(|subtree| {
+                        (subtree.get_name().clone(), subtree.get_hash().into_nodehash())
+                    })
+                    .collect::<HashMap<_, _>>()
+            })
+        }
+    }));
+
+    let children = list_subtrees(&walk.repo, hash)
+        .join(base_subtrees)
+        .map(move |(subtrees, base_subtrees)| {
+            stream::iter_ok(subtrees)
+                .map(move |subtree| {
+                    let bases = base_subtrees
+                        .iter()
+                        .filter_map(|base_subtrees| base_subtrees.get(subtree.get_name()))
+                        .cloned()
+                        .collect();
+                    walk_tree(
+                        walk.clone(),
+                        subtree,
+                        path.clone(),
+                        bases,
+                        depth.map(|depth| depth - 1),
+                    )
+                })
+                .flatten()
         })
-        .map(|(entry, linknode, basepath)| stream::once(Ok((entry, linknode, basepath))))
         .flatten_stream();
 
-    changed_entries.chain(root_entry_stream).boxify()
+    this.chain(children).boxify()
+}
+
+/// Trees directly under the tree with the given hash
+fn list_subtrees(repo: &BlobRepo, hash: NodeHash) -> BoxFuture<Vec<Box<Entry + Sync>>, Error> {
+    repo.get_root_entry(&HgManifestId::new(hash))
+        .get_content()
+        .and_then(|content| match content {
+            Content::Tree(manifest) => Ok(manifest),
+            _ => Err(format_err!("{} is not a tree", hash)),
+        })
+        .map(|manifest| {
+            manifest
+                .list()
+                .filter(|entry| entry.get_type() == Type::Tree)
+                .collect()
+        })
+        .flatten()
+        .boxify()
 }
 
 fn fetch_linknode(
@@ -751,16 +1233,13 @@ fn fetch_linknode(
     entry: Box<Entry + Sync>,
     basepath: MPath,
 ) -> BoxFuture<(Box<Entry + Sync>, NodeHash, MPath), Error> {
-    let path = match entry.get_name() {
-        &Some(ref name) => {
-            let path = basepath.clone().join(name.clone().into_iter());
-            if entry.get_type() == Type::Tree {
-                RepoPath::DirectoryPath(path)
-            } else {
-                RepoPath::FilePath(path)
-            }
-        }
-        &None => RepoPath::RootPath,
+    let path = basepath.join_element(entry.get_name());
+    let path = if entry.get_type() != Type::Tree {
+        RepoPath::FilePath(path)
+    } else if path.is_empty() {
+        RepoPath::RootPath
+    } else {
+        RepoPath::DirectoryPath(path)
     };
 
     let linknode_fut = repo.get_linknode(path, &entry.get_hash().into_nodehash());
@@ -769,43 +1248,126 @@ fn fetch_linknode(
         .boxify()
 }
 
-fn get_file_history(
-    repo: Arc<BlobRepo>,
-    startnode: NodeHash,
-    path: MPath,
-) -> BoxStream<(NodeHash, Parents, NodeHash, Option<(MPath, NodeHash)>), Error> {
-    if startnode == NULL_HASH {
-        return stream::empty().boxify();
-    }
-    let mut startstate = VecDeque::new();
-    startstate.push_back(startnode);
-    let seen_nodes: HashSet<_> = [startnode].iter().cloned().collect();
-
-    stream::unfold(
-        (startstate, seen_nodes),
-        move |cur_data: (VecDeque<NodeHash>, HashSet<NodeHash>)| {
-            let (mut nodes, mut seen_nodes) = cur_data;
-            let node = nodes.pop_front()?;
+/// Parents of a file revision as remotefilelog expects them to be in the history
+fn remotefilelog_parents(
+    parents: Parents,
+    copy: Option<(MPath, NodeHash)>,
+) -> (NodeHash, NodeHash, Option<MPath>) {
+    let (p1, p2) = match parents {
+        Parents::None => (NULL_HASH, NULL_HASH),
+        Parents::One(p) => (p, NULL_HASH),
+        Parents::Two(p1, p2) => (p1, p2),
+    };
 
-            let parents = repo.get_parents(&node);
-            let copy = repo.get_file_copy(&node);
+    if let Some((copied_from, copied_rev)) = copy {
+        // Mercurial has a complicated copy/renames logic.
+        // If (path1, filenode1) is copied/renamed from (path2, filenode2),
+        // filenode1's p1 is set to filenode2, and copy_from path is set to path2
+        // filenode1's p2 is null for non-merge commits. It might be non-null for merges.
+        (copied_rev, p1, Some(copied_from))
+    } else {
+        (p1, p2, None)
+    }
+}
 
-            let linknode = RepoPath::file(path.clone()).into_future().and_then({
-                let repo = repo.clone();
-                move |path| repo.get_linknode(path, &node)
+/// Wirepack parts with the history and the content of the given revisions of a file. The history
+/// of all the revisions is walked together, so shared ancestors are only sent once. The content
+/// is sent as fulltexts, including the copy metadata, along with the size and the lfs flag.
+fn create_file_wirepack_parts(
+    repo: Arc<BlobRepo>,
+    path: MPath,
+    nodes: Vec<NodeHash>,
+) -> BoxFuture<Vec<wirepack::Part>, Error> {
+    let repopath = try_boxfuture!(RepoPath::file(path.clone()));
+
+    let history = repo.get_file_history(path, nodes.clone())
+        .map(|entry| {
+            let (p1, p2, copied_from) = remotefilelog_parents(entry.parents, entry.copyfrom);
+            (entry.node, p1, p2, entry.linknode, copied_from)
+        })
+        .and_then(|(node, p1, p2, linknode, copied_from)| {
+            let copy_from = match copied_from {
+                Some(copied_from) => Some(RepoPath::file(copied_from)?),
+                None => None,
+            };
+            Ok(wirepack::Part::History(wirepack::HistoryEntry {
+                node,
+                p1,
+                p2,
+                linknode,
+                copy_from,
+            }))
+        })
+        .collect();
+
+    let data = future::join_all(nodes.into_iter().map(move |node| {
+        repo.get_raw_file_content(&node)
+            .join(repo.is_lfs_file(&node))
+            .map(move |(content, is_lfs)| {
+                // As in getfiles, the content of lfs files is the pointer, which the client
+                // resolves through the lfs server.
+                let metadata = wirepack::DataEntryMetadata {
+                    size: Some(content.len() as u64),
+                    flags: Some(if is_lfs { LFS_FLAG as u64 } else { 0 }),
+                };
+                wirepack::Part::Data(wirepack::DataEntry {
+                    node,
+                    delta_base: NULL_HASH,
+                    delta: Delta::new_fulltext(content.to_vec()),
+                    metadata: Some(metadata),
+                })
+            })
+    }));
+
+    history
+        .join(data)
+        .map(move |(history, data)| {
+            let mut parts = Vec::with_capacity(history.len() + data.len() + 2);
+            parts.push(wirepack::Part::HistoryMeta {
+                path: repopath.clone(),
+                entry_count: history.len() as u32,
+            });
+            parts.extend(history);
+            parts.push(wirepack::Part::DataMeta {
+                path: repopath,
+                entry_count: data.len() as u32,
             });
+            parts.extend(data);
+            parts
+        })
+        .boxify()
+}
 
-            let joined = parents
-                .join(linknode)
-                .join(copy)
-                .map(|(pl, c)| (pl.0, pl.1, c));
+/// For every node, follow the first parents down to a merge or a root changeset
+fn find_branches(
+    hgrepo: Arc<BlobRepo>,
+    nodes: Vec<NodeHash>,
+) -> BoxFuture<Vec<BranchRes>, Error> {
+    let branches = nodes.into_iter().map(move |top| {
+        let hgrepo = hgrepo.clone();
+        future::loop_fn(top, move |node| {
+            hgrepo
+                .get_changeset_parents(&HgChangesetId::new(node))
+                .map(move |parents| {
+                    let parents: Vec<_> = parents
+                        .into_iter()
+                        .map(|parent| parent.into_nodehash())
+                        .collect();
+                    if parents.len() == 1 {
+                        Loop::Continue(parents[0])
+                    } else {
+                        Loop::Break(BranchRes {
+                            top,
+                            node,
+                            p0: parents.get(0).cloned(),
+                            p1: parents.get(1).cloned(),
+                        })
+                    }
+                })
+        })
+    });
 
-            Some(joined.map(move |(parents, linknode, copy)| {
-                nodes.extend(parents.into_iter().filter(|p| seen_nodes.insert(*p)));
-                ((node, parents, linknode, copy), (nodes, seen_nodes))
-            }))
-        },
-    ).boxify()
+    future::join_all(branches).boxify()
 }
 
 fn create_remotefilelog_blob(
@@ -814,22 +1376,25 @@ fn create_remotefilelog_blob(
     path: MPath,
 ) -> BoxFuture<Bytes, Error> {
     // raw_content includes copy information
-    let raw_content_bytes = repo.get_file_content(&node).and_then(move |raw_content| {
+    let raw_content = repo.get_file_content(&node);
+    let is_lfs = repo.is_lfs_file(&node);
+    let raw_content_bytes = raw_content.join(is_lfs).and_then(move |(raw_content, is_lfs)| {
         // requires digit counting to know for sure, use reasonable approximation
         let approximate_header_size = 12;
         let mut writer = Cursor::new(Vec::with_capacity(
             approximate_header_size + raw_content.len(),
         ));
 
-        // Write header
-        // TODO(stash): support LFS files using METAKEYFLAG
+        // Write header. The content of lfs files is the pointer, which the client resolves
+        // through the lfs server.
+        let flags = if is_lfs { LFS_FLAG } else { 0 };
         let res = write!(
             writer,
             "v1\n{}{}\n{}{}\0",
             METAKEYSIZE,
             raw_content.len(),
             METAKEYFLAG,
-            0,
+            flags,
         );
 
         res.and_then(|_| writer.write_all(&raw_content))
@@ -837,7 +1402,7 @@ fn create_remotefilelog_blob(
             .map(|_| writer.into_inner())
     });
 
-    let file_history_bytes = get_file_history(repo, node, path)
+    let file_history_bytes = repo.get_file_history(path, vec![node])
         .collect()
         .and_then(|history| {
             let approximate_history_entry_size = 81;
@@ -845,27 +1410,13 @@ fn create_remotefilelog_blob(
                 history.len() * approximate_history_entry_size,
             ));
 
-            for (node, parents, linknode, copy) in history {
-                let (p1, p2) = match parents {
-                    Parents::None => (NULL_HASH, NULL_HASH),
-                    Parents::One(p) => (p, NULL_HASH),
-                    Parents::Two(p1, p2) => (p1, p2),
-                };
-
-                let (p1, p2, copied_from) = if let Some((copied_from, copied_rev)) = copy {
-                    // Mercurial has a complicated copy/renames logic.
-                    // If (path1, filenode1) is copied/renamed from (path2, filenode2),
-                    // filenode1's p1 is set to filenode2, and copy_from path is set to path2
-                    // filenode1's p2 is null for non-merge commits. It might be non-null for merges.
-                    (copied_rev, p1, Some(copied_from))
-                } else {
-                    (p1, p2, None)
-                };
+            for entry in history {
+                let (p1, p2, copied_from) = remotefilelog_parents(entry.parents, entry.copyfrom);
 
-                writer.write_all(node.sha1().as_ref())?;
+                writer.write_all(entry.node.sha1().as_ref())?;
                 writer.write_all(p1.sha1().as_ref())?;
                 writer.write_all(p2.sha1().as_ref())?;
-                writer.write_all(linknode.sha1().as_ref())?;
+                writer.write_all(entry.linknode.sha1().as_ref())?;
                 if let Some(copied_from) = copied_from {
                     writer.write_all(&copied_from.to_vec())?;
                 }
@@ -885,3 +1436,179 @@ fn create_remotefilelog_blob(
         .map(|bytes| Bytes::from(bytes))
         .boxify()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use branch_even;
+    use mercurial_types::Blob;
+
+    fn upload(repo: &BlobRepo, content: String, ty: Type, path: RepoPath) -> NodeHash {
+        let blob: Blob = Bytes::from(content).into();
+        let (hash, upload) = repo.upload_entry(blob, ty, None, None, path).unwrap();
+        upload.wait().unwrap();
+        hash
+    }
+
+    /// A repo whose root manifest has the directories `a` and `b/c`, which are the same tree
+    fn repo_with_repeated_subtree() -> (Arc<BlobRepo>, NodeHash) {
+        let repo = BlobRepo::new_memblob_empty(None).unwrap();
+        let file = upload(
+            &repo,
+            "content".into(),
+            Type::File,
+            RepoPath::file("a/f").unwrap(),
+        );
+        let subtree = upload(
+            &repo,
+            format!("f\0{}\n", file),
+            Type::Tree,
+            RepoPath::dir("a").unwrap(),
+        );
+        let b = upload(
+            &repo,
+            format!("c\0{}t\n", subtree),
+            Type::Tree,
+            RepoPath::dir("b").unwrap(),
+        );
+        let root = upload(
+            &repo,
+            format!("a\0{}t\nb\0{}t\n", subtree, b),
+            Type::Tree,
+            RepoPath::root(),
+        );
+        (Arc::new(repo), root)
+    }
+
+    fn walked_paths(
+        repo: Arc<BlobRepo>,
+        mfnodes: Vec<NodeHash>,
+        directories: Vec<&str>,
+        depth: Option<usize>,
+    ) -> Vec<String> {
+        let walk = Arc::new(TreeWalk {
+            repo: repo.clone(),
+            directories: directories
+                .into_iter()
+                .map(|dir| MPath::new(dir).unwrap())
+                .collect(),
+            visited: Mutex::new(HashSet::new()),
+        });
+        let trees = stream::iter_ok(mfnodes)
+            .map(move |mfnode| {
+                let root = repo.get_root_entry(&HgManifestId::new(mfnode));
+                walk_tree(walk.clone(), root, MPath::empty(), vec![], depth)
+            })
+            .flatten()
+            .collect()
+            .wait()
+            .unwrap();
+
+        let mut paths: Vec<_> = trees
+            .into_iter()
+            .map(|(entry, basepath)| {
+                let path = basepath.join_element(entry.get_name());
+                String::from_utf8_lossy(&path.to_vec()).into_owned()
+            })
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn changed_trees_repeated_subtree() {
+        let (repo, root) = repo_with_repeated_subtree();
+
+        assert_eq!(
+            walked_paths(repo.clone(), vec![root], vec![], None),
+            vec!["", "a", "b", "b/c"]
+        );
+        // The same manifest twice doesn't send anything twice
+        assert_eq!(
+            walked_paths(repo, vec![root, root], vec![], None),
+            vec!["", "a", "b", "b/c"]
+        );
+    }
+
+    #[test]
+    fn changed_trees_depth() {
+        let (repo, root) = repo_with_repeated_subtree();
+
+        assert!(walked_paths(repo.clone(), vec![root], vec![], Some(0)).is_empty());
+        assert_eq!(
+            walked_paths(repo.clone(), vec![root], vec![], Some(1)),
+            vec![""]
+        );
+        assert_eq!(
+            walked_paths(repo, vec![root], vec![], Some(2)),
+            vec!["", "a", "b"]
+        );
+    }
+
+    #[test]
+    fn changed_trees_directories() {
+        let (repo, root) = repo_with_repeated_subtree();
+
+        assert_eq!(
+            walked_paths(repo.clone(), vec![root], vec!["b/c"], None),
+            vec!["b/c"]
+        );
+        // `a` is walked first and is the same tree, but `b/c` is still sent
+        assert_eq!(
+            walked_paths(repo.clone(), vec![root], vec!["a", "b/c"], None),
+            vec!["a", "b/c"]
+        );
+        assert_eq!(
+            walked_paths(repo, vec![root], vec!["b"], Some(1)),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn branches_branch_even() {
+        let repo = Arc::new(branch_even::getrepo(None));
+        let root: NodeHash = "15c40d0abc36d47fb51c8eaec51ac7aad31f669c".parse().unwrap();
+        let head: NodeHash = "4f7f3fd428bec1a48f9314414b063c706d9c1aed".parse().unwrap();
+
+        // There are no merges, so every branch goes down to the root
+        let branches = find_branches(repo, vec![head, root]).wait().unwrap();
+        assert_eq!(
+            branches,
+            vec![
+                BranchRes {
+                    top: head,
+                    node: root,
+                    p0: None,
+                    p1: None,
+                },
+                BranchRes {
+                    top: root,
+                    node: root,
+                    p0: None,
+                    p1: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn lookup_revset_branch_even() {
+        let repo = Arc::new(branch_even::getrepo(None));
+        let root: NodeHash = "15c40d0abc36d47fb51c8eaec51ac7aad31f669c".parse().unwrap();
+        let lookup = |revset: &str| {
+            let revset: Revset = revset.parse().unwrap();
+            lookup_revset(revset.evaluate(&repo, RepoGenCache::new(10)))
+                .wait()
+                .unwrap()
+        };
+
+        assert_eq!(
+            lookup("roots(::4f7f3fd428bec1a48f9314414b063c706d9c1aed)"),
+            Some(root)
+        );
+        // Revsets with several changesets and unknown revisions aren't found
+        assert_eq!(lookup("::4f7f3fd428bec1a48f9314414b063c706d9c1aed"), None);
+        assert_eq!(lookup("::1111111111111111111111111111111111111111"), None);
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Serving stream clones (`stream_out`) from a pre-generated snapshot of revlog store files.
+//!
+//! A snapshot is a directory with the following layout:
+//! ```
+//! <snapshot>/requires   - store requirements, one per line (f.e. "revlogv1")
+//! <snapshot>/store/...  - revlog files, named as they should appear in the client's store
+//! ```
+//! Snapshots are meant to be regenerated periodically. To avoid serving a partially written
+//! snapshot, generate it into a new directory and atomically swap a symlink pointing to it. The
+//! symlink is resolved once per stream, so a swap never mixes files of two snapshots.
+//!
+//! The stream format (version 1) is:
+//! ```
+//! stream := <status> '\n' <filecount> ' ' <bytecount> '\n' <file>{filecount}
+//! file := <name> '\0' <size> '\n' <byte>{size}
+//! ```
+//! where status is 0 on success and 1 if stream clones are not allowed.
+
+use std::cmp;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{stream, Future, Stream};
+use futures_cpupool::CpuPool;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use errors::*;
+
+const STREAM_OK: &[u8] = b"0\n";
+const STREAM_NOT_ALLOWED: &[u8] = b"1\n";
+const READ_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// A stream clone snapshot. The snapshot files are read on a thread pool to avoid blocking the
+/// main thread with IO.
+#[derive(Clone)]
+pub struct StreamCloneSnapshot {
+    path: PathBuf,
+    pool: Arc<CpuPool>,
+}
+
+impl StreamCloneSnapshot {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self::new_with_pool(path, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn new_with_pool<P: Into<PathBuf>>(path: P, pool: Arc<CpuPool>) -> Self {
+        StreamCloneSnapshot {
+            path: path.into(),
+            pool,
+        }
+    }
+
+    /// Store requirements of the snapshot, read from its `requires` file
+    fn requirements(path: &Path) -> Result<Vec<String>> {
+        let mut content = String::new();
+        File::open(path.join("requires"))?.read_to_string(&mut content)?;
+        let mut requirements: Vec<_> = content
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect();
+        requirements.sort();
+        Ok(requirements)
+    }
+
+    /// The wire protocol capability advertising stream clones. Mercurial uses the plain
+    /// `stream` capability if `revlogv1` is the only requirement.
+    pub fn capability(&self) -> BoxFuture<String, Error> {
+        let path = self.path.clone();
+        self.pool
+            .spawn_fn(move || -> Result<String> {
+                let requirements = Self::requirements(&path)?;
+                if requirements == ["revlogv1"] {
+                    Ok("stream".to_string())
+                } else {
+                    Ok(format!("streamreqs={}", requirements.join(",")))
+                }
+            })
+            .boxify()
+    }
+
+    /// List (name, path, size) of all the files of the snapshot's store. Filelogs and
+    /// manifests go first and the changelog goes last, so that a client that reads the files as
+    /// they arrive never sees a changeset whose data it doesn't have yet.
+    fn store_files(path: &Path) -> Result<Vec<(String, PathBuf, u64)>> {
+        fn walk(dir: &Path, prefix: &str, out: &mut Vec<(String, PathBuf, u64)>) -> Result<()> {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry
+                    .file_name()
+                    .into_string()
+                    .map_err(|name| format_err!("non-utf8 file name in snapshot: {:?}", name))?;
+                let name = format!("{}{}", prefix, name);
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    walk(&entry.path(), &format!("{}/", name), out)?;
+                } else {
+                    out.push((name, entry.path(), metadata.len()));
+                }
+            }
+            Ok(())
+        }
+
+        let mut files = Vec::new();
+        walk(&path.join("store"), "", &mut files)?;
+        files.sort_by(|a, b| {
+            let a_changelog = a.0.starts_with("00changelog");
+            let b_changelog = b.0.starts_with("00changelog");
+            (a_changelog, &a.0).cmp(&(b_changelog, &b.0))
+        });
+        Ok(files)
+    }
+
+    /// Stream the content of the snapshot in the stream_out format. The snapshot path is
+    /// resolved before listing the files, and every file is read from the resolved directory.
+    pub fn stream_out(&self) -> BoxStream<Bytes, Error> {
+        let path = self.path.clone();
+        let pool = self.pool.clone();
+
+        self.pool
+            .spawn_fn(move || -> Result<_> { Self::store_files(&fs::canonicalize(&path)?) })
+            .map(move |files| {
+                let total_size: u64 = files.iter().map(|&(_, _, size)| size).sum();
+                let header = format!("{} {}\n", files.len(), total_size);
+
+                let file_streams = files.into_iter().map(move |(name, path, size)| {
+                    let file_header =
+                        stream::once(Ok(Bytes::from(format!("{}\0{}\n", name, size))));
+                    file_header
+                        .chain(read_file(pool.clone(), path, size))
+                        .boxify()
+                });
+
+                stream::once(Ok(Bytes::from(STREAM_OK)))
+                    .chain(stream::once(Ok(Bytes::from(header))))
+                    .chain(stream::iter_ok(file_streams).flatten())
+            })
+            .flatten_stream()
+            .boxify()
+    }
+}
+
+/// Response to stream_out for repos that don't allow stream clones
+pub fn stream_out_not_allowed() -> BoxStream<Bytes, Error> {
+    stream::once(Ok(Bytes::from(STREAM_NOT_ALLOWED))).boxify()
+}
+
+/// Read exactly `size` bytes of the file in chunks. The size was announced to the client before
+/// reading started, so a file that changed size in the meantime is an error.
+fn read_file(pool: Arc<CpuPool>, path: PathBuf, size: u64) -> BoxStream<Bytes, Error> {
+    let file = pool.spawn_fn(move || File::open(&path).map_err(Error::from));
+    file.map(move |file| {
+        stream::unfold((file, size), move |(mut file, remaining)| {
+            if remaining == 0 {
+                return None;
+            }
+            Some(pool.spawn_fn(move || -> Result<_> {
+                let mut buf = vec![0; cmp::min(READ_CHUNK_SIZE, remaining) as usize];
+                file.read_exact(&mut buf)?;
+                let len = buf.len() as u64;
+                Ok((Bytes::from(buf), (file, remaining - len)))
+            }))
+        })
+    }).flatten_stream()
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Write;
+
+    use tempdir::TempDir;
+
+    fn write_file<P: AsRef<Path>>(path: P, content: &[u8]) {
+        let path = path.as_ref();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap().write_all(content).unwrap();
+    }
+
+    #[test]
+    fn test_capability() {
+        let dir = TempDir::new("streamclone").unwrap();
+        let snapshot = StreamCloneSnapshot::new(dir.path());
+
+        write_file(dir.path().join("requires"), b"revlogv1\n");
+        assert_eq!(snapshot.capability().wait().unwrap(), "stream");
+
+        write_file(dir.path().join("requires"), b"revlogv1\ngeneraldelta\n");
+        assert_eq!(
+            snapshot.capability().wait().unwrap(),
+            "streamreqs=generaldelta,revlogv1"
+        );
+    }
+
+    #[test]
+    fn test_stream_out() {
+        let dir = TempDir::new("streamclone").unwrap();
+        write_file(dir.path().join("requires"), b"revlogv1\n");
+        write_file(dir.path().join("store/00changelog.i"), b"changelog");
+        write_file(dir.path().join("store/00manifesttree.i"), b"manifest");
+        write_file(dir.path().join("store/meta/dir/00manifest.i"), b"tree");
+
+        let snapshot = StreamCloneSnapshot::new(dir.path());
+        let chunks = snapshot.stream_out().collect().wait().unwrap();
+        let output: Vec<u8> = chunks.iter().flat_map(|b| b.iter().cloned()).collect();
+
+        assert_eq!(
+            output,
+            b"0\n\
+              3 21\n\
+              00manifesttree.i\x008\nmanifest\
+              meta/dir/00manifest.i\x004\ntree\
+              00changelog.i\x009\nchangelog"
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn test_stream_out_snapshot_swap() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new("streamclone").unwrap();
+        write_file(dir.path().join("old/requires"), b"revlogv1\n");
+        write_file(dir.path().join("old/store/00changelog.i"), b"old");
+        write_file(dir.path().join("new/requires"), b"revlogv1\n");
+        write_file(dir.path().join("new/store/00changelog.i"), b"new content");
+        let link = dir.path().join("snapshot");
+        symlink(dir.path().join("old"), &link).unwrap();
+
+        // Swap the snapshot once the sizes were sent
+        let snapshot = StreamCloneSnapshot::new(&link);
+        let (status, rest) = snapshot.stream_out().into_future().wait().ok().unwrap();
+        let (header, rest) = rest.into_future().wait().ok().unwrap();
+        fs::remove_file(&link).unwrap();
+        symlink(dir.path().join("new"), &link).unwrap();
+        let chunks = rest.collect().wait().unwrap();
+
+        let mut output: Vec<u8> = Vec::new();
+        for chunk in status.into_iter().chain(header).chain(chunks) {
+            output.extend_from_slice(&chunk);
+        }
+        assert_eq!(output, b"0\n1 3\n00changelog.i\x003\nold".to_vec());
+    }
+}
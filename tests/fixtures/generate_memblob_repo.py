@@ -45,6 +45,7 @@ extern crate membookmarks;
 extern crate mercurial_types;
 extern crate memheads;
 extern crate memlinknodes;
+extern crate obsmarkers;
 extern crate blobrepo;
 extern crate blobstore;
 extern crate ascii;
@@ -62,6 +63,7 @@ use membookmarks::MemBookmarks;
 use mercurial_types::{HgChangesetId, NodeHash, RepositoryId};
 use memheads::MemHeads;
 use memlinknodes::MemLinknodes;
+use obsmarkers::SqliteObsmarkers;
 use blobrepo::BlobRepo;
 use ascii::AsciiString;
 use blobstore::Blobstore;
@@ -76,6 +78,8 @@ pub fn getrepo(logger: Option<Logger>) -> BlobRepo {
     let linknodes = MemLinknodes::new();
     let changesets = SqliteChangesets::in_memory()
         .expect("cannot create in-memory changeset table");
+    let obsmarkers = SqliteObsmarkers::in_memory()
+        .expect("cannot create in-memory obsmarkers table");
     let repo_id = RepositoryId::new(0);
 
 """
@@ -147,7 +151,9 @@ pub fn getrepo(logger: Option<Logger>) -> BlobRepo {
                 )
         rs.writelines(
             """
-    BlobRepo::new_memblob(logger, heads, bookmarks, blobs, linknodes, changesets, repo_id)
+    BlobRepo::new_memblob(
+        logger, heads, bookmarks, blobs, linknodes, changesets, obsmarkers, repo_id
+    )
 }
 """
         )